@@ -2,13 +2,51 @@ use bioreader::sequence::fastq_record::{OwnedFastqRecord, RefFastqRecord};
 use flexmap::values::{VData, VRange};
 use kmerrs::consecutive::kmer::Kmer;
 
-use super::{data_structures::{Alignment, Alignments, Anchor, Seed}, process::{anchor_extractor::{SeedGroupPair, SeedGroupPaired}, range_extractor::Range}, sam::{Cigar, CigarRef}, stats::Stats};
+use crate::options::{LibraryType, Options, QueryCase, SortImpl};
+
+use super::{data_structures::{hamming, Alignment, Alignments, Anchor, Seed}, process::{anchor_extractor::{oriented_insert_size, SeedGroupPair, SeedGroupPaired}, range_extractor::Range}, sam::{Cigar, CigarRef}, stats::Stats};
+
+/// Lists shorter than this use the standard library sort with `--sort-impl auto`.
+pub const SORT_AUTO_STD_BELOW: usize = 64;
+
+/// Stable sort by key with the implementation selected by `--sort-impl`, glidesort if None.
+pub fn sort_by_key<T, K: Ord>(v: &mut [T], key: impl FnMut(&T) -> K, sort_impl: Option<SortImpl>) {
+    let use_std = match sort_impl {
+        Some(SortImpl::Std) => true,
+        Some(SortImpl::Auto) => v.len() < SORT_AUTO_STD_BELOW,
+        Some(SortImpl::Glidesort) | None => false,
+    };
+    if use_std {
+        v.sort_by_key(key);
+    } else {
+        glidesort::sort_by_key(v, key);
+    }
+}
+
+/// As `sort_by_key`, but the standard library unstable sort if None.
+pub fn sort_unstable_by_key<T, K: Ord>(v: &mut [T], key: impl FnMut(&T) -> K, sort_impl: Option<SortImpl>) {
+    match sort_impl {
+        Some(_) => sort_by_key(v, key, sort_impl),
+        None => v.sort_unstable_by_key(key),
+    }
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
     OK, Partial, Dropped
 }
 
+impl Status {
+    /// Value of the `fl:A` tag (`--status-tag`)
+    pub fn tag_char(&self) -> char {
+        match self {
+            Status::OK => 'O',
+            Status::Partial => 'P',
+            Status::Dropped => 'D',
+        }
+    }
+}
+
 
 pub trait Align {
     fn align(&mut self, q: &[u8], r: &[u8]) -> (i32, &Cigar, Status);
@@ -58,6 +96,14 @@ pub fn print_alignment(query: &[u8], reference: &[u8], cigar: &[u8]) {
                 r_str.push(reference[ri] as char);
                 ri += 1;
             }
+            // Skipped reference (e.g. an intron), shown in lower case.
+            c if *c == b'N' => {
+                assert!(ri < reference.len());
+                q_str.push('>');
+                m_str.push(' ');
+                r_str.push(reference[ri].to_ascii_lowercase() as char);
+                ri += 1;
+            }
             c => panic!("Unknown cigar char {}", *c as char),
         }
     });
@@ -67,6 +113,57 @@ pub fn print_alignment(query: &[u8], reference: &[u8], cigar: &[u8]) {
 }
 
 
+pub fn has_lowercase(seq: &[u8]) -> bool {
+    seq.iter().any(u8::is_ascii_lowercase)
+}
+
+/// Copy of a read with its lowercase bases uppercased or masked as N, for seeding and alignment. Seeds and
+/// alignments compare bytes, so `a` would not match `A` otherwise. Output keeps the bases as given.
+pub fn normalize_query_case(seq: &[u8], case: QueryCase) -> Vec<u8> {
+    seq.iter().map(|&c| match (case, c.is_ascii_lowercase()) {
+        (_, false) => c,
+        (QueryCase::Upper, true) => c.to_ascii_uppercase(),
+        (QueryCase::Mask, true) => b'N',
+    }).collect()
+}
+
+/// Reverse complement that keeps the case of every base, for writing a read with lowercase bases as given.
+/// Anything but ACGT becomes N.
+pub fn reverse_complement_keep_case(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|&c| {
+        let complement = match c.to_ascii_uppercase() {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            _ => b'N',
+        };
+        if c.is_ascii_lowercase() { complement.to_ascii_lowercase() } else { complement }
+    }).collect()
+}
+
+/// Whether any of the records has no bases, counting those as `reads_empty`. Empty reads must not be seeded:
+/// k-mer iteration and the flank arithmetic of anchors assume at least one base.
+pub fn skip_empty_reads(recs: &[&RefFastqRecord], stats: &mut Stats) -> bool {
+    let empty = recs.iter().filter(|rec| rec.seq().is_empty()).count();
+    stats.reads_empty += empty;
+    empty > 0
+}
+
+/// Shortest 3' soft-clip compared against `--adapter`; shorter clips match an adapter by chance.
+pub const ADAPTER_MIN_OVERLAP: usize = 5;
+
+/// Start of the adapter in `read` if the `clip` bases soft-clipped at its 3' end match the start of `adapter`,
+/// with at most one mismatch per ten compared bases. Bases past the end of the adapter are not compared.
+pub fn adapter_start(read: &[u8], clip: usize, adapter: &[u8]) -> Option<usize> {
+    if clip < ADAPTER_MIN_OVERLAP || clip >= read.len() {
+        return None
+    }
+    let start = read.len() - clip;
+    let compared = clip.min(adapter.len());
+    (hamming(&read[start..start + compared], &adapter[..compared]) as usize <= compared / 10).then_some(start)
+}
+
 pub fn is_alignment_valid(query: &[u8], reference: &[u8], cigar: &[u8]) -> bool {
     let mut qi = 0;
     let mut ri = 0;
@@ -103,6 +200,10 @@ pub fn is_alignment_valid(query: &[u8], reference: &[u8], cigar: &[u8]) -> bool
                 assert!(ri < reference.len());
                 ri += 1;
             }
+            c if *c == b'N' => {
+                assert!(ri < reference.len());
+                ri += 1;
+            }
             c => panic!("Unknown cigar char {}", *c as char),
         }
     }
@@ -110,6 +211,61 @@ pub fn is_alignment_valid(query: &[u8], reference: &[u8], cigar: &[u8]) -> bool
     return true
 }
 
+/// Rebuilds the query from the aligned reference region and the cigar and returns the number of positions that
+/// disagree with the actual query. Matches are taken from the reference. Mismatches, insertions and softclips
+/// are not encoded in the cigar and are taken from the query, but a mismatch op on identical bases counts as a
+/// disagreement. Unconsumed or overrun query and reference bases count as well, so 0 means the cigar, the
+/// reference region and the clipping fully explain the read.
+pub fn roundtrip_mismatches(query: &[u8], reference: &[u8], cigar: &[u8]) -> usize {
+    let mut reconstructed = Vec::with_capacity(query.len());
+    let mut qi = 0;
+    let mut ri = 0;
+    let mut disagreements = 0;
+
+    for op in cigar {
+        match op {
+            b'M' | b'X' => {
+                match (query.get(qi), reference.get(ri)) {
+                    (Some(&q), Some(&r)) => {
+                        if *op == b'M' {
+                            reconstructed.push(r);
+                        } else {
+                            disagreements += (q == r) as usize;
+                            reconstructed.push(q);
+                        }
+                    },
+                    _ => disagreements += 1,
+                }
+                qi += 1;
+                ri += 1;
+            },
+            b'D' | b'S' => {
+                match query.get(qi) {
+                    Some(&q) => reconstructed.push(q),
+                    None => disagreements += 1,
+                }
+                qi += 1;
+            },
+            b'I' | b'N' => {
+                disagreements += (ri >= reference.len()) as usize;
+                ri += 1;
+            },
+            _ => disagreements += 1,
+        }
+    }
+
+    disagreements += reconstructed.iter().zip(query).filter(|(a, b)| a != b).count();
+    disagreements += query.len().saturating_sub(qi) + reference.len().saturating_sub(ri);
+    disagreements
+}
+
+/// Shrinks a scratch buffer that grew beyond `max_capacity` back to it. The contents are kept.
+pub fn bound_capacity<T>(buffer: &mut Vec<T>, max_capacity: usize) {
+    if buffer.capacity() > max_capacity {
+        buffer.shrink_to(max_capacity);
+    }
+}
+
 pub trait Heuristic {
     fn set_max_alignment_score(&mut self, score: i32);
 }
@@ -118,22 +274,30 @@ pub trait Heuristic {
 pub trait KmerExtractor<const K: usize> {
     fn generate(&mut self, rec: &RefFastqRecord, stats: &mut Stats) -> &[(usize, Kmer<K>)];
     fn retrieve(&self) -> &[(usize, Kmer<K>)];
+    /// Releases scratch memory beyond `max_capacity` elements per buffer (--max-scratch-capacity).
+    fn shrink_scratch(&mut self, max_capacity: usize) { let _ = max_capacity; }
 }
 
 pub trait RangeExtractor<const C: usize, const F: usize> {
     fn generate(&mut self, kmers: &[(usize, Kmer<C>)], stats: &mut Stats) -> &[Range<F>];
     fn retrieve(&self) -> &[Range<F>];
+    /// Releases scratch memory beyond `max_capacity` elements per buffer (--max-scratch-capacity).
+    fn shrink_scratch(&mut self, max_capacity: usize) { let _ = max_capacity; }
 }
 
 pub trait SeedExtractor<const F: usize> {
     fn generate(&mut self, ranges: &[Range<F>], stats: &mut Stats) -> &[Seed];
     fn retrieve(&self) -> &[Seed];
+    /// Releases scratch memory beyond `max_capacity` elements per buffer (--max-scratch-capacity).
+    fn shrink_scratch(&mut self, max_capacity: usize) { let _ = max_capacity; }
 }
 
 pub trait AnchorExtractor {
     fn generate(&mut self, seeds: &[Seed], read_length: usize, stats: &mut Stats) -> &mut [Anchor];
     fn retrieve(&self) -> &[Anchor];
     fn retrieve_mut(&mut self) -> &mut [Anchor];
+    /// Releases scratch memory beyond `max_capacity` elements per buffer (--max-scratch-capacity).
+    fn shrink_scratch(&mut self, max_capacity: usize) { let _ = max_capacity; }
 }
 
 
@@ -142,16 +306,61 @@ pub trait AnchorExtractor {
 pub struct AnchorPair(pub Option<Anchor>, pub Option<Anchor>);
 
 impl AnchorPair {
-    pub fn resolve_orientation(&mut self, read_length_fwd: usize, read_length_rev: usize) {
+    /// Infers the strand of a mate without orientation from the other mate, according to the library type.
+    pub fn resolve_orientation(&mut self, read_length_fwd: usize, read_length_rev: usize, library_type: LibraryType) {
+        let opposite = library_type != LibraryType::Ff;
         if self.0.as_ref().is_some_and(|a| !a.orientation_set) && self.1.as_ref().is_some_and(|a| a.orientation_set) {
-            self.0.as_mut().unwrap().set_forward(!self.1.as_mut().unwrap().forward, read_length_fwd);
+            let forward = self.1.as_ref().unwrap().forward ^ opposite;
+            self.0.as_mut().unwrap().set_forward(forward, read_length_fwd);
         }
 
         if self.1.as_ref().is_some_and(|a| !a.orientation_set) && self.0.as_ref().is_some_and(|a| a.orientation_set) {
-            self.1.as_mut().unwrap().set_forward(!self.0.as_mut().unwrap().forward, read_length_rev);
+            let forward = self.0.as_ref().unwrap().forward ^ opposite;
+            self.1.as_mut().unwrap().set_forward(forward, read_length_rev);
+        }
+    }
+
+    /// Strands of two unoriented mates from their combined seed evidence: the strand of read 1 for which both
+    /// mates match the reference in the orientation required by the library type. Returns false and leaves
+    /// the mates unchanged if no or both strands qualify.
+    pub fn resolve_strand_jointly(&mut self, query_fwd: &[u8], query_fwd_rc: &[u8], query_rev: &[u8], query_rev_rc: &[u8],
+            reference: &[u8], library_type: LibraryType) -> bool {
+        let (a1, a2) = match (self.0.as_mut(), self.1.as_mut()) {
+            (Some(a1), Some(a2)) => (a1, a2),
+            _ => return false,
+        };
+        let opposite = library_type != LibraryType::Ff;
+        let strands1 = a1.possible_strands(query_fwd, query_fwd_rc, reference);
+        let strands2 = a2.possible_strands(query_rev, query_rev_rc, reference);
+        let possible = |(fwd, rev): (bool, bool), forward: bool| if forward { fwd } else { rev };
+
+        let candidates: Vec<bool> = [true, false].into_iter()
+            .filter(|&forward| possible(strands1, forward) && possible(strands2, forward ^ opposite))
+            .collect();
+        match candidates.as_slice() {
+            [forward] => {
+                a1.resolve_orientation_on(*forward, query_fwd, query_fwd_rc, reference)
+                    && a2.resolve_orientation_on(*forward ^ opposite, query_rev, query_rev_rc, reference)
+            },
+            _ => false,
         }
     }
 
+    /// Both mates aligned to the same reference, with strands and order matching the library type and an insert
+    /// size of at most `max_insert_size`.
+    pub fn is_proper_pair(&self, read_length_fwd: usize, read_length_rev: usize, library_type: LibraryType, max_insert_size: i64) -> bool {
+        let (a1, a2) = match (&self.0, &self.1) {
+            (Some(a1), Some(a2)) => (a1, a2),
+            _ => return false,
+        };
+        if a1.reference != a2.reference || !a1.orientation_set || !a2.orientation_set {
+            return false
+        }
+
+        oriented_insert_size(a1, a2, read_length_fwd, read_length_rev, library_type)
+            .is_some_and(|is| is > 0 && is <= max_insert_size)
+    }
+
     pub fn reference(&self) -> u64 {
         if self.0.is_some() { return self.0.as_ref().unwrap().reference } else { return self.1.as_ref().unwrap().reference }
     }
@@ -162,6 +371,8 @@ pub trait PairedAnchorExtractor {
     fn generate(&mut self, seeds_fwd: &[Seed], seeds_rev: &[Seed], read_length_fwd: usize, read_length_rev: usize, stats: &mut Stats) -> &mut [AnchorPair];
     fn retrieve(&self) -> &[AnchorPair];
     fn retrieve_mut(&mut self) -> &mut [AnchorPair];
+    /// Releases scratch memory beyond `max_capacity` elements per buffer (--max-scratch-capacity).
+    fn shrink_scratch(&mut self, max_capacity: usize) { let _ = max_capacity; }
 }
 pub trait PairedAnchorSorter {
     fn sort(&self, anchors: &mut [AnchorPair], rec_fwd: &RefFastqRecord, rec_fwd_revc: &OwnedFastqRecord,
@@ -174,38 +385,76 @@ pub trait AnchorAligner {
 }
 
 pub trait PairedAnchorMAPQ {
-    fn anchor_mapq(anchors: &mut [AnchorPair]) -> u8;
+    fn anchor_mapq(&self, anchors: &mut [AnchorPair]) -> u8;
 }
 
 pub trait AnchorScore {
-    fn score(a: &Anchor) -> i32;
+    fn score(&self, a: &Anchor) -> i32;
+}
+
+/// Weighted anchor score `match_weight * core_matches - mismatch_weight * mismatches - indel_weight * indels`
+/// used for ranking candidate anchors. With `chaining` the chain score stored in `Anchor::score` is used instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StdAnchorScore {
+    pub match_weight: i32,
+    pub mismatch_weight: i32,
+    pub indel_weight: i32,
+    pub chaining: bool,
+}
+
+impl Default for StdAnchorScore {
+    fn default() -> Self {
+        Self { match_weight: 1, mismatch_weight: 1, indel_weight: 1, chaining: false }
+    }
+}
+
+impl StdAnchorScore {
+    pub fn new(match_weight: i32, mismatch_weight: i32, indel_weight: i32) -> Self {
+        Self { match_weight, mismatch_weight, indel_weight, chaining: false }
+    }
+
+    pub fn from_options(options: &Options) -> Self {
+        Self {
+            chaining: options.args.chaining,
+            ..Self::new(options.args.match_weight, options.args.mismatch_weight, options.args.indel_weight)
+        }
+    }
 }
 
-pub struct StdAnchorScore;
 impl AnchorScore for StdAnchorScore {
-    fn score(a: &Anchor) -> i32 {
-        a.core_matches() as i32 - a.mismatches as i32
+    fn score(&self, a: &Anchor) -> i32 {
+        if self.chaining {
+            return a.score
+        }
+        self.match_weight * a.core_matches() as i32
+            - self.mismatch_weight * a.mismatches as i32
+            - self.indel_weight * a.indels() as i32
     }
 }
 
-pub struct StdPairedAnchorMAPQ;
+/// MAPQ as the score difference of the best and the second best pair, both scored with the ranking weights.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdPairedAnchorMAPQ {
+    pub scorer: StdAnchorScore,
+}
+
 impl StdPairedAnchorMAPQ {
-    fn score(a: &Anchor) -> i32 {
-        a.core_matches() as i32 - a.mismatches as i32
+    pub fn new(scorer: StdAnchorScore) -> Self {
+        Self { scorer }
     }
 
-    fn score_paired(a: &AnchorPair) -> i32 {
+    pub fn score_paired(&self, a: &AnchorPair) -> i32 {
         (match &a.0 {
-            Some(a) => Self::score(&a),
+            Some(a) => self.scorer.score(&a),
             None => 0,
         }) + (match &a.1 {
-            Some(a) => Self::score(&a),
+            Some(a) => self.scorer.score(&a),
             None => 0,
         })
     }
 }
 impl PairedAnchorMAPQ for StdPairedAnchorMAPQ {
-    fn anchor_mapq(anchors: &mut [AnchorPair]) -> u8 {
+    fn anchor_mapq(&self, anchors: &mut [AnchorPair]) -> u8 {
         assert!(!anchors.is_empty());
         if anchors.len() <= 1 { return 0 };
 
@@ -213,7 +462,41 @@ impl PairedAnchorMAPQ for StdPairedAnchorMAPQ {
         let best = &anchors[0];
         let second = &anchors[1];
 
-        (Self::score_paired(&best) - Self::score_paired(&second)) as u8
+        (self.score_paired(&best) - self.score_paired(&second)) as u8
+    }
+}
+
+/// BWA-MEM style MAPQ: scales the relative gap between the best and second best pair to [0, 60] and
+/// subtracts `4.343 * ln(n + 1)` for the `n` other pairs scoring within `margin` of the best.
+pub struct BwaPairedAnchorMAPQ {
+    pub margin: i32,
+    pub pair_score: StdPairedAnchorMAPQ,
+}
+
+impl BwaPairedAnchorMAPQ {
+    pub const MAX_MAPQ: f64 = 60.0;
+
+    pub fn new(margin: i32, scorer: StdAnchorScore) -> Self {
+        Self { margin, pair_score: StdPairedAnchorMAPQ::new(scorer) }
+    }
+}
+
+impl PairedAnchorMAPQ for BwaPairedAnchorMAPQ {
+    /// MAPQ of the reported pair `anchors[0]`. The other candidates may come in any order.
+    fn anchor_mapq(&self, anchors: &mut [AnchorPair]) -> u8 {
+        assert!(!anchors.is_empty());
+
+        let best = self.pair_score.score_paired(&anchors[0]);
+        if best <= 0 { return 0 };
+        // A unique hit is as certain as it gets.
+        if anchors.len() == 1 { return Self::MAX_MAPQ as u8 };
+
+        let others = anchors[1..].iter().map(|p| self.pair_score.score_paired(p));
+        let second = others.clone().max().unwrap().max(0);
+        let near_best = others.filter(|&score| best - score <= self.margin).count();
+
+        let mapq = Self::MAX_MAPQ * (1.0 - second as f64 / best as f64) - 4.343 * (near_best as f64 + 1.0).ln();
+        mapq.round().clamp(0.0, Self::MAX_MAPQ) as u8
     }
 }
 
@@ -270,20 +553,69 @@ impl<A,B> Or<A,B> {
         residue_matches: u32,
         alignment_block_length: usize,
         mapping_quality: u8,
+        tags: &[String],
     );
+
+    /// A read without alignment, as minimap2's `--paf-no-hit` line with `*` strand and reference.
+    fn write_unmapped(&mut self, query_name: &str, query_length: usize);
  }
 
  pub trait SAMOutput {
-    fn write();
+    fn write(
+        &mut self,
+        query_name: &str,
+        flag: u16,
+        reference_name: &str,
+        position: usize,
+        mapping_quality: u8,
+        cigar: CigarRef,
+        mate_reference_name: &str,
+        mate_position: usize,
+        template_length: i64,
+        seq: &[u8],
+        qual: &[u8],
+        tags: &[String],
+    );
+ }
+
+ /// BLAST tabular output (outfmt 6 / m8)
+ pub trait M8Output {
+    fn write(
+        &mut self,
+        query_name: &str,
+        subject_name: &str,
+        identity: f64,
+        alignment_length: usize,
+        mismatches: usize,
+        gap_opens: usize,
+        query_start: usize,
+        query_end: usize,
+        subject_start: usize,
+        subject_end: usize,
+        evalue: f64,
+        bitscore: f64,
+    );
  }
 
  #[derive(Clone)]
  pub struct NoSAMOutput;
 
  impl SAMOutput for NoSAMOutput {
-    fn write() {
-        todo!()
-    }
+    fn write(
+        &mut self,
+        _query_name: &str,
+        _flag: u16,
+        _reference_name: &str,
+        _position: usize,
+        _mapping_quality: u8,
+        _cigar: CigarRef,
+        _mate_reference_name: &str,
+        _mate_position: usize,
+        _template_length: i64,
+        _seq: &[u8],
+        _qual: &[u8],
+        _tags: &[String],
+    ) {}
  }
  
 
@@ -339,3 +671,218 @@ impl Print for SeedGroupPairedList {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{align::data_structures::AnchorSeed, options::MAX_INSERT_SIZE};
+
+    fn anchor(seeds: &[(u32, u64, u32)]) -> Anchor {
+        let (qpos, rpos, length) = seeds[0];
+        let mut a = Anchor::from_seed(&Seed { rpos, rval: 0, qpos, mismatch: 0, length: length as u8, flag: 0 });
+        for &(qpos, rpos, length) in &seeds[1..] {
+            a.seeds.push(AnchorSeed { qpos, rpos, length });
+        }
+        a
+    }
+
+    // Read length 100, mate at 1000..1100 and mate at 1200..1300
+    fn pair(first_left: bool, forward1: bool, forward2: bool) -> AnchorPair {
+        let (pos1, pos2) = if first_left { (1000, 1200) } else { (1200, 1000) };
+        let mut a1 = anchor(&[(0, pos1, 31)]);
+        let mut a2 = anchor(&[(0, pos2, 31)]);
+        (a1.orientation_set, a1.forward) = (true, forward1);
+        (a2.orientation_set, a2.forward) = (true, forward2);
+        AnchorPair(Some(a1), Some(a2))
+    }
+
+    #[test]
+    fn proper_pair_fr() {
+        let lt = LibraryType::Fr;
+        assert!(pair(true, true, false).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+        assert!(pair(false, false, true).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+        assert!(!pair(true, false, true).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+        assert!(!pair(true, true, true).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+        // The insert spans both mates, 1000..1300.
+        assert!(pair(true, true, false).is_proper_pair(100, 100, lt, 300));
+        assert!(!pair(true, true, false).is_proper_pair(100, 100, lt, 299));
+    }
+
+    #[test]
+    fn proper_pair_rf() {
+        let lt = LibraryType::Rf;
+        assert!(pair(true, false, true).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+        assert!(pair(false, true, false).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+        assert!(!pair(true, true, false).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+        assert!(!pair(true, false, false).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+    }
+
+    #[test]
+    fn proper_pair_ff() {
+        let lt = LibraryType::Ff;
+        assert!(pair(true, true, true).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+        assert!(pair(false, false, false).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+        assert!(!pair(false, true, true).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+        assert!(!pair(true, true, false).is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+    }
+
+    #[test]
+    fn bwa_mapq_unique_tied_and_separated() {
+        let mapq = BwaPairedAnchorMAPQ::new(5, StdAnchorScore::default());
+        // score_paired is the summed seed length of both mates.
+        let scored = |length: u32| AnchorPair(Some(anchor(&[(0, 1000, length)])), None);
+
+        assert_eq!(mapq.anchor_mapq(&mut [scored(50)]), 60);
+        assert_eq!(mapq.anchor_mapq(&mut [scored(50), scored(50)]), 0);
+        // 60 * (1 - 10/50), no other candidate within the margin.
+        assert_eq!(mapq.anchor_mapq(&mut [scored(50), scored(10)]), 48);
+        // The runner-up does not have to come second.
+        assert_eq!(mapq.anchor_mapq(&mut [scored(50), scored(10), scored(48)]), mapq.anchor_mapq(&mut [scored(50), scored(48), scored(10)]));
+    }
+
+    #[test]
+    fn mapq_weights_rank_pairs() {
+        // 60 matching bases with 2 mismatches against 50 exact ones.
+        let mut mismatching = anchor(&[(0, 1000, 60)]);
+        mismatching.mismatches = 2;
+        let pairs = [AnchorPair(Some(mismatching), None), AnchorPair(Some(anchor(&[(0, 5000, 50)])), None)];
+
+        let unweighted = StdPairedAnchorMAPQ::default();
+        assert!(unweighted.score_paired(&pairs[0]) > unweighted.score_paired(&pairs[1]));
+        assert_eq!(unweighted.anchor_mapq(&mut pairs.clone()), 8);
+
+        let mismatch_averse = StdPairedAnchorMAPQ::new(StdAnchorScore::new(1, 10, 1));
+        assert!(mismatch_averse.score_paired(&pairs[0]) < mismatch_averse.score_paired(&pairs[1]));
+        let mut reranked = [pairs[1].clone(), pairs[0].clone()];
+        assert_eq!(mismatch_averse.anchor_mapq(&mut reranked), 10);
+        // 60 * (1 - 40/50)
+        assert_eq!(BwaPairedAnchorMAPQ::new(5, StdAnchorScore::new(1, 10, 1)).anchor_mapq(&mut reranked), 12);
+    }
+
+    #[test]
+    fn insert_size_agrees_with_proper_pair() {
+        use crate::align::process::anchor_extractor::insert_size;
+        for lt in [LibraryType::Fr, LibraryType::Rf, LibraryType::Ff] {
+            for (first_left, forward1, forward2) in [(true, true, false), (true, false, true), (false, true, false), (true, true, true), (false, false, false)] {
+                let p = pair(first_left, forward1, forward2);
+                let is = insert_size(p.0.as_ref(), p.1.as_ref(), 100, 100, lt);
+                assert_eq!(is.is_some_and(|is| is > 0 && is <= MAX_INSERT_SIZE), p.is_proper_pair(100, 100, lt, MAX_INSERT_SIZE));
+            }
+        }
+    }
+
+    #[test]
+    fn resolve_orientation_follows_library_type() {
+        for (lt, expected) in [(LibraryType::Fr, false), (LibraryType::Rf, false), (LibraryType::Ff, true)] {
+            let mut p = pair(true, true, true);
+            p.1.as_mut().unwrap().orientation_set = false;
+            p.resolve_orientation(100, 100, lt);
+            assert_eq!(p.1.as_ref().unwrap().forward, expected);
+        }
+    }
+
+    #[test]
+    fn resolve_strand_jointly_single_seed_mates() {
+        // GAATTC (2) and CATG (16) are their own reverse complement, GTTT (10) is only found as the reverse complement of AAAC.
+        let reference = b"TTGAATTCTTGTTTTTCATGTT";
+        let single = |rpos: u64, length: u32| AnchorPair(Some(anchor(&[(0, 2, 6)])), Some(anchor(&[(0, rpos, length)])));
+
+        // Read 1 fits both strands, read 2 only the reverse strand, so FR places read 1 forward.
+        let mut p = single(10, 4);
+        assert!(p.resolve_strand_jointly(b"GAATTC", b"GAATTC", b"AAAC", b"GTTT", reference, LibraryType::Fr));
+        let (a1, a2) = (p.0.as_ref().unwrap(), p.1.as_ref().unwrap());
+        assert!(a1.orientation_set && a1.forward);
+        assert!(a2.orientation_set && !a2.forward);
+
+        // Both mates fit both strands.
+        let mut p = single(16, 4);
+        assert!(!p.resolve_strand_jointly(b"GAATTC", b"GAATTC", b"CATG", b"CATG", reference, LibraryType::Fr));
+        assert!(!p.0.as_ref().unwrap().orientation_set && !p.1.as_ref().unwrap().orientation_set);
+    }
+
+    #[test]
+    fn indel_weight_reorders_anchors() {
+        // Single exact seed vs. two seeds with a 2 bp shift in between
+        let exact = anchor(&[(0, 100, 31)]);
+        let gapped = anchor(&[(0, 100, 31), (40, 138, 31)]);
+
+        let default = StdAnchorScore::default();
+        assert!(default.score(&gapped) > default.score(&exact));
+
+        let indel_averse = StdAnchorScore::new(1, 1, 20);
+        assert!(indel_averse.score(&gapped) < indel_averse.score(&exact));
+    }
+
+    #[test]
+    fn skipped_reference_in_cigar() {
+        // 4 query bases, 3 skipped reference bases, 4 query bases.
+        let query = b"ACGTTGCA";
+        let reference = b"ACGTNNNTGCA";
+        let cigar = Cigar::from_rle_str("4M3N4M").unwrap();
+
+        assert!(is_alignment_valid(query, reference, &cigar.0));
+        assert_eq!(roundtrip_mismatches(query, reference, &cigar.0), 0);
+        print_alignment(query, reference, &cigar.0);
+        assert_eq!(cigar.reference_consumed(), reference.len());
+        assert_eq!(cigar.block_length(), query.len());
+
+        let mut pileup = crate::align::pileup::Pileup::default();
+        pileup.add(0, reference.len(), 0, query, &cigar);
+        let depth: Vec<u32> = pileup.references[&0].iter().map(|counts| counts[..5].iter().sum()).collect();
+        assert_eq!(depth, [1, 1, 1, 1, 0, 0, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn lowercase_query_against_uppercase_reference() {
+        let reference = b"TTACGTACCGTTAGGCATCCAGTT";
+        let query = b"ACGTaccgttAGGCATCC";
+        // The second seed lies in the lowercase part of the read.
+        let a = anchor(&[(0, 2, 4), (4, 6, 6), (10, 12, 8)]);
+        assert!(has_lowercase(query));
+        assert!(!a.validate_seeds(query, reference));
+
+        let upper = normalize_query_case(query, QueryCase::Upper);
+        assert!(!has_lowercase(&upper));
+        assert!(a.validate_seeds(&upper, reference));
+        assert_eq!(crate::align::data_structures::hamming(&upper, &reference[2..20]), 0);
+        let cigar = Cigar::from_rle_str("18M").unwrap();
+        assert!(is_alignment_valid(&upper, &reference[2..20], &cigar.0));
+
+        let masked = normalize_query_case(query, QueryCase::Mask);
+        assert_eq!(masked, b"ACGTNNNNNNAGGCATCC");
+        assert_eq!(crate::align::data_structures::hamming(&masked, &reference[2..20]), 6);
+        assert_eq!(reverse_complement_keep_case(query), b"GGATGCCTaacggtACGT");
+    }
+
+    #[test]
+    fn adapter_contaminated_read_aligns_after_trimming() {
+        let reference = b"TTACGTACCGTTAGGCATCCAGTTGACCTA";
+        let adapter = b"AGATCGGAAGAGC";
+        // 18 bases of insert followed by the first 8 adapter bases, which the aligner soft-clips.
+        let read = [&reference[2..20], &adapter[..8]].concat();
+        let cigar = Cigar::from_rle_str("18M8S").unwrap();
+        assert!(is_alignment_valid(&read, &reference[2..20], &cigar.0));
+
+        let start = adapter_start(&read, cigar.count_trailing_chars(b'S'), adapter).unwrap();
+        assert_eq!(start, 18);
+        let trimmed = &read[..start];
+        assert!(is_alignment_valid(trimmed, &reference[2..20], &Cigar::from_rle_str("18M").unwrap().0));
+
+        // A clip that is not adapter is left alone.
+        assert_eq!(adapter_start(&read, 8, b"CCCCCCCCCCCCC"), None);
+        assert_eq!(adapter_start(&read, ADAPTER_MIN_OVERLAP - 1, adapter), None);
+    }
+
+    #[test]
+    fn zero_length_read_is_skipped() {
+        let empty = RefFastqRecord::new(b"empty", b"", b"+", b"");
+        let read = RefFastqRecord::new(b"read", b"ACGT", b"+", b"IIII");
+        let mut stats = Stats::default();
+
+        assert!(!has_lowercase(empty.seq()));
+        assert!(skip_empty_reads(&[&empty], &mut stats));
+        assert!(skip_empty_reads(&[&read, &empty], &mut stats));
+        assert!(!skip_empty_reads(&[&read], &mut stats));
+        assert_eq!(stats.reads_empty, 2);
+    }
+}
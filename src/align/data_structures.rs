@@ -1,13 +1,13 @@
-use std::{cmp::{max, min}, fmt::Display, iter::{zip, Zip}, ops::Range, slice::Iter, thread::current};
+use std::{cmp::{max, min, min_by_key}, fmt::Display, iter::{zip, Zip}, ops::Range, slice::Iter, thread::current};
 
 use bioreader::sequence::{fastq_record::{OwnedFastqRecord, RefFastqRecord}, utils::reverse_complement_into_vec};
 use colored::{Color, Colorize};
 use thiserror::Error;
 use triple_accel::hamming as triple_hamming;
 
-use crate::align::common::Status;
+use crate::{align::common::Status, options::MiddleGap};
 
-use super::{common::{print_alignment, Align, Heuristic}, errors::{AlignmentError, AlignmentResult}, sam::Cigar};
+use super::{common::{print_alignment, Align, Heuristic}, errors::{AlignmentError, AlignmentResult}, process::alignment::{GAP_EXTENSION_PENALTY, GAP_OPENING_PENALTY, MISMATCH_PENALTY}, sam::Cigar};
 
 
 #[derive(Debug, Clone, Error)]
@@ -18,6 +18,17 @@ impl Display for ResolveOrientationError {
     }
 }
 
+/// Longest read accepted. Seeding, anchoring and whole-read alignment are built for short reads (50 to
+/// a few hundred bases); query positions are `u32` and seed lengths `u8`. Longer reads are skipped and counted
+/// rather than aligned, so long reads passed by mistake do not wrap positions.
+pub const MAX_READ_LENGTH: usize = u16::MAX as usize;
+
+/// `Seed::flag` bit of a seed whose core-mer is its own reverse complement. Such a seed fits either strand.
+/// Only set for an even core-mer length C; odd core-mers are never palindromic.
+pub const SEED_PALINDROMIC: u8 = 0b1;
+/// `Anchor::flag` bit of an anchor with seeds merged across mismatches (`--extend-max-mismatch`).
+pub const ANCHOR_SEED_MISMATCHES: u8 = 0b10;
+
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct Seed {
@@ -54,6 +65,15 @@ impl Seed {
         }
     }
 
+    pub fn with_palindromic(mut self, palindromic: bool) -> Self {
+        if palindromic { self.flag |= SEED_PALINDROMIC };
+        self
+    }
+
+    pub fn is_palindromic(&self) -> bool {
+        self.flag & SEED_PALINDROMIC != 0
+    }
+
     pub fn offset(&self) -> u64 {
         self.rpos as u64 - self.qpos as u64
     }
@@ -93,7 +113,7 @@ impl Seed {
             rval: self.rval,
             mismatch: self.mismatch,
             length: self.length,
-            flag: 0,
+            flag: self.flag,
         }
     }
 
@@ -199,6 +219,12 @@ impl AnchorSeed {
         (self.rpos as usize)..(self.rpos + self.length as u64) as usize
     }
 
+    /// Query range of the seed after `reverse(read_length)`, without modifying the seed.
+    pub fn qrange_reverse(&self, read_length: usize) -> Range<usize> {
+        let qpos = read_length - self.length as usize - self.qpos as usize;
+        qpos..qpos + self.length as usize
+    }
+
     pub fn extend_left(&mut self, by: usize) {
         self.qpos -= by as u32;
         self.rpos -= by as u64;
@@ -258,10 +284,6 @@ impl AnchorSeed {
         //  other:     ............            ....     
         //  Ignore c, as other is fully contained in self)
 
-        if other.qpos < self.qpos {
-            eprintln!("Weird!!!");
-        }
-        
         assert!(other.qpos >= self.qpos || other.length > self.length);
 
         let self_start = self.qpos;
@@ -309,6 +331,25 @@ impl AnchorSeed {
 }
 
 
+/// Alignment window across the origin of a circular reference, see `Anchor::circular_window`.
+pub struct CircularWindow {
+    pub sequence: Vec<u8>,
+    /// Reference position of the first window base
+    pub start: usize,
+}
+
+impl CircularWindow {
+    pub fn to_reference(&self, position: usize, ref_length: usize) -> usize {
+        (self.start + position) % ref_length
+    }
+}
+
+/// Mismatches up to which `Anchor::diagonal_align` emits an ungapped cigar without calling the aligner.
+pub const WHOLE_ALIGN_MAX_HAMMING: u64 = 4;
+
+/// Free end columns of the flank alignment of anchors with all seeds on one diagonal.
+pub const FLANK_FREE_ENDS: usize = 10;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 #[repr(C)]
 pub struct Anchor {
@@ -318,13 +359,16 @@ pub struct Anchor {
     pub forward: bool,
     pub orientation_set: bool,
     pub flagged_for_indel: bool,
-    pub flag: u8, //
+    /// `SEED_PALINDROMIC` while the anchor consists of a single palindromic seed, `ANCHOR_SEED_MISMATCHES`
+    pub flag: u8,
     pub counter1: u16,
     pub counter2: u16, // 24
     pub seeds: Vec<AnchorSeed>, // 40
     pub score: i32, // 44
     pub cigar: Option<Cigar>,
     pub reference_cigar_range: Range<usize>,
+    /// Result of the last alignment, `None` if the anchor was not aligned
+    pub status: Option<Status>,
 }
 
 
@@ -344,6 +388,36 @@ pub fn hamming(query: &[u8], reference: &[u8]) -> u64 {
     zip(query, reference).fold(0, |acc, (a,b)| acc + (a != b) as u64)
 }
 
+/// Score of alignment ops under the penalties of the aligner: matches are free, gap runs pay one opening.
+fn ops_score(ops: &[u8]) -> i32 {
+    let mut previous = b'M';
+    ops.iter().map(|&op| {
+        let score = match op {
+            b'X' => -MISMATCH_PENALTY,
+            b'I' | b'D' if op == previous => -GAP_EXTENSION_PENALTY,
+            b'I' | b'D' => -(GAP_OPENING_PENALTY + GAP_EXTENSION_PENALTY),
+            _ => 0,
+        };
+        previous = op;
+        score
+    }).sum()
+}
+
+/// Within the first `window` ops (outermost first), finds the innermost mismatch or gap. Returns the number
+/// of ops up to and including it, and how many query and reference bases these ops consume.
+fn terminal_clip<'b>(ops: impl Iterator<Item = &'b u8>, window: usize) -> (usize, usize, usize) {
+    let (mut clip, mut q, mut r) = (0, 0, 0);
+    let (mut q_acc, mut r_acc) = (0, 0);
+    for (i, op) in ops.take(window).enumerate() {
+        q_acc += matches!(op, b'M' | b'X' | b'D') as usize;
+        r_acc += matches!(op, b'M' | b'X' | b'I' | b'N') as usize;
+        if *op != b'M' {
+            (clip, q, r) = (i + 1, q_acc, r_acc);
+        }
+    }
+    (clip, q, r)
+}
+
 impl Anchor {
     #[inline(always)]
     pub fn from_seed(seed: &Seed) -> Self {
@@ -354,19 +428,138 @@ impl Anchor {
             forward: true,
             orientation_set: false,
             flagged_for_indel: false,
-            flag: 0,
+            flag: seed.flag & SEED_PALINDROMIC,
             counter1: 0,
             counter2: 0,
             score: 0,
             seeds: vec! [AnchorSeed{ qpos: seed.qpos, rpos: seed.rpos, length: seed.length as u32 }],
             cigar: None,
             reference_cigar_range: 0..0,
+            status: None,
+        }
+    }
+
+    /// `smart_align` for a circular reference (`--circular`). A read that overhangs the reference start or end is
+    /// aligned against a window joining the end and the start of the reference. An alignment across the origin is
+    /// then clipped to one side of it, see `clip_at_origin`.
+    pub fn smart_align_circular(&mut self, aligner: &mut (impl Align + Heuristic), query: &[u8], reference: &[u8], free_ends: usize, end_clip: usize, middle_gap: MiddleGap, max_score: i32) -> Status {
+        let Some((mut wrapped, window)) = self.circular_window(reference, query.len()) else {
+            return self.smart_align(aligner, query, reference, free_ends, end_clip, middle_gap, max_score)
+        };
+
+        let status = wrapped.smart_align(aligner, query, &window.sequence, free_ends, end_clip, middle_gap, max_score);
+        let start = window.to_reference(wrapped.reference_cigar_range.start, reference.len());
+        self.reference_cigar_range = start..start + wrapped.reference_cigar_range.len();
+        self.cigar = wrapped.cigar;
+        self.score = wrapped.score;
+        self.clip_at_origin(reference.len());
+        status
+    }
+
+    /// Soft-clips the part of an alignment that continues past the end of a circular reference of `ref_length`
+    /// bases, keeping the side of the origin with more reference bases. The score stays that of the whole alignment.
+    pub fn clip_at_origin(&mut self, ref_length: usize) {
+        let range = self.reference_cigar_range.clone();
+        let Some(cigar) = self.cigar.as_mut() else { return };
+        if range.end <= ref_length { return }
+
+        let is_reference = |op: &u8| matches!(op, b'M' | b'X' | b'=' | b'I' | b'N');
+        let is_query = |op: &u8| matches!(op, b'M' | b'X' | b'=' | b'D' | b'S');
+        let before = ref_length - range.start;
+        let after = range.len() - before;
+
+        // First column after the last reference base before the origin.
+        let mut consumed = 0;
+        let split = cigar.0.iter().position(|op| {
+            if consumed == before { return true }
+            consumed += is_reference(op) as usize;
+            false
+        }).unwrap_or(cigar.0.len());
+        let (head, tail) = cigar.0.split_at(split);
+
+        // A gap next to the clip is clipped with it, so that the kept part starts and ends with an aligned base.
+        let is_gap = |op: &&u8| **op == b'I' || **op == b'D';
+        let mut ops = Vec::with_capacity(cigar.0.len());
+        if before >= after {
+            let kept = head.len() - head.iter().rev().take_while(is_gap).count();
+            let deleted = head[kept..].iter().filter(|&&op| op == b'I').count();
+            ops.extend_from_slice(&head[..kept]);
+            ops.extend(head[kept..].iter().chain(tail).filter(|&&op| is_query(&op)).map(|_| b'S'));
+            self.reference_cigar_range = range.start..ref_length - deleted;
+        } else {
+            let clipped = tail.iter().take_while(is_gap).count();
+            let deleted = tail[..clipped].iter().filter(|&&op| op == b'I').count();
+            ops.extend(head.iter().chain(&tail[..clipped]).filter(|&&op| is_query(&op)).map(|_| b'S'));
+            ops.extend_from_slice(&tail[clipped..]);
+            self.reference_cigar_range = deleted..after;
+        }
+        cigar.0 = ops;
+    }
+
+    /// Bases of the read beyond the reference start and end, on the diagonals of the first and last seed.
+    pub fn circular_overhang(&self, read_length: usize, ref_length: usize) -> (usize, usize) {
+        let (first, last) = (self.seeds.first().unwrap(), self.seeds.last().unwrap());
+        (first.qbegin().saturating_sub(first.rbegin()), (read_length - last.qend()).saturating_sub(ref_length - last.rend()))
+    }
+
+    /// A copy of the anchor with its seeds moved into a window that joins the end and the start of a circular
+    /// reference. `None` if the read does not overhang exactly one end of the reference.
+    pub fn circular_window(&self, reference: &[u8], read_length: usize) -> Option<(Anchor, CircularWindow)> {
+        let len = reference.len();
+        let (left, right) = self.circular_overhang(read_length, len);
+        if (left == 0) == (right == 0) {
+            return None
+        }
+
+        // Half a window on each side of the origin. Short references are doubled entirely.
+        let half = min(len, 2 * read_length);
+        let mut sequence = Vec::with_capacity(2 * half);
+        sequence.extend_from_slice(&reference[len - half..]);
+        sequence.extend_from_slice(&reference[..half]);
+
+        let mut wrapped = self.clone();
+        for seed in wrapped.seeds.iter_mut() {
+            let rpos = if left > 0 { seed.rbegin() + half } else { seed.rbegin().checked_sub(len - half)? };
+            if rpos + seed.length as usize > sequence.len() {
+                return None
+            }
+            seed.rpos = rpos as u64;
         }
+        Some((wrapped, CircularWindow { sequence, start: len - half }))
+    }
+
+    /// Ungapped alignment of the whole read on the diagonal of its seeds, without calling the aligner. Only taken
+    /// if all seeds lie on one diagonal, the read fits the reference there and has at most
+    /// `WHOLE_ALIGN_MAX_HAMMING` mismatches, none within `end_clip` bases of an end, scoring within `max_score`.
+    /// Returns whether the anchor was aligned.
+    pub fn diagonal_align(&mut self, query: &[u8], reference: &[u8], end_clip: usize, max_score: i32) -> bool {
+        let (qr, rr) = self.whole(query.len(), reference.len());
+        let diagonal = |s: &AnchorSeed| s.rbegin() as i64 - s.qbegin() as i64;
+        if qr.len() != query.len() || rr.len() != qr.len() || !self.seeds.iter().all(|s| diagonal(s) == diagonal(&self.seeds[0])) {
+            return false
+        }
+
+        let (q, r) = (&query[qr], &reference[rr.clone()]);
+        let mismatches = triple_hamming(q, r) as u64;
+        let penalty = mismatches as i32 * MISMATCH_PENALTY;
+        let clip = min(end_clip, q.len());
+        let clean_ends = q[..clip] == r[..clip] && q[q.len() - clip..] == r[r.len() - clip..];
+        if mismatches > WHOLE_ALIGN_MAX_HAMMING || penalty > max_score || !clean_ends {
+            return false
+        }
+
+        self.cigar = Some(Cigar(zip(q, r).map(|(q, r)| if q == r { b'M' } else { b'X' }).collect()));
+        self.score = -penalty;
+        self.reference_cigar_range = rr;
+        true
     }
 
     pub fn whole_align(&mut self, aligner: &mut (impl Align + Heuristic), query: &[u8], reference: &[u8], free_ends: usize, mut max_score: i32) -> Status {
         let (mut qr, mut rr) = self.whole(query.len(), reference.len());
         
+        if self.diagonal_align(query, reference, 0, i32::MAX) {
+            return Status::OK
+        }
         self.cigar = Some(Cigar::new());
 
         // Left dove 
@@ -414,7 +607,10 @@ impl Anchor {
         status
     }
 
-    pub fn smart_align(&mut self, aligner: &mut (impl Align + Heuristic), query: &[u8], reference: &[u8], free_ends: usize, mut max_score: i32) -> Status {
+    /// `end_clip` soft-clips read ends instead of aligning them if a mismatch or gap falls within that many
+    /// alignment columns of the end. 0 keeps the aligned ends. `middle_gap` selects how gaps between seeds are
+    /// aligned, see `align_middle`.
+    pub fn smart_align(&mut self, aligner: &mut (impl Align + Heuristic), query: &[u8], reference: &[u8], free_ends: usize, end_clip: usize, middle_gap: MiddleGap, mut max_score: i32) -> Status {
         // Accurate alignment of flanks first.
         // Add threshold later and do hamming first, and if the score can possibly improve with perfect alignment, do that
 
@@ -426,7 +622,7 @@ impl Anchor {
         aligner.set_max_alignment_score(max_score + 1);
 
         // eprintln!("Align with max score {}", max_score + 1);
-        let (score,  status, qs, rs) = self.align_left_flank(aligner, query, reference, free_ends);
+        let (score,  status, qs, rs) = self.align_left_flank(aligner, query, reference, free_ends, end_clip);
         match status { 
             Status::OK => {
                 assert!(score != std::i32::MIN);
@@ -441,7 +637,7 @@ impl Anchor {
         alignment_score += score;
 
         // eprintln!("Max score before middle: {}", max_score);
-        let (score, status) = match self.align_middle(query, reference, &mut max_score) {
+        let (score, status) = match self.align_middle(aligner, query, reference, &mut max_score, middle_gap) {
             Ok(res) => res,
             Err(ar) => {
                 match ar {
@@ -465,6 +661,9 @@ impl Anchor {
                         println!("Q: {}", String::from_utf8_lossy(query));
                         println!("Self: {}", self);
                     },
+                    AlignmentError::MissingReferenceError(s) | AlignmentError::UnsupportedPenaltiesError(s) => {
+                        println!("Error: {}", s);
+                    },
                 };
                 panic!("Non recoverable error")
             },
@@ -481,7 +680,7 @@ impl Anchor {
         }
 
         aligner.set_max_alignment_score(max_score + 1);
-        let (score, status) = self.align_right_flank(aligner, query, reference, free_ends);
+        let (score, status) = self.align_right_flank(aligner, query, reference, free_ends, end_clip);
         
         match status { 
             Status::OK => {
@@ -503,7 +702,7 @@ impl Anchor {
     }
 
 
-    pub fn align_left_flank(&mut self, aligner: &mut impl Align, query: &[u8], reference: &[u8], free_ends: usize) -> (i32, Status, usize, usize) {
+    pub fn align_left_flank(&mut self, aligner: &mut impl Align, query: &[u8], reference: &[u8], free_ends: usize, end_clip: usize) -> (i32, Status, usize, usize) {
         
 
         // Accurate alignment of flanks first.
@@ -516,7 +715,7 @@ impl Anchor {
             lcigar.add_softclip(max(lr.0.len(), lr.1.len()));
             self.reference_cigar_range.start = lr.1.start - max(lr.0.len(), lr.1.len());
             // eprintln!("{:?} {:?}", lr.0, lr.1);
-            // Assumes match penalty score is 0, and other scores are negative.
+            // An empty flank scores MATCH_SCORE (0) per base.
             return (0, Status::OK, 0, 0)
         }
 
@@ -559,11 +758,16 @@ impl Anchor {
         let q_softclip: usize = min(r_deletions, q_dove);
         let r_offset: usize = min(q_inserts, r_dove);
         
+        // Optionally clip a noisy read end. The clipped columns no longer count towards the flank score.
+        let ops = &cigar.0[(r_offset + q_softclip)..];
+        let (clip_ops, q_clip, r_clip) = terminal_clip(ops.iter(), end_clip);
+        let score = if clip_ops > 0 { ops_score(&ops[clip_ops..]) } else { score };
+
         // Update cigar and set reference starting point
         let lcigar = self.cigar.as_mut().unwrap();
-        lcigar.add_softclip(q_softclip);
-        lcigar.0.extend_from_slice(&cigar.0[(r_offset + q_softclip)..]);
-        self.reference_cigar_range.start = lr.1.start + r_offset;
+        lcigar.add_softclip(q_softclip + q_clip);
+        lcigar.0.extend_from_slice(&ops[clip_ops..]);
+        self.reference_cigar_range.start = lr.1.start + r_offset + r_clip;
 
         // eprintln!("q_dove {}, r_dove {}, qinsert {}, rdel {}\n -> {} {}", q_dove, r_dove, q_inserts, r_deletions, 0, r_offset);
         // eprintln!("Q {:?}  R {:?}", lr.0, lr.1);
@@ -582,7 +786,7 @@ impl Anchor {
         self.cigar.as_mut().unwrap()
     }
 
-    pub fn align_right_flank(&mut self, aligner: &mut impl Align, query: &[u8], reference: &[u8], free_ends: usize) -> (i32, Status) {
+    pub fn align_right_flank(&mut self, aligner: &mut impl Align, query: &[u8], reference: &[u8], free_ends: usize, end_clip: usize) -> (i32, Status) {
         // Accurate alignment of flanks first.
         // Add threshold later and do hamming first, and if the score can possibly improve with perfect alignment, do that
 
@@ -627,25 +831,30 @@ impl Anchor {
         let q_softclip: usize = min(r_deletions, q_dove);
         let r_offset: usize = min(q_inserts, r_dove);
 
+        let ops = &cigar.0[0..cigar.0.len() - (r_offset + q_softclip)];
+        let (clip_ops, q_clip, r_clip) = terminal_clip(ops.iter().rev(), end_clip);
+        let score = if clip_ops > 0 { ops_score(&ops[0..ops.len() - clip_ops]) } else { score };
+
         // Update cigar and set reference starting point
         let lcigar = self.cigar.as_mut().unwrap();
-        lcigar.0.extend_from_slice(&cigar.0[0..cigar.0.len() - (r_offset + q_softclip)]);
-        lcigar.add_softclip(q_softclip);
-        self.reference_cigar_range.end = rr.1.end - r_offset;
+        lcigar.0.extend_from_slice(&ops[0..ops.len() - clip_ops]);
+        lcigar.add_softclip(q_softclip + q_clip);
+        self.reference_cigar_range.end = rr.1.end - r_offset - r_clip;
 
 
         (score, status)
     }
 
 
-    pub fn align_middle(&mut self, query: &[u8], reference: &[u8], max_score: &mut i32) -> AlignmentResult {
+    /// Pushes the ops of the seeds and the gaps between them. Gaps are compared base by base, except with
+    /// `MiddleGap::Align` where gaps whose query and reference spans differ in length (an indel between the
+    /// seeds) are aligned end to end.
+    pub fn align_middle(&mut self, aligner: &mut (impl Align + Heuristic), query: &[u8], reference: &[u8], max_score: &mut i32, middle_gap: MiddleGap) -> AlignmentResult {
         let mut current_i = 0;
         let mut next_i = 1;
 
-        let matches = (&self.seeds.first().unwrap()).length as usize;
-        self.cigar().add_matches(matches);
-
-        let mut score = 0;
+        let mut score = -self.push_seed_ops(0, query, reference) * MISMATCH_PENALTY;
+        *max_score += score;
         // eprintln!("Score align middle begin: {}", *max_score);
         while next_i < self.seeds.len() {
             let middle_range = self.between(&self.seeds[current_i], &self.seeds[next_i]);
@@ -666,22 +875,32 @@ impl Anchor {
             let middle_q = &query[middle_range.0.clone()];
             let middle_r = &reference[middle_range.1.clone()];
 
-            let mut mismatches = 0;
-            zip(middle_q, middle_r).for_each(|(q,r)| {
-                self.cigar().0.push(if *q == *r { b'M' } else { mismatches += 1; b'X' });
-            });
-            *max_score -= mismatches * 4;
-            score -= mismatches * 4;
-
-            // eprintln!("Score align middle iter:  {} ... mismatches {}", *max_score, mismatches);
-
-            if *max_score < 0 { 
-                // eprintln!("Max score drop middle: {}", *max_score);
-                return Ok((std::i32::MIN, Status::Dropped))
-            };
+            if middle_gap == MiddleGap::Align && middle_q.len() != middle_r.len() {
+                let gap_score = self.align_gap(aligner, middle_q, middle_r, *max_score);
+                if gap_score == std::i32::MIN {
+                    return Ok((std::i32::MIN, Status::Dropped))
+                }
+                *max_score += gap_score;
+                score += gap_score;
+            } else {
+                let mut mismatches = 0;
+                zip(middle_q, middle_r).for_each(|(q,r)| {
+                    self.cigar().0.push(if *q == *r { b'M' } else { mismatches += 1; b'X' });
+                });
+                *max_score -= mismatches * MISMATCH_PENALTY;
+                score -= mismatches * MISMATCH_PENALTY;
+
+                // eprintln!("Score align middle iter:  {} ... mismatches {}", *max_score, mismatches);
+
+                if *max_score < 0 { 
+                    // eprintln!("Max score drop middle: {}", *max_score);
+                    return Ok((std::i32::MIN, Status::Dropped))
+                };
+            }
 
-            let matches = (&self.seeds[next_i]).length as usize;
-            self.cigar().add_matches(matches);
+            let seed_penalty = self.push_seed_ops(next_i, query, reference) * MISMATCH_PENALTY;
+            *max_score -= seed_penalty;
+            score -= seed_penalty;
 
             current_i += 1;
             next_i += 1;
@@ -690,6 +909,66 @@ impl Anchor {
         return Ok((score, Status::OK));
     }
 
+    /// Appends the end to end alignment of a gap between seeds and returns its score, `i32::MIN` if it
+    /// exceeds `max_score`. A gap empty on one side is a single indel and scored without the aligner.
+    fn align_gap(&mut self, aligner: &mut (impl Align + Heuristic), middle_q: &[u8], middle_r: &[u8], max_score: i32) -> i32 {
+        if middle_q.is_empty() || middle_r.is_empty() {
+            // Internally `D` consumes only the query and `I` only the reference.
+            let (op, length) = if middle_r.is_empty() { (b'D', middle_q.len()) } else { (b'I', middle_r.len()) };
+            let score = -(GAP_OPENING_PENALTY + length as i32 * GAP_EXTENSION_PENALTY);
+            if -score > max_score { return std::i32::MIN };
+            self.cigar().0.extend(std::iter::repeat(op).take(length));
+            return score
+        }
+
+        aligner.set_ends_free(0, 0, 0, 0);
+        aligner.set_max_alignment_score(max_score + 1);
+        match aligner.align_into(middle_q, middle_r, self.cigar.as_mut().unwrap()) {
+            (score, Status::OK) => score,
+            _ => std::i32::MIN,
+        }
+    }
+
+    /// Appends `M`/`X` for the bases of a seed and returns the number of mismatches. Seeds are exact matches
+    /// and only compared base by base if `extend_seeds_with_mismatches` merged some across mismatches.
+    fn push_seed_ops(&mut self, index: usize, query: &[u8], reference: &[u8]) -> i32 {
+        let seed = self.seeds[index].clone();
+        if self.flag & ANCHOR_SEED_MISMATCHES == 0 {
+            self.cigar().add_matches(seed.length as usize);
+            return 0
+        }
+        let mut mismatches = 0;
+        let cigar = self.cigar();
+        for (q, r) in zip(&query[seed.qrange()], &reference[seed.rrange()]) {
+            cigar.0.push(if q == r { b'M' } else { mismatches += 1; b'X' });
+        }
+        mismatches
+    }
+
+    /// `extend_seeds`, then merges neighbouring seeds on the same diagonal that are separated by at most
+    /// `max_mismatches` mismatching bases (`--extend-max-mismatch`). Merged seeds are no longer exact matches.
+    pub fn extend_seeds_with_mismatches(&mut self, query: &[u8], reference: &[u8], max_mismatches: usize) {
+        self.extend_seeds(query, reference);
+        if !self.orientation_set || max_mismatches == 0 {
+            return
+        }
+
+        let mut i = 0;
+        while i + 1 < self.seeds.len() {
+            let (middle_q, middle_r) = self.between(&self.seeds[i], &self.seeds[i + 1]);
+            let mismatches = hamming(&query[middle_q.clone()], &reference[middle_r.clone()]) as usize;
+            if middle_q.len() == middle_r.len() && mismatches <= max_mismatches {
+                let right_len = self.seeds[i + 1].length as usize;
+                self.seeds[i].extend_right(middle_q.len() + right_len);
+                self.seeds.remove(i + 1);
+                self.mismatches += mismatches as u32;
+                if mismatches > 0 { self.flag |= ANCHOR_SEED_MISMATCHES };
+                continue
+            }
+            i += 1;
+        }
+    }
+
     pub fn extend_seeds(&mut self, query: &[u8], reference: &[u8]) {
 
         // Check orientation before this !
@@ -823,13 +1102,14 @@ impl Anchor {
         })
     }
     
+    /// Diagonal distance to the first seed of `other`, on the strand where it is closest for unoriented anchors.
     pub fn get_indel(&self, other: &Self, read_length: usize) -> i32 {
         let seed_self = &self.seeds[0];
         let seed_other = &other.seeds[0];
         let offset = if !self.orientation_set || !other.orientation_set {
             let offsets1 = seed_self.offsets(read_length);
             let offsets2 = seed_other.offsets(read_length);
-            min(offsets1.0 - offsets2.0, offsets1.1 - offsets2.1)
+            min_by_key(offsets1.0 - offsets2.0, offsets1.1 - offsets2.1, |d: &i64| d.abs())
         } else {
             let offset1 = seed_self.offset();
             let offset2 = seed_other.offset();
@@ -998,6 +1278,49 @@ impl Anchor {
     // }
 
 
+    /// Final strand resolution for anchors whose orientation could not be inferred from seed geometry
+    /// (typically single-seed anchors), via `any_orientation_valid`. Returns false if no configuration explains
+    /// the seed, in which case the anchor must not be reported.
+    pub fn resolve_orientation(&mut self, rec: &RefFastqRecord, rec_rc: &OwnedFastqRecord, reference: &[u8]) -> bool {
+        if self.orientation_set { return true };
+        if self.seeds.len() != 1 { return false };
+
+        if !self.any_orientation_valid(rec, rec_rc, reference) {
+            // Undo the reversal of the last configuration tried.
+            self.seeds.first_mut().unwrap().reverse(rec.seq().len());
+            return false
+        }
+        // The seed is left in the configuration that matched, on the read or on its reverse complement.
+        self.forward = self.validate_seeds(rec.seq(), reference);
+        self.orientation_set = true;
+        true
+    }
+
+    /// Read strands under which the single seed of an unoriented anchor matches the reference, as (forward, reverse).
+    pub fn possible_strands(&self, query: &[u8], query_rc: &[u8], reference: &[u8]) -> (bool, bool) {
+        if self.orientation_set || self.seeds.len() != 1 {
+            return (self.orientation_set && self.forward, self.orientation_set && !self.forward)
+        }
+        let configs = seed_configs(self.seeds.first().unwrap(), query, query_rc, reference);
+        (configs.iter().any(|c| c.forward() == Some(true)), configs.iter().any(|c| c.forward() == Some(false)))
+    }
+
+    /// `resolve_orientation` restricted to configurations on the given strand.
+    pub fn resolve_orientation_on(&mut self, forward: bool, query: &[u8], query_rc: &[u8], reference: &[u8]) -> bool {
+        if self.orientation_set { return self.forward == forward };
+        if self.seeds.len() != 1 { return false };
+
+        let configs = seed_configs(self.seeds.first().unwrap(), query, query_rc, reference);
+        match configs.iter().find(|c| c.forward() == Some(forward)) {
+            Some(config) => {
+                self.set_config(config, query.len());
+                self.orientation_set = true;
+                true
+            },
+            None => false,
+        }
+    }
+
     pub fn set_config(&mut self, config: &AnchorSeedConfig, read_length: usize) {
         type ASC = AnchorSeedConfig;
         match config {
@@ -1086,23 +1409,20 @@ impl Anchor {
         (start, start + read_length as u64)
     }
 
+    /// Free end columns for `smart_align`: `indel_free_ends` if the anchor is flagged for indel, i.e. its seeds lie
+    /// on different diagonals or another anchor of the read lies on a nearby one, so its flanks may be shifted
+    /// further. `FLANK_FREE_ENDS` otherwise.
+    pub fn flank_free_ends(&self, indel_free_ends: usize) -> usize {
+        if self.flagged_for_indel { indel_free_ends } else { FLANK_FREE_ENDS }
+    }
+
+    /// Adds a seed to the anchor. Seeds must come sorted by rpos. The first seed that is not a duplicate of the
+    /// initial one sets the orientation, seeds that do not fit it (query position going backwards, or overlapping
+    /// the last seed on another diagonal) are dropped. Orientation is never unset once set. Palindromic seeds
+    /// never set it: they are dropped until it is set, and an anchor started by one restarts from the next seed.
     pub fn add_seed(&mut self, seed: &Seed, read_length: u32) {
         self.seed_count += 1;
-
-        let s: &mut AnchorSeed = self.seeds.first_mut().unwrap();
-
-        if s.qpos == seed.qpos && s.rpos == seed.rpos {
-            let _ = s.clone();
-            if s.length > seed.length as u32 {
-                // eprintln!("----Replace\nFirst: qpos {}, rpos {}, len {}\nToAdd: {}", sc.qpos, sc.rpos, sc.length, seed.to_string());
-                self.mismatches = seed.mismatch as u32;
-                s.length = seed.length as u32;
-            }
-            if self.seeds.len() > 1 {
-                panic!("Expected only one seed");
-            }
-            return
-        }
+        let read_length = read_length as usize;
 
         let mut aseed = AnchorSeed {
             qpos: seed.qpos,
@@ -1110,51 +1430,81 @@ impl Anchor {
             length: seed.length as u32,
         };
 
-
         if !self.orientation_set {
-            if aseed.contains(s) {
-                eprintln!("Return1");
-                s.set(&mut aseed);
+            // Exactly one seed, in forward query coordinates.
+            let s: &mut AnchorSeed = self.seeds.first_mut().unwrap();
+
+            if s.qpos == aseed.qpos && s.rpos == aseed.rpos {
+                if s.length > aseed.length {
+                    self.mismatches = seed.mismatch as u32;
+                    s.length = aseed.length;
+                }
                 return
             }
 
-            self.forward = seed.qpos > s.qpos && seed.rpos > s.rpos;
-            eprintln!("Set direction ->> qpos {} rpos {} len {}\n{}\n--->  Forward? {}", s.qpos, s.rpos, s.length, seed.to_string(), self.forward);
+            if seed.is_palindromic() {
+                return
+            }
+            if self.flag & SEED_PALINDROMIC != 0 {
+                *s = aseed;
+                self.mismatches = seed.mismatch as u32;
+                self.flag &= !SEED_PALINDROMIC;
+                return
+            }
+
+            // The second seed follows the first on the reference. It has to follow it on the query in exactly one orientation.
+            let follows_forward = aseed.qbegin() > s.qbegin();
+            let follows_reverse = aseed.qrange_reverse(read_length).start > s.qrange_reverse(read_length).start;
+            if aseed.rpos <= s.rpos || follows_forward == follows_reverse {
+                return
+            }
+
+            self.forward = follows_forward;
             self.orientation_set = true;
             if !self.forward {
-                s.reverse(read_length as usize);
+                s.reverse(read_length);
             }
         }
-        
+
         if !self.forward {
-            aseed.reverse(read_length as usize);
+            aseed.reverse(read_length);
         }
 
-        if aseed.qpos < s.qpos {
-            eprintln!("Return {}  {}", s, aseed);
-            // eprintln!("\n\n\n-----\nAnchor: {} {} Size: {} ... {}", self.forward, self.forward_set, self.seed_count, self.seeds.len());
-            // eprintln!("Anchor: {}", self.to_string());
-            // eprintln!("Seed: {}", seed.to_string());
-            // eprintln!("qpos {}, rpos {}, length {} ", aseed.qpos, aseed.rpos, aseed.length);
-            // eprintln!("{} {}", self.seeds.first().unwrap().qpos, aseed.qpos);
-            if self.orientation_set && self.seed_count == 1 {
-                if !self.forward { s.reverse(read_length as usize); }
-                self.orientation_set = false;
-            }
+        let last = self.seeds.last_mut().unwrap();
+        if aseed.qbegin() < last.qbegin() {
             return
         }
 
-        // Assume seeds come sorted by rpos. This makes the logic for merging seeds a lot easier.
-        // After adding the second seed, orientation is clear. 
-        assert!(aseed.qpos >= self.seeds.first().unwrap().qpos);
-        match self.seeds.last_mut().unwrap().rpos_sorted_merge_into(&aseed) {
-            SeedOverlap::NoOverlap => self.seeds.push(aseed),
-            SeedOverlap::ContainedSelf => {},
-            _ => {},
-            // SeedOverlap::OffsetFwdOther => (),
-            // SeedOverlap::OffsetFwdSelf => todo!(),
-            // SeedOverlap::ContainedOther => todo!(),
+        if aseed.qbegin() >= last.qend() && aseed.rbegin() >= last.rend() {
+            self.flagged_for_indel |= aseed.offset() != last.offset();
+            self.seeds.push(aseed);
+        } else if aseed.offset() == last.offset() {
+            let _ = last.rpos_sorted_merge_into(&aseed);
+        }
+
+        debug_assert!(self.is_consistent(), "{}", self);
+    }
+
+    /// Checks that every seed lies within the read and the reference.
+    pub fn check_seed_bounds(&self, read_length: usize, reference_length: usize) -> Result<(), AlignmentError> {
+        for seed in &self.seeds {
+            if seed.qend() > read_length {
+                return Err(AlignmentError::QueryRangeError(format!("Seed {} exceeds read length {}", seed, read_length)))
+            }
+            if seed.rend() > reference_length {
+                return Err(AlignmentError::ReferenceRangeError(format!("Seed {} exceeds reference length {}", seed, reference_length)))
+            }
+        }
+        Ok(())
+    }
+
+    /// An anchor without orientation has a single forward seed. Otherwise seeds are ascending and disjoint
+    /// on both query and reference.
+    pub fn is_consistent(&self) -> bool {
+        if !self.orientation_set {
+            return self.seeds.len() == 1 && self.forward
         }
+        self.seeds.windows(2).all(|w| w[0].qend() <= w[1].qbegin() && w[0].rend() <= w[1].rbegin())
     }
 
 
@@ -1223,6 +1573,7 @@ impl Default for Anchor {
             seeds: Vec::new(),
             cigar: None,
             reference_cigar_range: 0..0,
+            status: None,
         }
     }
 }
@@ -1236,36 +1587,60 @@ pub enum AnchorSeedConfig {
     None,
 }
 
+impl AnchorSeedConfig {
+    /// Strand of the read in this configuration.
+    pub fn forward(&self) -> Option<bool> {
+        match self {
+            AnchorSeedConfig::QuerySeed | AnchorSeedConfig::QuerySeedRC => Some(true),
+            AnchorSeedConfig::QueryRCSeed | AnchorSeedConfig::QueryRCSeedRC => Some(false),
+            AnchorSeedConfig::None => None,
+        }
+    }
+}
+
+/// All configurations in which a seed matches the reference, in the order preferred by `get_seed_config`.
+pub fn seed_configs(seed: &AnchorSeed, query: &[u8], query_rc: &[u8], reference: &[u8]) -> Vec<AnchorSeedConfig> {
+    let qrange = seed.qrange();
+    let qrange_rc = seed.qrange_reverse(query.len());
+    let reference_seed = &reference[seed.rrange()];
+
+    type ASC = AnchorSeedConfig;
+    [
+        (ASC::QueryRCSeedRC, &query_rc[qrange_rc.clone()]),
+        (ASC::QuerySeed, &query[qrange.clone()]),
+        (ASC::QuerySeedRC, &query[qrange_rc]),
+        (ASC::QueryRCSeed, &query_rc[qrange]),
+    ].into_iter()
+        .filter(|(_, query_seed)| hamming(query_seed, reference_seed) == 0)
+        .map(|(config, _)| config)
+        .collect()
+}
+
 pub fn get_seed_config(seed: &AnchorSeed, query: &[u8], query_rc: &[u8], reference: &[u8]) -> AnchorSeedConfig {
 
     fn seed_match(query_seed: &[u8], reference_seed: &[u8]) -> bool {
         hamming(query_seed, reference_seed) == 0
     }
     
-    let mut seed_rc = seed.clone();
-    seed_rc.reverse(query.len());
+    let qrange = seed.qrange();
+    let qrange_rc = seed.qrange_reverse(query.len());
 
     let reference_seed = &reference[seed.rrange()];
 
     type ASC = AnchorSeedConfig;
-    if seed_match(&query_rc[seed_rc.qrange()], reference_seed) {
+    if seed_match(&query_rc[qrange_rc.clone()], reference_seed) {
         return ASC::QueryRCSeedRC;
     }
-    if seed_match(&query[seed.qrange()], reference_seed) {
+    if seed_match(&query[qrange.clone()], reference_seed) {
         return ASC::QuerySeed;
     }
-    if seed_match(&query[seed_rc.qrange()], reference_seed) {
+    if seed_match(&query[qrange_rc.clone()], reference_seed) {
         return ASC::QuerySeedRC;
     }
-    if seed_match(&query_rc[seed.qrange()], reference_seed) {
+    if seed_match(&query_rc[qrange.clone()], reference_seed) {
         return ASC::QueryRCSeed;
     }
 
-    eprintln!("{}", hamming(&query_rc[seed_rc.qrange()], reference_seed));
-    eprintln!("{}", hamming(&query[seed.qrange()], reference_seed));
-    eprintln!("{}", hamming(&query[seed_rc.qrange()], reference_seed));
-    eprintln!("{}", hamming(&query_rc[seed.qrange()], reference_seed));
-    
     ASC::None
 }
 
@@ -1276,7 +1651,8 @@ pub fn seed_match(seed: &AnchorSeed, query: &[u8], reference: &[u8]) -> bool {
     seed_match(&query[seed.qrange()], &reference[seed.rrange()])
 }
 
-#[derive(Clone)]
+/// A reported alignment: leftmost 0-based reference position and SAM cigar of the read on the aligned strand.
+#[derive(Clone, Debug)]
 pub struct Alignment {
     pub reference_id: u64,
     pub position: u32,
@@ -1290,4 +1666,348 @@ impl Alignment {
     fn valid(&self) -> bool {
         true
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rc(seq: &[u8]) -> Vec<u8> {
+        seq.iter().rev().map(|c| match c {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            c => *c,
+        }).collect()
+    }
+
+    const REFERENCE: &[u8] = b"ACGTACCGTTAGGCATCCAGTTGACCATGGTACGATCGTA";
+
+    #[test]
+    fn resolve_orientation_single_seed_reverse() {
+        // Query is the reverse complement of reference[10..30]. The single seed covers
+        // reference[14..22], which is at position 8 on the forward query.
+        let query = rc(&REFERENCE[10..30]);
+        let quality = vec![b'I'; query.len()];
+        let rec = RefFastqRecord::new(b"read", &query, b"+", &quality);
+        let mut rec_rc = OwnedFastqRecord::new();
+        rec.reverse_complement(&mut rec_rc);
+
+        let seed = Seed { rpos: 14, rval: 0, qpos: 8, mismatch: 0, length: 8, flag: 0 };
+        let mut anchor = Anchor::from_seed(&seed);
+        assert!(!anchor.orientation_set);
+
+        assert!(anchor.resolve_orientation(&rec, &rec_rc, REFERENCE));
+        assert!(anchor.orientation_set);
+        assert!(!anchor.forward);
+        assert!(anchor.validate_seeds(rec_rc.seq(), REFERENCE));
+    }
+
+    #[test]
+    fn seed_configs_are_resolved_without_allocating() {
+        let query = rc(&REFERENCE[10..30]);
+        let query_rc = rc(&query);
+        let seed = Seed { rpos: 14, rval: 0, qpos: 8, mismatch: 0, length: 8, flag: 0 };
+        let mut anchors = vec![Anchor::from_seed(&seed); 1000];
+
+        let (_, allocations) = crate::test_utils::count_allocations(|| {
+            for a in anchors.iter_mut() {
+                let config = get_seed_config(&a.seeds[0], &query, &query_rc, REFERENCE);
+                a.set_config(&config, query.len());
+            }
+        });
+        assert_eq!(allocations, 0);
+        assert!(anchors.iter().all(|a| !a.forward && a.validate_seeds(&query_rc, REFERENCE)));
+    }
+
+    #[test]
+    fn resolve_orientation_unexplained_seed() {
+        let query = b"TTTTTTTTTTTTTTTTTTTT".to_vec();
+        let quality = vec![b'I'; query.len()];
+        let rec = RefFastqRecord::new(b"read", &query, b"+", &quality);
+        let mut rec_rc = OwnedFastqRecord::new();
+        rec.reverse_complement(&mut rec_rc);
+
+        let seed = Seed { rpos: 14, rval: 0, qpos: 8, mismatch: 0, length: 8, flag: 0 };
+        let mut anchor = Anchor::from_seed(&seed);
+
+        assert!(!anchor.resolve_orientation(&rec, &rec_rc, REFERENCE));
+        assert!(!anchor.orientation_set);
+        assert_eq!(anchor.seeds[0].qpos, 8);
+    }
+
+    fn seed(qpos: u32, rpos: u64, length: u8) -> Seed {
+        Seed { rpos, rval: 0, qpos, mismatch: 0, length, flag: 0 }
+    }
+
+    fn anchor_from(seeds: &[Seed], read_length: u32) -> Anchor {
+        let mut anchor = Anchor::from_seed(&seeds[0]);
+        for s in &seeds[1..] {
+            anchor.add_seed(s, read_length);
+            assert!(anchor.is_consistent(), "{}", anchor);
+        }
+        anchor
+    }
+
+    #[test]
+    fn add_seed_forward() {
+        let anchor = anchor_from(&[seed(10, 1010, 15), seed(40, 1040, 15), seed(70, 1070, 15)], 100);
+        assert!(anchor.orientation_set && anchor.forward);
+        assert_eq!(anchor.seeds.iter().map(|s| s.qpos).collect::<Vec<_>>(), vec![10, 40, 70]);
+        assert_eq!(anchor.seed_count, 3);
+    }
+
+    #[test]
+    fn add_seed_reverse() {
+        // Query positions decrease along the reference, so the seeds are stored reversed: 100 - 15 - qpos.
+        let anchor = anchor_from(&[seed(70, 1010, 15), seed(40, 1040, 15), seed(10, 1070, 15)], 100);
+        assert!(anchor.orientation_set && !anchor.forward);
+        assert_eq!(anchor.seeds.iter().map(|s| s.qpos).collect::<Vec<_>>(), vec![15, 45, 75]);
+    }
+
+    #[test]
+    fn add_seed_mixed_keeps_orientation() {
+        // The third seed goes backwards on the query, the fourth overlaps the last seed on another diagonal.
+        let anchor = anchor_from(&[seed(10, 1010, 15), seed(40, 1040, 15), seed(20, 1050, 15), seed(45, 1048, 15)], 100);
+        assert!(anchor.orientation_set && anchor.forward);
+        assert_eq!(anchor.seeds.len(), 2);
+    }
+
+    #[test]
+    fn add_seed_merges_overlap_on_same_diagonal() {
+        let anchor = anchor_from(&[seed(10, 1010, 15), seed(40, 1040, 15), seed(50, 1050, 15)], 100);
+        assert_eq!(anchor.seeds.len(), 2);
+        assert_eq!((anchor.seeds[1].qpos, anchor.seeds[1].length), (40, 25));
+    }
+
+    #[test]
+    fn add_seed_flags_indel_between_seeds() {
+        // The third seed is 3 bases further along the reference than along the read.
+        let anchor = anchor_from(&[seed(10, 1010, 15), seed(40, 1040, 15), seed(70, 1073, 15)], 100);
+        assert!(anchor.flagged_for_indel);
+        assert_eq!(anchor.flank_free_ends(30), 30);
+
+        let anchor = anchor_from(&[seed(10, 1010, 15), seed(40, 1040, 15), seed(70, 1070, 15)], 100);
+        assert!(!anchor.flagged_for_indel);
+        assert_eq!(anchor.flank_free_ends(30), FLANK_FREE_ENDS);
+    }
+
+    #[test]
+    fn middle_gap_with_deletion_is_aligned() {
+        use crate::align::{common::roundtrip_mismatches, process::alignment::LIBWFA2Alignment};
+
+        let reference = b"ACGTTGCAAGCTTACGGATCCATGCAGTCAGGCTAACGTTAGCCATGGCATCGATTCG";
+        // Reference base 25 is deleted in the read, between a seed on 0..20 and one on read 30..50.
+        let query = [&reference[..25], &reference[26..51]].concat();
+        let mut anchor = anchor_from(&[seed(0, 0, 20), seed(30, 31, 20)], query.len() as u32);
+        assert!(anchor.flagged_for_indel);
+
+        let mut aligner = LIBWFA2Alignment::default();
+        anchor.cigar = Some(Cigar::new());
+        let (score, status) = anchor.align_middle(&mut aligner, &query, reference, &mut 100, MiddleGap::Align).unwrap();
+        assert_eq!(status, Status::OK);
+        assert_eq!(score, -(GAP_OPENING_PENALTY + GAP_EXTENSION_PENALTY));
+        assert_eq!(anchor.cigar().0.iter().filter(|&&op| op == b'I').count(), 1);
+        assert_eq!(roundtrip_mismatches(&query, &reference[..51], &anchor.cigar().0), 0);
+
+        // Compared base by base the gap is misaligned.
+        anchor.cigar = Some(Cigar::new());
+        anchor.align_middle(&mut aligner, &query, reference, &mut 100, MiddleGap::Hamming).unwrap();
+        assert!(roundtrip_mismatches(&query, &reference[..51], &anchor.cigar().0) > 0);
+    }
+
+    #[test]
+    fn clipped_read_ends_are_not_scored() {
+        use crate::align::process::alignment::LIBWFA2Alignment;
+
+        let reference = b"ACGTTGCAAGCTTACGGATCCATGCAGTCAGGCTAACGTTAGCCATGGCATCGATTCG";
+        // Mismatches 3 columns from either read end, outside the seed on 10..40.
+        let mut query = reference[..50].to_vec();
+        for i in [2, 47] {
+            query[i] = if query[i] == b'A' { b'C' } else { b'A' };
+        }
+        let mut aligner = LIBWFA2Alignment::default();
+
+        let mut anchor = anchor_from(&[seed(10, 10, 30)], query.len() as u32);
+        assert_eq!(anchor.smart_align(&mut aligner, &query, reference, 0, 0, MiddleGap::Hamming, 100), Status::OK);
+        assert_eq!(anchor.score, -2 * MISMATCH_PENALTY);
+        assert_eq!(anchor.reference_cigar_range, 0..50);
+
+        let mut anchor = anchor_from(&[seed(10, 10, 30)], query.len() as u32);
+        assert_eq!(anchor.smart_align(&mut aligner, &query, reference, 0, 5, MiddleGap::Hamming, 100), Status::OK);
+        assert_eq!(anchor.score, 0);
+        assert_eq!(anchor.reference_cigar_range, 3..47);
+        let cigar = anchor.cigar.as_ref().unwrap();
+        assert_eq!((cigar.count_leading_chars(b'S'), cigar.count_trailing_chars(b'S')), (3, 3));
+    }
+
+    #[test]
+    fn seeds_merged_across_a_mismatch_are_scored() {
+        use crate::align::process::alignment::LIBWFA2Alignment;
+
+        let reference = b"ACGTTGCAAGCTTACGGATCCATGCAGTCAGGCTAACGTTAGCCATGGCATCGATTCG";
+        let mut query = reference[..50].to_vec();
+        query[25] = if query[25] == b'A' { b'C' } else { b'A' };
+        let seeds = [seed(0, 0, 20), seed(30, 30, 20)];
+
+        // Without --extend-max-mismatch the seeds stay apart and only the gap is compared.
+        let mut anchor = anchor_from(&seeds, query.len() as u32);
+        anchor.extend_seeds_with_mismatches(&query, reference, 0);
+        assert_eq!(anchor.flag & ANCHOR_SEED_MISMATCHES, 0);
+        let mut aligner = LIBWFA2Alignment::default();
+        anchor.cigar = Some(Cigar::new());
+        let (score, _) = anchor.align_middle(&mut aligner, &query, reference, &mut 100, MiddleGap::Hamming).unwrap();
+        assert_eq!(score, -MISMATCH_PENALTY);
+        let unmerged = anchor.cigar().0.clone();
+
+        let mut anchor = anchor_from(&seeds, query.len() as u32);
+        anchor.extend_seeds_with_mismatches(&query, reference, 1);
+        assert_eq!(anchor.seeds.len(), 1);
+        assert_ne!(anchor.flag & ANCHOR_SEED_MISMATCHES, 0);
+        anchor.cigar = Some(Cigar::new());
+        let (score, _) = anchor.align_middle(&mut aligner, &query, reference, &mut 100, MiddleGap::Hamming).unwrap();
+        assert_eq!(score, -MISMATCH_PENALTY);
+        assert_eq!(anchor.cigar().0, unmerged);
+    }
+
+    #[test]
+    fn add_seed_without_orientation() {
+        // A duplicate keeps the shorter seed, a seed that follows in both orientations is ignored.
+        let anchor = anchor_from(&[seed(10, 1010, 31), seed(10, 1010, 15), seed(12, 1012, 11)], 100);
+        assert!(!anchor.orientation_set);
+        assert_eq!(anchor.seeds, vec![AnchorSeed { qpos: 10, rpos: 1010, length: 15 }]);
+    }
+
+    #[test]
+    fn add_seed_after_set_forward() {
+        let mut anchor = Anchor::from_seed(&seed(70, 1010, 15));
+        anchor.set_forward(false, 100);
+        anchor.add_seed(&seed(40, 1040, 15), 100);
+        anchor.add_seed(&seed(80, 1060, 15), 100);
+        assert!(anchor.is_consistent());
+        assert_eq!(anchor.seeds.iter().map(|s| s.qpos).collect::<Vec<_>>(), vec![15, 45]);
+    }
+
+    #[test]
+    fn add_seed_palindromic_does_not_set_orientation() {
+        // The palindromic seed fits the forward strand with the next seed, the other seeds are on the reverse strand.
+        let palindromic = seed(10, 1000, 15).with_palindromic(true);
+        let anchor = anchor_from(&[palindromic, seed(70, 1010, 15), seed(40, 1040, 15), seed(10, 1070, 15)], 100);
+        assert!(anchor.orientation_set && !anchor.forward);
+        assert_eq!(anchor.seeds.iter().map(|s| s.qpos).collect::<Vec<_>>(), vec![15, 45, 75]);
+        assert_eq!(anchor.flag, 0);
+    }
+
+    #[test]
+    fn add_seed_palindromic_supports_set_orientation() {
+        // Dropped before the orientation is set, kept after.
+        let palindromic = seed(40, 1040, 15).with_palindromic(true);
+        let anchor = anchor_from(&[seed(10, 1010, 15), palindromic.clone(), seed(70, 1070, 15)], 100);
+        assert!(anchor.orientation_set && anchor.forward);
+        assert_eq!(anchor.seeds.iter().map(|s| s.qpos).collect::<Vec<_>>(), vec![10, 70]);
+
+        let palindromic = seed(70, 1070, 15).with_palindromic(true);
+        let anchor = anchor_from(&[seed(10, 1010, 15), seed(40, 1040, 15), palindromic], 100);
+        assert!(anchor.orientation_set && anchor.forward);
+        assert_eq!(anchor.seeds.iter().map(|s| s.qpos).collect::<Vec<_>>(), vec![10, 40, 70]);
+    }
+
+    #[test]
+    fn seed_past_reference_end() {
+        let anchor = Anchor::from_seed(&seed(5, 30, 15));
+        assert!(anchor.check_seed_bounds(20, 45).is_ok());
+        assert!(matches!(anchor.check_seed_bounds(20, 40), Err(AlignmentError::ReferenceRangeError(_))));
+        assert!(matches!(anchor.check_seed_bounds(18, 45), Err(AlignmentError::QueryRangeError(_))));
+    }
+
+    #[test]
+    fn circular_window_read_across_origin() {
+        // The read covers the last 10 and the first 14 reference bases. Only the second part is seeded.
+        let query = [&REFERENCE[30..], &REFERENCE[..14]].concat();
+        let mut anchor = Anchor::from_seed(&seed(10, 0, 14));
+        anchor.set_forward(true, query.len());
+
+        let (wrapped, window) = anchor.circular_window(REFERENCE, query.len()).unwrap();
+        assert!(wrapped.validate_seeds(&query, &window.sequence));
+        let (qr, rr) = wrapped.whole(query.len(), window.sequence.len());
+        assert_eq!(qr, 0..query.len());
+        assert_eq!(&window.sequence[rr.clone()], &query[..]);
+        assert_eq!(window.to_reference(rr.start, REFERENCE.len()), 30);
+
+        let inside = Anchor::from_seed(&seed(0, 5, 14));
+        assert!(inside.circular_window(REFERENCE, 14).is_none());
+    }
+
+    #[test]
+    fn alignment_across_origin_is_clipped_to_the_reference() {
+        use crate::align::process::alignment::LIBWFA2Alignment;
+        let mut aligner = LIBWFA2Alignment::default();
+
+        // 10 bases before and 14 after the origin: the part after it is kept.
+        let query = [&REFERENCE[30..], &REFERENCE[..14]].concat();
+        let mut anchor = Anchor::from_seed(&seed(10, 0, 14));
+        anchor.set_forward(true, query.len());
+        assert_eq!(anchor.smart_align_circular(&mut aligner, &query, REFERENCE, 0, 0, MiddleGap::Hamming, 100), Status::OK);
+        assert_eq!(anchor.reference_cigar_range, 0..14);
+        assert_eq!(anchor.cigar().0, [[b'S'; 10].as_slice(), &[b'M'; 14]].concat());
+
+        // 20 bases before and 4 after the origin: the part before it is kept.
+        let query = [&REFERENCE[20..], &REFERENCE[..4]].concat();
+        let mut anchor = Anchor::from_seed(&seed(0, 20, 14));
+        anchor.set_forward(true, query.len());
+        assert_eq!(anchor.smart_align_circular(&mut aligner, &query, REFERENCE, 0, 0, MiddleGap::Hamming, 100), Status::OK);
+        assert_eq!(anchor.reference_cigar_range, 20..40);
+        assert_eq!(anchor.cigar().0, [[b'M'; 20].as_slice(), &[b'S'; 4]].concat());
+        assert_eq!(crate::align::common::roundtrip_mismatches(&query, &REFERENCE[20..40], &anchor.cigar().0), 0);
+    }
+
+    #[test]
+    fn gap_at_the_origin_is_clipped() {
+        // Two reference-only columns right before the origin, then 3 bases after it.
+        let mut anchor = Anchor::from_seed(&seed(0, 30, 5));
+        anchor.reference_cigar_range = 30..43;
+        anchor.cigar = Some(Cigar(b"MMMMMMMMIIMMM".to_vec()));
+        anchor.clip_at_origin(40);
+        assert_eq!(anchor.reference_cigar_range, 30..38);
+        assert_eq!(anchor.cigar().0, b"MMMMMMMMSSS");
+
+        let mut anchor = Anchor::from_seed(&seed(0, 38, 2));
+        anchor.reference_cigar_range = 38..48;
+        anchor.cigar = Some(Cigar(b"MMDIMMMMMMM".to_vec()));
+        anchor.clip_at_origin(40);
+        assert_eq!(anchor.reference_cigar_range, 1..8);
+        assert_eq!(anchor.cigar().0, b"SSSMMMMMMM");
+    }
+
+    #[test]
+    fn ungapped_whole_alignment_matches_the_aligner() {
+        use crate::align::process::alignment::LIBWFA2Alignment;
+
+        let reference = b"ACGTTGCAAGCTTACGGATCCATGCAGTCAGGCTAACGTTAGCCATGGCATCGATTCG";
+        let mut query = reference[4..54].to_vec();
+        for i in [3, 30, 44] {
+            query[i] = if query[i] == b'A' { b'C' } else { b'A' };
+        }
+        let mut aligner = LIBWFA2Alignment::default();
+
+        let mut anchor = anchor_from(&[seed(10, 14, 15)], query.len() as u32);
+        assert_eq!(anchor.whole_align(&mut aligner, &query, reference, 0, 100), Status::OK);
+        assert_eq!(anchor.reference_cigar_range, 4..54);
+
+        // The aligner on the same ranges, as whole_align does above WHOLE_ALIGN_MAX_HAMMING mismatches.
+        aligner.set_ends_free(0, 0, 0, 0);
+        let (score, cigar, status) = aligner.align(&query, &reference[4..54]);
+        assert_eq!(status, Status::OK);
+        assert_eq!(anchor.score, score);
+        assert_eq!(anchor.cigar().0, cigar.0);
+
+        // The mismatch at read position 3 lies within an end clip of 5 but not of 3. Clipping it, like a score
+        // above the limit, is left to the flank alignment.
+        let mut anchor = anchor_from(&[seed(10, 14, 15)], query.len() as u32);
+        assert!(anchor.diagonal_align(&query, reference, 3, 3 * MISMATCH_PENALTY));
+        assert!(!anchor_from(&[seed(10, 14, 15)], query.len() as u32).diagonal_align(&query, reference, 5, 100));
+        assert!(!anchor_from(&[seed(10, 14, 15)], query.len() as u32).diagonal_align(&query, reference, 0, 3 * MISMATCH_PENALTY - 1));
+    }
+}
@@ -13,4 +13,8 @@ pub enum AlignmentError {
     ReferenceRangeError(String),
     #[error("{0}")]
     InvalidAlignmentError(String),
+    #[error("{0}")]
+    MissingReferenceError(String),
+    #[error("{0}")]
+    UnsupportedPenaltiesError(String),
 }
\ No newline at end of file
@@ -7,4 +7,7 @@ pub mod common;
 pub mod modular_workflow;
 pub mod process;
 pub mod sam;
-pub mod errors;
\ No newline at end of file
+pub mod paf;
+pub mod errors;
+pub mod pileup;
+pub mod selftest;
\ No newline at end of file
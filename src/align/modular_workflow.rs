@@ -1,12 +1,12 @@
-use std::{cmp::min, os::linux::raw::stat};
+use std::{borrow::Cow, cmp::{max, min}, os::linux::raw::stat, time::{Duration, Instant}};
 
 use bioreader::sequence::fastq_record::{print_color_qualities, OwnedFastqRecord, RefFastqRecord};
 use colored::Colorize;
 use kmerrs::{consecutive::kmer::{Kmer, KmerIter}, minimizer::context_free::Minimizer};
 
-use crate::{align::{common::{AnchorScore, Print, StdAnchorScore}, data_structures::ToString}, database::common::FlexalignDatabase, flexalign::time, options::Options, GOLDSTD_EVAL};
+use crate::{align::{common::{sort_unstable_by_key, AnchorScore, Print, StdAnchorScore}, data_structures::{hamming, Alignment, Anchor, Seed, ToString, MAX_READ_LENGTH}, paf::PafColumns, pileup::PileupBuffer, sam::{mate_columns, xa_tag, AlternativeHit, Cigar, CigarRef, Flag, MatePosition}}, database::common::FlexalignDatabase, flexalign::time, options::{AmbiguousStrand, IdentityModel, MapqModel, Options, TiePolicy}, GOLDSTD_EVAL};
 
-use super::{common::{is_alignment_valid, print_alignment, Align, AnchorExtractor, AnchorPair, Heuristic, KmerExtractor, Or, PAFOutput, PairedAnchorExtractor, PairedAnchorMAPQ, PairedAnchorSorter, RangeExtractor, SAMOutput, SeedExtractor, StdPairedAnchorMAPQ}, process::{alignment::ani_abort_score, evaluate::{self, correct, get_id_from_header}, output::StdPAFOutput}, stats::Stats};
+use super::{common::{adapter_start, has_lowercase, skip_empty_reads, normalize_query_case, reverse_complement_keep_case, is_alignment_valid, BwaPairedAnchorMAPQ, print_alignment, roundtrip_mismatches, Align, AnchorExtractor, AnchorPair, Heuristic, KmerExtractor, M8Output, Or, PAFOutput, PairedAnchorExtractor, PairedAnchorMAPQ, PairedAnchorSorter, RangeExtractor, SAMOutput, SeedExtractor, StdPairedAnchorMAPQ}, process::{alignment::{ani_abort_score, quality_rebate, MinAni, MISMATCH_PENALTY}, duplicates::DuplicateMarker, evaluate::{self, correct, get_id_from_header}, output::{AnchorDumpOutput, ScoreOutput, StdPAFOutput, UnmappedFastqOutput}, anchor_extractor::ColinearChainer, seed_extractor::PairedSeedExtractor, kmer_extractor::is_repeat_read}, stats::Stats};
 
 
 #[derive(Clone)]
@@ -20,6 +20,7 @@ pub struct Modular<
     AE: AnchorExtractor,
     PO: PAFOutput,
     SO: SAMOutput,
+    MO: M8Output,
     D: FlexalignDatabase,
 > {
     pub options: &'a Options,
@@ -31,6 +32,17 @@ pub struct Modular<
 
     pub rec_rev: OwnedFastqRecord,
     pub(crate) output: Or<PO, SO>,
+    pub(crate) output_m8: Option<MO>,
+    /// Reads without a reported anchor (`--unmapped-fastq`)
+    pub(crate) unmapped_fastq: Option<UnmappedFastqOutput>,
+    /// Collects the reported alignments of the current read instead of writing them, see `align_batch`
+    pub(crate) alignments: Option<Vec<Alignment>>,
+    /// Candidate anchors of every read (`--dump-anchors`)
+    pub(crate) dump_anchors: Option<AnchorDumpOutput>,
+    /// Score of the reported anchor, written by the caller after each read (`--score-only`)
+    pub(crate) score_only: Option<ScoreOutput>,
+    /// Scratch of `--chaining`
+    pub(crate) chainer: ColinearChainer,
 }
 
 impl<   
@@ -43,20 +55,83 @@ impl<
     AE: AnchorExtractor,
     PO: PAFOutput,
     SO: SAMOutput,
+    MO: M8Output,
     D: FlexalignDatabase
-    > Modular<'a, C, F, KE, RE, SE, AE, PO, SO, D> { //RE, SE, 
+    > Modular<'a, C, F, KE, RE, SE, AE, PO, SO, MO, D> { //RE, SE, 
+    /// Reports a read without alignment to --unmapped-fastq and, with --split-by-reference, to the `unmapped` file.
+    fn write_unmapped(&mut self, rec: &RefFastqRecord) {
+        if let Some(unmapped) = self.unmapped_fastq.as_mut() {
+            unmapped.write_single(rec);
+        }
+        if self.options.args.split_by_reference.is_some() {
+            if let Some(output) = self.output.a.as_mut() {
+                output.write_unmapped(&String::from_utf8_lossy(rec.head()), rec.seq().len());
+            }
+            if let Some(output) = self.output.b.as_mut() {
+                let mut flag = Flag::new();
+                flag.unmapped(true);
+                output.write(&String::from_utf8_lossy(rec.head()), flag.bits(), "*", 0, 0, CigarRef(&[]), "*", 0, 0, rec.seq(), rec.qual(), &[]);
+            }
+        }
+    }
+
     pub fn run(
         &mut self,
         rec: &RefFastqRecord,
         stats: &mut Stats) -> ()
+    {
+        // Rare, so the copy is not kept around.
+        if has_lowercase(rec.seq()) {
+            let seq = normalize_query_case(rec.seq(), self.options.args.query_case);
+            return self.run_normalized(rec, &RefFastqRecord::new(rec.head(), &seq, b"+", rec.qual()), stats)
+        }
+        self.run_normalized(rec, rec, stats)
+    }
+
+    /// Maps `rec`, the read with its case normalized (`--query-case`). `given` is the read as given, which is
+    /// what the SAM and FASTQ outputs write.
+    fn run_normalized(
+        &mut self,
+        given: &RefFastqRecord,
+        rec: &RefFastqRecord,
+        stats: &mut Stats) -> ()
     {
         stats.reads_processed += 1;
 
+        if skip_empty_reads(&[rec], stats) {
+            self.write_unmapped(given);
+            return
+        }
+
+        if rec.seq().len() > MAX_READ_LENGTH {
+            stats.reads_oversized += 1;
+            self.write_unmapped(given);
+            return
+        }
+
+        // Release the capacity an outlier read left in the scratch buffers.
+        if let Some(max_capacity) = self.options.args.max_scratch_capacity {
+            self.kmer_extractor.shrink_scratch(max_capacity);
+            self.range_extractor.shrink_scratch(max_capacity);
+            self.seed_extractor.shrink_scratch(max_capacity);
+            self.anchor_extractor.shrink_scratch(max_capacity);
+            self.chainer.shrink_scratch(max_capacity);
+        }
+
         let (duration, kmers) = time(|| {
             self.kmer_extractor.generate(rec, stats)
         });
         stats.time_get_kmers += duration;
 
+        if is_repeat_read(kmers, self.options.args.max_repeat_kmer_fraction) {
+            stats.repeat_reads += 1;
+            if GOLDSTD_EVAL {
+                stats.gold_std_evaluation.as_mut().unwrap().add(false, 0);
+            }
+            self.write_unmapped(given);
+            return
+        }
+
         let (duration, ranges) = time(|| {
             self.range_extractor.generate(kmers, stats)
         });
@@ -78,15 +153,26 @@ impl<
             if GOLDSTD_EVAL {
                 stats.gold_std_evaluation.as_mut().unwrap().add(false, 0);
             }
+            self.write_unmapped(given);
             return
         }
 
+        if self.options.args.chaining {
+            let chainer = &mut self.chainer;
+            anchors.iter_mut().for_each(|a| a.score = chainer.score(&a.seeds));
+        }
+
+        let scorer = StdAnchorScore::from_options(self.options);
         let (duration, _) = time(|| {
-            anchors.sort_unstable_by_key(|a| {
-                - ((a.core_matches() - a.mismatches as usize - a.indels()) as i64)
-            });
+            sort_unstable_by_key(anchors, |a| - scorer.score(a), self.options.args.sort_impl);
         });
 
+        if let Some(dump) = self.dump_anchors.as_mut() {
+            for (rank, a) in anchors.iter().enumerate() {
+                dump.write(rec.head(), 0, rank, self.db.get_rname(a.reference as usize).unwrap(), scorer.score(a), a);
+            }
+        }
+
         let (duration, _) = time(|| {
             rec.reverse_complement(&mut self.rec_rev);
         });
@@ -96,17 +182,61 @@ impl<
 
 
 
-        let best = anchors.first().unwrap();
-        let ref_string = &self.db.get_rname(best.reference as usize).unwrap();
-        let reference = &self.db.get_reference(best.reference as usize).unwrap();
+        let tie_policy = self.options.args.tie_policy;
+        let tied = anchors.len() > 1 && scorer.score(&anchors[0]) == scorer.score(&anchors[1]);
+        stats.reads_tied += tied as usize;
 
-        let best_corelen = best.core_matches() - best.mismatches as usize - best.indels();
-        let second_best_corelen = if anchors.len() > 1 {
-            let second_best = anchors.get(1).unwrap();
-            second_best.core_matches() - second_best.mismatches as usize - second_best.indels()
-        } else { 0 };
+        // With --tie-policy report-both the runner-up is reported as well, which needs its strand too.
+        // Without reference sequences (--no-reference-seqs) only the seed geometry can tell the strand.
+        let max_references = self.options.args.max_references_per_read.unwrap_or(usize::MAX);
+        let report_second = tied && tie_policy == TiePolicy::ReportBoth
+            && (max_references > 1 || anchors[1].reference == anchors[0].reference) && {
+            let second = &mut anchors[1];
+            match self.db.get_reference(second.reference as usize) {
+                Some(reference) => second.resolve_orientation(rec, &self.rec_rev, reference),
+                None => second.orientation_set,
+            }
+        };
 
-        let pseudo_mapq = best_corelen - second_best_corelen;
+        let best = anchors.first_mut().unwrap();
+        let ref_string = &self.db.get_rname(best.reference as usize).unwrap();
+        let reference_length = self.db.get_reference_length(best.reference as usize).unwrap();
+        let reference = self.db.get_reference(best.reference as usize);
+        let resolved = match reference {
+            Some(reference) => best.resolve_orientation(rec, &self.rec_rev, reference),
+            None => best.orientation_set,
+        };
+        // Never report an anchor with an arbitrary strand.
+        if !resolved {
+            stats.anchors_unresolved += 1;
+            self.write_unmapped(given);
+            return
+        }
+        let best = anchors.first().unwrap();
+
+        // Same score as the sort, so the difference is never negative.
+        let best_corelen = scorer.score(best);
+        let second_best_corelen = anchors.get(1).map_or(0, |a| scorer.score(a));
+
+        let ambiguous = tied && tie_policy == TiePolicy::Unmapped;
+        let pseudo_mapq = if ambiguous { 0 } else { max(best_corelen - second_best_corelen, 0) as usize };
+        stats.add_mapped(1, pseudo_mapq as u8 as usize, false, report_second);
+        // Without reference sequences (--no-reference-seqs) everything that compares bases is skipped. Only the
+        // seed-based PAF output is left, see `check_no_reference_seqs`.
+        if let Some(reference) = reference {
+            let query = if best.forward { rec.seq() } else { self.rec_rev.seq() };
+            stats.add_reference_gc(reference_span(best, rec.seq().len(), reference));
+            stats.add_identity(score_and_identity(best, query, reference, self.options.args.identity_model).1);
+        }
+        if let (Some(score_only), Some(reference)) = (self.score_only.as_mut(), reference) {
+            let query = if best.forward { rec.seq() } else { self.rec_rev.seq() };
+            let (score, identity) = score_and_identity(best, query, reference, self.options.args.identity_model);
+            score_only.set(0, score, identity);
+        }
+        let mut tags = if ambiguous { vec!["tp:A:?".to_string()] } else { vec![] };
+        if self.options.args.zc_tag {
+            tags.push(tied_references_tag(anchors.iter().take_while(|a| scorer.score(a) == best_corelen).map(|a| a.reference)));
+        }
 
         // Compile time switch
         if GOLDSTD_EVAL {
@@ -137,21 +267,59 @@ impl<
 
 
         if self.output.has_a() {
-            self.output.a.as_mut().unwrap().write(
-                &String::from_utf8_lossy(rec.head()), 
-                rec.seq().len(),
-                best.seeds.first().unwrap().qbegin() as i32,
-                best.seeds.last().unwrap().qend() as i32,
-                best.forward,
-                ref_string,
-                reference.len(),
-                best.seeds.first().unwrap().rbegin() as i32,
-                best.seeds.last().unwrap().rend() as i32,
-                best.seed_count, 
-                0,
-                pseudo_mapq as u8);
+            let query = if best.forward { rec.seq() } else { self.rec_rev.seq() };
+            let primary_tags = match reference {
+                Some(reference) if self.options.args.paf_long => paf_long_tags(&tags, best, best_corelen, second_best_corelen, query, reference, self.options.args.identity_model),
+                _ => tags.clone(),
+            };
+            let columns = match reference {
+                Some(reference) => paf_columns(best, query, reference, best.seed_count, self.options.args.compat_minimap_paf),
+                None => PafColumns::from_seeds(best, best.seed_count, block_length(best)),
+            };
+            write_paf_columns(self.output.a.as_mut().unwrap(), rec, best, ref_string, reference_length, columns, pseudo_mapq as u8, &primary_tags);
+
+            if report_second {
+                write_paf_anchor(self.output.a.as_mut().unwrap(), self.db, &anchors[1], rec, &self.rec_rev, pseudo_mapq as u8, &tags, self.options.args.compat_minimap_paf);
+            }
+        }
+
+        if let (Some(_), Some(reference)) = (&self.options.args.query_seq, reference) {
+            let query = if best.forward { rec.seq() } else { self.rec_rev.seq() };
+            let mut scratch = Cigar::new();
+            let (position, cigar, edit_distance) = sam_alignment(best, query, reference, &mut scratch);
+            eprintln!("Reference: {} ({})", ref_string, best.reference);
+            eprintln!("Strand:    {}", if best.forward { '+' } else { '-' });
+            eprintln!("Position:  {}", position + 1);
+            eprintln!("Score:     {} (second best {})", best_corelen, second_best_corelen);
+            eprintln!("Cigar:     {} (NM {})", cigar.to_rle_string(), edit_distance);
+            eprintln!("Anchors:   {}", anchors.len());
+            best.visualize_alignment(query, reference);
         }
 
+        let reported = if report_second { &anchors[..2] } else { &anchors[..1] };
+
+        if let Some(output_m8) = self.output_m8.as_mut() {
+            for a in reported {
+                let query = if a.forward { rec.seq() } else { self.rec_rev.seq() };
+                write_m8(output_m8, &String::from_utf8_lossy(rec.head()), self.db.get_rname(a.reference as usize).unwrap(),
+                    a, query, self.db.get_reference(a.reference as usize).unwrap(), self.options.args.identity_model);
+            }
+        }
+
+        if self.output.has_b() {
+            for (rank, a) in reported.iter().enumerate() {
+                write_sam_single(self.output.b.as_mut().unwrap(), self.db, a, given, rec, &self.rec_rev, pseudo_mapq as u8, &tags, rank > 0);
+            }
+        }
+
+        if let Some(alignments) = self.alignments.as_mut() {
+            for a in reported {
+                let query = if a.forward { rec.seq() } else { self.rec_rev.seq() };
+                let mut scratch = Cigar::new();
+                let (position, cigar, _) = sam_alignment(a, query, self.db.get_reference(a.reference as usize).unwrap(), &mut scratch);
+                alignments.push(Alignment { reference_id: a.reference, position: position as u32, forward: a.forward, cigar: Cigar(cigar.0.to_vec()) });
+            }
+        }
     }
 }
 
@@ -169,6 +337,7 @@ pub struct ModularPE<
     AS: PairedAnchorSorter,
     PO: PAFOutput,
     SO: SAMOutput,
+    MO: M8Output,
     A: Align + Heuristic + Send,
     D: FlexalignDatabase,
 > {
@@ -178,17 +347,35 @@ pub struct ModularPE<
     pub kmer_extractor_rev: KE,
     pub range_extractor_fwd: RE,
     pub range_extractor_rev: RE,
-    pub seed_extractor_fwd: SE,
-    pub seed_extractor_rev: SE,
+    pub seed_extractor: PairedSeedExtractor<SE>,
     pub anchor_extractor: AE,
     pub anchor_sorter: AS,
 
     pub align: A,
+    /// Identity thresholds that bound the alignment score (`--min-ani`, `--min-ani-file`)
+    pub min_ani: &'a MinAni,
 
     pub output: Or<PO, SO>,
+    pub output_m8: Option<MO>,
 
     pub rec_fwd_revc: OwnedFastqRecord,
     pub rec_rev_revc: OwnedFastqRecord,
+
+    pub duplicates: Option<DuplicateMarker>,
+    /// Base counts of primary alignments of this worker (`--pileup`)
+    pub pileup: Option<PileupBuffer>,
+    /// Pairs with a mate left unmapped (`--unmapped-fastq`)
+    pub unmapped_fastq: Option<UnmappedFastqOutput>,
+    /// Candidate anchor pairs of every read pair (`--dump-anchors`)
+    pub dump_anchors: Option<AnchorDumpOutput>,
+    /// Scores of the primary pair, written by the caller after each pair (`--score-only`)
+    pub score_only: Option<ScoreOutput>,
+    /// Id of the only reference aligned against (`--force-reference`)
+    pub force_reference: Option<u64>,
+    /// References mates without seeds on them are rescued on (`--priority-ref`)
+    pub priority_references: Vec<u64>,
+    /// Uppercase adapter sequence trimmed from 3' soft-clips (`--adapter`)
+    pub adapter: Option<Vec<u8>>,
 }
 
 impl<   
@@ -202,16 +389,116 @@ impl<
     AS: PairedAnchorSorter,
     PO: PAFOutput,
     SO: SAMOutput,
+    MO: M8Output,
     A: Align + Heuristic + Send,
     D: FlexalignDatabase
-    > ModularPE<'a, C, F, KE, RE, SE, AE, AS, PO, SO, A, D> { //RE, SE, 
+    > ModularPE<'a, C, F, KE, RE, SE, AE, AS, PO, SO, MO, A, D> { //RE, SE, 
+    /// Reports a pair without any alignment to --unmapped-fastq and as unmapped SAM records if a mate failed QC
+    /// (--qc-fail-flag) or, with --split-by-reference, to the `unmapped` file.
+    fn write_unmapped_pair(&mut self, rec_fwd: &RefFastqRecord, rec_rev: &RefFastqRecord, qc_fail_fwd: bool, qc_fail_rev: bool) {
+        if let Some(unmapped) = self.unmapped_fastq.as_mut() {
+            unmapped.write_pair(rec_fwd, rec_rev);
+        }
+        let split = self.options.args.split_by_reference.is_some();
+        let qc_flag = self.options.args.qc_fail_flag;
+        let (qc_fail_fwd, qc_fail_rev) = (qc_flag && qc_fail_fwd, qc_flag && qc_fail_rev);
+        if let Some(output) = self.output.b.as_mut().filter(|_| split || qc_fail_fwd || qc_fail_rev) {
+            write_sam_unmapped_pair(output, rec_fwd, rec_rev, qc_fail_fwd, qc_fail_rev);
+        }
+        if let Some(output) = self.output.a.as_mut().filter(|_| split) {
+            for rec in [rec_fwd, rec_rev] {
+                output.write_unmapped(&String::from_utf8_lossy(rec.head()), rec.seq().len());
+            }
+        }
+    }
+
     pub fn run(
         &mut self,
         rec_fwd: &RefFastqRecord,
         rec_rev: &RefFastqRecord,
         stats: &mut Stats) -> ()
+    {
+        if has_lowercase(rec_fwd.seq()) || has_lowercase(rec_rev.seq()) {
+            let case = self.options.args.query_case;
+            let (seq_fwd, seq_rev) = (normalize_query_case(rec_fwd.seq(), case), normalize_query_case(rec_rev.seq(), case));
+            return self.run_normalized(
+                rec_fwd,
+                rec_rev,
+                &RefFastqRecord::new(rec_fwd.head(), &seq_fwd, b"+", rec_fwd.qual()),
+                &RefFastqRecord::new(rec_rev.head(), &seq_rev, b"+", rec_rev.qual()),
+                stats)
+        }
+        self.run_normalized(rec_fwd, rec_rev, rec_fwd, rec_rev, stats)
+    }
+
+    /// Maps the mates with their case normalized (`--query-case`). `given_fwd` and `given_rev` are the mates
+    /// as given, which is what the SAM and FASTQ outputs write.
+    fn run_normalized(
+        &mut self,
+        given_fwd: &RefFastqRecord,
+        given_rev: &RefFastqRecord,
+        rec_fwd: &RefFastqRecord,
+        rec_rev: &RefFastqRecord,
+        stats: &mut Stats) -> ()
+    {
+        if self.adapter.is_none() {
+            self.map_pair(given_fwd, given_rev, rec_fwd, rec_rev, false, stats);
+            return
+        }
+
+        // Counted apart first: the counts are dropped if a mate is trimmed, the trimmed pair is counted instead.
+        let mut pass = Stats::default();
+        let Some((end_fwd, end_rev)) = self.map_pair(given_fwd, given_rev, rec_fwd, rec_rev, false, &mut pass) else {
+            stats.add(&mut pass);
+            return
+        };
+        stats.adapter_trimmed += (end_fwd < rec_fwd.seq().len()) as usize + (end_rev < rec_rev.seq().len()) as usize;
+        self.map_pair(
+            &RefFastqRecord::new(given_fwd.head(), &given_fwd.seq()[..end_fwd], b"+", &given_fwd.qual()[..end_fwd]),
+            &RefFastqRecord::new(given_rev.head(), &given_rev.seq()[..end_rev], b"+", &given_rev.qual()[..end_rev]),
+            &RefFastqRecord::new(rec_fwd.head(), &rec_fwd.seq()[..end_fwd], b"+", &rec_fwd.qual()[..end_fwd]),
+            &RefFastqRecord::new(rec_rev.head(), &rec_rev.seq()[..end_rev], b"+", &rec_rev.qual()[..end_rev]),
+            true,
+            stats);
+    }
+
+    /// Maps and reports a pair, unless `--adapter` is found in a 3' soft-clip of the first pass (`rerun` false):
+    /// then nothing is reported and the ends of the mates without the adapter are returned.
+    fn map_pair(
+        &mut self,
+        given_fwd: &RefFastqRecord,
+        given_rev: &RefFastqRecord,
+        rec_fwd: &RefFastqRecord,
+        rec_rev: &RefFastqRecord,
+        rerun: bool,
+        stats: &mut Stats) -> Option<(usize, usize)>
     {
         stats.reads_processed += 2;
+        stats.pairs_processed += 1;
+
+        // Both mates are skipped if either is empty or too long.
+        if skip_empty_reads(&[rec_fwd, rec_rev], stats) {
+            self.write_unmapped_pair(given_fwd, given_rev, fails_read_qc(rec_fwd, self.options), fails_read_qc(rec_rev, self.options));
+            return None
+        }
+
+        let oversized = (rec_fwd.seq().len() > MAX_READ_LENGTH) as usize + (rec_rev.seq().len() > MAX_READ_LENGTH) as usize;
+        if oversized > 0 {
+            stats.reads_oversized += oversized;
+            self.write_unmapped_pair(given_fwd, given_rev, fails_read_qc(rec_fwd, self.options), fails_read_qc(rec_rev, self.options));
+            return None
+        }
+
+        if let Some(max_capacity) = self.options.args.max_scratch_capacity {
+            self.kmer_extractor_fwd.shrink_scratch(max_capacity);
+            self.kmer_extractor_rev.shrink_scratch(max_capacity);
+            self.range_extractor_fwd.shrink_scratch(max_capacity);
+            self.range_extractor_rev.shrink_scratch(max_capacity);
+            self.seed_extractor.shrink_scratch::<F>(max_capacity);
+            self.anchor_extractor.shrink_scratch(max_capacity);
+        }
+        let read_start = Instant::now();
+        let read_timeout = self.options.args.read_timeout_ms.map(Duration::from_millis);
 
         // Extract minimizer
         let (duration, kmers_fwd) = time(|| {
@@ -223,6 +510,19 @@ impl<
         });
         stats.time_get_kmers += duration;
 
+        // A mate failing QC (repeat, too short, low quality) is not seeded, its partner may still place the pair.
+        let max_repeat_fraction = self.options.args.max_repeat_kmer_fraction;
+        let repeat_fwd = is_repeat_read(kmers_fwd, max_repeat_fraction);
+        let repeat_rev = is_repeat_read(kmers_rev, max_repeat_fraction);
+        stats.repeat_reads += repeat_fwd as usize + repeat_rev as usize;
+        let qc_fail_fwd = repeat_fwd || fails_read_qc(rec_fwd, self.options);
+        let qc_fail_rev = repeat_rev || fails_read_qc(rec_rev, self.options);
+        stats.mates_qc_failed += qc_fail_fwd as usize + qc_fail_rev as usize;
+        let kmers_fwd = if qc_fail_fwd { &kmers_fwd[..0] } else { kmers_fwd };
+        let kmers_rev = if qc_fail_rev { &kmers_rev[..0] } else { kmers_rev };
+        // Only flagged in SAM output with --qc-fail-flag.
+        let qc_flag = self.options.args.qc_fail_flag;
+
 
         // Get ranges from minimizers
         let (duration, ranges_fwd) = time(|| {
@@ -236,16 +536,71 @@ impl<
 
 
         // Get Seeds from ranges
-        let (duration, seeds_fwd) = time(|| {
-            self.seed_extractor_fwd.generate(ranges_fwd, stats)
+        let (duration, (seeds_fwd, seeds_rev)) = time(|| {
+            self.seed_extractor.generate(ranges_fwd, ranges_rev, stats)
         });
         stats.time_range_header += duration;
-        stats.seeds += seeds_fwd.len();
-        let (duration, seeds_rev) = time(|| {
-            self.seed_extractor_rev.generate(ranges_rev, stats)
-        });
-        stats.time_range_header += duration;
-        stats.seeds += seeds_rev.len();
+        stats.seeds += seeds_fwd.len() + seeds_rev.len();
+
+        let forced;
+        let (seeds_fwd, seeds_rev): (&[Seed], &[Seed]) = match self.force_reference {
+            Some(rid) => {
+                let reference = self.db.get_reference(rid as usize).unwrap();
+                rec_fwd.reverse_complement(&mut self.rec_fwd_revc);
+                rec_rev.reverse_complement(&mut self.rec_rev_revc);
+                let scan_all = self.options.args.force_reference_scan;
+                let max_insert_size = self.options.args.max_insert_size as u64;
+                let forced_seeds = |seeds: &[Seed], mate_seeds: &[Seed], qc_fail: bool, rec: &RefFastqRecord, rec_revc: &OwnedFastqRecord| {
+                    let mut seeds: Vec<Seed> = seeds.iter().filter(|s| s.rval == rid).cloned().collect();
+                    if seeds.is_empty() && !qc_fail {
+                        // As far from the mate as a pair reaches; the whole reference only with --force-reference-scan.
+                        let window = match mate_seeds.iter().find(|s| s.rval == rid) {
+                            Some(mate) => mate.rpos.saturating_sub(max_insert_size) as usize..(mate.rpos + max_insert_size) as usize,
+                            None if scan_all => 0..reference.len(),
+                            None => 0..0,
+                        };
+                        seeds.extend(scan_seed(rec.seq(), rec_revc.seq(), rid, reference, window));
+                    }
+                    seeds
+                };
+                forced = (
+                    forced_seeds(seeds_fwd, seeds_rev, qc_fail_fwd, rec_fwd, &self.rec_fwd_revc),
+                    forced_seeds(seeds_rev, seeds_fwd, qc_fail_rev, rec_rev, &self.rec_rev_revc));
+                (&forced.0, &forced.1)
+            },
+            None => (seeds_fwd, seeds_rev),
+        };
+
+        let rescued;
+        let (seeds_fwd, seeds_rev): (&[Seed], &[Seed]) = match self.priority_references.is_empty() {
+            true => (seeds_fwd, seeds_rev),
+            false => {
+                rec_fwd.reverse_complement(&mut self.rec_fwd_revc);
+                rec_rev.reverse_complement(&mut self.rec_rev_revc);
+                let max_insert_size = self.options.args.max_insert_size as u64;
+                let mut rescue = |seeds: &[Seed], mate_seeds: &[Seed], qc_fail: bool, rec: &RefFastqRecord, rec_revc: &OwnedFastqRecord| {
+                    let mut seeds = seeds.to_vec();
+                    // Only a mate without any seed is rescued, and only near its mate's seed on the priority reference.
+                    if !seeds.is_empty() || qc_fail { return seeds };
+                    for &rid in self.priority_references.iter() {
+                        let Some(mate) = mate_seeds.iter().find(|s| s.rval == rid) else { continue };
+                        let reference = self.db.get_reference(rid as usize).unwrap();
+                        let window = mate.rpos.saturating_sub(max_insert_size) as usize..min((mate.rpos + max_insert_size) as usize, reference.len());
+                        let max_score = ani_abort_score(self.min_ani.get(rid), MISMATCH_PENALTY, rec.seq().len() as i32).abs();
+                        if let Some(seed) = rescue_seed(&mut self.align, rec.seq(), rec_revc.seq(), rid, reference, window, max_score) {
+                            // Seeds stay ordered by reference, which the anchor extractor groups by.
+                            seeds.insert(seeds.partition_point(|s| s.rval <= rid), seed);
+                            stats.seeds_rescued += 1;
+                        }
+                    }
+                    seeds
+                };
+                rescued = (
+                    rescue(seeds_fwd, seeds_rev, qc_fail_fwd, rec_fwd, &self.rec_fwd_revc),
+                    rescue(seeds_rev, seeds_fwd, qc_fail_rev, rec_rev, &self.rec_rev_revc));
+                (&rescued.0, &rescued.1)
+            },
+        };
 
         // eprintln!("Header {} ... \nID {}", String::from_utf8_lossy(rec_fwd.head()), get_id_from_header(&String::from_utf8_lossy(rec_fwd.head()), self.db));
         let (duration, mut anchors) = time(|| {
@@ -254,11 +609,24 @@ impl<
         stats.time_get_anchors += duration;
         stats.anchors += anchors.len();
 
+        // Both mates of a pair share its rank. A pair mapped again after adapter trimming is dumped once.
+        if let Some(dump) = self.dump_anchors.as_mut().filter(|_| !rerun) {
+            let scorer = StdAnchorScore::from_options(self.options);
+            for (rank, AnchorPair(a1, a2)) in anchors.iter().enumerate() {
+                for (mate, a) in [(1, a1), (2, a2)] {
+                    if let Some(a) = a {
+                        dump.write(rec_fwd.head(), mate, rank, self.db.get_rname(a.reference as usize).unwrap(), scorer.score(a), a);
+                    }
+                }
+            }
+        }
+
         if anchors.is_empty() {
             if GOLDSTD_EVAL {
                 stats.gold_std_evaluation.as_mut().unwrap().add(false, 0);
             }
-            return
+            self.write_unmapped_pair(given_fwd, given_rev, qc_fail_fwd, qc_fail_rev);
+            return None
         }
 
         // eprintln!("Read: {}", String::from_utf8_lossy(rec_fwd.head()));
@@ -334,11 +702,18 @@ impl<
         let anchors_len: usize = extension_anchors.len();
         let alignment_anchors = &mut extension_anchors[0..min(self.options.args.align_top_y, anchors_len)];
 
+        let mut timed_out = false;
         let (duration, _) = time(|| {
             let mut min_score_1 = None;
             let mut min_score_2 = None;
 
             alignment_anchors.iter_mut().enumerate().for_each(|(i, (AnchorPair(a1, a2)))| {
+                // Past the timeout the remaining anchors keep their seed-based extension without alignment.
+                if timed_out || read_timeout.is_some_and(|t| read_start.elapsed() > t) {
+                    timed_out = true;
+                    return
+                }
+
                 let reference = match a1 {
                     Some(a) => &self.db.get_reference(a.reference as usize).unwrap(),
                     None => &self.db.get_reference(a2.as_ref().unwrap().reference as usize).unwrap(),
@@ -351,27 +726,52 @@ impl<
                             a.score = 0i32;
                         } else {
 
-                            if min_score_1.is_none() {
-                                min_score_1 = Some(ani_abort_score(0.5, 4, query.len() as i32).abs());
-                            }
-                            self.align.set_max_alignment_score(min_score_1.unwrap());
-                            // eprintln!("Align max score: {}", min_score_1.unwrap());
+                            // Bounded by the identity threshold of this reference and by the best score so far.
+                            let reference_limit = ani_abort_score(self.min_ani.get(a.reference), MISMATCH_PENALTY, query.len() as i32).abs();
+                            let max_score_1 = min_score_1.map_or(reference_limit, |s| min(s, reference_limit));
+                            self.align.set_max_alignment_score(max_score_1);
+                            // eprintln!("Align max score: {}", max_score_1);
 
                             if a.seeds.len() > 1 && a.seeds[0].qbegin() > a.seeds[1].qbegin() {
                                 eprintln!("1  {}", a);
                             }
 
-                            let status = a.smart_align(&mut self.align, query, reference, 10, min_score_1.unwrap());
+                            // Anchors not flagged for indel first try the ungapped diagonal, which needs no aligner call.
+                            let end_clip = self.options.args.end_clip_preference;
+                            let free_ends = a.flank_free_ends(self.options.args.indel_free_ends);
+                            let status = match self.options.args.circular {
+                                _ if !a.flagged_for_indel && a.diagonal_align(query, reference, end_clip, max_score_1) => super::common::Status::OK,
+                                true => a.smart_align_circular(&mut self.align, query, reference, free_ends, end_clip, self.options.args.middle_gap, max_score_1),
+                                false => a.smart_align(&mut self.align, query, reference, free_ends, end_clip, self.options.args.middle_gap, max_score_1),
+                            };
                             // let status = a.whole_align(&mut self.align, query, reference, 10, min_score_1.unwrap());
                             
 
                             // let (qr, rr) = a.whole(query.len(), reference.len());
                             // let (duration, (score, cigar, status)) = time(|| self.align.align(&query[qr], &reference[rr]));
                             
-                            match status {
-                                super::common::Status::OK => stats.alignments_successful += 1,
-                                super::common::Status::Dropped => stats.alignments_dropped += 1,
-                                super::common::Status::Partial => stats.alignments_partial += 1,
+                            a.status = Some(status);
+                            stats.add_alignment(status);
+
+                            if self.options.args.left_align_indels && matches!(status, super::common::Status::OK) {
+                                if let Some(region) = reference.get(a.reference_cigar_range.clone()) {
+                                    a.cigar().normalize_indels(query, region);
+                                }
+                            }
+
+                            if self.options.args.qual_aware && matches!(status, super::common::Status::OK) {
+                                let qual = if a.forward { rec_fwd.qual() } else { self.rec_fwd_revc.qual() };
+                                a.score += quality_rebate(a.cigar(), qual, MISMATCH_PENALTY);
+                            }
+
+                            if self.options.args.roundtrip && matches!(status, super::common::Status::OK) {
+                                let region = reference.get(a.reference_cigar_range.clone()).unwrap_or_default();
+                                let disagreements = roundtrip_mismatches(query, region, &a.cigar().0);
+                                if disagreements > 0 {
+                                    stats.roundtrip_failures += 1;
+                                    eprintln!("Roundtrip failed for {}/1: {} positions differ (cigar {})",
+                                        String::from_utf8_lossy(rec_fwd.head()), disagreements, a.cigar().to_rle_string());
+                                }
                             }
 
                             let score = a.score;
@@ -386,7 +786,7 @@ impl<
                             //     eprintln!("{}/1: {} ANI: {}", i, score, ani);
                             // }
 
-                            if score != std::i32::MIN && -score < min_score_1.unwrap() {
+                            if score != std::i32::MIN && min_score_1.map_or(true, |s| -score < s) {
                                 // eprintln!("Set {} -> {}", min_score_1.unwrap(), -score);
                                 min_score_1 = Some(-score);
                             }
@@ -402,25 +802,49 @@ impl<
                         if query.len() == 0 { 
                             a.score = 0i32;
                         } else {
-                            if min_score_2.is_none() {
-                                min_score_2 = Some(ani_abort_score(0.5, 4, query.len() as i32).abs());
-                            }
+                            let reference_limit = ani_abort_score(self.min_ani.get(a.reference), MISMATCH_PENALTY, query.len() as i32).abs();
+                            let max_score_2 = min_score_2.map_or(reference_limit, |s| min(s, reference_limit));
 
                             if a.seeds.len() > 1 && a.seeds[0].qbegin() > a.seeds[1].qbegin() {
                                 eprintln!("2  {}", a);
                             }
 
-                            self.align.set_max_alignment_score(min_score_2.unwrap());
-                            let status = a.smart_align(&mut self.align, query, reference, 10, min_score_2.unwrap());
+                            self.align.set_max_alignment_score(max_score_2);
+                            // Anchors not flagged for indel first try the ungapped diagonal, which needs no aligner call.
+                            let end_clip = self.options.args.end_clip_preference;
+                            let free_ends = a.flank_free_ends(self.options.args.indel_free_ends);
+                            let status = match self.options.args.circular {
+                                _ if !a.flagged_for_indel && a.diagonal_align(query, reference, end_clip, max_score_2) => super::common::Status::OK,
+                                true => a.smart_align_circular(&mut self.align, query, reference, free_ends, end_clip, self.options.args.middle_gap, max_score_2),
+                                false => a.smart_align(&mut self.align, query, reference, free_ends, end_clip, self.options.args.middle_gap, max_score_2),
+                            };
                             // let status = a.whole_align(&mut self.align, query, reference, 10, min_score_2.unwrap());
                             
                             // let (qr, rr) = a.whole(query.len(), reference.len());
                             // let (duration, (score, cigar, status)) = time(|| self.align.align(&query[qr], &reference[rr]));
                                                
-                            match status {
-                                super::common::Status::OK => stats.alignments_successful += 1,
-                                super::common::Status::Dropped => stats.alignments_dropped += 1,
-                                super::common::Status::Partial => stats.alignments_partial += 1,
+                            a.status = Some(status);
+                            stats.add_alignment(status);
+
+                            if self.options.args.left_align_indels && matches!(status, super::common::Status::OK) {
+                                if let Some(region) = reference.get(a.reference_cigar_range.clone()) {
+                                    a.cigar().normalize_indels(query, region);
+                                }
+                            }
+
+                            if self.options.args.qual_aware && matches!(status, super::common::Status::OK) {
+                                let qual = if a.forward { rec_rev.qual() } else { self.rec_rev_revc.qual() };
+                                a.score += quality_rebate(a.cigar(), qual, MISMATCH_PENALTY);
+                            }
+
+                            if self.options.args.roundtrip && matches!(status, super::common::Status::OK) {
+                                let region = reference.get(a.reference_cigar_range.clone()).unwrap_or_default();
+                                let disagreements = roundtrip_mismatches(query, region, &a.cigar().0);
+                                if disagreements > 0 {
+                                    stats.roundtrip_failures += 1;
+                                    eprintln!("Roundtrip failed for {}/2: {} positions differ (cigar {})",
+                                        String::from_utf8_lossy(rec_rev.head()), disagreements, a.cigar().to_rle_string());
+                                }
                             }
 
                             // match status {
@@ -456,7 +880,7 @@ impl<
                             //     eprintln!("{}/2: {} ANI: {}", i, score, ani);
                             // }
 
-                            if score != std::i32::MIN && -score < min_score_2.unwrap() {
+                            if score != std::i32::MIN && min_score_2.map_or(true, |s| -score < s) {
                                 // eprintln!("Set {} -> {}", min_score_2.unwrap(), -score);
                                 min_score_2 = Some(-score);
                             }
@@ -482,6 +906,47 @@ impl<
             // });
         });
         stats.time_alignment += duration;
+        stats.reads_timed_out += timed_out as usize;
+
+        if let Some(max_softclip) = self.options.args.max_softclip {
+            let aligned = min(self.options.args.align_top_y, extension_anchors.len());
+            for AnchorPair(a1, a2) in extension_anchors[..aligned].iter_mut() {
+                for mate in [a1, a2] {
+                    if mate.as_ref().is_some_and(|a| a.cigar.as_ref().is_some_and(|c| c.softclip_length() > max_softclip)) {
+                        stats.mates_softclip_filtered += 1;
+                        *mate = None;
+                    }
+                }
+            }
+
+            // Pairs without any mate left move to the end and are cut off, keeping the order of the others.
+            let mut kept = 0;
+            for i in 0..extension_anchors.len() {
+                if extension_anchors[i].0.is_some() || extension_anchors[i].1.is_some() {
+                    extension_anchors.swap(i, kept);
+                    kept += 1;
+                }
+            }
+            if kept == 0 {
+                self.write_unmapped_pair(given_fwd, given_rev, qc_fail_fwd, qc_fail_rev);
+                return None
+            }
+            extension_anchors = &mut std::mem::take(&mut extension_anchors)[..kept];
+        }
+
+        // The 3' end of a mate is the end of its cigar on the forward strand and the start on the reverse strand.
+        let adapter_trim = |a: &Option<Anchor>, read: &[u8], adapter: &[u8]| a.as_ref().and_then(|a| {
+            let cigar = a.cigar.as_ref()?;
+            let clip = if a.forward { cigar.count_trailing_chars(b'S') } else { cigar.count_leading_chars(b'S') };
+            adapter_start(read, clip, adapter)
+        });
+        let trim = match (self.adapter.as_ref().filter(|_| !rerun), extension_anchors.first()) {
+            (Some(adapter), Some(AnchorPair(a1, a2))) => (adapter_trim(a1, rec_fwd.seq(), adapter), adapter_trim(a2, rec_rev.seq(), adapter)),
+            _ => (None, None),
+        };
+        if trim.0.is_some() || trim.1.is_some() {
+            return Some((trim.0.unwrap_or(rec_fwd.seq().len()), trim.1.unwrap_or(rec_rev.seq().len())))
+        }
 
 //#######################
 
@@ -504,15 +969,94 @@ impl<
         // }
 
 
+        let tie_policy = self.options.args.tie_policy;
+        let pair_score = StdPairedAnchorMAPQ::new(StdAnchorScore::from_options(self.options));
+        let tied = extension_anchors.len() > 1
+            && pair_score.score_paired(&extension_anchors[0]) == pair_score.score_paired(&extension_anchors[1]);
+        stats.reads_tied += tied as usize;
+
+        // Anchors whose strand could not be inferred from seed geometry get a final resolution against
+        // the reference. Mates that still cannot be resolved are dropped instead of reported as forward.
+        // With --tie-policy report-both the runner-up pair is reported as well and is resolved the same way.
+        let resolved = if tied && tie_policy == TiePolicy::ReportBoth { 2 } else { 1 };
+        let mut ambiguous_strand = false;
+        for (i, pair) in extension_anchors[..resolved].iter_mut().enumerate() {
+            let reference = &self.db.get_reference(pair.reference() as usize).unwrap();
+
+            let unoriented = |a: &Option<Anchor>| a.as_ref().is_some_and(|a| !a.orientation_set);
+            if unoriented(&pair.0) && unoriented(&pair.1) && !pair.resolve_strand_jointly(rec_fwd.seq(), self.rec_fwd_revc.seq(),
+                    rec_rev.seq(), self.rec_rev_revc.seq(), reference, self.options.args.library_type) {
+                stats.pairs_ambiguous_strand += 1;
+                match self.options.args.ambiguous_strand {
+                    AmbiguousStrand::Report => ambiguous_strand |= i == 0,
+                    AmbiguousStrand::Drop => (pair.0, pair.1) = (None, None),
+                }
+            }
+
+            let AnchorPair(a1, a2) = pair;
+            if a1.as_mut().is_some_and(|a| !a.resolve_orientation(rec_fwd, &self.rec_fwd_revc, reference)) {
+                stats.anchors_unresolved += 1;
+                *a1 = None;
+            }
+            if a2.as_mut().is_some_and(|a| !a.resolve_orientation(rec_rev, &self.rec_rev_revc, reference)) {
+                stats.anchors_unresolved += 1;
+                *a2 = None;
+            }
+        }
+        let AnchorPair(a1, a2) = extension_anchors.first().unwrap();
+        // A mate counts as unmapped without an anchor or when its alignment fell below --min-ani.
+        let unmapped_mate = |a: &Option<Anchor>| a.as_ref().map_or(true, |a| matches!(a.status, Some(super::common::Status::Dropped)));
+        if a1.is_none() && a2.is_none() {
+            self.write_unmapped_pair(given_fwd, given_rev, qc_fail_fwd, qc_fail_rev);
+            return None
+        }
+        if let Some(unmapped) = self.unmapped_fastq.as_mut().filter(|_| unmapped_mate(a1) || unmapped_mate(a2)) {
+            unmapped.write_pair(given_fwd, given_rev);
+        }
+
         let best_after = extension_anchors.first().unwrap().clone();
 
 
-        let pseudo_mapq = StdPairedAnchorMAPQ::anchor_mapq(extension_anchors);
+        let ambiguous = tied && tie_policy == TiePolicy::Unmapped;
+        let pseudo_mapq = if ambiguous { 0 } else {
+            match self.options.args.mapq_model {
+                MapqModel::Simple => pair_score.anchor_mapq(extension_anchors),
+                MapqModel::Bwa => BwaPairedAnchorMAPQ::new(self.options.args.mapq_margin, pair_score.scorer).anchor_mapq(extension_anchors),
+            }
+        };
         let anchor_pair = extension_anchors.first().unwrap();
+        for a in [&anchor_pair.0, &anchor_pair.1].into_iter().flatten() {
+            if let Some(cigar) = &a.cigar {
+                stats.add_softclip(cigar.softclip_length(), self.options.args.softclip_threshold);
+                if cigar.block_length() > 0 {
+                    stats.add_identity(cigar.identity(self.options.args.identity_model));
+                }
+            }
+        }
+        let max_references = self.options.args.max_references_per_read.unwrap_or(usize::MAX);
+        let second = extension_anchors.get(1).filter(|p| resolved == 2 && (p.0.is_some() || p.1.is_some())
+            && (max_references > 1 || p.reference() == anchor_pair.reference()));
+        let top_n = self.options.args.report_top_n.min(max_references);
+        let secondary = second.is_some() || (top_n > 1 && self.output.has_a()
+            && extension_anchors[resolved..].iter().any(|p| p.reference() != anchor_pair.reference()));
+        stats.add_mapped(
+            (!unmapped_mate(&anchor_pair.0)) as usize + (!unmapped_mate(&anchor_pair.1)) as usize,
+            pseudo_mapq as usize,
+            anchor_pair.is_proper_pair(rec_fwd.seq().len(), rec_rev.seq().len(), self.options.args.library_type, self.options.args.max_insert_size),
+            secondary);
         
         let reference_id = if anchor_pair.0.is_some() { &anchor_pair.0.as_ref().unwrap().reference } else { &anchor_pair.1.as_ref().unwrap().reference };
 
+        // Both mates of a pair are on the same reference. With --reference-buffer-reuse it is looked up once
+        // for validation and output of both mates.
         let reference = &self.db.get_reference(*reference_id as usize).unwrap();
+        let ref_string = &self.db.get_rname(*reference_id as usize).unwrap();
+        let reuse_reference = self.options.args.reference_buffer_reuse;
+        for (a, read_length) in [(&anchor_pair.0, rec_fwd.seq().len()), (&anchor_pair.1, rec_rev.seq().len())] {
+            if let Some(a) = a.as_ref().filter(|_| !unmapped_mate(a)) {
+                stats.add_reference_gc(reference_span(a, read_length, reference));
+            }
+        }
         
         
         let valid_fwd = anchor_pair.0.as_ref().map(|a| a.validate_seeds(if a.forward { rec_fwd.seq() } else { self.rec_fwd_revc.seq() }, reference));
@@ -607,11 +1151,59 @@ impl<
         //     std::io::stdin().read_line(&mut name).expect("Read line failed.");
         // }
 
+        if let Some(score_only) = self.score_only.as_mut() {
+            for (mate, (a, rec, rec_revc)) in [(&anchor_pair.0, rec_fwd, &self.rec_fwd_revc), (&anchor_pair.1, rec_rev, &self.rec_rev_revc)].into_iter().enumerate() {
+                if let Some(a) = a.as_ref().filter(|_| !unmapped_mate(a)) {
+                    let query = if a.forward { rec.seq() } else { rec_revc.seq() };
+                    let reference = self.db.get_reference(a.reference as usize).unwrap();
+                    let (score, identity) = score_and_identity(a, query, reference, self.options.args.identity_model);
+                    score_only.set(mate, score, identity);
+                }
+            }
+        }
+
+        let mut primary_tags = vec![];
+        if ambiguous {
+            primary_tags.push("tp:A:?".to_string());
+        } else if top_n > 0 {
+            primary_tags.push("tp:A:P".to_string());
+        }
+        if top_n > 0 {
+            primary_tags.push("rk:i:1".to_string());
+        }
+        if ambiguous_strand {
+            primary_tags.push("st:A:?".to_string());
+        }
+        if self.options.args.zc_tag {
+            let best_score = pair_score.score_paired(anchor_pair);
+            primary_tags.push(tied_references_tag(extension_anchors.iter()
+                .filter(|p| (p.0.is_some() || p.1.is_some()) && pair_score.score_paired(p) == best_score)
+                .map(|p| p.reference())));
+        }
+        let (s1, s2) = (pair_score.score_paired(anchor_pair), extension_anchors.get(1).map_or(0, |p| pair_score.score_paired(p)));
+        let (status_tag, score_tag) = (self.options.args.status_tag, self.options.args.score_tag);
+        let mut tags_fwd = with_score_tag(with_status_tag(&primary_tags, anchor_pair.0.as_ref(), status_tag), anchor_pair.0.as_ref(), score_tag);
+        let mut tags_rev = with_score_tag(with_status_tag(&primary_tags, anchor_pair.1.as_ref(), status_tag), anchor_pair.1.as_ref(), score_tag);
+        if self.output.has_a() && self.options.args.paf_mate_tags {
+            let [mate_fwd, mate_rev] = mate_positions(self.db, anchor_pair, rec_fwd, rec_rev, &self.rec_fwd_revc, &self.rec_rev_revc);
+            tags_fwd = with_mate_tags(tags_fwd, anchor_pair.0.as_ref(), mate_rev);
+            tags_rev = with_mate_tags(tags_rev, anchor_pair.1.as_ref(), mate_fwd);
+        }
+
+        if let Some(pileup) = self.pileup.as_mut() {
+            for (a, rec, rec_revc) in [(&anchor_pair.0, rec_fwd, &self.rec_fwd_revc), (&anchor_pair.1, rec_rev, &self.rec_rev_revc)] {
+                if let Some((a, cigar)) = a.as_ref().and_then(|a| a.cigar.as_ref().filter(|_| !a.reference_cigar_range.is_empty()).map(|c| (a, c))) {
+                    let query = if a.forward { rec.seq() } else { rec_revc.seq() };
+                    pileup.add(a.reference, reference.len(), a.reference_cigar_range.start, query, cigar);
+                }
+            }
+        }
+
         if anchor_pair.0.is_some() {
 
             let best = anchor_pair.0.as_ref().unwrap();
-            let ref_string = &self.db.get_rname(best.reference as usize).unwrap();
-            let reference = &self.db.get_reference(best.reference as usize).unwrap();
+            let reference = if reuse_reference { *reference } else { self.db.get_reference(best.reference as usize).unwrap() };
+            let ref_string = if reuse_reference { *ref_string } else { self.db.get_rname(best.reference as usize).unwrap() };
             let query = if best.forward { rec_fwd.seq() } else { self.rec_fwd_revc.seq() };
             let hamming = best.hamming(query, reference);
             
@@ -663,19 +1255,9 @@ impl<
             
 
             if self.output.has_a() {
-                self.output.a.as_mut().unwrap().write(
-                    &String::from_utf8_lossy(rec_fwd.head()), 
-                    rec_fwd.seq().len(),
-                    best.seeds.first().unwrap().qbegin() as i32,
-                    best.seeds.last().unwrap().qend() as i32,
-                    best.forward,
-                    ref_string,
-                    reference.len(),
-                    best.seeds.first().unwrap().rbegin() as i32,
-                    best.seeds.last().unwrap().rend() as i32,
-                    (query.len() - hamming as usize) as u32, 
-                    0,
-                    pseudo_mapq);
+                let columns = paf_columns(best, query, reference, (query.len() - hamming as usize) as u32, self.options.args.compat_minimap_paf);
+                write_paf_columns(self.output.a.as_mut().unwrap(), rec_fwd, best, ref_string, reference.len(), columns, pseudo_mapq,
+                    &if self.options.args.paf_long { paf_long_tags(&tags_fwd, best, s1, s2, query, reference, self.options.args.identity_model) } else { tags_fwd.clone() });
             }
 
         }
@@ -683,8 +1265,8 @@ impl<
                 
         if anchor_pair.1.is_some() {
             let best = anchor_pair.1.as_ref().unwrap();
-            let ref_string = &self.db.get_rname(best.reference as usize).unwrap();
-            let reference = &self.db.get_reference(best.reference as usize).unwrap();
+            let reference = if reuse_reference { *reference } else { self.db.get_reference(best.reference as usize).unwrap() };
+            let ref_string = if reuse_reference { *ref_string } else { self.db.get_rname(best.reference as usize).unwrap() };
             let query = if best.forward { rec_rev.seq() } else { self.rec_rev_revc.seq() };
 
             let hamming = best.hamming(query, reference);
@@ -726,19 +1308,71 @@ impl<
 
 
             if self.output.has_a() {
-                self.output.a.as_mut().unwrap().write(
-                    &String::from_utf8_lossy(rec_rev.head()), 
-                    rec_rev.seq().len(),
-                    best.seeds.first().unwrap().qbegin() as i32,
-                    best.seeds.last().unwrap().qend() as i32,
-                    best.forward,
-                    ref_string,
-                    reference.len(),
-                    best.seeds.first().unwrap().rbegin() as i32,
-                    best.seeds.last().unwrap().rend() as i32,
-                    (query.len() - hamming as usize) as u32, 
-                    0,
-                    pseudo_mapq);
+                let columns = paf_columns(best, query, reference, (query.len() - hamming as usize) as u32, self.options.args.compat_minimap_paf);
+                write_paf_columns(self.output.a.as_mut().unwrap(), rec_rev, best, ref_string, reference.len(), columns, pseudo_mapq,
+                    &if self.options.args.paf_long { paf_long_tags(&tags_rev, best, s1, s2, query, reference, self.options.args.identity_model) } else { tags_rev.clone() });
+            }
+        }
+
+        if let (Some(second), true) = (second, self.output.has_a()) {
+            for (a, rec, rec_revc) in [(&second.0, rec_fwd, &self.rec_fwd_revc), (&second.1, rec_rev, &self.rec_rev_revc)] {
+                if let Some(a) = a {
+                    write_paf_anchor(self.output.a.as_mut().unwrap(), self.db, a, rec, rec_revc, pseudo_mapq, &primary_tags, self.options.args.compat_minimap_paf);
+                }
+            }
+        }
+
+        if top_n > 1 && self.output.has_a() {
+            // The primary pair is rank 1, a reported runner-up on another reference rank 2.
+            let mut written = vec![anchor_pair.reference()];
+            if let Some(reference) = second.map(|p| p.reference()).filter(|r| *r != anchor_pair.reference()) {
+                written.push(reference);
+            }
+            for (rank, pair) in (written.len() + 1..).zip(top_n_pairs(&written, &extension_anchors[resolved..], top_n)) {
+                let tags = vec![
+                    "tp:A:S".to_string(),
+                    format!("rk:i:{}", rank),
+                    format!("s1:i:{}", pair_score.score_paired(pair)),
+                ];
+                for (a, rec, rec_revc) in [(&pair.0, rec_fwd, &self.rec_fwd_revc), (&pair.1, rec_rev, &self.rec_rev_revc)] {
+                    if let Some(a) = a.as_ref().filter(|a| a.orientation_set) {
+                        write_paf_anchor(self.output.a.as_mut().unwrap(), self.db, a, rec, rec_revc, 0, &tags, self.options.args.compat_minimap_paf);
+                    }
+                }
+            }
+        }
+
+        let reported = [Some(anchor_pair), second];
+
+        if let Some(output_m8) = self.output_m8.as_mut() {
+            for pair in reported.iter().flatten() {
+                for (a, rec, rec_revc) in [(&pair.0, rec_fwd, &self.rec_fwd_revc), (&pair.1, rec_rev, &self.rec_rev_revc)] {
+                    if let Some(a) = a {
+                        let query = if a.forward { rec.seq() } else { rec_revc.seq() };
+                        write_m8(output_m8, &String::from_utf8_lossy(rec.head()), self.db.get_rname(a.reference as usize).unwrap(),
+                            a, query, self.db.get_reference(a.reference as usize).unwrap(), self.options.args.identity_model);
+                    }
+                }
+            }
+        }
+
+        if self.output.has_b() {
+            let max_xa = self.options.args.max_xa;
+            let duplicate = self.duplicates.as_ref()
+                .is_some_and(|d| d.is_duplicate(anchor_pair, rec_fwd.seq().len(), rec_rev.seq().len()));
+            stats.duplicates += duplicate as usize;
+            for (rank, pair) in reported.iter().flatten().enumerate() {
+                let secondary = rank > 0;
+                let proper_pair = pair.is_proper_pair(rec_fwd.seq().len(), rec_rev.seq().len(), self.options.args.library_type, self.options.args.max_insert_size);
+                // Each mate is placed once, for its own record and the RNEXT/PNEXT of the other.
+                let (mut scratch_fwd, mut scratch_rev) = (Cigar::new(), Cigar::new());
+                let placed_fwd = place_mate(self.db, pair.0.as_ref(), rec_fwd, &self.rec_fwd_revc, &mut scratch_fwd);
+                let placed_rev = place_mate(self.db, pair.1.as_ref(), rec_rev, &self.rec_rev_revc, &mut scratch_rev);
+                let (mate_fwd, mate_rev) = (mate_position(self.db, &placed_fwd), mate_position(self.db, &placed_rev));
+                write_sam_mate(self.output.b.as_mut().unwrap(), self.db, true, pair, placed_fwd, mate_rev, proper_pair, duplicate, qc_flag && qc_fail_fwd, secondary, &with_score_tag(with_status_tag(&primary_tags, pair.0.as_ref(), status_tag), pair.0.as_ref(), score_tag),
+                    extension_anchors[resolved..].iter().filter_map(|p| p.0.as_ref()), given_fwd, rec_fwd, &self.rec_fwd_revc, pseudo_mapq, max_xa);
+                write_sam_mate(self.output.b.as_mut().unwrap(), self.db, false, pair, placed_rev, mate_fwd, proper_pair, duplicate, qc_flag && qc_fail_rev, secondary, &with_score_tag(with_status_tag(&primary_tags, pair.1.as_ref(), status_tag), pair.1.as_ref(), score_tag),
+                    extension_anchors[resolved..].iter().filter_map(|p| p.1.as_ref()), given_rev, rec_rev, &self.rec_rev_revc, pseudo_mapq, max_xa);
             }
         }
 
@@ -806,5 +1440,577 @@ impl<
         //     0,
         //     pseudo_mapq as u8);
 
+        None
+    }
+}
+
+
+/// Pairs reported by `--report-top-n`: one per reference not `written` yet, in the order of the sorted anchor
+/// list, until `top_n` references are reported including the written ones.
+fn top_n_pairs<'p>(written: &[u64], pairs: &'p [AnchorPair], top_n: usize) -> Vec<&'p AnchorPair> {
+    let mut seen = written.to_vec();
+    let mut reported = Vec::new();
+    for pair in pairs {
+        if seen.len() >= top_n { break }
+        if seen.contains(&pair.reference()) { continue }
+        seen.push(pair.reference());
+        reported.push(pair);
+    }
+    reported
+}
+
+/// Leftmost 0-based reference position, SAM cigar and edit distance of an anchor. Anchors that went through
+/// alignment report the aligned reference range, all others the ungapped extension of their first seed.
+/// The cigar borrows the one of the anchor and is only built in `scratch` for unaligned anchors.
+fn sam_alignment<'c>(a: &'c Anchor, query: &[u8], reference: &[u8], scratch: &'c mut Cigar) -> (usize, CigarRef<'c>, u64) {
+    match &a.cigar {
+        Some(cigar) if !a.reference_cigar_range.is_empty() => {
+            (a.reference_cigar_range.start, cigar.as_cigar_ref(), cigar.edit_distance())
+        },
+        _ => {
+            let (qr, rr) = a.whole(query.len(), reference.len());
+            scratch.0.clear();
+            scratch.add_softclip(qr.start);
+            scratch.add_matches(qr.len());
+            scratch.add_softclip(query.len() - qr.end);
+            (rr.start, scratch.as_cigar_ref(), hamming(&query[qr], &reference[rr]))
+        },
+    }
+}
+
+/// Reference bases under an anchor: its aligned range, or the range of the read placed by its seeds. Empty
+/// without reference sequences.
+fn reference_span<'r>(a: &Anchor, read_length: usize, reference: &'r [u8]) -> &'r [u8] {
+    let range = match &a.cigar {
+        Some(_) if !a.reference_cigar_range.is_empty() => a.reference_cigar_range.clone(),
+        _ if reference.is_empty() => 0..0,
+        _ => a.whole(read_length, reference.len()).1,
+    };
+    &reference[range.start.min(reference.len())..range.end.min(reference.len())]
+}
+
+/// Seed for `--force-reference` from the best ungapped placement of the whole read on either strand within
+/// `window` of the reference: the longest exact run of that placement. None if the read is longer than the
+/// window or matches nowhere. The cost is the read length times the window length.
+fn scan_seed(query: &[u8], query_rc: &[u8], reference_id: u64, reference: &[u8], window: std::ops::Range<usize>) -> Option<Seed> {
+    let offset = window.start.min(reference.len());
+    let scanned = &reference[offset..window.end.min(reference.len())];
+    if query.is_empty() || scanned.len() < query.len() { return None };
+
+    let (rc, start) = [false, true].into_iter()
+        .flat_map(|rc| (0..=scanned.len() - query.len()).map(move |start| (rc, start)))
+        .min_by_key(|&(rc, start)| hamming(if rc { query_rc } else { query }, &scanned[start..start + query.len()]))?;
+    let oriented = if rc { query_rc } else { query };
+    let window = &scanned[start..start + query.len()];
+
+    let (mut run_start, mut best) = (0, (0, 0));
+    for i in 0..=oriented.len() {
+        if i < oriented.len() && oriented[i] == window[i] { continue };
+        if i - run_start > best.1 {
+            best = (run_start, i - run_start);
+        }
+        run_start = i + 1;
+    }
+    let (qbegin, length) = (best.0, best.1.min(u8::MAX as usize));
+    if length == 0 { return None };
+
+    // Seeds keep forward query positions, the strand is resolved later.
+    let qpos = if rc { query.len() - length - qbegin } else { qbegin };
+    Some(Seed { rpos: (offset + start + qbegin) as u64, rval: reference_id, qpos: qpos as u32, mismatch: 0, length: length as u8, flag: 0 })
+}
+
+/// Seed for `--priority-ref` from WFA alignments of the read on either strand against windows of twice its length
+/// within `range` of the reference, overlapping by half: the longest match run of the best alignment. Alignments
+/// above `max_score` are abandoned.
+fn rescue_seed<A: Align + Heuristic>(aligner: &mut A, query: &[u8], query_rc: &[u8], reference_id: u64, reference: &[u8], range: std::ops::Range<usize>, max_score: i32) -> Option<Seed> {
+    if query.is_empty() { return None };
+
+    let step = query.len();
+    let end = min(range.end, reference.len());
+    let mut cigar = Cigar::new();
+    let mut best: Option<(i32, bool, usize, Cigar)> = None;
+    aligner.set_max_alignment_score(max_score);
+    for rc in [false, true] {
+        let oriented = if rc { query_rc } else { query };
+        for start in (range.start..end).step_by(step) {
+            let window = &reference[start..min(start + 2 * step, end)];
+            aligner.set_ends_free(0, 0, window.len() as i32, window.len() as i32);
+            cigar.0.clear();
+            let (score, status) = aligner.align_into(oriented, window, &mut cigar);
+            if matches!(status, super::common::Status::OK) && best.as_ref().map_or(true, |b| score > b.0) {
+                best = Some((score, rc, start, cigar.clone()));
+            }
+            if start + 2 * step >= end { break };
+        }
+    }
+    let (_, rc, start, cigar) = best?;
+
+    // (query begin, window begin, length) of the current and the longest match run
+    let (mut q, mut r) = (0, 0);
+    let (mut run, mut longest) = ((0, 0, 0), (0, 0, 0));
+    for &op in cigar.0.iter() {
+        if op == b'M' {
+            if run.2 == 0 { run = (q, r, 0) };
+            run.2 += 1;
+            if run.2 > longest.2 { longest = run };
+        } else {
+            run.2 = 0;
+        }
+        q += matches!(op, b'M' | b'X' | b'D' | b'S') as usize;
+        r += matches!(op, b'M' | b'X' | b'I' | b'N') as usize;
+    }
+    let (qbegin, rbegin, length) = (longest.0, longest.1, longest.2.min(u8::MAX as usize));
+    if length == 0 { return None };
+
+    let qpos = if rc { query.len() - length - qbegin } else { qbegin };
+    Some(Seed { rpos: (start + rbegin) as u64, rval: reference_id, qpos: qpos as u32, mismatch: 0, length: length as u8, flag: 0 })
+}
+
+/// A mate is shorter than --qc-min-length or has a mean phred quality below --qc-min-mean-quality.
+fn fails_read_qc(rec: &RefFastqRecord, options: &Options) -> bool {
+    let too_short = rec.seq().len() < options.args.qc_min_length;
+    let low_quality = options.args.qc_min_mean_quality > 0.0 && !rec.qual().is_empty() && {
+        let total: u64 = rec.qual().iter().map(|&q| q.saturating_sub(33) as u64).sum();
+        (total as f64 / rec.qual().len() as f64) < options.args.qc_min_mean_quality
+    };
+    too_short || low_quality
+}
+
+/// Writes both mates of a pair without any anchor as unmapped SAM records, flagging those that failed QC.
+fn write_sam_unmapped_pair<SO: SAMOutput>(output: &mut SO, rec_fwd: &RefFastqRecord, rec_rev: &RefFastqRecord, qc_fail_fwd: bool, qc_fail_rev: bool) {
+    for (first, rec, qc_fail) in [(true, rec_fwd, qc_fail_fwd), (false, rec_rev, qc_fail_rev)] {
+        let mut flag = Flag::new();
+        flag.paired_end(true)
+            .read1(first)
+            .read2(!first)
+            .unmapped(true)
+            .mate_unmapped(true)
+            .alignment_failed_qc(qc_fail);
+        output.write(&String::from_utf8_lossy(rec.head()), flag.bits(), "*", 0, 0, CigarRef(&[]), "*", 0, 0, rec.seq(), rec.qual(), &[]);
+    }
+}
+
+/// Leftmost 0-based position, cigar and edit distance of a mapped mate as written to SAM.
+type Placement<'c> = (usize, CigarRef<'c>, u64);
+
+/// Places a mapped mate on its reference with `sam_alignment`, None for an unmapped mate.
+fn place_mate<'c, D: FlexalignDatabase>(db: &D, a: Option<&'c Anchor>, rec: &RefFastqRecord, rec_revc: &OwnedFastqRecord,
+        scratch: &'c mut Cigar) -> Option<(&'c Anchor, Placement<'c>)> {
+    a.map(|a| {
+        let query = if a.forward { rec.seq() } else { rec_revc.seq() };
+        (a, sam_alignment(a, query, db.get_reference(a.reference as usize).unwrap(), scratch))
+    })
+}
+
+/// 1-based SAM position of a placed mate.
+fn mate_position<'d, D: FlexalignDatabase>(db: &'d D, placed: &Option<(&Anchor, Placement)>) -> Option<MatePosition<'d>> {
+    placed.as_ref().map(|(a, (position, _, _))| {
+        MatePosition { reference: a.reference, reference_name: db.get_rname(a.reference as usize).unwrap(), position: position + 1 }
+    })
+}
+
+/// SAM positions of both mates of a pair, None for an unmapped mate (`--paf-mate-tags`).
+fn mate_positions<'d, D: FlexalignDatabase>(db: &'d D, anchor_pair: &AnchorPair, rec_fwd: &RefFastqRecord, rec_rev: &RefFastqRecord,
+        rec_fwd_revc: &OwnedFastqRecord, rec_rev_revc: &OwnedFastqRecord) -> [Option<MatePosition<'d>>; 2] {
+    [(&anchor_pair.0, rec_fwd, rec_fwd_revc), (&anchor_pair.1, rec_rev, rec_rev_revc)].map(|(a, rec, rec_revc)| {
+        let mut scratch = Cigar::new();
+        mate_position(db, &place_mate(db, a.as_ref(), rec, rec_revc, &mut scratch))
+    })
+}
+
+/// Adds the mate's reference and start to the PAF tags of a mapped mate.
+fn with_mate_tags(mut tags: Vec<String>, a: Option<&Anchor>, mate: Option<MatePosition>) -> Vec<String> {
+    if let (Some(a), Some(mate)) = (a, mate) {
+        tags.extend(mate.paf_tags(a.reference));
+    }
+    tags
+}
+
+/// Writes the SAM record of one mate of the best anchor pair. Alternative anchors of the same mate are
+/// listed in the XA tag, skipping those with unresolved strand or the same position as the primary.
+/// `placed` is the placement of this mate. An unmapped mate is placed at the position of its mapped mate.
+/// `secondary` marks the runner-up pair of `--tie-policy report-both`.
+fn write_sam_mate<'b, SO: SAMOutput, D: FlexalignDatabase>(
+        output: &mut SO,
+        db: &D,
+        first: bool,
+        anchor_pair: &AnchorPair,
+        placed: Option<(&Anchor, Placement)>,
+        mate_position: Option<MatePosition>,
+        proper_pair: bool,
+        duplicate: bool,
+        qc_fail: bool,
+        secondary: bool,
+        tags: &[String],
+        alternatives: impl Iterator<Item = &'b Anchor>,
+        given: &RefFastqRecord,
+        rec: &RefFastqRecord,
+        rec_revc: &OwnedFastqRecord,
+        mapq: u8,
+        max_xa: usize) {
+    let (anchor, mate) = if first { (&anchor_pair.0, &anchor_pair.1) } else { (&anchor_pair.1, &anchor_pair.0) };
+
+    let mut flag = Flag::new();
+    flag.paired_end(true)
+        .read1(first)
+        .read2(!first)
+        .unmapped(anchor.is_none())
+        .mate_unmapped(mate.is_none())
+        .mate_reverse(mate.as_ref().is_some_and(|m| !m.forward))
+        .both_aligned(proper_pair)
+        .duplicate(duplicate)
+        .alignment_failed_qc(qc_fail)
+        .not_primary(secondary);
+
+    let (a, (position, cigar, edit_distance)) = match placed {
+        Some(placed) => placed,
+        None => {
+            let (rname, position) = mate_position.map_or(("*", 0), |m| (m.reference_name, m.position));
+            let (rnext, pnext) = mate_columns(mate.as_ref().map(|m| m.reference), mate_position.as_ref());
+            output.write(&String::from_utf8_lossy(rec.head()), flag.bits(), rname, position, 0, CigarRef(&[]), rnext, pnext, 0, given.seq(), rec.qual(), &[]);
+            return
+        },
+    };
+    flag.reverse(!a.forward);
+
+    let rname = db.get_rname(a.reference as usize).unwrap();
+    let query = if a.forward { rec.seq() } else { rec_revc.seq() };
+    let seq = given_seq(a.forward, given.seq(), query);
+    let qual = if a.forward { rec.qual() } else { rec_revc.qual() };
+
+    let mut alt_scratch = Cigar::new();
+    let hits = alternatives
+        .filter(|alt| alt.orientation_set)
+        .map(|alt| {
+            let alt_reference = db.get_reference(alt.reference as usize).unwrap();
+            let alt_query = if alt.forward { rec.seq() } else { rec_revc.seq() };
+            let (alt_position, alt_cigar, alt_edit_distance) = sam_alignment(alt, alt_query, alt_reference, &mut alt_scratch);
+            AlternativeHit {
+                reference_name: db.get_rname(alt.reference as usize).unwrap(),
+                forward: alt.forward,
+                position: alt_position + 1,
+                cigar: alt_cigar.to_rle_string(),
+                edit_distance: alt_edit_distance,
+            }
+        })
+        .filter(|hit| hit.reference_name != rname || hit.position != position + 1)
+        .take(max_xa)
+        .collect::<Vec<_>>();
+
+    let mut tags = [vec![format!("NM:i:{}", edit_distance)], tags.to_vec()].concat();
+    if let Some(xa) = xa_tag(&hits) {
+        tags.push(xa);
+    }
+
+    // An unmapped mate is placed at this record's position.
+    let own = MatePosition { reference: a.reference, reference_name: rname, position: position + 1 };
+    let (rnext, pnext) = mate_columns(Some(a.reference), Some(mate_position.as_ref().unwrap_or(&own)));
+    output.write(&String::from_utf8_lossy(rec.head()), flag.bits(), rname, position + 1, mapq, cigar, rnext, pnext, 0, &seq, qual, &tags);
+}
+
+/// SEQ of a record on the strand of its alignment: the bases as given, while `query`, the bases on that
+/// strand, may be a case-normalized copy (`--query-case`).
+fn given_seq<'s>(forward: bool, given: &'s [u8], query: &'s [u8]) -> Cow<'s, [u8]> {
+    match (has_lowercase(given), forward) {
+        (false, _) => Cow::Borrowed(query),
+        (true, true) => Cow::Borrowed(given),
+        (true, false) => Cow::Owned(reverse_complement_keep_case(given)),
     }
-}
\ No newline at end of file
+}
+
+/// Writes the SAM record of a single-end read. `secondary` marks the runner-up of `--tie-policy report-both`.
+fn write_sam_single<SO: SAMOutput, D: FlexalignDatabase>(output: &mut SO, db: &D, a: &Anchor,
+        given: &RefFastqRecord, rec: &RefFastqRecord, rec_revc: &OwnedFastqRecord, mapq: u8, tags: &[String], secondary: bool) {
+    let reference = db.get_reference(a.reference as usize).unwrap();
+    let query = if a.forward { rec.seq() } else { rec_revc.seq() };
+    let seq = given_seq(a.forward, given.seq(), query);
+    let qual = if a.forward { rec.qual() } else { rec_revc.qual() };
+    let mut scratch = Cigar::new();
+    let (position, cigar, edit_distance) = sam_alignment(a, query, reference, &mut scratch);
+    let mut flag = Flag::new();
+    flag.reverse(!a.forward).not_primary(secondary);
+    let tags = [vec![format!("NM:i:{}", edit_distance)], tags.to_vec()].concat();
+
+    output.write(
+        &String::from_utf8_lossy(rec.head()),
+        flag.bits(),
+        db.get_rname(a.reference as usize).unwrap(),
+        position + 1,
+        mapq,
+        cigar,
+        "*",
+        0,
+        0,
+        &seq,
+        qual,
+        &tags);
+}
+
+/// Writes an anchor as a BLAST tabular line. Query coordinates refer to the read as given, for reverse
+/// strand hits the subject coordinates are swapped (sstart > send) as BLAST does.
+///
+/// flexalign does not compute BLAST statistics. The bitscore is derived from a blastn-like raw score
+/// (match 2, mismatch -3, gap open 5, gap extend 2) with lambda = 0.625 and K = 0.41, and the evalue
+/// uses the read and reference length as search space: evalue = qlen * rlen * 2^-bitscore.
+fn write_m8<MO: M8Output>(output: &mut MO, query_name: &str, reference_name: &str, a: &Anchor, query: &[u8], reference: &[u8], model: IdentityModel) {
+    let mut scratch = Cigar::new();
+    let (position, cigar, edit_distance) = sam_alignment(a, query, reference, &mut scratch);
+
+    let gaps = cigar.0.iter().filter(|&&c| c == b'I' || c == b'D').count();
+    let gap_opens = cigar.gap_opens();
+    let mismatches = edit_distance as usize - gaps;
+    let alignment_length = cigar.0.iter().filter(|&&c| c != b'S' && c != b'N').count();
+    let matches = alignment_length - mismatches - gaps;
+    // From the edit distance: a cigar built from seeds has M for mismatches as well.
+    let columns = match model {
+        IdentityModel::Blast => alignment_length,
+        IdentityModel::GapCompressed => matches + mismatches + gap_opens,
+    };
+    let identity = if columns == 0 { 0.0 } else { 100.0 * matches as f64 / columns as f64 };
+
+    let clip_left = cigar.count_leading_chars(b'S');
+    let clip_right = cigar.count_trailing_chars(b'S');
+    let (query_start, query_end) = if a.forward {
+        (clip_left + 1, query.len() - clip_right)
+    } else {
+        (clip_right + 1, query.len() - clip_left)
+    };
+    let (subject_start, subject_end) = (position + 1, position + cigar.reference_consumed());
+    let (subject_start, subject_end) = if a.forward { (subject_start, subject_end) } else { (subject_end, subject_start) };
+
+    let raw_score = 2.0 * matches as f64 - 3.0 * mismatches as f64 - 5.0 * gap_opens as f64 - 2.0 * (gaps - gap_opens) as f64;
+    let bitscore = (0.625 * raw_score - 0.41f64.ln()) / 2f64.ln();
+    let evalue = query.len() as f64 * reference.len() as f64 * 2f64.powf(-bitscore);
+
+    output.write(query_name, reference_name, identity, alignment_length, mismatches, gap_opens,
+        query_start, query_end, subject_start, subject_end, evalue, bitscore);
+}
+
+/// PAF alignment block length. Seed-only anchors use the larger of their query and reference span.
+fn block_length(a: &Anchor) -> usize {
+    match &a.cigar {
+        Some(cigar) if !cigar.0.is_empty() => cigar.block_length(),
+        _ => {
+            let (first, last) = (a.seeds.first().unwrap(), a.seeds.last().unwrap());
+            max(last.qend() - first.qbegin(), last.rend() - first.rbegin())
+        },
+    }
+}
+
+/// `tags` plus `fl:A:<O|P|D>` with the alignment status of the anchor if enabled (`--status-tag`) and the anchor was aligned.
+fn with_status_tag(tags: &[String], a: Option<&Anchor>, enabled: bool) -> Vec<String> {
+    let mut tags = tags.to_vec();
+    if let Some(status) = a.and_then(|a| a.status).filter(|_| enabled) {
+        tags.push(format!("fl:A:{}", status.tag_char()));
+    }
+    tags
+}
+
+/// Whether the anchor carries the result of a completed WFA alignment.
+fn is_aligned(a: &Anchor) -> bool {
+    a.status.is_some_and(|s| !matches!(s, super::common::Status::Dropped)) && a.score != i32::MIN
+}
+
+/// `AS:i` in aligner penalties: 0 for a perfect match, negative otherwise. Aligned anchors report the WFA score,
+/// ungapped placements `MISMATCH_PENALTY` per mismatch of their `edit_distance` (see `sam_alignment`).
+fn alignment_score(a: &Anchor, edit_distance: u64) -> i32 {
+    if is_aligned(a) && a.cigar.is_some() && !a.reference_cigar_range.is_empty() { a.score } else { -(edit_distance as i32 * MISMATCH_PENALTY) }
+}
+
+/// `--score-only` columns of a reported anchor, defined the same for single- and paired-end reads: the
+/// `alignment_score` of its SAM placement and the identity of its cigar if aligned, otherwise the Hamming
+/// identity of the ungapped placement, its bases minus mismatches over its bases.
+fn score_and_identity(a: &Anchor, query: &[u8], reference: &[u8], identity_model: IdentityModel) -> (i32, f64) {
+    let mut scratch = Cigar::new();
+    let (_, _, edit_distance) = sam_alignment(a, query, reference, &mut scratch);
+    let identity = match &a.cigar {
+        Some(cigar) if is_aligned(a) && !a.reference_cigar_range.is_empty() => cigar.identity(identity_model),
+        _ => {
+            let columns = a.whole(query.len(), reference.len()).0.len();
+            if columns == 0 { 0.0 } else { columns.saturating_sub(edit_distance as usize) as f64 / columns as f64 }
+        },
+    };
+    (alignment_score(a, edit_distance), identity)
+}
+
+/// `tags` plus `AS:i:<score>` with the alignment score of the anchor if enabled (`--score-tag`) and the anchor
+/// was aligned with a score.
+fn with_score_tag(mut tags: Vec<String>, a: Option<&Anchor>, enabled: bool) -> Vec<String> {
+    if let Some(a) = a.filter(|a| enabled && is_aligned(a)) {
+        tags.push(format!("AS:i:{}", a.score));
+    }
+    tags
+}
+
+/// `ZC:i:<n>` with the number of distinct references among the candidates scoring the same as the best (`--zc-tag`).
+/// Above 1 the read is ambiguous across references rather than within one.
+fn tied_references_tag(references: impl Iterator<Item = u64>) -> String {
+    let mut references: Vec<u64> = references.collect();
+    references.sort_unstable();
+    references.dedup();
+    format!("ZC:i:{}", references.len())
+}
+
+/// minimap2's optional PAF tags for `--paf-long`, appended to `tags`. `tp:A:P` is only added if `tags` has no type yet.
+/// `NM` and `AS` describe the same alignment as the SAM record.
+fn paf_long_tags(tags: &[String], a: &Anchor, s1: i32, s2: i32, query: &[u8], reference: &[u8], identity_model: IdentityModel) -> Vec<String> {
+    let mut scratch = Cigar::new();
+    let (_, _, edit_distance) = sam_alignment(a, query, reference, &mut scratch);
+    let mut long = tags.to_vec();
+    if !tags.iter().any(|t| t.starts_with("tp:A:")) {
+        long.push("tp:A:P".to_string());
+    }
+    long.push(format!("cm:i:{}", a.seed_count));
+    long.push(format!("s1:i:{}", s1));
+    long.push(format!("s2:i:{}", s2));
+    long.push(format!("NM:i:{}", edit_distance));
+    // Already there with --score-tag.
+    if !tags.iter().any(|t| t.starts_with("AS:i:")) {
+        long.push(format!("AS:i:{}", alignment_score(a, edit_distance)));
+    }
+    if let Some(cigar) = a.cigar.as_ref().filter(|c| !c.0.is_empty()) {
+        long.push(format!("de:f:{:.4}", 1.0 - cigar.identity(identity_model)));
+        long.push(format!("cg:Z:{}", cigar.to_rle_string()));
+    }
+    long
+}
+
+/// Writes an anchor as a PAF line with ungapped coordinates from its seeds, like the primary output.
+fn write_paf_anchor<PO: PAFOutput, D: FlexalignDatabase>(output: &mut PO, db: &D, a: &Anchor,
+        rec: &RefFastqRecord, rec_revc: &OwnedFastqRecord, mapq: u8, tags: &[String], compat_minimap: bool) {
+    let query = if a.forward { rec.seq() } else { rec_revc.seq() };
+    let columns = match db.get_reference(a.reference as usize) {
+        Some(reference) => {
+            let hamming = a.hamming(query, reference);
+            paf_columns(a, query, reference, (query.len() - hamming as usize) as u32, compat_minimap)
+        },
+        // Seeds only (--no-reference-seqs), like the primary line.
+        None => PafColumns::from_seeds(a, a.seed_count, block_length(a)),
+    };
+    let reference_length = db.get_reference_length(a.reference as usize).unwrap();
+    write_paf_columns(output, rec, a, db.get_rname(a.reference as usize).unwrap(), reference_length, columns, mapq, tags);
+}
+
+/// PAF columns of an anchor, in minimap2's convention with `--compat-minimap-paf`. `residue_matches` is only
+/// used for the default output.
+fn paf_columns(a: &Anchor, query: &[u8], reference: &[u8], residue_matches: u32, compat_minimap: bool) -> PafColumns {
+    match compat_minimap {
+        true => PafColumns::minimap(a, query, reference),
+        false => PafColumns::from_seeds(a, residue_matches, block_length(a)),
+    }
+}
+
+fn write_paf_columns<PO: PAFOutput>(output: &mut PO, rec: &RefFastqRecord, a: &Anchor, reference_name: &str,
+        reference_length: usize, columns: PafColumns, mapq: u8, tags: &[String]) {
+    output.write(
+        &String::from_utf8_lossy(rec.head()),
+        rec.seq().len(),
+        columns.query_start as i32,
+        columns.query_end as i32,
+        a.forward,
+        reference_name,
+        reference_length,
+        columns.reference_start as i32,
+        columns.reference_end as i32,
+        columns.residue_matches,
+        columns.block_length,
+        mapq,
+        tags);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct M8Identity(Vec<(f64, usize, usize)>);
+
+    impl M8Output for M8Identity {
+        fn write(&mut self, _: &str, _: &str, identity: f64, alignment_length: usize, mismatches: usize, _: usize,
+                _: usize, _: usize, _: usize, _: usize, _: f64, _: f64) {
+            self.0.push((identity, alignment_length, mismatches));
+        }
+    }
+
+    #[test]
+    fn top_n_counts_every_written_reference() {
+        let pair = |reference: u64| AnchorPair(Some(Anchor::from_seed(&Seed { rpos: 0, rval: reference, qpos: 0, mismatch: 0, length: 31, flag: 0 })), None);
+        let pairs = [pair(1), pair(2), pair(2), pair(3), pair(4)];
+        let references = |reported: Vec<&AnchorPair>| reported.into_iter().map(|p| p.reference()).collect::<Vec<_>>();
+
+        assert_eq!(references(top_n_pairs(&[0], &pairs, 3)), [1, 2]);
+        // A runner-up on reference 1 is already written and takes the second slot.
+        assert_eq!(references(top_n_pairs(&[0, 1], &pairs, 3)), [2]);
+        assert_eq!(references(top_n_pairs(&[0, 1], &pairs, 2)), Vec::<u64>::new());
+        assert_eq!(references(top_n_pairs(&[0], &pairs, 10)), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn score_only_of_ungapped_mismatching_anchor() {
+        let reference = b"TTTTTTTTTTACGTACCGTTAGGCATCCAGTTGACCTAGGCATTTTTTTTTT";
+        let mut query = reference[10..40].to_vec();
+        query[15] = b'A';
+        query[25] = b'G';
+        let a = Anchor::from_seed(&Seed { rpos: 10, rval: 0, qpos: 0, mismatch: 0, length: 10, flag: 0 });
+
+        // The cigar from the seed has M over the mismatches, the identity counts them.
+        assert_eq!(score_and_identity(&a, &query, reference, IdentityModel::Blast), (-2 * MISMATCH_PENALTY, 28.0 / 30.0));
+    }
+
+    #[test]
+    fn m8_identity_of_ungapped_mismatching_anchor() {
+        let reference = b"TTTTTTTTTTACGTACCGTTAGGCATCCAGTTGACCTAGGCATTTTTTTTTT";
+        let mut query = reference[10..40].to_vec();
+        query[15] = b'A';
+        query[25] = b'G';
+        // Unaligned: the cigar comes from the seed and has M over the mismatches.
+        let a = Anchor::from_seed(&Seed { rpos: 10, rval: 0, qpos: 0, mismatch: 0, length: 10, flag: 0 });
+
+        for model in [IdentityModel::Blast, IdentityModel::GapCompressed] {
+            let mut output = M8Identity::default();
+            write_m8(&mut output, "read", "ref", &a, &query, reference, model);
+            assert_eq!(output.0, [(100.0 * 28.0 / 30.0, 30, 2)]);
+        }
+    }
+
+    #[test]
+    fn scan_seed_takes_the_longest_exact_run_on_either_strand() {
+        use crate::test_utils::{pseudo_random_bases, reverse_complement};
+
+        let reference = pseudo_random_bases(2000, 7);
+        let mut query = reference[500..600].to_vec();
+        query[40] = if query[40] == b'A' { b'C' } else { b'A' };
+        let query_rc = reverse_complement(&query);
+
+        let seed = scan_seed(&query, &query_rc, 3, &reference, 0..reference.len()).unwrap();
+        assert_eq!((seed.rpos, seed.rval, seed.qpos, seed.length), (541, 3, 41, 59));
+        // Read 2 of a pair is given reverse complemented, its seed keeps forward query positions.
+        let seed = scan_seed(&query_rc, &query, 3, &reference, 400..700).unwrap();
+        assert_eq!((seed.rpos, seed.qpos, seed.length), (541, 0, 59));
+
+        assert!(scan_seed(&query, &query_rc, 3, &reference, 500..550).is_none());
+        assert!(scan_seed(&[], &[], 3, &reference, 0..reference.len()).is_none());
+    }
+
+    #[test]
+    fn rescue_seed_searches_only_the_given_range() {
+        use crate::{align::process::alignment::LIBWFA2Alignment, test_utils::{pseudo_random_bases, reverse_complement}};
+
+        let reference = pseudo_random_bases(5000, 7);
+        let mut query = reference[3000..3150].to_vec();
+        query[40] = if query[40] == b'A' { b'C' } else { b'A' };
+        let query_rc = reverse_complement(&query);
+        let max_score = ani_abort_score(0.9, MISMATCH_PENALTY, query.len() as i32).abs();
+        let mut aligner = LIBWFA2Alignment::default();
+
+        let seed = rescue_seed(&mut aligner, &query, &query_rc, 3, &reference, 2500..3700, max_score).unwrap();
+        assert_eq!((seed.rpos, seed.rval, seed.qpos, seed.length), (3041, 3, 41, 109));
+        let seed = rescue_seed(&mut aligner, &query_rc, &query, 3, &reference, 2500..3700, max_score).unwrap();
+        assert_eq!((seed.rpos, seed.qpos, seed.length), (3041, 0, 109));
+
+        // The read lies outside the range, which is cut at the reference end.
+        assert!(rescue_seed(&mut aligner, &query, &query_rc, 3, &reference, 0..1000, max_score).is_none());
+        assert!(rescue_seed(&mut aligner, &query, &query_rc, 3, &reference, 4000..6000, max_score).is_none());
+    }
+}
@@ -0,0 +1,187 @@
+use std::{fs::File, io::{self, BufRead, BufReader, Write}, process::exit, sync::{Arc, Mutex}};
+
+use std::iter::zip;
+
+use crate::{align::{common::SAMOutput, data_structures::Anchor, process::output::StdSAMOutput, sam::{sam_header, Cigar, Flag}}, database::common::FlexalignDatabase, io::output_buffer::{OutputBuffer, OutputTarget}};
+
+/// PAF columns 3-4 and 8-11 of an anchor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PafColumns {
+    pub query_start: usize,
+    pub query_end: usize,
+    pub reference_start: usize,
+    pub reference_end: usize,
+    pub residue_matches: u32,
+    pub block_length: usize,
+}
+
+impl PafColumns {
+    /// Default output: the range from the first to the last seed, with query coordinates on the aligned strand.
+    pub fn from_seeds(a: &Anchor, residue_matches: u32, block_length: usize) -> Self {
+        let (first, last) = (a.seeds.first().unwrap(), a.seeds.last().unwrap());
+        Self {
+            query_start: first.qbegin(),
+            query_end: last.qend(),
+            reference_start: first.rbegin(),
+            reference_end: last.rend(),
+            residue_matches,
+            block_length,
+        }
+    }
+
+    /// minimap2's convention (`--compat-minimap-paf`). `query` is the read on the strand of the anchor.
+    /// Aligned anchors report the aligned region without soft-clips, all others the ungapped extension of their
+    /// seeds. Residue matches are identical bases only, and query coordinates refer to the read as given.
+    pub fn minimap(a: &Anchor, query: &[u8], reference: &[u8]) -> Self {
+        let (query_start, query_end, reference_start, reference_end, residue_matches, block_length) = match &a.cigar {
+            Some(cigar) if !a.reference_cigar_range.is_empty() => {
+                let matches = cigar.0.iter().filter(|&&op| op == b'M').count();
+                (cigar.count_leading_chars(b'S'), query.len() - cigar.count_trailing_chars(b'S'),
+                    a.reference_cigar_range.start, a.reference_cigar_range.end, matches, cigar.block_length())
+            },
+            _ => {
+                let (qr, rr) = a.whole(query.len(), reference.len());
+                let matches = zip(&query[qr.clone()], &reference[rr.clone()]).filter(|(q, r)| q == r).count();
+                (qr.start, qr.end, rr.start, rr.end, matches, qr.len().max(rr.len()))
+            },
+        };
+
+        let (query_start, query_end) = if a.forward {
+            (query_start, query_end)
+        } else {
+            (query.len() - query_end, query.len() - query_start)
+        };
+
+        Self {
+            query_start,
+            query_end,
+            reference_start,
+            reference_end,
+            residue_matches: residue_matches as u32,
+            block_length,
+        }
+    }
+}
+
+/// Converts a PAF file into SAM on stdout. The header uses the reference lengths of the index. The `cg:Z`
+/// cigar is extended by soft-clips for the unaligned read ends; records without it get the cigar `*`.
+/// SEQ and QUAL are not part of PAF and are written as `*`. All other PAF tags are passed on.
+pub fn sam_from_paf<D: FlexalignDatabase>(db: &D, paf_path: &str) {
+    let reader = match File::open(paf_path) {
+        Err(why) => {
+            eprintln!("Cannot open PAF file {}: {}", paf_path, why);
+            exit(9);
+        },
+        Ok(file) => BufReader::new(file),
+    };
+
+    let writer = Arc::new(Mutex::new(OutputTarget::Stdout(io::stdout())));
+    let _ = writer.lock().expect("Cannot lock writer").write_all(sam_header(db, None).as_bytes());
+    let mut output = StdSAMOutput::new(OutputBuffer::new(writer, 2usize.pow(24)), None);
+
+    let mut skipped = 0;
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line.expect("Valid PAF file");
+        if line.is_empty() { continue };
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 12 {
+            eprintln!("PAF line {} has {} columns, expected at least 12", line_number + 1, fields.len());
+            exit(9);
+        }
+        let number = |i: usize| -> usize {
+            fields[i].parse().unwrap_or_else(|_| {
+                eprintln!("PAF line {}: column {} is not a number ({})", line_number + 1, i + 1, fields[i]);
+                exit(9);
+            })
+        };
+
+        let (query_name, query_length, query_start, query_end) = (fields[0], number(1), number(2), number(3));
+        let (forward, reference_name, reference_start) = (fields[4] != "-", fields[5], number(7));
+        let mapq = number(11).min(255) as u8;
+
+        if db.get_rid(reference_name).is_none() {
+            skipped += 1;
+            continue
+        }
+
+        let mut cigar = Cigar::new();
+        let mut tags = Vec::new();
+        for tag in &fields[12..] {
+            match tag.strip_prefix("cg:Z:") {
+                Some(cg) => {
+                    match Cigar::from_rle_str(cg) {
+                        Some(cg) => {
+                            // PAF query coordinates are on the read as given, SAM stores the aligned strand.
+                            let (left, right) = if forward {
+                                (query_start, query_length - query_end)
+                            } else {
+                                (query_length - query_end, query_start)
+                            };
+                            cigar.add_softclip(left);
+                            cigar.0.extend_from_slice(&cg.0);
+                            cigar.add_softclip(right);
+                        },
+                        None => eprintln!("PAF line {}: cannot parse cigar {}", line_number + 1, cg),
+                    }
+                },
+                None => tags.push(tag.to_string()),
+            }
+        }
+
+        let mut flag = Flag::new();
+        flag.reverse(!forward);
+
+        output.write(query_name, flag.bits(), reference_name, reference_start + 1, mapq, cigar.as_cigar_ref(), "*", 0, 0, &[], &[], &tags);
+    }
+
+    if skipped > 0 {
+        eprintln!("Skipped {} PAF records with references that are not in the index", skipped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::align::{data_structures::Seed, process::output::{gaf_line, paf_line}};
+
+    #[test]
+    fn minimap_columns_reverse_strand() {
+        // 25 bp read aligned on '-': 3S 10M 1X 5M 1 reference base 4M 2S (I consumes the reference internally).
+        let reference = [b'A'; 40];
+        let query = [b'A'; 25];
+        let seed = Seed { rpos: 5, rval: 0, qpos: 3, mismatch: 0, length: 10, flag: 0 };
+        let mut anchor = Anchor::from_seed(&seed);
+        anchor.forward = false;
+        anchor.orientation_set = true;
+        let mut cigar = Cigar::new();
+        cigar.add_softclip(3);
+        cigar.add_matches(10);
+        cigar.0.push(b'X');
+        cigar.add_matches(5);
+        cigar.0.push(b'I');
+        cigar.add_matches(4);
+        cigar.add_softclip(2);
+        anchor.cigar = Some(cigar);
+        anchor.reference_cigar_range = 5..26;
+
+        let c = PafColumns::minimap(&anchor, &query, &reference);
+        let line = paf_line("read", query.len(), c.query_start as i32, c.query_end as i32, anchor.forward, "ref", reference.len(),
+            c.reference_start as i32, c.reference_end as i32, c.residue_matches, c.block_length, 60, &[]);
+        assert_eq!(line, "read\t25\t2\t22\t-\tref\t40\t5\t26\t19\t21\t60\n");
+    }
+
+    #[test]
+    fn gaf_line_reverse_strand() {
+        let tags = ["tp:A:P".to_string(), "cg:Z:3M1I5M".to_string()];
+        let line = gaf_line("read", 20, 2, 11, false, "ref", 100, 30, 38, 8, 9, 60, &tags);
+        let fields: Vec<&str> = line.trim_end().split('\t').collect();
+        // Required columns: name, length, start, end, strand, path, path length, start, end, matches, block, mapq
+        assert_eq!(fields[..12], ["read", "20", "2", "11", "+", "<ref", "100", "62", "70", "8", "9", "60"]);
+        assert_eq!(fields[12..], ["tp:A:P", "cg:Z:5M1I3M"]);
+
+        let line = gaf_line("read", 20, 2, 11, true, "ref", 100, 30, 38, 8, 9, 60, &tags);
+        assert!(line.starts_with("read\t20\t2\t11\t+\t>ref\t100\t30\t38\t"));
+        assert!(line.ends_with("cg:Z:3M1I5M\n"));
+    }
+}
@@ -0,0 +1,187 @@
+use std::{collections::{hash_map::Entry, HashMap}, fs::File, io::{BufWriter, Write}, path::Path, process::exit, sync::{Arc, Mutex}};
+
+use crate::database::common::FlexalignDatabase;
+
+use super::sam::Cigar;
+
+/// Columns of `BaseCounts`.
+pub const PILEUP_COLUMNS: [&str; 6] = ["A", "C", "G", "T", "del", "ins"];
+
+/// Counts of A, C, G, T, deleted bases and insertions following the position.
+pub type BaseCounts = [u32; 6];
+
+/// Base counts of primary alignments per reference position (`--pileup`). A reference gets its count
+/// array, 24 bytes per base, on its first alignment.
+#[derive(Clone, Debug, Default)]
+pub struct Pileup {
+    pub references: HashMap<u64, Vec<BaseCounts>>,
+}
+
+impl Pileup {
+    /// Walks the cigar of an alignment starting at `reference_start`. `query` is the read on the aligned strand.
+    /// Alignments across the origin of a circular reference (`--circular`) wrap around.
+    /// Internally `D` consumes only the query (an insertion) and `I` only the reference (a deletion).
+    pub fn add(&mut self, reference_id: u64, reference_length: usize, reference_start: usize, query: &[u8], cigar: &Cigar) {
+        let counts = self.references.entry(reference_id).or_insert_with(|| vec![[0; 6]; reference_length]);
+
+        let (mut q, mut r) = (0, reference_start);
+        for op in cigar.0.iter() {
+            match op {
+                b'M' | b'X' => {
+                    if let Some(column) = base_column(query[q]) {
+                        counts[r % reference_length][column] += 1;
+                    }
+                    q += 1;
+                    r += 1;
+                },
+                b'I' => {
+                    counts[r % reference_length][4] += 1;
+                    r += 1;
+                },
+                // Skipped reference is not covered.
+                b'N' => r += 1,
+                b'D' => {
+                    // Counted once per inserted base at the last aligned position.
+                    if r > reference_start {
+                        counts[(r - 1) % reference_length][5] += 1;
+                    }
+                    q += 1;
+                },
+                _ => q += 1,
+            }
+        }
+    }
+
+    /// Adds the counts of `other` and leaves it empty.
+    pub fn merge_from(&mut self, other: &mut Pileup) {
+        for (id, counts) in other.references.drain() {
+            match self.references.entry(id) {
+                Entry::Occupied(mut entry) => {
+                    for (total, add) in entry.get_mut().iter_mut().zip(counts) {
+                        total.iter_mut().zip(add).for_each(|(t, a)| *t += a);
+                    }
+                },
+                Entry::Vacant(entry) => { entry.insert(counts); },
+            }
+        }
+    }
+
+    /// Writes `reference, position (1-based), depth, A, C, G, T, del, ins` for every covered position.
+    /// Depth counts bases and deletions.
+    pub fn write<D: FlexalignDatabase>(&self, db: &D, path: &Path) {
+        let file = File::create(path).unwrap_or_else(|why| {
+            eprintln!("Cannot create pileup file {}: {}", path.display(), why);
+            exit(9);
+        });
+        let mut out = BufWriter::new(file);
+
+        let _ = writeln!(out, "reference\tposition\tdepth\t{}", PILEUP_COLUMNS.join("\t"));
+        let mut ids: Vec<&u64> = self.references.keys().collect();
+        ids.sort();
+        for id in ids {
+            let name = db.get_rname(*id as usize).unwrap();
+            for (position, counts) in self.references[id].iter().enumerate() {
+                if counts.iter().all(|&c| c == 0) { continue };
+                let depth: u32 = counts[..5].iter().sum();
+                let _ = writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    name, position + 1, depth, counts[0], counts[1], counts[2], counts[3], counts[4], counts[5]);
+            }
+        }
+        let _ = out.flush();
+    }
+}
+
+/// Per-worker `Pileup` that is merged into the shared one when dropped, so workers do not lock per read.
+/// Clones start empty.
+pub struct PileupBuffer {
+    local: Pileup,
+    shared: Arc<Mutex<Pileup>>,
+}
+
+impl PileupBuffer {
+    pub fn new(shared: Arc<Mutex<Pileup>>) -> Self {
+        Self { local: Pileup::default(), shared }
+    }
+
+    /// See `Pileup::add`
+    pub fn add(&mut self, reference_id: u64, reference_length: usize, reference_start: usize, query: &[u8], cigar: &Cigar) {
+        self.local.add(reference_id, reference_length, reference_start, query, cigar);
+    }
+}
+
+impl Clone for PileupBuffer {
+    fn clone(&self) -> Self {
+        Self::new(Arc::clone(&self.shared))
+    }
+}
+
+impl Drop for PileupBuffer {
+    fn drop(&mut self) {
+        if !self.local.references.is_empty() {
+            self.shared.lock().expect("Cannot lock pileup").merge_from(&mut self.local);
+        }
+    }
+}
+
+fn base_column(base: u8) -> Option<usize> {
+    match base {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::align::sam::Cigar;
+
+    use super::{Pileup, PileupBuffer};
+
+    #[test]
+    fn add_walks_indels_and_soft_clips() {
+        let reference = b"ACGTACGTAC";
+        // 2 soft-clipped bases, CGT, an inserted G, AC, reference base 6 deleted, TA.
+        let query = b"TTCGTGACTA";
+        let cigar = Cigar(b"SSMMMDMMIMM".to_vec());
+        let mut pileup = Pileup::default();
+        pileup.add(0, reference.len(), 1, query, &cigar);
+
+        let counts = &pileup.references[&0];
+        let expected: [[u32; 6]; 10] = [
+            [0, 0, 0, 0, 0, 0],
+            [0, 1, 0, 0, 0, 0],
+            [0, 0, 1, 0, 0, 0],
+            [0, 0, 0, 1, 0, 1],
+            [1, 0, 0, 0, 0, 0],
+            [0, 1, 0, 0, 0, 0],
+            [0, 0, 0, 0, 1, 0],
+            [0, 0, 0, 1, 0, 0],
+            [1, 0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0, 0],
+        ];
+        assert_eq!(counts.as_slice(), expected.as_slice());
+    }
+
+    #[test]
+    fn buffers_merge_into_shared_pileup_on_drop() {
+        let shared = Arc::new(Mutex::new(Pileup::default()));
+        let cigar = Cigar(b"MMMM".to_vec());
+        let mut first = PileupBuffer::new(Arc::clone(&shared));
+        let mut second = first.clone();
+        first.add(0, 8, 0, b"ACGT", &cigar);
+        second.add(0, 8, 2, b"GTAC", &cigar);
+        second.add(1, 4, 0, b"ACGT", &cigar);
+        drop(first);
+        drop(second);
+
+        let shared = shared.lock().unwrap();
+        let depth: Vec<u32> = shared.references[&0].iter().map(|c| c.iter().sum()).collect();
+        assert_eq!(depth, vec![1, 1, 2, 2, 1, 1, 0, 0]);
+        assert_eq!(shared.references[&1].len(), 4);
+    }
+}
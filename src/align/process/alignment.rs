@@ -1,7 +1,9 @@
 
+use std::{cmp::min, collections::HashMap, fs::read_to_string, path::Path, process::exit};
+
 use libwfa2::{affine_wavefront::{AffineWavefronts, AlignmentSpan, AlignmentStatus, HeuristicStrategy}, bindings::wfa::wavefront_aligner_set_heuristic_xdrop};
 
-use crate::align::{common::{Align, Heuristic, Status}, sam::{Cigar, CigarRef}};
+use crate::{align::{common::{Align, Heuristic, Status}, errors::AlignmentError, sam::{Cigar, CigarRef}}, database::common::FlexalignDatabase};
 
 
 // pub struct FastAlignment {
@@ -13,8 +15,107 @@ use crate::align::{common::{Align, Heuristic, Status}, sam::{Cigar, CigarRef}};
 pub struct LIBWFA2Alignment {
     pub aligner: AffineWavefronts,
     pub cigar: Cigar,
+    pub work: AlignmentWork,
+}
+
+/// Aligner calls since the last `take_work` (`--dump-alignment-work`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AlignmentWork {
+    pub calls: usize,
+    /// Score steps the wavefronts advanced: the absolute final score, or the score bound of dropped alignments
+    pub steps: u64,
+    /// Query plus reference bases passed to the aligner
+    pub bases: u64,
+}
+
+/// Match score of the aligner. Ungapped segments (seeds, seed gaps, the whole-read fast path) are scored
+/// without calling WFA, which is only consistent with the aligner for this match score.
+pub const MATCH_SCORE: i32 = 0;
+
+/// Mismatch penalty of the aligner, also charged per `X` in the ungapped segments.
+pub const MISMATCH_PENALTY: i32 = 4;
+
+/// Gap penalties of the default aligner, a gap of length n costs `GAP_OPENING_PENALTY + n * GAP_EXTENSION_PENALTY`.
+pub const GAP_OPENING_PENALTY: i32 = 6;
+pub const GAP_EXTENSION_PENALTY: i32 = 2;
+
+/// Rejects penalties the ungapped score accounting does not reproduce.
+fn check_penalties(match_: i32, mismatch: i32) -> Result<(), AlignmentError> {
+    if match_ != MATCH_SCORE || mismatch != MISMATCH_PENALTY {
+        return Err(AlignmentError::UnsupportedPenaltiesError(format!(
+            "Unsupported penalties (match {}, mismatch {}). Ungapped segments are scored with match {} and mismatch {}.",
+            match_, mismatch, MATCH_SCORE, MISMATCH_PENALTY)))
+    }
+    Ok(())
+}
+
+/// Phred quality at and above which a mismatch is charged the full penalty.
+const REBATE_QUALITY: u8 = 20;
+
+/// Score correction for mismatches at low-quality query bases. The penalty of each `X` is scaled by
+/// `min(q, 20) / 20`, the returned value is the part given back (alignment scores are negative).
+pub fn quality_rebate(cigar: &Cigar, qual: &[u8], mismatch_penalty: i32) -> i32 {
+    let mut qi = 0;
+    let mut rebate = 0;
+    for &op in cigar.0.iter() {
+        match op {
+            b'X' => {
+                let q = qual.get(qi).map_or(REBATE_QUALITY, |q| min(q.saturating_sub(33), REBATE_QUALITY));
+                rebate += mismatch_penalty * (REBATE_QUALITY - q) as i32 / REBATE_QUALITY as i32;
+                qi += 1;
+            },
+            b'M' | b'=' | b'S' | b'D' => qi += 1,
+            _ => {},
+        }
+    }
+    rebate
+}
+
+/// Minimum identity of an alignment per reference id (`--min-ani-file`), `default` for all others (`--min-ani`).
+#[derive(Clone, Debug)]
+pub struct MinAni {
+    pub default: f64,
+    pub per_reference: HashMap<u64, f64>,
 }
 
+impl MinAni {
+    pub fn new(default: f64) -> Self {
+        Self { default, per_reference: HashMap::new() }
+    }
+
+    /// Reads `<reference name> <identity>` per line. Names that are not in the index are reported and skipped.
+    pub fn from_file<D: FlexalignDatabase>(db: &D, default: f64, path: &Path) -> Self {
+        let content = read_to_string(path).unwrap_or_else(|why| {
+            eprintln!("Cannot read --min-ani-file {}: {}", path.display(), why);
+            exit(9);
+        });
+
+        let mut min_ani = Self::new(default);
+        for (line_number, line) in content.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') { continue };
+
+            let ani = match fields.as_slice() {
+                [_, ani] => ani.parse::<f64>().ok().filter(|ani| (0.0..=1.0).contains(ani)),
+                _ => None,
+            };
+            let Some(ani) = ani else {
+                eprintln!("--min-ani-file line {}: expected a reference name and an identity in [0, 1], got '{}'", line_number + 1, line);
+                exit(9);
+            };
+
+            match db.get_rid(fields[0]) {
+                Some(&rid) => { min_ani.per_reference.insert(rid as u64, ani); },
+                None => eprintln!("--min-ani-file: reference {} is not in the index, skipped", fields[0]),
+            }
+        }
+        min_ani
+    }
+
+    pub fn get(&self, reference: u64) -> f64 {
+        *self.per_reference.get(&reference).unwrap_or(&self.default)
+    }
+}
 
 pub fn ani_abort_score(min_ani: f64, mismatch: i32, overlap_length: i32) -> i32 {
     let score = (1.0 - min_ani) * overlap_length as f64 * mismatch as f64;
@@ -41,6 +142,7 @@ impl Clone for LIBWFA2Alignment {
         Self { 
             aligner: new_aligner,
             cigar: self.cigar.clone(),
+            work: AlignmentWork::default(),
         }
     }
 }
@@ -50,7 +152,9 @@ impl Align for LIBWFA2Alignment {
         self.cigar.0.clear();
 
         // Perform alignment
-        match self.aligner.align(q, r) {
+        let status = self.aligner.align(q, r);
+        self.count_work(q, r, &status);
+        match status {
             AlignmentStatus::Completed => {
                 self.cigar.0.extend_from_slice(self.aligner.cigar());
                 (self.aligner.score(), &self.cigar, Status::OK)
@@ -78,7 +182,9 @@ impl Align for LIBWFA2Alignment {
     
     fn align_into(&mut self, q: &[u8], r: &[u8], cigar: &mut Cigar) -> (i32, Status) {
         // Perform alignment
-        match self.aligner.align(q, r) {
+        let status = self.aligner.align(q, r);
+        self.count_work(q, r, &status);
+        match status {
             AlignmentStatus::Completed => {
                 cigar.0.extend_from_slice(self.aligner.cigar());
                 (self.aligner.score(), Status::OK)
@@ -104,15 +210,34 @@ impl Align for LIBWFA2Alignment {
 }
 
 impl LIBWFA2Alignment {
-    pub fn set_penalties(&mut self, match_: i32, mismatch: i32, gap_opening: i32, gap_extension: i32) {
+    pub fn set_penalties(&mut self, match_: i32, mismatch: i32, gap_opening: i32, gap_extension: i32) -> Result<(), AlignmentError> {
+        check_penalties(match_, mismatch)?;
         self.aligner.set_penalties(match_, mismatch, gap_opening, gap_extension);
+        Ok(())
     }
 
-    pub fn with_penalties(match_: i32, mismatch: i32, gap_opening: i32, gap_extension: i32) -> Self {
-        Self {
+    pub fn with_penalties(match_: i32, mismatch: i32, gap_opening: i32, gap_extension: i32) -> Result<Self, AlignmentError> {
+        check_penalties(match_, mismatch)?;
+        Ok(Self {
             aligner: AffineWavefronts::with_penalties(match_, mismatch, gap_opening, gap_extension),
             cigar: Cigar(Vec::new()),
-        }
+            work: AlignmentWork::default(),
+        })
+    }
+
+    /// Returns the work since the last call and resets it.
+    pub fn take_work(&mut self) -> AlignmentWork {
+        std::mem::take(&mut self.work)
+    }
+
+    fn count_work(&mut self, q: &[u8], r: &[u8], status: &AlignmentStatus) {
+        let score = match status {
+            AlignmentStatus::Completed | AlignmentStatus::Partial if self.aligner.score() != i32::MIN => self.aligner.score(),
+            _ => self.aligner.get_max_alignment_steps(),
+        };
+        self.work.calls += 1;
+        self.work.steps += score.unsigned_abs() as u64;
+        self.work.bases += (q.len() + r.len()) as u64;
     }
 
     pub fn set_below_ani_abort(&mut self, min_ani: f64, overlap_length: usize) {
@@ -131,7 +256,7 @@ impl Heuristic for LIBWFA2Alignment {
 
 impl Default for LIBWFA2Alignment {
     fn default() -> Self {
-        let mut aligner = AffineWavefronts::with_penalties(0, 4, 6, 2);
+        let mut aligner = AffineWavefronts::with_penalties(MATCH_SCORE, MISMATCH_PENALTY, GAP_OPENING_PENALTY, GAP_EXTENSION_PENALTY);
         // aligner.set_heuristic(&HeuristicStrategy::XDrop { xdrop: std::i32::MIN, score_steps: 2 });
         // aligner.set_heuristic(&HeuristicStrategy::BandedStatic { band_min_k: -1, band_max_k: 1 });
         aligner.set_alignment_scope(libwfa2::affine_wavefront::AlignmentScope::Alignment);
@@ -142,6 +267,49 @@ impl Default for LIBWFA2Alignment {
         Self { 
             aligner: aligner,
             cigar: Cigar(Vec::new()),
+            work: AlignmentWork::default(),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quality_rebate_scales_mismatch_penalties_by_quality() {
+        // Query bases: S 0, M 1, X 2, M 3, X 4, D 5, X 6. The I consumes no query base.
+        let cigar = Cigar(b"SMXMIXDX".to_vec());
+        let qual = b"#I+I5I!";
+        // Phred 10 gives back half the penalty, 20 nothing, 0 all of it.
+        assert_eq!(quality_rebate(&cigar, qual, MISMATCH_PENALTY), MISMATCH_PENALTY / 2 + MISMATCH_PENALTY);
+        assert_eq!(quality_rebate(&cigar, &[b'I'; 7], MISMATCH_PENALTY), 0);
+        // Without qualities for a base the full penalty stays.
+        assert_eq!(quality_rebate(&Cigar(b"MMX".to_vec()), b"!!", MISMATCH_PENALTY), 0);
+    }
+
+    #[test]
+    fn unsupported_penalties_are_an_error() {
+        assert!(LIBWFA2Alignment::with_penalties(MATCH_SCORE, MISMATCH_PENALTY + 1, GAP_OPENING_PENALTY, GAP_EXTENSION_PENALTY).is_err());
+        assert!(LIBWFA2Alignment::with_penalties(-1, MISMATCH_PENALTY, GAP_OPENING_PENALTY, GAP_EXTENSION_PENALTY).is_err());
+
+        let mut aligner = LIBWFA2Alignment::with_penalties(MATCH_SCORE, MISMATCH_PENALTY, GAP_OPENING_PENALTY, GAP_EXTENSION_PENALTY).unwrap();
+        assert!(aligner.set_penalties(MATCH_SCORE, MISMATCH_PENALTY, GAP_OPENING_PENALTY + 1, GAP_EXTENSION_PENALTY).is_ok());
+        assert!(aligner.set_penalties(MATCH_SCORE, 2 * MISMATCH_PENALTY, GAP_OPENING_PENALTY, GAP_EXTENSION_PENALTY).is_err());
+    }
+
+    #[test]
+    fn min_ani_file_overrides_known_references() {
+        use crate::test_utils::{build_db, pseudo_random_bases, temp_path};
+
+        let (_, db) = build_db(&pseudo_random_bases(1000, 3), &[]);
+        let path = temp_path("min-ani", "txt");
+        std::fs::write(&path, "# reference identity\n\nref 0.9\nmissing 0.8\n").unwrap();
+        let min_ani = MinAni::from_file(&db, 0.5, &path);
+        std::fs::remove_file(&path).unwrap();
+
+        let rid = *db.get_rid("ref").unwrap() as u64;
+        assert_eq!(min_ani.per_reference.len(), 1);
+        assert_eq!(min_ani.get(rid), 0.9);
+        assert_eq!(min_ani.get(rid + 1), 0.5);
+    }
+}
@@ -1,8 +1,8 @@
-use std::{cmp::{max, min}, fmt::Display, mem::swap, ops::Range};
+use std::{cmp::{max, min, Reverse}, fmt::Display, mem::swap, ops::Range};
 
 use flate2::read;
 
-use crate::{align::{common::{AnchorExtractor, AnchorPair, AnchorScore, PairedAnchorExtractor, PairedAnchorMAPQ, SeedGroupPairList, SeedGroupPairedList, StdAnchorScore, StdPairedAnchorMAPQ}, data_structures::{Anchor, AnchorSeed, Seed}, stats::{self, Stats}}, flexalign::time};
+use crate::{align::{common::{bound_capacity, sort_by_key, AnchorExtractor, AnchorPair, AnchorScore, PairedAnchorExtractor, PairedAnchorMAPQ, SeedGroupPairList, SeedGroupPairedList, StdAnchorScore, StdPairedAnchorMAPQ}, data_structures::{Anchor, AnchorSeed, Seed, SeedOverlap}, stats::{self, Stats}}, flexalign::time, options::{AnchorStrategy, GroupSeed, LibraryType, MiddleGap, SeedSortKey, SortImpl, MAX_INSERT_SIZE}};
 
 
 #[repr(C)]
@@ -51,6 +51,14 @@ pub struct StdAnchorExtractor {
     pub other_indices: Vec<usize>,
     pub anchor_map: micromap::Map<u64, u32, 64>,
     pub groups: Vec<(u32, u32)>,
+    /// See `seed_group_indices_module`
+    pub rpos_window: Option<u64>,
+    /// Seed the other seeds of a group are compared against (`--group-seed`)
+    pub group_seed: GroupSeed,
+    /// See `--sort-impl`
+    pub sort_impl: Option<SortImpl>,
+    /// Order of the seeds passed to `generate` (`--seed-sort-key`)
+    pub seed_sort_key: SeedSortKey,
 }
 
 #[derive(Clone)]
@@ -64,13 +72,32 @@ pub struct StdPairedAnchorExtractor {
     pub anchors: Vec<AnchorPair>,
     pub anchors_fwd: Vec<Anchor>,
     pub anchors_rev: Vec<Anchor>,
+
+    pub scorer: StdAnchorScore,
+    pub library_type: LibraryType,
+    /// Anchor pairs kept per read (`--max-anchors-per-read`), all if None
+    pub max_anchors: Option<usize>,
+    /// Only emit pairs with both mates on the same reference within `max_insert_size` (`--strict-pairing`)
+    pub strict_pairing: bool,
+    /// Largest insert size of a pair of anchors (`--max-insert-size`)
+    pub max_insert_size: i64,
+    /// Seed the other seeds of a group are compared against (`--group-seed`)
+    pub group_seed: GroupSeed,
+    /// See `--sort-impl`
+    pub sort_impl: Option<SortImpl>,
+    /// Order of the seeds passed to `generate` (`--seed-sort-key`)
+    pub seed_sort_key: SeedSortKey,
+    /// Diagonals from the group seed up to which seeds join its anchor, see `with_middle_gap`
+    pub max_indel: u32,
 }
 
 
 
 
+/// Splits seeds sorted by reference into one group per reference. With `rpos_window`, a reference is split
+/// further where consecutive seeds are more than that many bases apart (`--group-rpos-window`).
 #[inline(always)]
-pub fn seed_group_indices_module(seeds: &[Seed], groups: &mut Vec<(u32, u32)>) -> usize {
+pub fn seed_group_indices_module(seeds: &[Seed], groups: &mut Vec<(u32, u32)>, rpos_window: Option<u64>) -> usize {
     let mut last_idx = 0;
     // let mut groups = Vec::new();
     let mut max_size = 0;
@@ -79,7 +106,8 @@ pub fn seed_group_indices_module(seeds: &[Seed], groups: &mut Vec<(u32, u32)>) -
     for i in 1..seeds.len() {
         let prev = &seeds[i-1];
         let next = &seeds[i];
-        if prev.rval != next.rval {
+        let distant = rpos_window.is_some_and(|w| prev.rpos.abs_diff(next.rpos) > w);
+        if prev.rval != next.rval || distant {
             groups.push((last_idx as u32, i as u32));
             max_size = max(max_size, i - last_idx);
             last_idx = i;
@@ -141,8 +169,26 @@ pub fn seed_group_indices_paired_module(seeds: &[Seed], groups: &mut SeedGroupPa
 }
 
 
+/// Largest diagonal distance between two anchors of a reference that is taken for an indel of the read. Both
+/// anchors are flagged for indel, which widens their flank alignment (`--indel-free-ends`).
+pub const MAX_INDEL_OFFSET: u32 = 10;
+
+/// Position in `indices` of the seed a group is compared against (`--group-seed`). Ties go to the earlier seed.
+fn group_seed(seeds: &[Seed], indices: &[usize], read_length: usize, choice: GroupSeed) -> usize {
+    let position = match choice {
+        GroupSeed::First => None,
+        GroupSeed::Longest => indices.iter().enumerate()
+            .min_by_key(|(_, &index)| Reverse(seeds[index].length)),
+        GroupSeed::Central => indices.iter().enumerate()
+            .min_by_key(|(_, &index)| (2 * seeds[index].qpos as i64 + seeds[index].length as i64 - read_length as i64).abs()),
+    };
+    position.map_or(0, |(position, _)| position)
+}
+
+/// Splits a group of seeds into anchors. Seeds join the anchor of the group seed up to `max_indel` diagonals
+/// away from it, 0 keeps every anchor on a single diagonal.
 #[inline(always)]
-pub fn group_into_anchor_module<'a>(seeds_extern: &[Seed], start: usize, end: usize, read_length: usize, indices: &'a mut Vec<usize>, other_indices: &'a mut Vec<usize>, anchors: &mut Vec<Anchor>) {
+pub fn group_into_anchor_module<'a>(seeds_extern: &[Seed], start: usize, end: usize, read_length: usize, indices: &'a mut Vec<usize>, other_indices: &'a mut Vec<usize>, anchors: &mut Vec<Anchor>, choice: GroupSeed, sort_impl: Option<SortImpl>, seed_sort_key: SeedSortKey, max_indel: u32) {
     let seeds = &seeds_extern[start..end];
     
 
@@ -153,7 +199,6 @@ pub fn group_into_anchor_module<'a>(seeds_extern: &[Seed], start: usize, end: us
 
     let anchor_group_index = anchors.len();
     let mut added_anchors = 0;
-    let indel_flag = false;
 
     if !seeds.is_empty() {
         // Group by exact offset. If there are seeds left, distribute them onto 
@@ -163,30 +208,37 @@ pub fn group_into_anchor_module<'a>(seeds_extern: &[Seed], start: usize, end: us
         other_indices.clear();
 
         while !indices.is_empty() {
-            let first = seeds.get(*indices.first().unwrap()).unwrap();
-
-            // Set first seed as an anchor.
-            let mut a: Anchor = Anchor::from_seed(first);
-
-            let mut offset = None;
+            let first_index = indices[group_seed(seeds, indices, read_length, choice)];
+            let first = &seeds[first_index];
             let mut forward = None;
 
-            // Find seeds that have 0 indels with respect to the first anchor
-            for index in indices.iter().skip(1) {
-                let next: &Seed = &seeds[*index];
-                let (offset_first, fwd, indel_first) = first.closest_offset(&next, read_length);
+            // Keep seeds within `max_indel` diagonals of the group seed, the others form the next anchors.
+            // Pairs with a palindromic seed fit either strand and leave it to the other members.
+            indices.retain(|&index| {
+                if index == first_index { return true };
+                let (_, fwd, indel_first) = first.closest_offset(&seeds[index], read_length);
+                let informative = !first.is_palindromic() && !seeds[index].is_palindromic();
+                let keep = indel_first <= max_indel as u64 && (!informative || forward.map_or(true, |f| f == fwd));
+                if !keep {
+                    other_indices.push(index);
+                } else if informative {
+                    forward = Some(fwd);
+                }
+                keep
+            });
 
-                if indel_first == 0 && (forward.is_none() || forward.unwrap() == fwd) {
-                    if offset.is_none() {
-                        offset = Some(offset_first);
-                        a.set_forward(fwd, read_length);
-                        forward = Some(fwd);
-                    }
-                    a.add_seed(&next, read_length as u32);
+            // Anchor::add_seed expects ascending reference positions. Seeds sorted by rpos already are
+            // (--seed-sort-key rpos), seeds sorted by offset are not.
+            if seed_sort_key == SeedSortKey::Offset {
+                sort_by_key(indices, |&index| seeds[index].rpos, sort_impl);
+            }
 
-                } else {
-                    other_indices.push(*index);
-                }
+            let mut a: Anchor = Anchor::from_seed(&seeds[indices[0]]);
+            if let Some(fwd) = forward {
+                a.set_forward(fwd, read_length);
+            }
+            for index in indices.iter().skip(1) {
+                a.add_seed(&seeds[*index], read_length as u32);
             }
 
             anchors.push(a);
@@ -197,26 +249,18 @@ pub fn group_into_anchor_module<'a>(seeds_extern: &[Seed], start: usize, end: us
             swap(other_indices, indices);
         }
 
-        // let mut local_anchors = &anchors[anchor_group_index..];
-        // for i in 0..local_anchors.len() {
-        //     for j in i+1..local_anchors.len() {
-        //         let a = &local_anchors[i];
-        //         let b = &local_anchors[j];
-
-        //         let offset_dist = a.get_indel(b, read_length);
-        //         if offset_dist.abs() < 10 {
-        //             indel_flag = true;
-        //         }
-        //     }
-        // }
-        
+        // Anchors of one reference on nearby diagonals are the parts of a read around an indel.
+        let group = &mut anchors[anchor_group_index..];
+        for i in 0..group.len() {
+            for j in i + 1..group.len() {
+                if (1..=MAX_INDEL_OFFSET).contains(&group[i].get_indel(&group[j], read_length).unsigned_abs()) {
+                    group[i].flagged_for_indel = true;
+                    group[j].flagged_for_indel = true;
+                }
+            }
+        }
     }
     // eprintln!("END -- Seed group -- {}", seeds.first().unwrap().rval );
-
-    if indel_flag {
-        println!("Indel")
-    }
-
 }
 
 
@@ -228,6 +272,10 @@ impl StdAnchorExtractor {
             other_indices: Vec::new(),
             anchor_map: micromap::Map::default(),
             groups: Vec::new(),
+            rpos_window: None,
+            group_seed: GroupSeed::First,
+            sort_impl: None,
+            seed_sort_key: SeedSortKey::Rpos,
         }
     }
 
@@ -318,12 +366,12 @@ impl AnchorExtractor for StdAnchorExtractor {
 
         // let max_size = self.seed_group_indices(seeds);
         //TODO: Revisit and check function
-        let max_size = seed_group_indices_module(seeds, &mut self.groups); 
+        let max_size = seed_group_indices_module(seeds, &mut self.groups, self.rpos_window);
 
         let (duration, _) = time(|| {
-            glidesort::sort_by_key(&mut self.groups, |(start, end)| {
+            sort_by_key(&mut self.groups, |(start, end)| {
                 -1i32 * (end - start) as i32
-            });
+            }, self.sort_impl);
         });
 
         let skip_threshold = min(max_size as i32, 32i32) - 10;
@@ -339,7 +387,7 @@ impl AnchorExtractor for StdAnchorExtractor {
             };
 
             // self.group_into_anchor(seeds, start as usize, end as usize, read_length);
-            group_into_anchor_module(seeds, start as usize, end as usize, read_length, &mut self.indices, &mut self.other_indices, &mut self.anchors);
+            group_into_anchor_module(seeds, start as usize, end as usize, read_length, &mut self.indices, &mut self.other_indices, &mut self.anchors, self.group_seed, self.sort_impl, self.seed_sort_key, 0);
         }
 
         &mut self.anchors
@@ -352,12 +400,279 @@ impl AnchorExtractor for StdAnchorExtractor {
     fn retrieve_mut(&mut self) -> &mut [Anchor] {
         &mut self.anchors
     }
+
+    fn shrink_scratch(&mut self, max_capacity: usize) {
+        bound_capacity(&mut self.anchors, max_capacity);
+        bound_capacity(&mut self.indices, max_capacity);
+        bound_capacity(&mut self.other_indices, max_capacity);
+        bound_capacity(&mut self.groups, max_capacity);
+    }
 }
 
 
 
-impl StdPairedAnchorExtractor {
+/// Maximum number of preceding seeds (in reference order) considered as chain predecessors.
+const CHAIN_LOOKBACK: usize = 32;
+/// Maximum difference between query and reference distance of two chained seeds.
+const CHAIN_MAX_GAP: u64 = 32;
+/// Maximum number of anchors reported per read, as for the exact strategy.
+const CHAIN_MAX_ANCHORS: usize = 8;
+
+/// Groups seeds into anchors by colinear chaining instead of requiring exact offsets. Per reference and
+/// strand, a DP over the seeds (sorted by reference position) finds the highest scoring chain, where
+/// each seed contributes the query bases it newly covers and a diagonal shift costs one per base.
+/// Chains of a single seed are reported with unset orientation, like the exact strategy does.
+#[derive(Clone)]
+pub struct ChainingAnchorExtractor {
+    pub anchors: Vec<Anchor>,
+    pub groups: Vec<(u32, u32)>,
+    /// See `seed_group_indices_module`
+    pub rpos_window: Option<u64>,
+    chain: Vec<(u32, u64, u32, usize)>,
+    chainer: ColinearChainer,
+    chain_scores: Vec<(i32, usize)>,
+    /// See `--sort-impl`
+    pub sort_impl: Option<SortImpl>,
+}
+
+/// Scratch of the colinear chaining DP shared by `ChainingAnchorExtractor` and `--chaining`: each seed
+/// contributes the query bases it newly covers and a diagonal shift costs one per base.
+#[derive(Clone, Default)]
+pub struct ColinearChainer {
+    scores: Vec<i32>,
+    predecessors: Vec<usize>,
+}
+
+impl ColinearChainer {
+    /// Runs the DP over `n` rpos-sorted `(qpos, rpos, length)` items. Returns the end and score of the best chain.
+    fn chain(&mut self, n: usize, item: impl Fn(usize) -> (u32, u64, u32)) -> Option<(usize, i32)> {
+        self.scores.clear();
+        self.predecessors.clear();
+        for i in 0..n {
+            let (qi, ri, li) = item(i);
+            let mut best = li as i32;
+            let mut best_predecessor = usize::MAX;
+
+            for j in i.saturating_sub(CHAIN_LOOKBACK)..i {
+                let (qj, rj, lj) = item(j);
+                if qj >= qi || rj >= ri { continue };
+                let gap = ((qi - qj) as u64).abs_diff(ri - rj);
+                if gap > CHAIN_MAX_GAP { continue };
+                // Seeds sharing query bases can only be chained on the same diagonal.
+                if qj + lj > qi && gap != 0 { continue };
+
+                let covered = min(li, qi - qj) as i32;
+                let score = self.scores[j] + covered - gap as i32;
+                if score > best {
+                    best = score;
+                    best_predecessor = j;
+                }
+            }
+            self.scores.push(best);
+            self.predecessors.push(best_predecessor);
+        }
+
+        self.scores.iter().enumerate().max_by_key(|(_, s)| **s).map(|(i, &s)| (i, s))
+    }
+
+    /// Chaining score of rpos-sorted anchor seeds, 0 without seeds. See `--chaining`.
+    pub fn score(&mut self, seeds: &[AnchorSeed]) -> i32 {
+        self.chain(seeds.len(), |i| (seeds[i].qpos, seeds[i].rpos, seeds[i].length)).map_or(0, |(_, score)| score)
+    }
+
+    pub fn shrink_scratch(&mut self, max_capacity: usize) {
+        bound_capacity(&mut self.scores, max_capacity);
+        bound_capacity(&mut self.predecessors, max_capacity);
+    }
+}
+
+impl ChainingAnchorExtractor {
     pub fn new() -> Self {
+        Self {
+            anchors: Vec::new(),
+            groups: Vec::new(),
+            rpos_window: None,
+            chain: Vec::new(),
+            chainer: ColinearChainer::default(),
+            chain_scores: Vec::new(),
+            sort_impl: None,
+        }
+    }
+
+    /// Chain the seeds of a single reference in one orientation. Returns the score of the best chain
+    /// and pushes the corresponding anchor.
+    fn chain_group(&mut self, seeds: &[Seed], start: usize, end: usize, read_length: usize, forward: bool) -> Option<i32> {
+        self.chain.clear();
+        for (i, seed) in seeds[start..end].iter().enumerate() {
+            let qpos = if forward { seed.qpos } else { seed.reverse(read_length).qpos };
+            self.chain.push((qpos, seed.rpos, seed.length as u32, start + i));
+        }
+        sort_by_key(&mut self.chain, |&(qpos, rpos, _, _)| (rpos, qpos), self.sort_impl);
+
+        let chain = &self.chain;
+        let (last, score) = self.chainer.chain(chain.len(), |i| (chain[i].0, chain[i].1, chain[i].2))?;
+
+        let mut index = last;
+        let mut members = vec![index];
+        while self.chainer.predecessors[index] != usize::MAX {
+            index = self.chainer.predecessors[index];
+            members.push(index);
+        }
+        members.reverse();
+
+        // A single seed has no orientation of its own; report it only once.
+        if members.len() == 1 && !forward { return None };
+
+        let first = &seeds[self.chain[members[0]].3];
+        let mut a = Anchor::from_seed(first);
+        if members.len() > 1 {
+            a.set_forward(forward, read_length);
+        }
+
+        for &m in &members[1..] {
+            let (qpos, rpos, length, index) = self.chain[m];
+            a.seed_count += 1;
+            a.mismatches += seeds[index].mismatch as u32;
+            let aseed = AnchorSeed { qpos, rpos, length };
+            match a.seeds.last_mut().unwrap().rpos_sorted_merge_into(&aseed) {
+                SeedOverlap::NoOverlap => a.seeds.push(aseed),
+                _ => {},
+            }
+        }
+
+        a.score = score;
+        self.anchors.push(a);
+        Some(score)
+    }
+}
+
+impl AnchorExtractor for ChainingAnchorExtractor {
+    fn generate(&mut self, seeds: &[Seed], read_length: usize, stats: &mut Stats) -> &mut [Anchor] {
+        self.anchors.clear();
+        self.chain_scores.clear();
+
+        let _ = seed_group_indices_module(seeds, &mut self.groups, self.rpos_window);
+        stats.anchors += self.groups.len();
+
+        for g in 0..self.groups.len() {
+            let (start, end) = self.groups[g];
+            for forward in [true, false] {
+                if let Some(score) = self.chain_group(seeds, start as usize, end as usize, read_length, forward) {
+                    self.chain_scores.push((score, self.anchors.len() - 1));
+                }
+            }
+        }
+
+        // Keep the best chains, in the order of their score.
+        sort_by_key(&mut self.chain_scores, |(score, _)| -score, self.sort_impl);
+        self.chain_scores.truncate(CHAIN_MAX_ANCHORS);
+        let mut anchors = Vec::with_capacity(self.chain_scores.len());
+        for &(_, index) in &self.chain_scores {
+            anchors.push(self.anchors[index].clone());
+        }
+        self.anchors = anchors;
+
+        &mut self.anchors
+    }
+
+    fn retrieve(&self) -> &[Anchor] {
+        &self.anchors
+    }
+
+    fn retrieve_mut(&mut self) -> &mut [Anchor] {
+        &mut self.anchors
+    }
+
+    fn shrink_scratch(&mut self, max_capacity: usize) {
+        bound_capacity(&mut self.anchors, max_capacity);
+        bound_capacity(&mut self.groups, max_capacity);
+        bound_capacity(&mut self.chain, max_capacity);
+        self.chainer.shrink_scratch(max_capacity);
+        bound_capacity(&mut self.chain_scores, max_capacity);
+    }
+}
+
+/// Runtime selection of the single-end anchor extractor (--anchor-strategy).
+#[derive(Clone)]
+pub enum SelectedAnchorExtractor {
+    Exact(StdAnchorExtractor),
+    Chaining(ChainingAnchorExtractor),
+}
+
+impl SelectedAnchorExtractor {
+    pub fn new(strategy: AnchorStrategy) -> Self {
+        match strategy {
+            AnchorStrategy::Exact => Self::Exact(StdAnchorExtractor::new()),
+            AnchorStrategy::Chaining => Self::Chaining(ChainingAnchorExtractor::new()),
+        }
+    }
+
+    pub fn with_group_rpos_window(mut self, rpos_window: Option<u64>) -> Self {
+        match &mut self {
+            Self::Exact(e) => e.rpos_window = rpos_window,
+            Self::Chaining(e) => e.rpos_window = rpos_window,
+        }
+        self
+    }
+
+    pub fn with_sort_impl(mut self, sort_impl: Option<SortImpl>) -> Self {
+        match &mut self {
+            Self::Exact(e) => e.sort_impl = sort_impl,
+            Self::Chaining(e) => e.sort_impl = sort_impl,
+        }
+        self
+    }
+
+    /// Only used by the exact strategy, chaining sorts the seeds of a chain itself.
+    pub fn with_seed_sort_key(mut self, seed_sort_key: SeedSortKey) -> Self {
+        if let Self::Exact(e) = &mut self {
+            e.seed_sort_key = seed_sort_key;
+        }
+        self
+    }
+
+    /// Only used by the exact strategy, chaining does not compare seeds against a group seed.
+    pub fn with_group_seed(mut self, group_seed: GroupSeed) -> Self {
+        if let Self::Exact(e) = &mut self {
+            e.group_seed = group_seed;
+        }
+        self
+    }
+}
+
+impl AnchorExtractor for SelectedAnchorExtractor {
+    fn generate(&mut self, seeds: &[Seed], read_length: usize, stats: &mut Stats) -> &mut [Anchor] {
+        match self {
+            Self::Exact(e) => e.generate(seeds, read_length, stats),
+            Self::Chaining(e) => e.generate(seeds, read_length, stats),
+        }
+    }
+
+    fn retrieve(&self) -> &[Anchor] {
+        match self {
+            Self::Exact(e) => e.retrieve(),
+            Self::Chaining(e) => e.retrieve(),
+        }
+    }
+
+    fn retrieve_mut(&mut self) -> &mut [Anchor] {
+        match self {
+            Self::Exact(e) => e.retrieve_mut(),
+            Self::Chaining(e) => e.retrieve_mut(),
+        }
+    }
+
+    fn shrink_scratch(&mut self, max_capacity: usize) {
+        match self {
+            Self::Exact(e) => e.shrink_scratch(max_capacity),
+            Self::Chaining(e) => e.shrink_scratch(max_capacity),
+        }
+    }
+}
+
+
+impl StdPairedAnchorExtractor {
+    pub fn new(scorer: StdAnchorScore, library_type: LibraryType) -> Self {
         Self {
             anchors_fwd: Vec::new(),
             anchors_rev: Vec::new(),
@@ -366,17 +681,88 @@ impl StdPairedAnchorExtractor {
             other_indices: Vec::new(),
             groups: Vec::new(),
             groups_paired: Vec::new(),
+            scorer,
+            library_type,
+            max_anchors: None,
+            strict_pairing: false,
+            max_insert_size: MAX_INSERT_SIZE,
+            group_seed: GroupSeed::First,
+            sort_impl: None,
+            seed_sort_key: SeedSortKey::Rpos,
+            max_indel: 0,
         }
     }
+
+    pub fn with_group_seed(mut self, group_seed: GroupSeed) -> Self {
+        self.group_seed = group_seed;
+        self
+    }
+
+    pub fn with_sort_impl(mut self, sort_impl: Option<SortImpl>) -> Self {
+        self.sort_impl = sort_impl;
+        self
+    }
+
+    pub fn with_seed_sort_key(mut self, seed_sort_key: SeedSortKey) -> Self {
+        self.seed_sort_key = seed_sort_key;
+        self
+    }
+
+    /// With `MiddleGap::Align` seeds up to `MAX_INDEL_OFFSET` diagonals apart form one anchor, whose gaps
+    /// between seeds are then aligned with WFA. Compared base by base such gaps would be misaligned, so
+    /// anchors stay on one diagonal otherwise.
+    pub fn with_middle_gap(mut self, middle_gap: MiddleGap) -> Self {
+        self.max_indel = if middle_gap == MiddleGap::Align { MAX_INDEL_OFFSET } else { 0 };
+        self
+    }
+
+    pub fn with_max_anchors(mut self, max_anchors: Option<usize>) -> Self {
+        self.max_anchors = max_anchors;
+        self
+    }
+
+    pub fn with_strict_pairing(mut self, strict_pairing: bool) -> Self {
+        self.strict_pairing = strict_pairing;
+        self
+    }
+
+    pub fn with_max_insert_size(mut self, max_insert_size: i64) -> Self {
+        self.max_insert_size = max_insert_size;
+        self
+    }
 }
 
-pub fn insert_size(a_fwd: Option<&Anchor>, a_rev: Option<&Anchor>, read_length_fwd: usize, read_length_rev: usize) -> Option<i64> {
-    if a_fwd.is_none() || a_rev.is_none() { return None };
-    
-    let span_fwd = a_fwd.as_ref().unwrap().reference_pos(read_length_fwd);
-    let span_rev = a_rev.as_ref().unwrap().reference_pos(read_length_rev);
+/// Fragment span from the leading to the trailing mate, where leading and trailing are given by the library
+/// type: for FR the forward strand mate leads, for RF the reverse strand mate, and for FF read 1 leads on the
+/// forward strand and read 2 on the reverse strand. The span is positive if the mates are arranged as the
+/// library expects and zero or negative otherwise. None if the strands do not fit the library type.
+pub fn oriented_insert_size(a1: &Anchor, a2: &Anchor, read_length_fwd: usize, read_length_rev: usize, library_type: LibraryType) -> Option<i64> {
+    let span1 = a1.reference_pos(read_length_fwd);
+    let span2 = a2.reference_pos(read_length_rev);
+
+    let first_leads = match library_type {
+        LibraryType::Fr if a1.forward != a2.forward => a1.forward,
+        LibraryType::Rf if a1.forward != a2.forward => !a1.forward,
+        LibraryType::Ff if a1.forward == a2.forward => a1.forward,
+        _ => return None,
+    };
+    let (leading, trailing) = if first_leads { (span1, span2) } else { (span2, span1) };
+
+    Some(trailing.1 as i64 - leading.0 as i64)
+}
+
+/// Fragment span of a mate pair, measured like `oriented_insert_size` so that pairing and proper pairs agree.
+/// Mates whose strand is not resolved yet are measured over their outer span. None if a mate is missing or
+/// the strands do not fit the library type.
+pub fn insert_size(a_fwd: Option<&Anchor>, a_rev: Option<&Anchor>, read_length_fwd: usize, read_length_rev: usize, library_type: LibraryType) -> Option<i64> {
+    let (a_fwd, a_rev) = (a_fwd?, a_rev?);
+    if a_fwd.orientation_set && a_rev.orientation_set {
+        return oriented_insert_size(a_fwd, a_rev, read_length_fwd, read_length_rev, library_type)
+    }
 
-    Some(if span_fwd.0 < span_rev.0 { span_rev.0 as i64 - span_fwd.1 as i64 } else { span_fwd.0 as i64 - span_rev.1 as i64 })
+    let span_fwd = a_fwd.reference_pos(read_length_fwd);
+    let span_rev = a_rev.reference_pos(read_length_rev);
+    Some(max(span_fwd.1, span_rev.1) as i64 - min(span_fwd.0, span_rev.0) as i64)
 }
 
 // pub fn pair_anchors(anchors_fwd: &Vec<Anchor>, anchors_rev: &Vec<Anchor>, anchor_pairs: &Vec<AnchorPair>, max_dist: usize, read_length: usize) {
@@ -396,8 +782,6 @@ pub fn insert_size(a_fwd: Option<&Anchor>, a_rev: Option<&Anchor>, read_length_f
 
 impl PairedAnchorExtractor for StdPairedAnchorExtractor {
     fn generate(&mut self, seeds_fwd: &[Seed], seeds_rev: &[Seed], read_length_fwd: usize, read_length_rev: usize, stats: &mut Stats) -> &mut [AnchorPair] {
-        let _ = stats;
-
         self.groups.clear();
         self.groups_paired.clear();
         self.anchors_fwd.clear();
@@ -410,7 +794,7 @@ impl PairedAnchorExtractor for StdPairedAnchorExtractor {
         
         // eprintln!("Anchors (Fwd -> Rev): {} -> {}", fwd_size, self.groups.len());
 
-        glidesort::sort_by_key(&mut self.groups, |e| (e.reference, e.forward));
+        sort_by_key(&mut self.groups, |e| (e.reference, e.forward), self.sort_impl);
 
         let mut current_idx = 0;
         let mut next_idx;
@@ -419,26 +803,35 @@ impl PairedAnchorExtractor for StdPairedAnchorExtractor {
             return &mut self.anchors
         }
 
-        while current_idx < self.groups.len() - 1 {
+        // The last group has no successor. Only strict pairing visits it, to count the mate it drops.
+        let end = if self.strict_pairing { self.groups.len() } else { self.groups.len() - 1 };
+        while current_idx < end {
             next_idx = current_idx + 1;
             let current = &self.groups[current_idx];
-            let next = &self.groups[next_idx];
+            let next = self.groups.get(next_idx);
 
             // eprintln!("Current {}, Next {}", current, next);
 
             self.anchors_fwd.clear();
             self.anchors_rev.clear();
 
-            if current.reference == next.reference {
+            if let Some(next) = next.filter(|next| current.reference == next.reference) {
                 // process single(
 
                 assert!(!current.forward);
                 assert!(next.forward);
 
-                group_into_anchor_module(seeds_rev, current.start as usize, current.start as usize + current.size as usize, read_length_rev, &mut self.indices, &mut self.other_indices, &mut self.anchors_rev);
-                group_into_anchor_module(seeds_fwd, next.start as usize, next.start as usize + next.size as usize, read_length_fwd, &mut self.indices, &mut self.other_indices, &mut self.anchors_fwd);
+                group_into_anchor_module(seeds_rev, current.start as usize, current.start as usize + current.size as usize, read_length_rev, &mut self.indices, &mut self.other_indices, &mut self.anchors_rev, self.group_seed, self.sort_impl, self.seed_sort_key, self.max_indel);
+                group_into_anchor_module(seeds_fwd, next.start as usize, next.start as usize + next.size as usize, read_length_fwd, &mut self.indices, &mut self.other_indices, &mut self.anchors_fwd, self.group_seed, self.sort_impl, self.seed_sort_key, self.max_indel);
                 
                 if self.anchors_fwd.len() <= 1 && self.anchors_rev.len() <= 1 {
+                    let distant = insert_size(self.anchors_fwd.last(), self.anchors_rev.last(), read_length_fwd, read_length_rev, self.library_type)
+                        .map_or(true, |is| is > self.max_insert_size);
+                    if self.strict_pairing && distant {
+                        stats.mates_unpaired_dropped += 2;
+                        current_idx += 2;
+                        continue
+                    }
                     self.anchors.push(AnchorPair(
                         self.anchors_fwd.pop(),
                         self.anchors_rev.pop(),
@@ -452,22 +845,26 @@ impl PairedAnchorExtractor for StdPairedAnchorExtractor {
                 } else {
                     for a_fwd in &self.anchors_fwd {
                         for a_rev in  &self.anchors_rev {
-                            match insert_size(Some(a_fwd), Some(a_rev), read_length_fwd, read_length_rev) {
-                                Some(is) => if is < 1000 {
+                            // None: the resolved strands do not fit the library type.
+                            if let Some(is) = insert_size(Some(a_fwd), Some(a_rev), read_length_fwd, read_length_rev, self.library_type) {
+                                if is <= self.max_insert_size {
                                     self.anchors.push(AnchorPair(
                                         Some(a_fwd.clone()),
                                         Some(a_rev.clone()),
                                     ));
-                                    self.anchors.last_mut().unwrap().resolve_orientation(read_length_fwd, read_length_rev);
-                                },
-                                None => panic!("This if branch is only entered if anchor has both reads"),
-                            }; 
+                                    self.anchors.last_mut().unwrap().resolve_orientation(read_length_fwd, read_length_rev, self.library_type);
+                                }
+                            }
                         }
                     }
                 }
                 current_idx += 2;
+            } else if self.strict_pairing {
+                // A mate without a partner on this reference, i.e. an orphan or one half of a cross-reference pair.
+                stats.mates_unpaired_dropped += 1;
+                current_idx += 1;
             } else if current.forward {
-                group_into_anchor_module(seeds_fwd, current.start as usize, current.start as usize + current.size as usize, read_length_fwd, &mut self.indices, &mut self.other_indices, &mut self.anchors_fwd);
+                group_into_anchor_module(seeds_fwd, current.start as usize, current.start as usize + current.size as usize, read_length_fwd, &mut self.indices, &mut self.other_indices, &mut self.anchors_fwd, self.group_seed, self.sort_impl, self.seed_sort_key, self.max_indel);
                 
                 while !self.anchors_fwd.is_empty() {
                     self.anchors.push(AnchorPair(
@@ -477,7 +874,7 @@ impl PairedAnchorExtractor for StdPairedAnchorExtractor {
                 }
                 current_idx += 1;
             } else {
-                group_into_anchor_module(seeds_rev, current.start as usize, current.start as usize + current.size as usize, read_length_rev, &mut self.indices, &mut self.other_indices, &mut self.anchors_rev);
+                group_into_anchor_module(seeds_rev, current.start as usize, current.start as usize + current.size as usize, read_length_rev, &mut self.indices, &mut self.other_indices, &mut self.anchors_rev, self.group_seed, self.sort_impl, self.seed_sort_key, self.max_indel);
                 while !self.anchors_rev.is_empty() {
                     self.anchors.push(AnchorPair(
                         None,
@@ -488,17 +885,30 @@ impl PairedAnchorExtractor for StdPairedAnchorExtractor {
             }
         }
 
-        glidesort::sort_by_key(&mut self.anchors, |AnchorPair(a_fwd, a_rev)| {
+        let scorer = &self.scorer;
+        let key = |AnchorPair(a_fwd, a_rev): &AnchorPair| {
             let s1 = match a_fwd {
-                Some(a) => StdAnchorScore::score(a),
+                Some(a) => scorer.score(a),
                 None => 0,
             };
             let s2 = match a_rev {
-                Some(a) => StdAnchorScore::score(a),
+                Some(a) => scorer.score(a),
                 None => 0,
             };
             - (s1 + s2)
-        });
+        };
+
+        // With a cap only the best anchors are selected and sorted. Pairs scoring the same as the last kept one
+        // are kept by reference and position, so the selection does not depend on the generation order.
+        match self.max_anchors.filter(|&max| max < self.anchors.len()) {
+            Some(max_anchors) => {
+                let key = |p: &AnchorPair| (key(p), p.reference(), p.0.as_ref().map(|a| a.seeds[0].rpos), p.1.as_ref().map(|a| a.seeds[0].rpos));
+                self.anchors.select_nth_unstable_by_key(max_anchors - 1, key);
+                self.anchors.truncate(max_anchors);
+                sort_by_key(&mut self.anchors, key, self.sort_impl);
+            },
+            None => sort_by_key(&mut self.anchors, key, self.sort_impl),
+        }
 
 
         &mut self.anchors
@@ -511,4 +921,197 @@ impl PairedAnchorExtractor for StdPairedAnchorExtractor {
     fn retrieve_mut(&mut self) -> &mut [AnchorPair] {
         &mut self.anchors
     }
+
+    fn shrink_scratch(&mut self, max_capacity: usize) {
+        bound_capacity(&mut self.groups, max_capacity);
+        bound_capacity(&mut self.groups_paired, max_capacity);
+        bound_capacity(&mut self.indices, max_capacity);
+        bound_capacity(&mut self.other_indices, max_capacity);
+        bound_capacity(&mut self.anchors, max_capacity);
+        bound_capacity(&mut self.anchors_fwd, max_capacity);
+        bound_capacity(&mut self.anchors_rev, max_capacity);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seed(rval: u64, rpos: u64, qpos: u32) -> Seed {
+        Seed { rpos, rval, qpos, mismatch: 0, length: 20, flag: 0 }
+    }
+
+    #[test]
+    fn strict_pairing_drops_mates_on_different_references() {
+        let seeds_fwd = [seed(0, 1000, 10)];
+        let seeds_rev = [seed(1, 5000, 30)];
+
+        let mut stats = Stats::default();
+        let mut extractor = StdPairedAnchorExtractor::new(StdAnchorScore::default(), LibraryType::Fr);
+        // Only the first of the two groups is a candidate without strict pairing.
+        assert_eq!(extractor.generate(&seeds_fwd, &seeds_rev, 100, 100, &mut stats).len(), 1);
+        assert_eq!(stats.mates_unpaired_dropped, 0);
+
+        let mut extractor = extractor.with_strict_pairing(true);
+        assert!(extractor.generate(&seeds_fwd, &seeds_rev, 100, 100, &mut stats).is_empty());
+        assert_eq!(stats.mates_unpaired_dropped, 2);
+    }
+
+    #[test]
+    fn strict_pairing_keeps_pairs_up_to_the_max_insert_size() {
+        // Mate 1 spans 990..1090, mate 2 ends 90 bases after its seed.
+        let seeds_fwd = [seed(0, 1000, 10)];
+        let mut stats = Stats::default();
+        let mut extractor = StdPairedAnchorExtractor::new(StdAnchorScore::default(), LibraryType::Fr).with_strict_pairing(true);
+        assert_eq!(extractor.generate(&seeds_fwd, &[seed(0, 900 + MAX_INSERT_SIZE as u64, 10)], 100, 100, &mut stats).len(), 1);
+        assert!(extractor.generate(&seeds_fwd, &[seed(0, 901 + MAX_INSERT_SIZE as u64, 10)], 100, 100, &mut stats).is_empty());
+        assert_eq!(stats.mates_unpaired_dropped, 2);
+
+        let mut extractor = extractor.with_max_insert_size(2000);
+        assert_eq!(extractor.generate(&seeds_fwd, &[seed(0, 2900, 10)], 100, 100, &mut stats).len(), 1);
+        assert!(extractor.generate(&seeds_fwd, &[seed(0, 2901, 10)], 100, 100, &mut stats).is_empty());
+    }
+
+    #[test]
+    fn last_unpaired_group_is_only_visited_with_strict_pairing() {
+        let mut stats = Stats::default();
+        let mut extractor = StdPairedAnchorExtractor::new(StdAnchorScore::default(), LibraryType::Fr);
+
+        // Seeds of mate 1 only: a single group, which is also the last.
+        assert!(extractor.generate(&[seed(0, 1000, 10)], &[], 100, 100, &mut stats).is_empty());
+        assert_eq!(stats.mates_unpaired_dropped, 0);
+
+        let mut extractor = extractor.with_strict_pairing(true);
+        assert!(extractor.generate(&[seed(0, 1000, 10)], &[], 100, 100, &mut stats).is_empty());
+        assert_eq!(stats.mates_unpaired_dropped, 1);
+    }
+
+    #[test]
+    fn spurious_first_seed_splits_the_group_only_under_first() {
+        // Three seeds on the forward diagonal 1000 of a 100 base read, preceded by a short seed that lies on the
+        // reverse strand diagonal of the first of them.
+        let seed = |rpos: u64, qpos: u32, length: u8| Seed { rpos, rval: 0, qpos, mismatch: 0, length, flag: 0 };
+        let seeds = [seed(945, 80, 15), seed(1010, 10, 20), seed(1040, 40, 25), seed(1070, 70, 20)];
+
+        let group = |choice: GroupSeed| {
+            let (mut indices, mut other_indices, mut anchors) = (Vec::new(), Vec::new(), Vec::new());
+            group_into_anchor_module(&seeds, 0, seeds.len(), 100, &mut indices, &mut other_indices, &mut anchors, choice, None, SeedSortKey::Rpos, 0);
+            anchors.iter().map(|a| a.seeds.len()).collect::<Vec<_>>()
+        };
+
+        // The spurious seed takes the first seed of the diagonal into its own anchor.
+        let first = group(GroupSeed::First);
+        assert_eq!(first.len(), 2);
+        assert!(first.iter().all(|&len| len < 3));
+        // The longest and the most central seed are both the one at read position 40.
+        assert_eq!(group(GroupSeed::Longest), [3, 1]);
+        assert_eq!(group(GroupSeed::Central), [3, 1]);
+    }
+
+    #[test]
+    fn query_overlapping_seeds_chain_only_on_the_same_diagonal() {
+        let aseed = |qpos: u32, rpos: u64| AnchorSeed { qpos, rpos, length: 20 };
+        let mut chainer = ColinearChainer::default();
+        assert_eq!(chainer.score(&[aseed(0, 1000), aseed(10, 1010)]), 30);
+        // Sharing read bases 10..20 two bases apart on the reference, the seeds are no chain.
+        assert_eq!(chainer.score(&[aseed(0, 1000), aseed(10, 1012)]), 20);
+
+        let seed = |rpos: u64, qpos: u32| Seed { rpos, rval: 0, qpos, mismatch: 0, length: 20, flag: 0 };
+        let mut stats = Stats::default();
+        let mut extractor = ChainingAnchorExtractor::new();
+        let anchors = extractor.generate(&[seed(1000, 0), seed(1012, 10)], 100, &mut stats);
+        assert_eq!(anchors.len(), 1);
+        assert_eq!((anchors[0].seed_count, anchors[0].seeds[0].length), (1, 20));
+
+        let anchors = extractor.generate(&[seed(1000, 0), seed(1010, 10)], 100, &mut stats);
+        assert_eq!(anchors[0].seed_count, 2);
+        assert_eq!((anchors[0].seeds[0].rpos, anchors[0].seeds[0].length), (1000, 30));
+    }
+
+    /// Reference, mates and their seeds of a pair whose mate 1 lacks reference bases 1070..1073. Mate 2 is an
+    /// exact reverse complement further downstream.
+    fn deletion_pair() -> (Vec<u8>, Vec<u8>, Vec<u8>, Vec<Seed>, Vec<Seed>) {
+        use crate::test_utils::{build_db, pseudo_random_bases, reverse_complement, seeds};
+
+        let reference = pseudo_random_bases(3000, 11);
+        let (_, db) = build_db(&reference, &[]);
+
+        let fwd = [&reference[1000..1070], &reference[1073..1153]].concat();
+        let rev = reverse_complement(&reference[1300..1450]);
+        let (seeds_fwd, seeds_rev) = (seeds(&db, &fwd), seeds(&db, &rev));
+        assert!(seeds_fwd.iter().any(|s| s.offset() == 1000) && seeds_fwd.iter().any(|s| s.offset() == 1003));
+        (reference, fwd, rev, seeds_fwd, seeds_rev)
+    }
+
+    #[test]
+    fn palindromic_read_has_no_palindromic_seeds_with_odd_core_mers() {
+        use crate::test_utils::{build_db, pseudo_random_bases, reverse_complement, seeds, C};
+
+        // Bases 1000..1100 are followed by their reverse complement, so a read centered on 1100 equals its own
+        // reverse complement.
+        let mut reference = pseudo_random_bases(3000, 5);
+        let arm = reverse_complement(&reference[1000..1100]);
+        reference[1100..1200].copy_from_slice(&arm);
+        let (_, db) = build_db(&reference, &[]);
+
+        let read = reference[1025..1175].to_vec();
+        assert_eq!(reverse_complement(&read), read);
+        let read_seeds = seeds(&db, &read);
+        assert!(!read_seeds.is_empty());
+        // Only a core-mer of even length can be its own reverse complement.
+        assert_eq!(C % 2, 1);
+        assert!(read_seeds.iter().all(|s| !s.is_palindromic()));
+    }
+
+    #[test]
+    fn anchors_around_a_deletion_are_flagged_and_aligned_with_the_wider_band() {
+        use crate::align::{common::{roundtrip_mismatches, Status}, process::alignment::LIBWFA2Alignment};
+
+        let (reference, fwd, rev, seeds_fwd, seeds_rev) = deletion_pair();
+
+        let mut extractor = StdPairedAnchorExtractor::new(StdAnchorScore::default(), LibraryType::Fr);
+        let pairs = extractor.generate(&seeds_fwd, &seeds_rev, fwd.len(), rev.len(), &mut Stats::default());
+        let mates_fwd = pairs.iter().filter_map(|p| p.0.as_ref()).collect::<Vec<_>>();
+        assert!(mates_fwd.len() >= 2);
+        assert!(mates_fwd.iter().all(|a| a.flagged_for_indel));
+        assert!(pairs.iter().filter_map(|p| p.1.as_ref()).all(|a| !a.flagged_for_indel));
+
+        // The anchor left of the deletion reaches over it only with the wider band; without free ends the read
+        // has to end where its diagonal does, at 1150.
+        let left = mates_fwd.iter().min_by_key(|a| a.seeds[0].qbegin()).unwrap();
+        let mut aligner = LIBWFA2Alignment::default();
+        let mut wide = (*left).clone();
+        assert_eq!(wide.smart_align(&mut aligner, &fwd, &reference, wide.flank_free_ends(20), 0, MiddleGap::Hamming, 100), Status::OK);
+        let cigar = &wide.cigar.as_ref().unwrap().0;
+        assert_eq!(wide.reference_cigar_range, 1000..1153);
+        assert_eq!((cigar.iter().filter(|&&op| op == b'I').count(), cigar.iter().filter(|&&op| op == b'D').count()), (3, 0));
+        assert_eq!(roundtrip_mismatches(&fwd, &reference[wide.reference_cigar_range.clone()], cigar), 0);
+
+        let mut narrow = (*left).clone();
+        assert_eq!(narrow.smart_align(&mut aligner, &fwd, &reference, 0, 0, MiddleGap::Hamming, 100), Status::OK);
+        assert_eq!(narrow.reference_cigar_range.end, 1150);
+        assert!(narrow.score < wide.score);
+    }
+
+    #[test]
+    fn deletion_between_seeds_is_aligned_with_middle_gap_align() {
+        use crate::align::{common::{roundtrip_mismatches, Status}, process::alignment::LIBWFA2Alignment};
+
+        let (reference, fwd, rev, seeds_fwd, seeds_rev) = deletion_pair();
+
+        // Both sides of the deletion end up in one anchor, the gap between them is aligned.
+        let mut extractor = StdPairedAnchorExtractor::new(StdAnchorScore::default(), LibraryType::Fr).with_middle_gap(MiddleGap::Align);
+        let pairs = extractor.generate(&seeds_fwd, &seeds_rev, fwd.len(), rev.len(), &mut Stats::default());
+        let mut a = pairs.iter().filter_map(|p| p.0.clone()).find(|a| a.flagged_for_indel).unwrap();
+        assert!(a.seeds.iter().any(|s| s.offset() != a.seeds[0].offset()));
+
+        let mut aligner = LIBWFA2Alignment::default();
+        let status = a.smart_align(&mut aligner, &fwd, &reference, a.flank_free_ends(20), 0, MiddleGap::Align, 100);
+        assert_eq!(status, Status::OK);
+        let cigar = &a.cigar.as_ref().unwrap().0;
+        assert_eq!(a.reference_cigar_range, 1000..1153);
+        assert_eq!(cigar.iter().filter(|&&op| op == b'I').count(), 3);
+        assert_eq!(roundtrip_mismatches(&fwd, &reference[a.reference_cigar_range.clone()], cigar), 0);
+    }
 }
@@ -1,15 +1,24 @@
 use bioreader::sequence::fastq_record::{OwnedFastqRecord, RefFastqRecord};
 
-use crate::{align::{common::{AnchorPair, PairedAnchorSorter}, data_structures::{get_seed_config, Anchor, AnchorSeedConfig}, stats::Stats}, database::common::FlexalignDatabase};
+use crate::{align::{common::{sort_by_key, AnchorPair, PairedAnchorSorter}, data_structures::{get_seed_config, Anchor, AnchorSeedConfig}, stats::Stats}, database::common::FlexalignDatabase, options::SortImpl};
 
 #[derive(Clone)]
 pub struct PairedAnchorHeuristicSorter<'a, D: FlexalignDatabase> {
     pub db: &'a D,
+    /// Mismatches allowed between seeds merged during extension, see `Anchor::extend_seeds_with_mismatches`
+    pub extend_max_mismatch: usize,
+    /// See `--sort-impl`
+    pub sort_impl: Option<SortImpl>,
 }
 
 impl<'a, D: FlexalignDatabase> PairedAnchorHeuristicSorter<'a, D> {
-    pub fn new(db: &'a D) -> Self {
-        Self { db }
+    pub fn new(db: &'a D, extend_max_mismatch: usize) -> Self {
+        Self { db, extend_max_mismatch, sort_impl: None }
+    }
+
+    pub fn with_sort_impl(mut self, sort_impl: Option<SortImpl>) -> Self {
+        self.sort_impl = sort_impl;
+        self
     }
 
     pub fn fix_anchor(a: &mut Anchor, query: &[u8], query_rc: &[u8], reference: &[u8]) -> () {
@@ -27,14 +36,14 @@ impl<'a, D: FlexalignDatabase> PairedAnchorHeuristicSorter<'a, D> {
                     // This means during the anchor building phase, two seeds must have been merged that actually do not work together.
                     // This can happend for k-mers that appear both as their regular and their reverse complement in a single query.
                     // let any = a.seeds.iter().any(|s| matches!(get_seed_config(s, query, query_rc, reference), ASC::None));
-                    let index = a.seeds.iter().position(|s| matches!(get_seed_config(s, query, query_rc, reference), ASC::None));
-                    match index {
-                        Some(index) => {
-                            let new_seed = a.seeds[index].clone();
-                            a.seeds.clear();
-                            a.seeds.push(new_seed.clone());
-
-                            let config = get_seed_config(&new_seed, query, query_rc, reference);
+                    let found = a.seeds.iter().enumerate()
+                        .map(|(i, s)| (i, get_seed_config(s, query, query_rc, reference)))
+                        .find(|(_, config)| matches!(config, ASC::None));
+                    match found {
+                        Some((index, config)) => {
+                            // Keep only that seed, in place, and reuse the config computed above.
+                            a.seeds.swap(0, index);
+                            a.seeds.truncate(1);
                             a.set_config(&config, query.len());
                         },
                         None => panic!("Nothing correct?"),
@@ -147,7 +156,7 @@ impl<'a, D: FlexalignDatabase> PairedAnchorSorter for PairedAnchorHeuristicSorte
                         a.score = 0i32;
                     } else {
 
-                        a.extend_seeds(query, reference);
+                        a.extend_seeds_with_mismatches(query, reference, self.extend_max_mismatch);
                         a.score = a.core_matches() as i32;                      
                         a.score = (query.len() as u64 - a.hamming(query, reference)) as i32;
                         // eprintln!("Set score {}", a.score);
@@ -155,10 +164,6 @@ impl<'a, D: FlexalignDatabase> PairedAnchorSorter for PairedAnchorHeuristicSorte
                         if !a.seeds.as_slice().windows(2).all(|w: &[crate::align::data_structures::AnchorSeed]| w[0].qend() <= w[1].qbegin() && w[0].rend() <= w[1].rbegin()) {
                             panic!("{}", a);
                         }
-
-                        if a.flagged_for_indel {
-                            eprintln!("Heyu");
-                        }
                     }
                     // eprintln!("{}", query.len());
 
@@ -176,7 +181,7 @@ impl<'a, D: FlexalignDatabase> PairedAnchorSorter for PairedAnchorHeuristicSorte
                     if query.len() == 0 { 
                         a.score = 0i32;
                     } else {
-                        a.extend_seeds(query, reference);
+                        a.extend_seeds_with_mismatches(query, reference, self.extend_max_mismatch);
                         a.score = a.core_matches() as i32;  
                         a.score = (query.len() as u64 - a.hamming(query, reference)) as i32;
 
@@ -187,9 +192,6 @@ impl<'a, D: FlexalignDatabase> PairedAnchorSorter for PairedAnchorHeuristicSorte
                         }
 
                         // eprintln!("Set score {}", a.score);
-                        if a.flagged_for_indel {
-                            eprintln!("Heyu");
-                        }
                     }
                     // eprintln!("{}", query.len());
                     if a.seeds.len() > 1 && a.seeds[0].qbegin() > a.seeds[1].qbegin() {
@@ -246,7 +248,7 @@ impl<'a, D: FlexalignDatabase> PairedAnchorSorter for PairedAnchorHeuristicSorte
             }
         });
 
-        glidesort::sort_by_key(&mut anchors,|AnchorPair(a1, a2)| {
+        sort_by_key(anchors, |AnchorPair(a1, a2)| {
             let s1 = match a1 {
                 Some(a) => a.score,
                 None => 0,
@@ -257,7 +259,7 @@ impl<'a, D: FlexalignDatabase> PairedAnchorSorter for PairedAnchorHeuristicSorte
             };
 
             - ((s1 + s2) as i64)
-        });
+        }, self.sort_impl);
 
 
         anchors.iter().for_each(|AnchorPair(a1, a2)| {
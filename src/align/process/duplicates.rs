@@ -0,0 +1,48 @@
+use std::{collections::HashSet, sync::{Arc, Mutex}};
+
+use crate::align::{common::AnchorPair, data_structures::Anchor};
+
+const SHARDS: usize = 64;
+
+/// 5' end on the reference and strand of a mate. None if the mate is unaligned.
+type MateKey = Option<(u64, bool)>;
+
+/// Marks read pairs whose mates start at the same 5' reference positions on the same strands as an earlier
+/// pair. The set of seen coordinates is shared by all worker threads and sharded by reference to keep lock
+/// contention low.
+///
+/// Reads are not sorted by position, so no coordinate window can be used and every distinct fragment stays in
+/// memory until the run ends (about 50 bytes per pair). With multiple threads, which read of a duplicate set
+/// counts as the first one depends on scheduling.
+#[derive(Clone)]
+pub struct DuplicateMarker {
+    seen: Arc<Vec<Mutex<HashSet<(u64, MateKey, MateKey)>>>>,
+}
+
+impl DuplicateMarker {
+    pub fn new() -> Self {
+        Self {
+            seen: Arc::new((0..SHARDS).map(|_| Mutex::new(HashSet::new())).collect()),
+        }
+    }
+
+    fn mate_key(a: &Option<Anchor>, read_length: usize) -> MateKey {
+        a.as_ref().filter(|a| a.orientation_set).map(|a| {
+            let (start, end) = a.reference_pos(read_length);
+            (if a.forward { start } else { end }, a.forward)
+        })
+    }
+
+    /// Records the primary pair and returns true if a pair with the same coordinates was seen before.
+    pub fn is_duplicate(&self, pair: &AnchorPair, read_length_fwd: usize, read_length_rev: usize) -> bool {
+        let key = (
+            pair.reference(),
+            Self::mate_key(&pair.0, read_length_fwd),
+            Self::mate_key(&pair.1, read_length_rev),
+        );
+        if key.1.is_none() && key.2.is_none() { return false };
+
+        let shard = &self.seen[key.0 as usize % SHARDS];
+        !shard.lock().expect("Cannot lock duplicate set").insert(key)
+    }
+}
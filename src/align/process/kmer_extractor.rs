@@ -1,20 +1,113 @@
-use std::cmp::min;
+use std::{cmp::min, collections::HashSet, fs::read_to_string, path::Path, process::exit, sync::Arc};
 
 use bioreader::sequence::fastq_record::RefFastqRecord;
 use kmerrs::{consecutive::kmer::{Kmer, KmerIter}, minimizer::context_free::Minimizer};
 
-use crate::align::{common::KmerExtractor, stats::Stats};
+use crate::{align::{common::{bound_capacity, KmerExtractor}, stats::Stats}, database::common::is_ambiguous};
+
+/// Normalized dinucleotide entropy of a 2-bit packed C-mer in [0, 1]. Homopolymers score 0, dinucleotide
+/// repeats about 0.26 for C = 15. Reading the dinucleotides in either direction yields the same score.
+pub fn complexity<const C: usize>(cmer: u64) -> f64 {
+    let n = C - 1;
+    let mut counts = [0u8; 16];
+    for i in 0..n {
+        counts[((cmer >> (2 * i)) & 0b1111) as usize] += 1;
+    }
+
+    let entropy = counts.iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / n as f64;
+            - p * p.log2()
+        })
+        .sum::<f64>();
+
+    entropy / (min(n, 16) as f64).log2()
+}
+
+/// Reads with fewer minimizers are never treated as a single repeated k-mer.
+pub const REPEAT_READ_MIN_KMERS: usize = 8;
+
+/// Fraction of the extracted k-mers equal to the most frequent one. Uses a majority vote, so the value is
+/// exact whenever a single k-mer makes up more than half of the read.
+pub fn dominant_kmer_fraction<const K: usize>(kmers: &[(usize, Kmer<K>)]) -> f64 {
+    if kmers.is_empty() { return 0.0 }
+
+    let mut candidate = &kmers[0].1;
+    let mut votes = 0usize;
+    for (_, kmer) in kmers {
+        if votes == 0 {
+            candidate = kmer;
+        }
+        if kmer == candidate { votes += 1 } else { votes -= 1 }
+    }
+
+    kmers.iter().filter(|(_, kmer)| kmer == candidate).count() as f64 / kmers.len() as f64
+}
+
+/// Homopolymer and simple-repeat reads yield one k-mer at almost every position and a single huge range.
+/// A `max_fraction` of 1 or more never marks a read.
+pub fn is_repeat_read<const K: usize>(kmers: &[(usize, Kmer<K>)], max_fraction: f64) -> bool {
+    max_fraction < 1.0 && kmers.len() >= REPEAT_READ_MIN_KMERS && dominant_kmer_fraction(kmers) > max_fraction
+}
+
+/// Reads the canonical core-mer values of a `--kmer-blacklist`, one C-mer or K-mer per line (the core-mer of a
+/// K-mer is taken). Further columns, e.g. counts, and lines starting with `#` are ignored.
+pub fn load_kmer_blacklist<const K: usize, const C: usize>(path: &Path) -> HashSet<u64> {
+    let content = read_to_string(path).unwrap_or_else(|why| {
+        eprintln!("Cannot read --kmer-blacklist {}: {}", path.display(), why);
+        exit(9);
+    });
+
+    let mut blacklist = HashSet::new();
+    for (line_number, line) in content.lines().enumerate() {
+        let Some(kmer) = line.split_whitespace().next().filter(|field| !field.starts_with('#')) else { continue };
+        let seq = kmer.to_ascii_uppercase();
+        let cmer = match seq.len() {
+            len if len == C => KmerIter::<C, true>::new(seq.as_bytes()).next().map(|(_, fwd, rev)| min(fwd, rev).0),
+            len if len == K => KmerIter::<K, true>::new(seq.as_bytes()).next().map(|(_, fwd, rev)| min(fwd.middle::<C>(), rev.middle::<C>()).0),
+            _ => None,
+        };
+        let Some(cmer) = cmer else {
+            eprintln!("--kmer-blacklist line {}: expected a {}-mer or {}-mer of ACGT, got '{}'", line_number + 1, C, K, kmer);
+            exit(9);
+        };
+        blacklist.insert(cmer);
+    }
+    blacklist
+}
 
 #[derive(Clone)]
 pub struct StdKmerExtractor<const K: usize, const C: usize, M: Minimizer + Default> {
     pub kmers: Vec<(usize, Kmer<K>)>,
     pub minimizer: M,
+    pub min_complexity: f64,
+    /// Only every `stride`-th read position is tested for a minimizer (`--kmer-stride`)
+    pub stride: usize,
+    /// Canonical core-mers that are never reported (`--kmer-blacklist`)
+    pub blacklist: Option<Arc<HashSet<u64>>>,
 }
 
 impl<const K: usize, const C: usize, M: Minimizer + Default> 
         Default for StdKmerExtractor<K, C, M> {
     fn default() -> Self {
-        Self { kmers: Vec::new(), minimizer: Default::default() }
+        Self { kmers: Vec::new(), minimizer: Default::default(), min_complexity: 0.0, stride: 1, blacklist: None }
+    }
+}
+
+impl<const K: usize, const C: usize, M: Minimizer + Default> StdKmerExtractor<K, C, M> {
+    pub fn new(min_complexity: f64) -> Self {
+        Self { min_complexity, ..Default::default() }
+    }
+
+    pub fn with_stride(mut self, stride: usize) -> Self {
+        self.stride = stride.max(1);
+        self
+    }
+
+    pub fn with_blacklist(mut self, blacklist: Option<Arc<HashSet<u64>>>) -> Self {
+        self.blacklist = blacklist;
+        self
     }
 }
 
@@ -26,9 +119,19 @@ impl<
     fn generate(&mut self, rec: &RefFastqRecord, stats: &mut Stats) -> &[(usize, Kmer<K>)] {
         let iter = KmerIter::<K, true>::new(rec.seq());
         self.kmers.clear();
+
+        // Windows with ambiguous bases are not indexed, so they cannot seed either. Most reads have none.
+        let check_ambiguous = is_ambiguous(rec.seq());
         for (pos, kmer_fwd, kmer_rev) in iter {
+            // Positions stay those of the read, skipped ones are simply not reported.
+            if pos % self.stride != 0 { continue };
             stats.kmers_processed += 1;
 
+            if check_ambiguous && rec.seq().get(pos..pos + K).is_some_and(is_ambiguous) {
+                stats.kmers_ambiguous += 1;
+                continue;
+            }
+
             let cmer_fwd = kmer_fwd.middle::<C>();
             let cmer_rev = kmer_rev.middle::<C>();
             let kmer = if cmer_fwd < cmer_rev { kmer_fwd } else { kmer_rev };
@@ -44,6 +147,16 @@ impl<
 
             stats.minimizer += 1;
 
+            if self.min_complexity > 0.0 && complexity::<C>(cmer.0) < self.min_complexity {
+                stats.minimizer_low_complexity += 1;
+                continue;
+            }
+
+            if self.blacklist.as_ref().is_some_and(|blacklist| blacklist.contains(&cmer.0)) {
+                stats.minimizer_blacklisted += 1;
+                continue;
+            }
+
             self.kmers.push((pos, kmer));
         }
 
@@ -54,4 +167,96 @@ impl<
     fn retrieve(&self) -> &[(usize, Kmer<K>)] {
         &self.kmers
     }
+
+    fn shrink_scratch(&mut self, max_capacity: usize) {
+        bound_capacity(&mut self.kmers, max_capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use kmerrs::syncmer::closed_syncmer::ClosedSyncmer;
+
+    use super::*;
+
+    const K: usize = 31;
+    const C: usize = 15;
+    const S: usize = 7;
+    const L: usize = C - S + 1;
+
+    #[test]
+    fn blacklisted_kmers_produce_no_seeds() {
+        let seq = b"ACGTTGCAAGGCTTAGCCATGGTACCGATTCGAGCTTAGGCATCGATCGGATCCTAGCTAGGCTTAACGGTACCATGCAGTCGATCGTTAGCAAGTCCGATCGTAGCTAGCTTGACGGATCCATGCA";
+        let qual = vec![b'I'; seq.len()];
+        let rec = RefFastqRecord::new(b"read", seq, b"+", &qual);
+        let mut stats = Stats::default();
+
+        let mut extractor = StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(0.0);
+        let blacklist: HashSet<u64> = extractor.generate(&rec, &mut stats).iter().map(|(_, kmer)| kmer.middle::<C>().0).collect();
+        assert!(!blacklist.is_empty());
+
+        let count = blacklist.len();
+        let mut extractor = extractor.with_blacklist(Some(Arc::new(blacklist)));
+        assert!(extractor.generate(&rec, &mut stats).is_empty());
+        assert!(stats.minimizer_blacklisted >= count);
+    }
+
+    #[test]
+    fn complexity_of_repeats_and_distinct_dinucleotides() {
+        // Independent of the 2-bit code of each base: the score only counts distinct dinucleotides.
+        let pack = |codes: &[u64]| codes.iter().fold(0u64, |cmer, &code| cmer << 2 | code);
+        assert_eq!(complexity::<C>(pack(&[2; C])), 0.0);
+        let dinucleotide = complexity::<C>(pack(&[0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0]));
+        assert!((dinucleotide - 1.0 / 14f64.log2()).abs() < 1e-9);
+        // 14 distinct dinucleotides over 14 positions.
+        assert!((complexity::<C>(pack(&[0, 0, 1, 0, 2, 0, 3, 1, 1, 2, 1, 3, 2, 2, 3])) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn min_complexity_skips_and_counts_minimizers() {
+        let seq = b"ACGTTGCAAGGCTTAGCCATGGTACCGATTCGAGCTTAGGCATCGATCGGATCCTAGCTAGGCTTAACGGTACCATGCAGTCGATCGTTAGCAAGTCCGATCGTAGCTAGCTTGACGGATCCATGCA";
+        let qual = vec![b'I'; seq.len()];
+        let rec = RefFastqRecord::new(b"read", seq, b"+", &qual);
+
+        let mut stats = Stats::default();
+        let mut extractor = StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(0.0);
+        let scores = extractor.generate(&rec, &mut stats).iter().map(|(pos, kmer)| (*pos, complexity::<C>(kmer.middle::<C>().0))).collect::<Vec<_>>();
+        assert!(!scores.is_empty());
+        assert_eq!(stats.minimizer_low_complexity, 0);
+
+        // Minimizers below the threshold are dropped, the others kept in order.
+        let threshold = 0.9;
+        let kept = scores.iter().filter(|(_, score)| *score >= threshold).map(|(pos, _)| *pos).collect::<Vec<_>>();
+        let mut stats = Stats::default();
+        let mut extractor = StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(threshold);
+        assert_eq!(extractor.generate(&rec, &mut stats).iter().map(|(pos, _)| *pos).collect::<Vec<_>>(), kept);
+        assert_eq!(stats.minimizer_low_complexity, scores.len() - kept.len());
+    }
+
+    #[test]
+    fn blacklist_matches_canonical_core_mers() {
+        // A core-mer, its K-mer reverse complement with flanks and a count column all yield one entry.
+        let path = std::env::temp_dir().join(format!("flexalign-blacklist-{}.txt", std::process::id()));
+        fs::write(&path, "# top core-mers\nACGTTGCAAGGCTTA\t1234\nGGGGGGGGTAAGCCTTGCAACGTCCCCCCCC\n").unwrap();
+        let blacklist = load_kmer_blacklist::<K, C>(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(blacklist.len(), 1);
+    }
+
+    #[test]
+    fn repeat_reads_need_a_fraction_below_1() {
+        let kmers = |seq: &[u8]| KmerIter::<K, true>::new(seq).map(|(pos, fwd, rev)| (pos, min(fwd, rev))).collect::<Vec<_>>();
+        let poly_a = kmers(&[b'A'; 100]);
+        let distinct = kmers(b"ACGTTGCAAGGCTTAGCCATGGTACCGATTCGAGCTTAGGCATCGATCGGATCCTAGCTAGGCTTAACGGTACCATGCAGTCGATCGTTAGCAAGTCCGATCG");
+        assert_eq!(dominant_kmer_fraction(&poly_a), 1.0);
+
+        assert!(is_repeat_read(&poly_a, 0.9));
+        assert!(!is_repeat_read(&distinct, 0.9));
+        // The default disables the check, and too few k-mers never count as a repeat.
+        assert!(!is_repeat_read(&poly_a, 1.0));
+        assert!(!is_repeat_read(&poly_a[..REPEAT_READ_MIN_KMERS - 1], 0.9));
+    }
 }
@@ -5,4 +5,5 @@ pub mod anchor_extractor;
 pub mod anchor_sorter;
 pub mod output;
 pub mod evaluate;
-pub mod alignment;
\ No newline at end of file
+pub mod alignment;
+pub mod duplicates;
\ No newline at end of file
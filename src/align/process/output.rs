@@ -1,17 +1,109 @@
-use crate::{align::common::PAFOutput, io::output_buffer::OutputBuffer};
+use std::time::Duration;
+
+use bioreader::sequence::fastq_record::RefFastqRecord;
+
+use crate::{align::{common::{M8Output, PAFOutput, SAMOutput}, data_structures::Anchor, process::alignment::AlignmentWork, sam::{Cigar, CigarRef, ReadGroup}}, io::output_buffer::{OutputBuffer, SplitOutputBuffer}};
 
 
 #[derive(Clone)]
 pub struct StdPAFOutput {
     pub buffer: OutputBuffer,
+    /// Replaces `buffer` with one file per reference (`--split-by-reference`)
+    pub split: Option<SplitOutputBuffer>,
+    /// Writes GAF lines instead of PAF (`--format gaf`)
+    pub gaf: bool,
 }
 
 impl StdPAFOutput {
     pub fn new(buffer: OutputBuffer) -> Self {
         Self {
-            buffer
+            buffer,
+            split: None,
+            gaf: false,
         }
     }
+
+    pub fn with_gaf(mut self, gaf: bool) -> Self {
+        self.gaf = gaf;
+        self
+    }
+
+    pub fn split_by_reference(mut self, split: SplitOutputBuffer) -> Self {
+        self.split = Some(split);
+        self
+    }
+}
+
+/// One PAF record including the trailing newline.
+pub fn paf_line(
+    query_name: &str,
+    query_length: usize,
+    query_start: i32,
+    query_end: i32,
+    fwd: bool,
+    reference_name: &str,
+    reference_length: usize,
+    reference_start: i32,
+    reference_end: i32,
+    residue_matches: u32,
+    alignment_block_length: usize,
+    mapping_quality: u8,
+    tags: &[String],
+) -> String {
+    let mut line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", 
+        query_name, 
+        query_length,
+        query_start,
+        query_end,
+        if fwd { '+' } else { '-' },
+        reference_name,
+        reference_length,
+        reference_start,
+        reference_end,
+        residue_matches,
+        alignment_block_length,
+        mapping_quality);
+    for tag in tags {
+        line.push('\t');
+        line.push_str(tag);
+    }
+    line.push('\n');
+    line
+}
+
+/// One GAF record of a linear reference, a path of the single segment `>reference` or `<reference` on the
+/// reverse strand. Path coordinates and a `cg:Z` cigar are given along the path, i.e. reversed on `<`.
+pub fn gaf_line(
+    query_name: &str,
+    query_length: usize,
+    query_start: i32,
+    query_end: i32,
+    fwd: bool,
+    reference_name: &str,
+    reference_length: usize,
+    reference_start: i32,
+    reference_end: i32,
+    residue_matches: u32,
+    alignment_block_length: usize,
+    mapping_quality: u8,
+    tags: &[String],
+) -> String {
+    let path = format!("{}{}", if fwd { '>' } else { '<' }, reference_name);
+    let (path_start, path_end) = match fwd {
+        true => (reference_start, reference_end),
+        false => (reference_length as i32 - reference_end, reference_length as i32 - reference_start),
+    };
+    let tags = tags.iter()
+        .map(|tag| match tag.strip_prefix("cg:Z:").and_then(Cigar::from_rle_str) {
+            Some(mut cigar) if !fwd => {
+                cigar.0.reverse();
+                format!("cg:Z:{}", cigar.to_rle_string())
+            },
+            _ => tag.clone(),
+        })
+        .collect::<Vec<_>>();
+    paf_line(query_name, query_length, query_start, query_end, true, &path, reference_length, path_start, path_end,
+        residue_matches, alignment_block_length, mapping_quality, &tags)
 }
 
 impl PAFOutput for StdPAFOutput {
@@ -29,20 +121,26 @@ impl PAFOutput for StdPAFOutput {
         residue_matches: u32,
         alignment_block_length: usize,
         mapping_quality: u8,
+        tags: &[String],
     ) {
-        self.buffer.write(format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n", 
-            query_name, 
-            query_length,
-            query_start,
-            query_end,
-            if fwd { '+' } else { '-' },
-            reference_name,
-            reference_length,
-            reference_start,
-            reference_end,
-            residue_matches,
-            alignment_block_length,
-            mapping_quality));
+        let line = match self.gaf {
+            true => gaf_line(query_name, query_length, query_start, query_end, fwd, reference_name, reference_length,
+                reference_start, reference_end, residue_matches, alignment_block_length, mapping_quality, tags),
+            false => paf_line(query_name, query_length, query_start, query_end, fwd, reference_name, reference_length,
+                reference_start, reference_end, residue_matches, alignment_block_length, mapping_quality, tags),
+        };
+        match self.split.as_mut() {
+            Some(split) => split.write(reference_name, line),
+            None => self.buffer.write(line),
+        }
+    }
+
+    fn write_unmapped(&mut self, query_name: &str, query_length: usize) {
+        let line = format!("{}\t{}\t0\t0\t*\t*\t0\t0\t0\t0\t0\t0\n", query_name, query_length);
+        match self.split.as_mut() {
+            Some(split) => split.write("*", line),
+            None => self.buffer.write(line),
+        }
     }
 }
 
@@ -50,9 +148,73 @@ impl PAFOutput for StdPAFOutput {
 #[derive(Clone)]
 pub struct StdSAMOutput {
     pub buffer: OutputBuffer,
+    pub read_group_tag: Option<String>,
+    /// Replaces `buffer` with one file per reference (`--split-by-reference`)
+    pub split: Option<SplitOutputBuffer>,
 }
 
 impl StdSAMOutput {
+    pub fn new(buffer: OutputBuffer, read_group: Option<&ReadGroup>) -> Self {
+        Self {
+            buffer,
+            read_group_tag: read_group.map(|rg| rg.tag()),
+            split: None,
+        }
+    }
+
+    pub fn split_by_reference(mut self, split: SplitOutputBuffer) -> Self {
+        self.split = Some(split);
+        self
+    }
+}
+
+impl SAMOutput for StdSAMOutput {
+    fn write(
+        &mut self,
+        query_name: &str,
+        flag: u16,
+        reference_name: &str,
+        position: usize,
+        mapping_quality: u8,
+        cigar: CigarRef,
+        mate_reference_name: &str,
+        mate_position: usize,
+        template_length: i64,
+        seq: &[u8],
+        qual: &[u8],
+        tags: &[String],
+    ) {
+        let mut line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            query_name.split(' ').next().unwrap(),
+            flag,
+            reference_name,
+            position,
+            mapping_quality,
+            cigar.to_rle_string(),
+            mate_reference_name,
+            mate_position,
+            template_length,
+            if seq.is_empty() { "*".into() } else { String::from_utf8_lossy(seq) },
+            if qual.is_empty() { "*".into() } else { String::from_utf8_lossy(qual) });
+        for tag in tags.iter().chain(self.read_group_tag.iter()) {
+            line.push('\t');
+            line.push_str(tag);
+        }
+        line.push('\n');
+        match self.split.as_mut() {
+            Some(split) => split.write(reference_name, line),
+            None => self.buffer.write(line),
+        }
+    }
+}
+
+
+#[derive(Clone)]
+pub struct StdM8Output {
+    pub buffer: OutputBuffer,
+}
+
+impl StdM8Output {
     pub fn new(buffer: OutputBuffer) -> Self {
         Self {
             buffer
@@ -60,3 +222,169 @@ impl StdSAMOutput {
     }
 }
 
+impl M8Output for StdM8Output {
+    fn write(
+        &mut self,
+        query_name: &str,
+        subject_name: &str,
+        identity: f64,
+        alignment_length: usize,
+        mismatches: usize,
+        gap_opens: usize,
+        query_start: usize,
+        query_end: usize,
+        subject_start: usize,
+        subject_end: usize,
+        evalue: f64,
+        bitscore: f64,
+    ) {
+        self.buffer.write(format!("{}\t{}\t{:.3}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.2e}\t{:.1}\n",
+            query_name.split(' ').next().unwrap(),
+            subject_name,
+            identity,
+            alignment_length,
+            mismatches,
+            gap_opens,
+            query_start,
+            query_end,
+            subject_start,
+            subject_end,
+            evalue,
+            bitscore));
+    }
+}
+
+
+/// Reads left unmapped, written back unchanged as FASTQ (`--unmapped-fastq`). Paired-end runs keep one
+/// output per mate so the files stay in sync.
+#[derive(Clone)]
+pub struct UnmappedFastqOutput {
+    pub buffer_fwd: OutputBuffer,
+    pub buffer_rev: Option<OutputBuffer>,
+}
+
+impl UnmappedFastqOutput {
+    pub fn new(buffer_fwd: OutputBuffer, buffer_rev: Option<OutputBuffer>) -> Self {
+        Self {
+            buffer_fwd,
+            buffer_rev,
+        }
+    }
+
+    pub fn write_single(&mut self, rec: &RefFastqRecord) {
+        self.buffer_fwd.write(fastq_record(rec));
+    }
+
+    pub fn write_pair(&mut self, rec_fwd: &RefFastqRecord, rec_rev: &RefFastqRecord) {
+        self.buffer_fwd.write(fastq_record(rec_fwd));
+        self.buffer_rev.as_mut().expect("No output for unmapped second mates").write(fastq_record(rec_rev));
+    }
+}
+
+/// Column names of `AnchorDumpOutput`, written once at the top of the file.
+pub const ANCHOR_DUMP_HEADER: &str = "read\tmate\trank\treference\tstrand\torientation_set\tscore\tseed_count\tseeds\n";
+
+/// Candidate anchors of every read, one TSV row per anchor (`--dump-anchors`).
+#[derive(Clone)]
+pub struct AnchorDumpOutput {
+    pub buffer: OutputBuffer,
+}
+
+impl AnchorDumpOutput {
+    pub fn new(buffer: OutputBuffer) -> Self {
+        Self { buffer }
+    }
+
+    /// `mate` is 0 for single-end reads. The strand is `.` while the orientation is not set. Seeds are in
+    /// the stored orientation, i.e. query positions refer to the reverse complement on the reverse strand.
+    /// `score` is the anchor score the candidates were ranked by.
+    pub fn write(&mut self, read_name: &[u8], mate: u8, rank: usize, reference_name: &str, score: i32, a: &Anchor) {
+        let strand = match (a.orientation_set, a.forward) {
+            (false, _) => '.',
+            (true, true) => '+',
+            (true, false) => '-',
+        };
+        let seeds = a.seeds.iter()
+            .map(|s| format!("{}:{}:{}", s.qpos, s.rpos, s.length))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.buffer.write(format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            String::from_utf8_lossy(read_name).split(' ').next().unwrap(),
+            mate,
+            rank,
+            reference_name,
+            strand,
+            a.orientation_set,
+            score,
+            a.seed_count,
+            seeds));
+    }
+}
+
+/// Column names of `AlignmentWorkOutput`, written once at the top of the file.
+pub const ALIGNMENT_WORK_HEADER: &str = "read\tcalls\tsteps\tbases\ttime_us\n";
+
+/// Aligner work and wall time of every read pair, one TSV row per pair (`--dump-alignment-work`).
+#[derive(Clone)]
+pub struct AlignmentWorkOutput {
+    pub buffer: OutputBuffer,
+}
+
+impl AlignmentWorkOutput {
+    pub fn new(buffer: OutputBuffer) -> Self {
+        Self { buffer }
+    }
+
+    pub fn write(&mut self, read_name: &[u8], work: AlignmentWork, time: Duration) {
+        self.buffer.write(format!("{}\t{}\t{}\t{}\t{}\n",
+            String::from_utf8_lossy(read_name).split(' ').next().unwrap(),
+            work.calls,
+            work.steps,
+            work.bases,
+            time.as_micros()));
+    }
+}
+
+/// Column names of `ScoreOutput`, written once at the top of the file.
+pub const SCORE_HEADER: &str = "read\tmate\tscore\tidentity\n";
+
+/// Score and identity of the reported alignment of every read (`--score-only`). Unmapped reads are written
+/// with `*` in both columns.
+#[derive(Clone)]
+pub struct ScoreOutput {
+    pub buffer: OutputBuffer,
+    /// (score, identity) of each mate of the current read, set by the workflow
+    pub current: [Option<(i32, f64)>; 2],
+}
+
+impl ScoreOutput {
+    pub fn new(buffer: OutputBuffer) -> Self {
+        Self { buffer, current: [None; 2] }
+    }
+
+    /// `mate` is 0 for single-end reads and read 1, 1 for read 2.
+    pub fn set(&mut self, mate: usize, score: i32, identity: f64) {
+        self.current[mate] = Some((score, identity));
+    }
+
+    /// Writes the rows of the current read and clears it. Mates are numbered 1 and 2, single-end reads 0.
+    pub fn finish(&mut self, read_name: &[u8], paired: bool) {
+        let name = String::from_utf8_lossy(read_name);
+        let name = name.split(' ').next().unwrap();
+        let mates: &[(usize, u8)] = if paired { &[(0, 1), (1, 2)] } else { &[(0, 0)] };
+        for &(index, mate) in mates {
+            let (score, identity) = match self.current[index].take() {
+                Some((score, identity)) => (score.to_string(), format!("{:.4}", identity)),
+                None => ("*".to_string(), "*".to_string()),
+            };
+            self.buffer.write(format!("{}\t{}\t{}\t{}\n", name, mate, score, identity));
+        }
+    }
+}
+
+fn fastq_record(rec: &RefFastqRecord) -> String {
+    format!("@{}\n{}\n+\n{}\n",
+        String::from_utf8_lossy(rec.head()),
+        String::from_utf8_lossy(rec.seq()),
+        String::from_utf8_lossy(rec.qual()))
+}
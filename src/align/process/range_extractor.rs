@@ -1,20 +1,28 @@
 use flexmap::values::VRange;
 use kmerrs::consecutive::kmer::Kmer;
 
-use crate::{align::{common::RangeExtractor, stats::Stats}, database::common::FlexalignDatabase, flexalign::time};
+use crate::{align::{common::{bound_capacity, sort_unstable_by_key, RangeExtractor}, stats::Stats}, database::common::FlexalignDatabase, flexalign::time, options::SortImpl};
 
-pub type Range<'a, const F: usize> = (usize, Kmer<F>, VRange<'a>, usize);
+/// Query position, flanks, range, range size and whether the core-mer is its own reverse complement (even C only).
+pub type Range<'a, const F: usize> = (usize, Kmer<F>, VRange<'a>, usize, bool);
 
 #[derive(Clone)]
 pub struct StdRangeExtractor<'a, const K: usize, const C: usize, const F: usize, D: FlexalignDatabase> {
     pub ranges: Vec<Range<'a, F>>,
     pub db: &'a D,
+    pub max_range_size: usize,
+    /// See `--sort-impl`
+    pub sort_impl: Option<SortImpl>,
 }
 
 impl<'a, const K: usize, const C: usize, const F: usize, D: FlexalignDatabase> RangeExtractor<K, F> for StdRangeExtractor<'a, K, C, F, D> {
     fn retrieve(&self) -> &[Range<F>] {
         &self.ranges
     }
+
+    fn shrink_scratch(&mut self, max_capacity: usize) {
+        bound_capacity(&mut self.ranges, max_capacity);
+    }
     
     fn generate(&mut self, kmers: &[(usize, Kmer<K>)], stats: &mut Stats) -> &[Range<F>] {
         self.ranges.clear();
@@ -34,9 +42,11 @@ impl<'a, const K: usize, const C: usize, const F: usize, D: FlexalignDatabase> R
                 None => continue,
             };
             let range_len = (&range).positions.len();
-            self.ranges.push((*pos, fmer, range, range_len));
+            if range_len > self.max_range_size { continue };
+            // An odd-length core-mer is never its own reverse complement: its middle base would have to be its own complement.
+            self.ranges.push((*pos, fmer, range, range_len, C % 2 == 0 && cmer.is_own_rc()));
         }
-        self.ranges.sort_unstable_by_key(|r| r.2.positions.len());
+        sort_unstable_by_key(&mut self.ranges, |r| r.2.positions.len(), self.sort_impl);
         
         // let (duration, _) = time(|| self.ranges.sort_unstable_by_key(|r| r.2.positions.len()));
         // stats.time_range_sorting += duration;
@@ -47,8 +57,13 @@ impl<'a, const K: usize, const C: usize, const F: usize, D: FlexalignDatabase> R
 }
 
 impl<'a, const K: usize, const C: usize, const F: usize, D: FlexalignDatabase> StdRangeExtractor<'a, K, C, F, D> {
-    pub fn new(db: &'a D) -> Self {
-        Self { ranges: Vec::new(), db }
+    pub fn new(db: &'a D, max_range_size: usize) -> Self {
+        Self { ranges: Vec::new(), db, max_range_size, sort_impl: None }
+    }
+
+    pub fn with_sort_impl(mut self, sort_impl: Option<SortImpl>) -> Self {
+        self.sort_impl = sort_impl;
+        self
     }
 }
 
@@ -1,28 +1,48 @@
 
 use flexmap::VD;
 
-use crate::{align::{common::SeedExtractor, data_structures::Seed, stats::Stats}, flexalign::time};
+use crate::{align::{common::{bound_capacity, sort_by_key, SeedExtractor}, data_structures::Seed, stats::Stats}, flexalign::time, options::{SeedSortKey, SortImpl}};
 
 use super::range_extractor::Range;
 
+/// --max-best-flex used for a second lookup when fewer than --min-ranges ranges yielded seeds.
+pub const RECOVERY_MAX_BEST_FLEX: usize = 128;
+
 #[derive(Clone)]
 pub struct StdSeedExtractor<const K: usize, const C: usize, const F: usize> {
     pub seeds: Vec<Seed>,
     pub max_best_flex: usize,
     pub max_ranges: usize,
     pub min_ranges: usize,
+    pub min_seed_length: usize,
+    pub sort_key: SeedSortKey,
+    /// See `--sort-impl`
+    pub sort_impl: Option<SortImpl>,
 }
 
 impl<const K: usize, const C: usize, const F: usize> StdSeedExtractor<K, C, F> {
-    pub fn new(max_best_flex: usize, max_ranges: usize, min_ranges: usize) -> Self {
+    pub fn new(max_best_flex: usize, max_ranges: usize, min_ranges: usize, min_seed_length: usize) -> Self {
         Self {
             seeds: Vec::new(),
             max_best_flex,
             max_ranges,
             min_ranges,
+            min_seed_length,
+            sort_key: SeedSortKey::Rpos,
+            sort_impl: None,
         }
     }
 
+    pub fn with_sort_key(mut self, sort_key: SeedSortKey) -> Self {
+        self.sort_key = sort_key;
+        self
+    }
+
+    pub fn with_sort_impl(mut self, sort_impl: Option<SortImpl>) -> Self {
+        self.sort_impl = sort_impl;
+        self
+    }
+
     pub fn retrieve_seeds(
         &mut self,
         ranges: &[Range<F>],
@@ -32,7 +52,7 @@ impl<const K: usize, const C: usize, const F: usize> StdSeedExtractor<K, C, F> {
 
         let mut matches = 0;
         let mut discarded_max_flex_count = 0;
-        for (qpos, flex, range, _range_size) in ranges {
+        for (qpos, flex, range, _range_size, palindromic) in ranges {
             match range.header {
                 Some(headers) => {
                     let mut min_dist = u32::MAX;
@@ -61,7 +81,7 @@ impl<const K: usize, const C: usize, const F: usize> StdSeedExtractor<K, C, F> {
                         let dist = header.dist(flex.0 as u32);
                         if dist == min_dist {    
                             let (value, rpos) = VD::get(range.positions[index].0);
-                            self.seeds.push(Seed::from_flexmer::<K,C,F>(*qpos, rpos, value, dist));
+                            self.seeds.push(Seed::from_flexmer::<K,C,F>(*qpos, rpos, value, dist).with_palindromic(*palindromic));
                         }
                     }
                 },
@@ -69,7 +89,7 @@ impl<const K: usize, const C: usize, const F: usize> StdSeedExtractor<K, C, F> {
                     for cell in range.positions {
                         // self.seeds.push((*pos, cell.clone()));
                         let (value, rpos) = VD::get(cell.0);
-                        self.seeds.push(Seed::from_coremer::<K,C,F>(*qpos, rpos, value));
+                        self.seeds.push(Seed::from_coremer::<K,C,F>(*qpos, rpos, value).with_palindromic(*palindromic));
                     }
                 },
             };
@@ -82,6 +102,32 @@ impl<const K: usize, const C: usize, const F: usize> StdSeedExtractor<K, C, F> {
 }
 
 
+/// Seed extraction for both mates of a pair. Each mate keeps its own seed buffer, as the seeds of read 1 are still
+/// borrowed while read 2 is seeded, and its own configuration (read 2 takes the `--rev-*` seeding options).
+#[derive(Clone)]
+pub struct PairedSeedExtractor<SE> {
+    pub read1: SE,
+    pub read2: SE,
+}
+
+impl<SE> PairedSeedExtractor<SE> {
+    pub fn new(read1: SE, read2: SE) -> Self {
+        Self { read1, read2 }
+    }
+
+    /// Seeds of read 1 and read 2 from their ranges.
+    pub fn generate<const F: usize>(&mut self, ranges_read1: &[Range<F>], ranges_read2: &[Range<F>], stats: &mut Stats) -> (&[Seed], &[Seed])
+            where SE: SeedExtractor<F> {
+        (self.read1.generate(ranges_read1, stats), self.read2.generate(ranges_read2, stats))
+    }
+
+    pub fn shrink_scratch<const F: usize>(&mut self, max_capacity: usize) where SE: SeedExtractor<F> {
+        self.read1.shrink_scratch(max_capacity);
+        self.read2.shrink_scratch(max_capacity);
+    }
+}
+
+
 impl<const K: usize, const C: usize, const F: usize> SeedExtractor<F> for StdSeedExtractor<K, C, F> {
     fn generate(&mut self, ranges: &[Range<F>], stats: &mut crate::align::stats::Stats) -> &[Seed] {
         self.seeds.clear();
@@ -98,21 +144,29 @@ impl<const K: usize, const C: usize, const F: usize> SeedExtractor<F> for StdSee
             let old_ranges = ranges;
             let (ranges, discarded_max_flex_count) = self.retrieve_seeds(
                 ranges,
-                128,
+                RECOVERY_MAX_BEST_FLEX,
                 self.max_ranges,
                 stats
             );
             // eprintln!("{} -> {} (Still discarded: {})", old_ranges, ranges, discarded_max_flex_count);
         }
         
+        // Exact flexmer hits have length K, coremer and inexact flexmer hits length C.
+        if self.min_seed_length > 0 {
+            let min_seed_length = self.min_seed_length;
+            self.seeds.retain(|seed| seed.length as usize >= min_seed_length);
+        }
+
         // stats.time_range_header += duration;
         stats.seeds += self.seeds.len();
 
 
+        // Seeds of a reference stay contiguous with either key, anchors are built from rpos-sorted members.
         let (duration, _) = time(|| {
-            glidesort::sort_by_key(&mut self.seeds, |seed: &Seed| {
-                (seed.rval, seed.rpos)//, seed.length) // seed.offset
-            });
+            match self.sort_key {
+                SeedSortKey::Rpos => sort_by_key(&mut self.seeds, |seed: &Seed| (seed.rval, seed.rpos), self.sort_impl),
+                SeedSortKey::Offset => sort_by_key(&mut self.seeds, |seed: &Seed| (seed.rval, seed.rpos as i64 - seed.qpos as i64, seed.rpos), self.sort_impl),
+            }
         });
         stats.time_seed_sorting += duration;
 
@@ -122,4 +176,8 @@ impl<const K: usize, const C: usize, const F: usize> SeedExtractor<F> for StdSee
     fn retrieve(&self) -> &[Seed] {
         &self.seeds
     }
+
+    fn shrink_scratch(&mut self, max_capacity: usize) {
+        bound_capacity(&mut self.seeds, max_capacity);
+    }
 }
\ No newline at end of file
@@ -1,26 +1,31 @@
-use std::{fs::File, io::{self}, sync::{Arc, Mutex}};
+use std::{collections::HashSet, fs::File, io::{self, Cursor, Read, Write}, path::Path, process::exit, sync::{Arc, Mutex}, time::Instant};
 
-use bioreader::{parallel::fastq::{read_fastq_paired_end_state_par, read_fastq_single_end_state_par}, sequence::fastq_record::{OwnedFastqRecord, RefFastqRecord}, utils::is_gzip};
+use bioreader::{parallel::fastq::{read_fastq_paired_end_state_par, Merge, read_fastq_single_end_state_par}, sequence::fastq_record::{OwnedFastqRecord, RefFastqRecord}, utils::is_gzip};
 use flate2::read::GzDecoder;
+use gzp::{deflate::Bgzf, par::decompress::ParDecompressBuilder};
 use kmerrs::syncmer::closed_syncmer::ClosedSyncmer;
 use log::info;
 
 use crate::{
     align::{
-        common::{NoSAMOutput, Or},
+        common::{Or, StdAnchorScore},
+        data_structures::{Alignment, MAX_READ_LENGTH},
+        errors::AlignmentError,
         modular_workflow::{Modular, ModularPE}, 
         process::{
-            alignment::LIBWFA2Alignment, anchor_extractor::{StdAnchorExtractor, StdPairedAnchorExtractor}, 
+            alignment::{LIBWFA2Alignment, MinAni}, duplicates::DuplicateMarker, anchor_extractor::{ColinearChainer, SelectedAnchorExtractor, StdPairedAnchorExtractor}, 
             anchor_sorter::PairedAnchorHeuristicSorter, 
-            kmer_extractor::StdKmerExtractor, 
-            output::StdPAFOutput, 
+            kmer_extractor::{load_kmer_blacklist, StdKmerExtractor}, 
+            output::{AlignmentWorkOutput, AnchorDumpOutput, ScoreOutput, StdM8Output, StdPAFOutput, StdSAMOutput, UnmappedFastqOutput, ALIGNMENT_WORK_HEADER, ANCHOR_DUMP_HEADER, SCORE_HEADER}, 
             range_extractor::StdRangeExtractor, 
-            seed_extractor::StdSeedExtractor
+            seed_extractor::{PairedSeedExtractor, StdSeedExtractor}
         }, 
+        pileup::{Pileup, PileupBuffer},
+        sam::{sam_header, ReadGroup},
         stats::Stats, 
         workflow
     }, 
-    database::common::FlexalignDatabase, io::output_buffer::{OutputBuffer, OutputTarget}, options::Options};
+    database::common::FlexalignDatabase, flexalign::shutdown_requested, io::output_buffer::{OutputBuffer, OutputTarget, SplitOutputBuffer, SplitTarget, MAX_OPEN_SPLIT_FILES}, options::{OutputFormat, Options}, utils::is_bgzf};
 
 
 pub fn process_fastq_wrapper<
@@ -41,15 +46,17 @@ pub fn process_fastq_wrapper<
         };
 
         
+        let output_threshold = if options.args.flush_per_read { 0 } else { 2usize.pow(24) };
         let stdout_writer = Arc::new(Mutex::new(OutputTarget::Stdout(io::stdout())));
-        let stdout_buffer_fwd = OutputBuffer::new(Arc::clone(&stdout_writer), 2usize.pow(24));
+        let stdout_buffer_fwd = OutputBuffer::new(Arc::clone(&stdout_writer), output_threshold);
 
         let mut handler_fwd: workflow::Standard<K, C, F, S, L, HEADER_THRESHOLD, ClosedSyncmer<C, S, L>, FM> = 
             workflow::Standard::new(&db, ClosedSyncmer::<C,S,L>::new(), &options, stdout_buffer_fwd);
 
         let fwd_gzip = is_gzip(fwd).expect(format!("Cannot check if file is gzipped. Check file: {}", fwd.to_str().unwrap()).as_str());
 
-        let stats;
+        let mut stats;
+        let start = Instant::now();
 
         // Distinguish between single- and paired-end reads
         match rev_option {
@@ -67,7 +74,7 @@ pub fn process_fastq_wrapper<
                     panic!("Reads must either both be compressed (.gz) or uncompressed.")
                 };
 
-                let stdout_buffer_rev = OutputBuffer::new(Arc::clone(&stdout_writer), 2usize.pow(24));
+                let stdout_buffer_rev = OutputBuffer::new(Arc::clone(&stdout_writer), output_threshold);
                 let mut handler_rev: workflow::Standard<K, C, F, S, L, HEADER_THRESHOLD, ClosedSyncmer<C, S, L>, FM> = 
                     workflow::Standard::new(&db, ClosedSyncmer::<C,S,L>::new(), &options, stdout_buffer_rev);
 
@@ -79,9 +86,9 @@ pub fn process_fastq_wrapper<
                 
                 if fwd_gzip {
                     stats = read_fastq_paired_end_state_par(
-                        GzDecoder::new(file_fwd),
-                        GzDecoder::new(file_rev),
-                        usize::pow(2, 24),
+                        gz_reader(fwd, file_fwd, options.args.threads_io),
+                        gz_reader(rev, file_rev, options.args.threads_io),
+                        options.args.batch_size,
                         options.args.threads,
                         worker,
                     );
@@ -89,7 +96,7 @@ pub fn process_fastq_wrapper<
                     stats = read_fastq_paired_end_state_par(
                         file_fwd,
                         file_rev,
-                        usize::pow(2, 24),
+                        options.args.batch_size,
                         options.args.threads,
                         worker,
                     );
@@ -104,15 +111,15 @@ pub fn process_fastq_wrapper<
 
                 if fwd_gzip {
                     stats = read_fastq_single_end_state_par(
-                        GzDecoder::new(file_fwd),
-                        usize::pow(2, 24),
+                        gz_reader(fwd, file_fwd, options.args.threads_io),
+                        options.args.batch_size,
                         options.args.threads,
                         worker,
                     );
                 } else {
                     stats = read_fastq_single_end_state_par(
                         file_fwd,
-                        usize::pow(2, 24),
+                        options.args.batch_size,
                         options.args.threads,
                         worker,
                     );
@@ -120,6 +127,7 @@ pub fn process_fastq_wrapper<
             },
         }
 
+        stats.as_mut().unwrap().wall_time = Some(start.elapsed());
         eprintln!("{}", stats.as_ref().unwrap());
         // stats.as_ref().unwrap().plot_mapq();
         // dbg!(stats);
@@ -138,7 +146,91 @@ pub fn process_fastq_wrapper_modular<
         const L: usize,
         const HEADER_THRESHOLD: usize,
         FM: FlexalignDatabase + Clone + Sync + Send,
-    >(options: &Options, db: &FM) {
+    >(options: &Options, db: &FM) -> Stats {
+
+    let read_group = ReadGroup::from_options(options);
+
+    // Shared by all inputs, so the per-reference files collect the reads of every input.
+    let split_target = options.args.split_by_reference.as_ref().map(|dir| {
+        let target = match options.args.format {
+            OutputFormat::Paf => SplitTarget::new(dir, "paf", None, MAX_OPEN_SPLIT_FILES),
+            OutputFormat::Gaf => SplitTarget::new(dir, "gaf", None, MAX_OPEN_SPLIT_FILES),
+            OutputFormat::Sam => SplitTarget::new(dir, "sam", Some(sam_header(db, read_group.as_ref())), MAX_OPEN_SPLIT_FILES),
+            OutputFormat::M8 => {
+                eprintln!("--split-by-reference supports PAF, GAF and SAM output only");
+                exit(9);
+            },
+        };
+        Arc::new(Mutex::new(target))
+    });
+
+    let blacklist = options.args.kmer_blacklist.as_ref().map(|path| Arc::new(load_kmer_blacklist::<K, C>(path)));
+
+    let min_ani = match &options.args.min_ani_file {
+        Some(path) => MinAni::from_file(db, options.args.min_ani, path),
+        None => MinAni::new(options.args.min_ani),
+    };
+
+    let pileup = options.args.pileup.as_ref().map(|_| Arc::new(Mutex::new(Pileup::default())));
+
+    let force_reference = options.args.force_reference.as_ref().map(|name| match db.get_rid(name) {
+        Some(&rid) => rid as u64,
+        None => {
+            eprintln!("Reference {} of --force-reference is not in the index.", name);
+            exit(9);
+        },
+    });
+
+    let priority_references: Vec<u64> = options.args.priority_ref.iter().map(|name| match db.get_rid(name) {
+        Some(&rid) => rid as u64,
+        None => {
+            eprintln!("Reference {} of --priority-ref is not in the index.", name);
+            exit(9);
+        },
+    }).collect();
+
+    // Also shared by all inputs: one file for single-end inputs and one per mate for paired-end inputs.
+    let unmapped_targets = options.args.unmapped_fastq.as_ref().map(|prefix| {
+        let create = |suffix: &str| {
+            let path = format!("{}{}", prefix, suffix);
+            let file = File::create(&path).unwrap_or_else(|why| {
+                eprintln!("Cannot create unmapped FASTQ file {}: {}", path, why);
+                exit(9);
+            });
+            Arc::new(Mutex::new(OutputTarget::File(file)))
+        };
+        let single = options.rev.iter().any(Option::is_none).then(|| create(".fq"));
+        let paired = options.rev.iter().any(Option::is_some).then(|| (create("_1.fq"), create("_2.fq")));
+        (single, paired)
+    });
+    let dump_target = options.args.dump_anchors.as_ref().map(|path| {
+        let mut file = File::create(path).unwrap_or_else(|why| {
+            eprintln!("Cannot create anchor dump {}: {}", path.display(), why);
+            exit(9);
+        });
+        let _ = file.write_all(ANCHOR_DUMP_HEADER.as_bytes());
+        Arc::new(Mutex::new(OutputTarget::File(file)))
+    });
+    let score_target = options.args.score_only.as_ref().map(|path| {
+        let mut file = File::create(path).unwrap_or_else(|why| {
+            eprintln!("Cannot create score output {}: {}", path.display(), why);
+            exit(9);
+        });
+        let _ = file.write_all(SCORE_HEADER.as_bytes());
+        Arc::new(Mutex::new(OutputTarget::File(file)))
+    });
+    let work_target = options.args.dump_alignment_work.as_ref().map(|path| {
+        let mut file = File::create(path).unwrap_or_else(|why| {
+            eprintln!("Cannot create alignment work dump {}: {}", path.display(), why);
+            exit(9);
+        });
+        let _ = file.write_all(ALIGNMENT_WORK_HEADER.as_bytes());
+        Arc::new(Mutex::new(OutputTarget::File(file)))
+    });
+    let reference_kmers_ambiguous = db.ambiguous_kmers();
+
+    // Counts summed over all inputs, returned for --benchmark.
+    let mut total = Stats::default();
 
     for (index, (fwd, rev_option)) in options.fwd.iter().zip(options.rev.iter()).enumerate() {
 
@@ -150,42 +242,80 @@ pub fn process_fastq_wrapper_modular<
         eprintln!("Process: {:?} {:?}", fwd, rev_option);
 
 
-        let out_buffer = if options.output_prefix.is_some() {
+        let writer = if options.output_prefix.is_some() {
             let path: &std::path::PathBuf = options.output_prefix.as_ref().unwrap().get(index).expect(&format!("There is no output for input {:?}", fwd));
-            let file_writer = Arc::new(Mutex::new(OutputTarget::File(File::create(path).expect(&format!("Cannot open output file {:?}", path)))));
-            OutputBuffer::new(Arc::clone(&file_writer), 2usize.pow(24))
+            Arc::new(Mutex::new(OutputTarget::File(File::create(path).expect(&format!("Cannot open output file {:?}", path)))))
         } else {
-            let stdout_writer = Arc::new(Mutex::new(OutputTarget::Stdout(io::stdout())));
-            OutputBuffer::new(Arc::clone(&stdout_writer), 2usize.pow(24))
+            Arc::new(Mutex::new(OutputTarget::Stdout(io::stdout())))
         };
 
-        // let output: StdPAFOutput = StdPAFOutput::new(stdout_buffer);
-        let output: Or<StdPAFOutput, NoSAMOutput> = Or::<StdPAFOutput, NoSAMOutput> {
-            a: Some(StdPAFOutput::new(out_buffer)),
-            b: None,
+        // The header goes straight to the writer, the buffer is cloned into every worker.
+        if options.args.format == OutputFormat::Sam && split_target.is_none() && score_target.is_none() {
+            let _ = writer.lock().expect("Cannot lock writer").write_all(sam_header(db, read_group.as_ref()).as_bytes());
+        }
+        // --flush-per-read writes every record through to its target as soon as it is formatted.
+        let output_threshold = if options.args.flush_per_read { 0 } else { 2usize.pow(24) };
+        let out_buffer = OutputBuffer::new(Arc::clone(&writer), output_threshold);
+
+        let (output, output_m8): (Or<StdPAFOutput, StdSAMOutput>, Option<StdM8Output>) = match options.args.format {
+            OutputFormat::Paf => (Or::new_a(StdPAFOutput::new(out_buffer)), None),
+            OutputFormat::Gaf => (Or::new_a(StdPAFOutput::new(out_buffer).with_gaf(true)), None),
+            OutputFormat::Sam => (Or::new_b(StdSAMOutput::new(out_buffer, read_group.as_ref())), None),
+            OutputFormat::M8 => (Or { a: None, b: None }, Some(StdM8Output::new(out_buffer))),
         };
+        // --score-only replaces the regular output.
+        let (mut output, output_m8) = match score_target.is_some() {
+            true => (Or { a: None, b: None }, None),
+            false => (output, output_m8),
+        };
+        if let Some(target) = &split_target {
+            let split = SplitOutputBuffer::new(Arc::clone(target), output_threshold);
+            output.a = output.a.map(|o| o.split_by_reference(split.clone()));
+            output.b = output.b.map(|o| o.split_by_reference(split));
+        }
+
+        let unmapped_fastq = unmapped_targets.as_ref().map(|(single, paired)| match (rev_option, paired) {
+            (Some(_), Some((fwd, rev))) => UnmappedFastqOutput::new(
+                OutputBuffer::new(Arc::clone(fwd), output_threshold),
+                Some(OutputBuffer::new(Arc::clone(rev), output_threshold))),
+            _ => UnmappedFastqOutput::new(OutputBuffer::new(Arc::clone(single.as_ref().unwrap()), output_threshold), None),
+        });
+        let dump_anchors = dump_target.as_ref().map(|target| AnchorDumpOutput::new(OutputBuffer::new(Arc::clone(target), output_threshold)));
+        let score_only = score_target.as_ref().map(|target| ScoreOutput::new(OutputBuffer::new(Arc::clone(target), output_threshold)));
+        let mut alignment_work = work_target.as_ref().map(|target| AlignmentWorkOutput::new(OutputBuffer::new(Arc::clone(target), output_threshold)));
 
 
 
         let mut modular_fwd = Modular {
             options,
             db,
-            kmer_extractor: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::default(),
-            range_extractor: StdRangeExtractor::<K, C, F, FM>::new(db),
+            kmer_extractor: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(options.args.min_complexity).with_stride(options.args.kmer_stride).with_blacklist(blacklist.clone()),
+            range_extractor: StdRangeExtractor::<K, C, F, FM>::new(db, options.args.query_max_range_size.unwrap_or(usize::MAX)).with_sort_impl(options.args.sort_impl),
             seed_extractor: StdSeedExtractor::<K, C, F>::new(
                 options.args.max_best_flex,
                 options.args.max_range_size,
-                options.args.min_ranges
-            ),
-            anchor_extractor: StdAnchorExtractor::new(),
+                options.args.min_ranges,
+                options.args.min_seed_length
+            ).with_sort_key(options.args.seed_sort_key).with_sort_impl(options.args.sort_impl),
+            anchor_extractor: SelectedAnchorExtractor::new(options.args.anchor_strategy).with_group_rpos_window(options.args.group_rpos_window)
+                        .with_group_seed(options.args.group_seed)
+                        .with_sort_impl(options.args.sort_impl)
+                        .with_seed_sort_key(options.args.seed_sort_key),
             rec_rev: OwnedFastqRecord::new(),
             output: output.clone(),
+            output_m8: output_m8.clone(),
+            unmapped_fastq: unmapped_fastq.clone().filter(|_| rev_option.is_none()),
+            alignments: None,
+            dump_anchors: dump_anchors.clone().filter(|_| rev_option.is_none()),
+            score_only: score_only.clone().filter(|_| rev_option.is_none()),
+            chainer: ColinearChainer::default(),
         };        
 
 
         let fwd_gzip = is_gzip(fwd).expect(format!("Cannot check if file is gzipped. Check file: {}", fwd.to_str().unwrap()).as_str());
 
-        let stats;
+        let mut stats;
+        let start = Instant::now();
 
         // Distinguish between single- and paired-end reads
         match rev_option {
@@ -207,44 +337,62 @@ pub fn process_fastq_wrapper_modular<
                 let mut modular_rev = Modular {
                     options,
                     db,
-                    kmer_extractor: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::default(),
-                    range_extractor: StdRangeExtractor::<K, C, F, FM>::new(db),
+                    kmer_extractor: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(options.args.min_complexity).with_stride(options.args.kmer_stride).with_blacklist(blacklist.clone()),
+                    range_extractor: StdRangeExtractor::<K, C, F, FM>::new(db, options.args.query_max_range_size.unwrap_or(usize::MAX)).with_sort_impl(options.args.sort_impl),
                     seed_extractor: StdSeedExtractor::<K, C, F>::new(
                         options.args.max_best_flex,
                         options.args.max_range_size,
-                        options.args.min_ranges
-                    ),
-                    anchor_extractor: StdAnchorExtractor::new(),
+                        options.args.min_ranges,
+                        options.args.min_seed_length
+                    ).with_sort_key(options.args.seed_sort_key).with_sort_impl(options.args.sort_impl),
+                    anchor_extractor: SelectedAnchorExtractor::new(options.args.anchor_strategy).with_group_rpos_window(options.args.group_rpos_window)
+                        .with_group_seed(options.args.group_seed)
+                        .with_sort_impl(options.args.sort_impl)
+                        .with_seed_sort_key(options.args.seed_sort_key),
                     rec_rev: OwnedFastqRecord::new(),
                     // output_paf: Some(output),
                     // output_sam: None::<NoSAMOutput>,
                     output: output.clone(),
+                    output_m8: output_m8.clone(),
+                    unmapped_fastq: None,
+                    alignments: None,
+                    dump_anchors: None,
+                    score_only: None,
+                    chainer: ColinearChainer::default(),
                 };  
 
 
                 let mut modular_pe = ModularPE {
                     options,
                     db,
-                    kmer_extractor_fwd: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::default(),
-                    kmer_extractor_rev: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::default(),
-                    range_extractor_fwd: StdRangeExtractor::<K, C, F, FM>::new(db),
-                    range_extractor_rev: StdRangeExtractor::<K, C, F, FM>::new(db),
-                    seed_extractor_fwd: StdSeedExtractor::<K, C, F>::new(
-                        options.args.max_best_flex,
-                        options.args.max_range_size,
-                        options.args.min_ranges
-                    ),
-                    seed_extractor_rev: StdSeedExtractor::<K, C, F>::new(
-                        options.args.max_best_flex,
-                        options.args.max_range_size,
-                        options.args.min_ranges
-                    ),
-                    anchor_extractor: StdPairedAnchorExtractor::new(),
-                    anchor_sorter: PairedAnchorHeuristicSorter::new(db),
+                    kmer_extractor_fwd: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(options.args.min_complexity).with_stride(options.args.kmer_stride).with_blacklist(blacklist.clone()),
+                    kmer_extractor_rev: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(options.args.min_complexity).with_stride(options.args.kmer_stride).with_blacklist(blacklist.clone()),
+                    range_extractor_fwd: StdRangeExtractor::<K, C, F, FM>::new(db, options.args.query_max_range_size.unwrap_or(usize::MAX)).with_sort_impl(options.args.sort_impl),
+                    range_extractor_rev: StdRangeExtractor::<K, C, F, FM>::new(db, options.args.query_max_range_size.unwrap_or(usize::MAX)).with_sort_impl(options.args.sort_impl),
+                    seed_extractor: PairedSeedExtractor::new(paired_seed_extractor(options, false), paired_seed_extractor(options, true)),
+                    anchor_extractor: StdPairedAnchorExtractor::new(StdAnchorScore::from_options(options), options.args.library_type)
+                        .with_max_anchors(options.args.max_anchors_per_read)
+                        .with_strict_pairing(options.args.strict_pairing)
+                        .with_max_insert_size(options.args.max_insert_size)
+                        .with_group_seed(options.args.group_seed)
+                        .with_sort_impl(options.args.sort_impl)
+                        .with_seed_sort_key(options.args.seed_sort_key)
+                        .with_middle_gap(options.args.middle_gap),
+                    anchor_sorter: PairedAnchorHeuristicSorter::new(db, options.args.extend_max_mismatch).with_sort_impl(options.args.sort_impl),
                     align: LIBWFA2Alignment::default(),
+                    min_ani: &min_ani,
                     output: output,
+                    output_m8: output_m8,
                     rec_fwd_revc: OwnedFastqRecord::new(),
                     rec_rev_revc: OwnedFastqRecord::new(),
+                    duplicates: options.args.mark_duplicates.then(DuplicateMarker::new),
+                    pileup: pileup.as_ref().map(|shared| PileupBuffer::new(Arc::clone(shared))),
+                    unmapped_fastq: unmapped_fastq,
+                    dump_anchors: dump_anchors,
+                    score_only: score_only,
+                    force_reference: force_reference,
+                    priority_references: priority_references.clone(),
+                    adapter: options.args.adapter.as_ref().map(|adapter| adapter.to_ascii_uppercase().into_bytes()),
                 };  
 
 
@@ -254,22 +402,30 @@ pub fn process_fastq_wrapper_modular<
                 };
 
                 let worker_pe = move |rec_fwd: &RefFastqRecord, rec_rev: &RefFastqRecord, stats: &mut Stats| {
+                    if shutdown_requested() { return }
+                    let start = alignment_work.is_some().then(Instant::now);
                     modular_pe.run(rec_fwd, rec_rev, stats);
+                    if let Some(score_only) = modular_pe.score_only.as_mut() {
+                        score_only.finish(rec_fwd.head(), true);
+                    }
+                    if let (Some(output), Some(start)) = (alignment_work.as_mut(), start) {
+                        output.write(rec_fwd.head(), modular_pe.align.take_work(), start.elapsed());
+                    }
                 };
                 
                 if fwd_gzip {
                     stats = read_fastq_paired_end_state_par(
-                        GzDecoder::new(file_fwd),
-                        GzDecoder::new(file_rev),
-                        usize::pow(2, 24),
+                        ShutdownReader(gz_reader(fwd, file_fwd, options.args.threads_io)),
+                        ShutdownReader(gz_reader(rev, file_rev, options.args.threads_io)),
+                        options.args.batch_size,
                         options.args.threads,
                         worker_pe,//worker,
                     );
                 } else {
                     stats = read_fastq_paired_end_state_par(
-                        file_fwd,
-                        file_rev,
-                        usize::pow(2, 24),
+                        ShutdownReader(file_fwd),
+                        ShutdownReader(file_rev),
+                        options.args.batch_size,
                         options.args.threads,
                         worker_pe,//worker,
                     );
@@ -279,20 +435,24 @@ pub fn process_fastq_wrapper_modular<
             None => {
 
                 let worker = move |rec: &RefFastqRecord, stats: &mut Stats| {
+                    if shutdown_requested() { return }
                     modular_fwd.run(rec, stats);
+                    if let Some(score_only) = modular_fwd.score_only.as_mut() {
+                        score_only.finish(rec.head(), false);
+                    }
                 };
 
                 if fwd_gzip {
                     stats = read_fastq_single_end_state_par(
-                        GzDecoder::new(file_fwd),
-                        usize::pow(2, 24),
+                        ShutdownReader(gz_reader(fwd, file_fwd, options.args.threads_io)),
+                        options.args.batch_size,
                         options.args.threads,
                         worker,
                     );
                 } else {
                     stats = read_fastq_single_end_state_par(
-                        file_fwd,
-                        usize::pow(2, 24),
+                        ShutdownReader(file_fwd),
+                        options.args.batch_size,
                         options.args.threads,
                         worker,
                     );
@@ -300,11 +460,532 @@ pub fn process_fastq_wrapper_modular<
             },
         }
 
+        stats.as_mut().unwrap().wall_time = Some(start.elapsed());
+        stats.as_mut().unwrap().reference_kmers_ambiguous = reference_kmers_ambiguous;
         eprintln!("{}", stats.as_ref().unwrap());
+        if stats.as_ref().unwrap().reads_oversized > 0 {
+            eprintln!("Warning: skipped {} reads longer than {} bases in {:?}. Long reads are not supported.",
+                stats.as_ref().unwrap().reads_oversized, MAX_READ_LENGTH, fwd);
+        }
+        if options.args.identity_histogram {
+            eprintln!("{}", stats.as_ref().unwrap().identity_summary());
+            stats.as_ref().unwrap().plot_identity();
+        }
+        total.merge_from(stats.as_mut().unwrap());
         // stats.as_ref().unwrap().plot_mapq();
         // dbg!(stats);
+
+        if shutdown_requested() {
+            break
+        }
     };
 
+    if let (Some(path), Some(pileup)) = (&options.args.pileup, pileup) {
+        pileup.lock().expect("Cannot lock pileup").write(db, path);
+    }
+
+    let summary = total.summary();
+    eprintln!("Summary\n{}", summary);
+    if let Some(path) = &options.args.summary {
+        if let Err(why) = std::fs::write(path, &summary) {
+            eprintln!("Cannot write summary {}: {}", path.display(), why);
+            exit(9);
+        }
+    }
+    total
 }
 
 
+/// Runs the single-end pipeline on one sequence given on the command line. The record is fed through the
+/// regular FASTQ reader from memory, with a constant base quality.
+pub fn process_single_sequence<
+        const K: usize, 
+        const C: usize, 
+        const F: usize, 
+        const S: usize, 
+        const L: usize,
+        const HEADER_THRESHOLD: usize,
+        FM: FlexalignDatabase + Clone + Sync + Send,
+    >(options: &Options, db: &FM, name: &str, seq: &str) {
+
+    let seq = seq.trim().to_ascii_uppercase();
+    if seq.is_empty() || !seq.bytes().all(|c| matches!(c, b'A' | b'C' | b'G' | b'T' | b'N')) {
+        eprintln!("--query-seq must be a non-empty nucleotide sequence (ACGTN).");
+        std::process::exit(9);
+    }
+    let fastq = format!("@{}\n{}\n+\n{}\n", name, seq, "I".repeat(seq.len()));
+
+    let blacklist = options.args.kmer_blacklist.as_ref().map(|path| Arc::new(load_kmer_blacklist::<K, C>(path)));
+    let stdout_writer = Arc::new(Mutex::new(OutputTarget::Stdout(io::stdout())));
+    let mut modular = Modular {
+        options,
+        db,
+        kmer_extractor: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(options.args.min_complexity).with_stride(options.args.kmer_stride).with_blacklist(blacklist.clone()),
+        range_extractor: StdRangeExtractor::<K, C, F, FM>::new(db, options.args.query_max_range_size.unwrap_or(usize::MAX)).with_sort_impl(options.args.sort_impl),
+        seed_extractor: StdSeedExtractor::<K, C, F>::new(
+            options.args.max_best_flex,
+            options.args.max_range_size,
+            options.args.min_ranges,
+            options.args.min_seed_length
+        ).with_sort_key(options.args.seed_sort_key).with_sort_impl(options.args.sort_impl),
+        anchor_extractor: SelectedAnchorExtractor::new(options.args.anchor_strategy).with_group_rpos_window(options.args.group_rpos_window)
+                        .with_group_seed(options.args.group_seed)
+                        .with_sort_impl(options.args.sort_impl)
+                        .with_seed_sort_key(options.args.seed_sort_key),
+        rec_rev: OwnedFastqRecord::new(),
+        output: Or::<StdPAFOutput, StdSAMOutput>::new_a(StdPAFOutput::new(OutputBuffer::new(stdout_writer, 2usize.pow(24)))),
+        output_m8: None::<StdM8Output>,
+        unmapped_fastq: None,
+        alignments: None,
+        dump_anchors: None,
+        score_only: None,
+        chainer: ColinearChainer::default(),
+    };
+
+    let worker = move |rec: &RefFastqRecord, stats: &mut Stats| {
+        modular.run(rec, stats);
+        if stats.anchors == 0 {
+            eprintln!("No anchors found for {}.", String::from_utf8_lossy(rec.head()));
+        }
+    };
+
+    let stats = read_fastq_single_end_state_par(Cursor::new(fastq.into_bytes()), usize::pow(2, 24), 1, worker);
+    eprintln!("{}", stats.as_ref().unwrap());
+}
+
+/// Aligns a batch of in-memory single-end reads `(name, seq, qual)` without going through a FASTQ reader.
+/// One pipeline is built for the whole batch; the result holds the reported alignments of each read, in input order.
+/// Fails if the database was loaded with `--no-reference-seqs`, since positions and cigars need the reference.
+/// `blacklist` holds the `--kmer-blacklist` core-mers, loaded once by the caller with `load_kmer_blacklist`.
+pub fn align_batch<
+        const K: usize, 
+        const C: usize, 
+        const F: usize, 
+        const S: usize, 
+        const L: usize,
+        const HEADER_THRESHOLD: usize,
+        FM: FlexalignDatabase + Clone + Sync + Send,
+    >(options: &Options, db: &FM, blacklist: Option<Arc<HashSet<u64>>>, reads: &[(&str, &[u8], &[u8])]) -> Result<Vec<Vec<Alignment>>, AlignmentError> {
+
+    if options.args.no_reference_seqs {
+        return Err(AlignmentError::MissingReferenceError("align_batch needs the reference sequences, which are not loaded with --no-reference-seqs.".to_string()))
+    }
+
+    let mut modular = Modular {
+        options,
+        db,
+        kmer_extractor: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(options.args.min_complexity).with_stride(options.args.kmer_stride).with_blacklist(blacklist),
+        range_extractor: StdRangeExtractor::<K, C, F, FM>::new(db, options.args.query_max_range_size.unwrap_or(usize::MAX)).with_sort_impl(options.args.sort_impl),
+        seed_extractor: StdSeedExtractor::<K, C, F>::new(
+            options.args.max_best_flex,
+            options.args.max_range_size,
+            options.args.min_ranges,
+            options.args.min_seed_length
+        ).with_sort_key(options.args.seed_sort_key).with_sort_impl(options.args.sort_impl),
+        anchor_extractor: SelectedAnchorExtractor::new(options.args.anchor_strategy).with_group_rpos_window(options.args.group_rpos_window)
+                        .with_group_seed(options.args.group_seed)
+                        .with_sort_impl(options.args.sort_impl)
+                        .with_seed_sort_key(options.args.seed_sort_key),
+        rec_rev: OwnedFastqRecord::new(),
+        output: Or::<StdPAFOutput, StdSAMOutput> { a: None, b: None },
+        output_m8: None::<StdM8Output>,
+        unmapped_fastq: None,
+        alignments: Some(Vec::new()),
+        dump_anchors: None,
+        score_only: None,
+        chainer: ColinearChainer::default(),
+    };
+
+    let mut stats = Stats::default();
+    Ok(reads.iter().map(|(name, seq, qual)| {
+        let rec = RefFastqRecord::new(name.as_bytes(), seq, b"+", qual);
+        modular.run(&rec, &mut stats);
+        std::mem::take(modular.alignments.as_mut().unwrap())
+    }).collect())
+}
+
+/// Seed extractor of read 1 or, with `rev`, read 2 of paired-end input. Read 2 takes the `--rev-*` seeding
+/// options where given, read 1 always the shared ones.
+fn paired_seed_extractor<const K: usize, const C: usize, const F: usize>(options: &Options, rev: bool) -> StdSeedExtractor<K, C, F> {
+    let args = &options.args;
+    let (max_best_flex, max_range_size, min_ranges) = match rev {
+        true => (args.rev_max_best_flex.unwrap_or(args.max_best_flex), args.rev_max_range_size.unwrap_or(args.max_range_size), args.rev_min_ranges.unwrap_or(args.min_ranges)),
+        false => (args.max_best_flex, args.max_range_size, args.min_ranges),
+    };
+    StdSeedExtractor::<K, C, F>::new(max_best_flex, max_range_size, min_ranges, args.min_seed_length)
+        .with_sort_key(args.seed_sort_key)
+        .with_sort_impl(args.sort_impl)
+}
+
+/// Decompressing reader for gzipped input. Block gzip is decompressed with `threads_io` threads,
+/// plain gzip (or `threads_io` <= 1) falls back to a single `GzDecoder`.
+fn gz_reader(path: &Path, file: File, threads_io: usize) -> Box<dyn Read + Send> {
+    if threads_io > 1 && is_bgzf(path).unwrap_or(false) {
+        return Box::new(ParDecompressBuilder::<Bgzf>::new()
+            .num_threads(threads_io)
+            .expect("Invalid number of --threads-io")
+            .from_reader(file))
+    }
+    Box::new(GzDecoder::new(file))
+}
+
+/// Ends the input on SIGINT, so the reader stops parsing batches that the workers would skip anyway.
+struct ShutdownReader<R: Read>(R);
+
+impl<R: Read> Read for ShutdownReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if shutdown_requested() { return Ok(0) }
+        self.0.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use crate::{options::Args, test_utils::{build_db, pseudo_random_bases, reverse_complement, temp_path, TestDB, C, F, K, L, S}};
+    use super::*;
+
+    type TestModular<'a> = Modular<'a, C, F, StdKmerExtractor<K, C, ClosedSyncmer<C, S, L>>, StdRangeExtractor<'a, K, C, F, TestDB>,
+        StdSeedExtractor<K, C, F>, SelectedAnchorExtractor, StdPAFOutput, StdSAMOutput, StdM8Output, TestDB>;
+    type TestModularPE<'a> = ModularPE<'a, C, F, StdKmerExtractor<K, C, ClosedSyncmer<C, S, L>>, StdRangeExtractor<'a, K, C, F, TestDB>,
+        StdSeedExtractor<K, C, F>, StdPairedAnchorExtractor, PairedAnchorHeuristicSorter<'a, TestDB>, StdPAFOutput, StdSAMOutput, StdM8Output, LIBWFA2Alignment, TestDB>;
+
+    /// Buffer writing every record straight to the file at `path`.
+    fn file_buffer(path: &Path) -> OutputBuffer {
+        OutputBuffer::new(Arc::new(Mutex::new(OutputTarget::File(File::create(path).unwrap()))), 0)
+    }
+
+    /// Single-end workflow configured from `options` as in `process_fastq`, writing SAM to `sam`.
+    fn modular<'a>(options: &'a Options, db: &'a TestDB, sam: &Path) -> TestModular<'a> {
+        Modular {
+            options,
+            db,
+            kmer_extractor: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(options.args.min_complexity).with_stride(options.args.kmer_stride),
+            range_extractor: StdRangeExtractor::<K, C, F, _>::new(db, options.args.query_max_range_size.unwrap_or(usize::MAX)),
+            seed_extractor: StdSeedExtractor::<K, C, F>::new(options.args.max_best_flex, options.args.max_range_size, options.args.min_ranges, options.args.min_seed_length)
+                .with_sort_key(options.args.seed_sort_key),
+            anchor_extractor: SelectedAnchorExtractor::new(options.args.anchor_strategy).with_group_rpos_window(options.args.group_rpos_window)
+                .with_group_seed(options.args.group_seed)
+                .with_seed_sort_key(options.args.seed_sort_key),
+            rec_rev: OwnedFastqRecord::new(),
+            output: Or::new_b(StdSAMOutput::new(file_buffer(sam), None)),
+            output_m8: None,
+            unmapped_fastq: None,
+            alignments: None,
+            dump_anchors: None,
+            score_only: None,
+            chainer: ColinearChainer::default(),
+        }
+    }
+
+    /// Paired-end workflow configured from `options` as in `process_fastq`, writing SAM to `sam`.
+    fn modular_pe<'a>(options: &'a Options, db: &'a TestDB, min_ani: &'a MinAni, sam: &Path) -> TestModularPE<'a> {
+        ModularPE {
+            options,
+            db,
+            kmer_extractor_fwd: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(options.args.min_complexity).with_stride(options.args.kmer_stride),
+            kmer_extractor_rev: StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(options.args.min_complexity).with_stride(options.args.kmer_stride),
+            range_extractor_fwd: StdRangeExtractor::<K, C, F, _>::new(db, options.args.query_max_range_size.unwrap_or(usize::MAX)),
+            range_extractor_rev: StdRangeExtractor::<K, C, F, _>::new(db, options.args.query_max_range_size.unwrap_or(usize::MAX)),
+            seed_extractor: PairedSeedExtractor::new(paired_seed_extractor(options, false), paired_seed_extractor(options, true)),
+            anchor_extractor: StdPairedAnchorExtractor::new(StdAnchorScore::from_options(options), options.args.library_type)
+                .with_max_anchors(options.args.max_anchors_per_read)
+                .with_strict_pairing(options.args.strict_pairing)
+                .with_max_insert_size(options.args.max_insert_size)
+                .with_group_seed(options.args.group_seed)
+                .with_seed_sort_key(options.args.seed_sort_key)
+                .with_middle_gap(options.args.middle_gap),
+            anchor_sorter: PairedAnchorHeuristicSorter::new(db, options.args.extend_max_mismatch),
+            align: LIBWFA2Alignment::default(),
+            min_ani,
+            output: Or::new_b(StdSAMOutput::new(file_buffer(sam), None)),
+            output_m8: None,
+            rec_fwd_revc: OwnedFastqRecord::new(),
+            rec_rev_revc: OwnedFastqRecord::new(),
+            duplicates: options.args.mark_duplicates.then(DuplicateMarker::new),
+            pileup: None,
+            unmapped_fastq: None,
+            dump_anchors: None,
+            score_only: None,
+            force_reference: None,
+            priority_references: Vec::new(),
+            adapter: options.args.adapter.as_ref().map(|adapter| adapter.to_ascii_uppercase().into_bytes()),
+        }
+    }
+
+    #[test]
+    fn align_batch_reports_each_read_in_input_order() {
+        let reference = pseudo_random_bases(3000, 11);
+        let (mut options, db) = build_db(&reference, &[]);
+
+        let fwd = reference[500..650].to_vec();
+        let rev = reverse_complement(&reference[1800..1950]);
+        let unrelated = pseudo_random_bases(150, 97);
+        let quality = vec![b'I'; 150];
+        let reads = [("fwd", fwd.as_slice(), quality.as_slice()), ("none", unrelated.as_slice(), quality.as_slice()), ("rev", rev.as_slice(), quality.as_slice())];
+
+        let batch = align_batch::<K, C, F, S, L, 16, _>(&options, &db, None, &reads).unwrap();
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch[0].len(), 1);
+        assert_eq!((batch[0][0].reference_id, batch[0][0].position, batch[0][0].forward), (0, 500, true));
+        assert!(batch[1].is_empty());
+        assert_eq!(batch[2].len(), 1);
+        assert_eq!((batch[2][0].reference_id, batch[2][0].position, batch[2][0].forward), (0, 1800, false));
+
+        options.args.no_reference_seqs = true;
+        assert!(matches!(align_batch::<K, C, F, S, L, 16, _>(&options, &db, None, &reads), Err(AlignmentError::MissingReferenceError(_))));
+    }
+
+    #[test]
+    fn single_end_identity_is_summarized() {
+        let reference = pseudo_random_bases(3000, 11);
+        let (options, db) = build_db(&reference, &[]);
+        let sam_path = temp_path("single-end-identity", "sam");
+
+        // 3 mismatches over 150 bases.
+        let mut read = reference[500..650].to_vec();
+        for i in [20, 75, 130] {
+            read[i] = if read[i] == b'A' { b'C' } else { b'A' };
+        }
+        let quality = vec![b'I'; 150];
+        let mut stats = Stats::default();
+        {
+            let mut modular = modular(&options, &db, &sam_path);
+            modular.run(&RefFastqRecord::new(b"read", &read, b"+", &quality), &mut stats);
+        }
+        std::fs::remove_file(&sam_path).unwrap();
+
+        assert_eq!(stats.identity_histogram.iter().sum::<usize>(), 1);
+        assert!(stats.summary().contains("Mean identity (aligned mates)...............98.00%\n"));
+    }
+
+    #[test]
+    fn lowercase_read_is_mapped_and_written_as_given() {
+        let reference = pseudo_random_bases(3000, 11);
+        let (options, db) = build_db(&reference, &[]);
+
+        let sam_path = temp_path("query-case", "sam");
+        let fastq_path = temp_path("query-case", "fq");
+
+        // Reverse strand, with a soft-masked stretch in the middle.
+        let mut mapped = reverse_complement(&reference[1800..1950]);
+        mapped[40..80].make_ascii_lowercase();
+        let mut unrelated = pseudo_random_bases(150, 97);
+        unrelated.make_ascii_lowercase();
+        let quality = vec![b'I'; 150];
+
+        {
+            let mut modular = modular(&options, &db, &sam_path);
+            modular.unmapped_fastq = Some(UnmappedFastqOutput::new(file_buffer(&fastq_path), None));
+            let mut stats = Stats::default();
+            modular.run(&RefFastqRecord::new(b"mapped", &mapped, b"+", &quality), &mut stats);
+            modular.run(&RefFastqRecord::new(b"unrelated", &unrelated, b"+", &quality), &mut stats);
+        }
+
+        let sam = std::fs::read_to_string(&sam_path).unwrap();
+        let fastq = std::fs::read_to_string(&fastq_path).unwrap();
+        std::fs::remove_file(&sam_path).unwrap();
+        std::fs::remove_file(&fastq_path).unwrap();
+
+        // Seeded and aligned on the uppercased copy, written with the case of the read.
+        let columns = sam.lines().next().unwrap().split('\t').collect::<Vec<_>>();
+        assert_eq!(sam.lines().count(), 1);
+        assert_eq!((columns[0], columns[1], columns[3]), ("mapped", "16", "1801"));
+        assert_eq!(columns[9].as_bytes(), crate::align::common::reverse_complement_keep_case(&mapped));
+
+        assert_eq!(fastq, format!("@unrelated\n{}\n+\n{}\n", String::from_utf8_lossy(&unrelated), String::from_utf8_lossy(&quality)));
+    }
+
+    #[test]
+    fn single_end_records_carry_the_edit_distance() {
+        let reference = pseudo_random_bases(3000, 11);
+        let (options, db) = build_db(&reference, &[]);
+        let sam_path = temp_path("single-nm", "sam");
+
+        let exact = reference[500..650].to_vec();
+        let mut mismatch = reference[1800..1950].to_vec();
+        mismatch[75] = if mismatch[75] == b'A' { b'C' } else { b'A' };
+        let quality = vec![b'I'; 150];
+
+        {
+            let mut modular = modular(&options, &db, &sam_path);
+            let mut stats = Stats::default();
+            modular.run(&RefFastqRecord::new(b"exact", &exact, b"+", &quality), &mut stats);
+            modular.run(&RefFastqRecord::new(b"mismatch", &mismatch, b"+", &quality), &mut stats);
+        }
+
+        let sam = std::fs::read_to_string(&sam_path).unwrap();
+        std::fs::remove_file(&sam_path).unwrap();
+
+        let records = sam.lines().map(|line| line.split('\t').collect::<Vec<_>>()).collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0][0], records[0][11]), ("exact", "NM:i:0"));
+        assert_eq!((records[1][0], records[1][11]), ("mismatch", "NM:i:1"));
+    }
+
+    #[test]
+    fn empty_records_are_written_unmapped_without_seeding() {
+        let reference = pseudo_random_bases(3000, 11);
+        let (options, db) = build_db(&reference, &[]);
+        let min_ani = MinAni::new(options.args.min_ani);
+        let sam_path = temp_path("empty-record", "sam");
+        let fastq_paths = [temp_path("empty-record", "fq"), temp_path("empty-record", "fq")];
+
+        let empty = RefFastqRecord::new(b"empty", b"", b"+", b"");
+        let mapped = reference[500..650].to_vec();
+        let quality = vec![b'I'; 150];
+        let mapped = RefFastqRecord::new(b"mapped", &mapped, b"+", &quality);
+
+        let mut stats = Stats::default();
+        {
+            let mut modular = modular(&options, &db, &sam_path);
+            modular.unmapped_fastq = Some(UnmappedFastqOutput::new(file_buffer(&fastq_paths[0]), None));
+            modular.run(&empty, &mut stats);
+        }
+        assert_eq!(std::fs::read_to_string(&sam_path).unwrap(), "");
+        assert_eq!(std::fs::read_to_string(&fastq_paths[0]).unwrap(), "@empty\n\n+\n\n");
+        assert_eq!((stats.reads_processed, stats.reads_empty, stats.seeds), (1, 1, 0));
+
+        // The mapped mate of an empty one is skipped with it.
+        let mut stats = Stats::default();
+        {
+            let mut modular_pe = modular_pe(&options, &db, &min_ani, &sam_path);
+            modular_pe.unmapped_fastq = Some(UnmappedFastqOutput::new(file_buffer(&fastq_paths[0]), Some(file_buffer(&fastq_paths[1]))));
+            modular_pe.run(&mapped, &empty, &mut stats);
+        }
+        assert_eq!(std::fs::read_to_string(&sam_path).unwrap(), "");
+        assert!(std::fs::read_to_string(&fastq_paths[0]).unwrap().starts_with("@mapped\n"));
+        assert_eq!(std::fs::read_to_string(&fastq_paths[1]).unwrap(), "@empty\n\n+\n\n");
+        assert_eq!((stats.reads_processed, stats.reads_empty, stats.seeds), (2, 1, 0));
+
+        for path in [&sam_path, &fastq_paths[0], &fastq_paths[1]] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn proper_pair_records_point_at_each_other() {
+        let reference = pseudo_random_bases(3000, 11);
+        let (options, db) = build_db(&reference, &[]);
+
+        let sam_path = temp_path("mate-columns", "sam");
+        let min_ani = MinAni::new(options.args.min_ani);
+        let fwd = reference[500..650].to_vec();
+        let rev = reverse_complement(&reference[800..950]);
+        let quality = vec![b'I'; 150];
+
+        {
+            let mut modular_pe = modular_pe(&options, &db, &min_ani, &sam_path);
+            let mut stats = Stats::default();
+            modular_pe.run(&RefFastqRecord::new(b"pair", &fwd, b"+", &quality), &RefFastqRecord::new(b"pair", &rev, b"+", &quality), &mut stats);
+        }
+
+        let sam = std::fs::read_to_string(&sam_path).unwrap();
+        std::fs::remove_file(&sam_path).unwrap();
+
+        let records = sam.lines().map(|line| line.split('\t').collect::<Vec<_>>()).collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        let (read1, read2) = (&records[0], &records[1]);
+        assert_eq!((read1[3], read2[3]), ("501", "801"));
+        for (record, mate) in [(read1, read2), (read2, read1)] {
+            assert!(record[1].parse::<u16>().unwrap() & 0x2 != 0);
+            assert_eq!((record[6], record[7]), ("=", mate[3]));
+        }
+    }
+
+    #[test]
+    fn timed_out_pair_is_reported_from_its_seeds() {
+        let reference = pseudo_random_bases(3000, 11);
+        let fwd = reference[500..650].to_vec();
+        let rev = reverse_complement(&reference[800..950]);
+        let quality = vec![b'I'; 150];
+
+        for (args, timed_out) in [(&[][..], 0), (&["--read-timeout-ms", "0"][..], 1)] {
+            let (options, db) = build_db(&reference, args);
+            let min_ani = MinAni::new(options.args.min_ani);
+            let sam_path = temp_path("read-timeout", "sam");
+            let mut stats = Stats::default();
+            {
+                let mut modular_pe = modular_pe(&options, &db, &min_ani, &sam_path);
+                modular_pe.run(&RefFastqRecord::new(b"pair", &fwd, b"+", &quality), &RefFastqRecord::new(b"pair", &rev, b"+", &quality), &mut stats);
+            }
+            let sam = std::fs::read_to_string(&sam_path).unwrap();
+            std::fs::remove_file(&sam_path).unwrap();
+
+            // Without any alignment the mates are still placed by their seeds.
+            assert_eq!(stats.reads_timed_out, timed_out);
+            assert_eq!(stats.alignments == 0, timed_out == 1);
+            let positions = sam.lines().map(|line| line.split('\t').nth(3).unwrap().to_string()).collect::<Vec<_>>();
+            assert_eq!(positions, ["501", "801"]);
+        }
+    }
+
+    #[test]
+    fn rev_seeding_options_only_change_read_2() {
+        let config = |extractor: StdSeedExtractor<K, C, F>| (extractor.max_best_flex, extractor.max_ranges, extractor.min_ranges);
+
+        let options = Options::from_args(Args::parse_from(["flexalign", "-r", "ref.fa"]));
+        let shared = (options.args.max_best_flex, options.args.max_range_size, options.args.min_ranges);
+        assert_eq!(config(paired_seed_extractor(&options, false)), shared);
+        assert_eq!(config(paired_seed_extractor(&options, true)), shared);
+
+        let options = Options::from_args(Args::parse_from(["flexalign", "-r", "ref.fa",
+            "--rev-max-best-flex", "32", "--rev-max-range-size", "512", "--rev-min-ranges", "1"]));
+        assert_eq!(config(paired_seed_extractor(&options, false)), shared);
+        assert_eq!(config(paired_seed_extractor(&options, true)), (32, 512, 1));
+    }
+
+    #[test]
+    fn tie_policies_of_a_duplicated_segment() {
+        // Bases 500..900 occur again at 2000..2400, so every read within them ties between both copies.
+        let mut reference = pseudo_random_bases(3000, 11);
+        let segment = reference[500..900].to_vec();
+        reference[2000..2400].copy_from_slice(&segment);
+        let fwd = reference[500..650].to_vec();
+        let rev = reverse_complement(&reference[750..900]);
+        let quality = vec![b'I'; 150];
+        let flag = |record: &Vec<String>| record[1].parse::<u16>().unwrap();
+        let read_sam = |path: &Path| {
+            let sam = std::fs::read_to_string(path).unwrap();
+            std::fs::remove_file(path).unwrap();
+            sam.lines().map(|line| line.split('\t').map(str::to_string).collect::<Vec<_>>()).collect::<Vec<_>>()
+        };
+
+        for policy in ["best", "report-both", "unmapped"] {
+            let (options, db) = build_db(&reference, &["--tie-policy", policy]);
+            let sam_path = temp_path("tie-policy", "sam");
+            let mut stats = Stats::default();
+            modular(&options, &db, &sam_path).run(&RefFastqRecord::new(b"read", &fwd, b"+", &quality), &mut stats);
+            let records = read_sam(&sam_path);
+            assert_eq!(stats.reads_tied, 1);
+
+            match policy {
+                "report-both" => {
+                    assert_eq!(records.len(), 2);
+                    assert_eq!((flag(&records[0]), flag(&records[1])), (0, 0x100));
+                    let mut positions = [records[0][3].as_str(), records[1][3].as_str()];
+                    positions.sort_unstable();
+                    assert_eq!(positions, ["2001", "501"]);
+                },
+                _ => {
+                    assert_eq!(records.len(), 1);
+                    assert_eq!(flag(&records[0]), 0);
+                    assert_eq!(records[0].iter().any(|tag| tag == "tp:A:?"), policy == "unmapped");
+                },
+            }
+        }
+
+        // Both records of the runner-up pair are secondary.
+        let (options, db) = build_db(&reference, &["--tie-policy", "report-both"]);
+        let min_ani = MinAni::new(options.args.min_ani);
+        let sam_path = temp_path("tie-policy", "sam");
+        {
+            let mut modular_pe = modular_pe(&options, &db, &min_ani, &sam_path);
+            modular_pe.run(&RefFastqRecord::new(b"pair", &fwd, b"+", &quality), &RefFastqRecord::new(b"pair", &rev, b"+", &quality), &mut Stats::default());
+        }
+        let records = read_sam(&sam_path);
+        assert_eq!(records.len(), 4);
+        assert!(records[..2].iter().all(|record| flag(record) & 0x100 == 0));
+        assert!(records[2..].iter().all(|record| flag(record) & 0x100 != 0));
+    }
+}
@@ -1,6 +1,8 @@
-use std::fmt::Display;
+use std::{fmt::Display, process::exit};
+use crate::{database::common::FlexalignDatabase, options::{IdentityModel, Options}};
 
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct Flag(u16);
 
 impl Flag {
@@ -8,6 +10,10 @@ impl Flag {
         Self(0)
     }
 
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
     pub fn paired_end(&mut self, on: bool) -> &mut Self {
         match on {
             true => self.0 |= 0x1u16,   // Use |= to set the bit
@@ -24,7 +30,7 @@ impl Flag {
         self
     }
 
-    pub fn read1_mapped(&mut self, on: bool) -> &mut Self {
+    pub fn unmapped(&mut self, on: bool) -> &mut Self {
         match on {
             true => self.0 |= 0x4u16,
             false => self.0 &= !0x4u16,
@@ -32,7 +38,7 @@ impl Flag {
         self
     }
 
-    pub fn read2_mapped(&mut self, on: bool) -> &mut Self {
+    pub fn mate_unmapped(&mut self, on: bool) -> &mut Self {
         match on {
             true => self.0 |= 0x8u16,
             false => self.0 &= !0x8u16,
@@ -40,66 +46,66 @@ impl Flag {
         self
     }
 
-    pub fn read1_rc(&mut self, on: bool) -> &mut Self {
+    pub fn reverse(&mut self, on: bool) -> &mut Self {
         match on {
-            true => self.0 |= 0x16u16,
-            false => self.0 &= !0x16u16,
+            true => self.0 |= 0x10u16,
+            false => self.0 &= !0x10u16,
         };
         self
     }
 
-    pub fn read2_rc(&mut self, on: bool) -> &mut Self {
+    pub fn mate_reverse(&mut self, on: bool) -> &mut Self {
         match on {
-            true => self.0 |= 0x32u16,
-            false => self.0 &= !0x32u16,
+            true => self.0 |= 0x20u16,
+            false => self.0 &= !0x20u16,
         };
         self
     }
 
     pub fn read1(&mut self, on: bool) -> &mut Self {
         match on {
-            true => self.0 |= 0x64u16,
-            false => self.0 &= !0x64u16,
+            true => self.0 |= 0x40u16,
+            false => self.0 &= !0x40u16,
         };
         self
     }
 
     pub fn read2(&mut self, on: bool) -> &mut Self {
         match on {
-            true => self.0 |= 0x128u16,
-            false => self.0 &= !0x128u16,
+            true => self.0 |= 0x80u16,
+            false => self.0 &= !0x80u16,
         };
         self
     }
 
     pub fn not_primary(&mut self, on: bool) -> &mut Self {
         match on {
-            true => self.0 |= 0x256u16,
-            false => self.0 &= !0x256u16,
+            true => self.0 |= 0x100u16,
+            false => self.0 &= !0x100u16,
         };
         self
     }
 
     pub fn alignment_failed_qc(&mut self, on: bool) -> &mut Self {
         match on {
-            true => self.0 |= 0x512u16,
-            false => self.0 &= !0x512u16,
+            true => self.0 |= 0x200u16,
+            false => self.0 &= !0x200u16,
         };
         self
     }
 
     pub fn duplicate(&mut self, on: bool) -> &mut Self {
         match on {
-            true => self.0 |= 0x1024u16,
-            false => self.0 &= !0x1024u16,
+            true => self.0 |= 0x400u16,
+            false => self.0 &= !0x400u16,
         };
         self
     }
 
     pub fn supplementary(&mut self, on: bool) -> &mut Self {
         match on {
-            true => self.0 |= 0x2048u16,
-            false => self.0 &= !0x2048u16,
+            true => self.0 |= 0x800u16,
+            false => self.0 &= !0x800u16,
         };
         self
     }
@@ -112,44 +118,44 @@ impl Flag {
         (self.0 & 0x2u16) != 0
     }
 
-    pub fn is_read1_mapped(&self) -> bool {
+    pub fn is_unmapped(&self) -> bool {
         (self.0 & 0x4u16) != 0
     }
 
-    pub fn is_read2_mapped(&self) -> bool {
+    pub fn is_mate_unmapped(&self) -> bool {
         (self.0 & 0x8u16) != 0
     }
 
-    pub fn is_read1_rc(&self) -> bool {
-        (self.0 & 0x16u16) != 0
+    pub fn is_reverse(&self) -> bool {
+        (self.0 & 0x10u16) != 0
     }
 
-    pub fn is_read2_rc(&self) -> bool {
-        (self.0 & 0x32u16) != 0
+    pub fn is_mate_reverse(&self) -> bool {
+        (self.0 & 0x20u16) != 0
     }
 
     pub fn is_read1(&self) -> bool {
-        (self.0 & 0x64u16) != 0
+        (self.0 & 0x40u16) != 0
     }
 
     pub fn is_read2(&self) -> bool {
-        (self.0 & 0x128u16) != 0
+        (self.0 & 0x80u16) != 0
     }
 
     pub fn is_not_primary(&self) -> bool {
-        (self.0 & 0x256u16) != 0
+        (self.0 & 0x100u16) != 0
     }
 
     pub fn is_alignmend_failed_qc(&self) -> bool {
-        (self.0 & 0x512u16) != 0
+        (self.0 & 0x200u16) != 0
     }
 
     pub fn is_duplicate(&self) -> bool {
-        (self.0 & 0x1024u16) != 0
+        (self.0 & 0x400u16) != 0
     }
 
     pub fn is_supplementary(&self) -> bool {
-        (self.0 & 0x2048u16) != 0
+        (self.0 & 0x800u16) != 0
     }
 }
 
@@ -158,6 +164,9 @@ type CigarOp = u8;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Cigar(pub Vec<CigarOp>);
+
+/// Borrowed view of a cigar, e.g. the aligner's buffer, for output without cloning it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct CigarRef<'a>(pub &'a [CigarOp]);
 
 impl AsRef::<Cigar> for Cigar {
@@ -166,14 +175,24 @@ impl AsRef::<Cigar> for Cigar {
     }
 }
 
-// \*|([0-9]+[MIDNSHP=X])+
-impl Cigar {
-    pub fn add_softclip(&mut self, n: usize) {
-        self.0.extend(std::iter::repeat(b'S').take(n));
+/// Iterator over the `(op, run length)` pairs of a cigar, see `CigarRef::runs`.
+pub struct CigarRuns<'a>(&'a [CigarOp]);
+
+impl<'a> Iterator for CigarRuns<'a> {
+    type Item = (CigarOp, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let &op = self.0.first()?;
+        let length = self.0.iter().take_while(|&&c| c == op).count();
+        self.0 = &self.0[length..];
+        Some((op, length))
     }
+}
 
-    pub fn add_matches(&mut self, n: usize) {
-        self.0.extend(std::iter::repeat(b'M').take(n));
+impl<'a> CigarRef<'a> {
+    /// Consecutive equal ops as `(op, run length)`, in internal (WFA) op convention.
+    pub fn runs(&self) -> CigarRuns<'a> {
+        CigarRuns(self.0)
     }
 
     pub fn count_leading_chars(&self, c: u8) -> usize {
@@ -189,6 +208,204 @@ impl Cigar {
             .count()
     }
 
+    /// Run-length encoded SAM representation, e.g. `3S10M1X2M`. An empty cigar is written as `*`.
+    /// Internally ops follow WFA, where `D` consumes only the query and `I` only the reference,
+    /// so both are swapped to match the SAM definition.
+    pub fn to_rle_string(&self) -> String {
+        if self.0.is_empty() {
+            return "*".to_string()
+        }
+
+        fn sam_op(op: u8) -> char {
+            match op {
+                b'D' => 'I',
+                b'I' => 'D',
+                op => op as char,
+            }
+        }
+
+        self.runs().map(|(op, count)| format!("{}{}", count, sam_op(op))).collect()
+    }
+
+    /// Edit distance to the reference (SAM `NM`), counting mismatches and inserted and deleted bases.
+    pub fn edit_distance(&self) -> u64 {
+        self.0.iter().filter(|&&c| c == b'X' || c == b'I' || c == b'D').count() as u64
+    }
+
+    /// Number of reference bases covered by the alignment (M, X, =, the reference-only I and skipped N).
+    pub fn reference_consumed(&self) -> usize {
+        self.runs().filter(|(op, _)| matches!(op, b'M' | b'X' | b'=' | b'I' | b'N')).map(|(_, n)| n).sum()
+    }
+
+    /// Number of query bases covered by the cigar (M, X, =, the query-only D and soft-clips).
+    pub fn query_consumed(&self) -> usize {
+        self.runs().filter(|(op, _)| matches!(op, b'M' | b'X' | b'=' | b'D' | b'S')).map(|(_, n)| n).sum()
+    }
+
+    /// Alignment block length (PAF column 11): all aligned columns including gaps, without soft-clips and skipped reference.
+    pub fn block_length(&self) -> usize {
+        self.runs().filter(|(op, _)| matches!(op, b'M' | b'X' | b'=' | b'I' | b'D')).map(|(_, n)| n).sum()
+    }
+
+    /// Number of gaps in either sequence, each run of I or D counts once.
+    pub fn gap_opens(&self) -> usize {
+        self.runs().filter(|(op, _)| matches!(op, b'I' | b'D')).count()
+    }
+
+    /// Matches over the aligned columns of `model` (`--identity-model`), 0 without aligned columns.
+    pub fn identity(&self, model: IdentityModel) -> f64 {
+        let matches = self.0.iter().filter(|&&c| c == b'M' || c == b'=').count();
+        let mismatches = self.0.iter().filter(|&&c| c == b'X').count();
+        let columns = match model {
+            IdentityModel::Blast => matches + mismatches + self.0.iter().filter(|&&c| c == b'I' || c == b'D').count(),
+            IdentityModel::GapCompressed => matches + mismatches + self.gap_opens(),
+        };
+        if columns == 0 { 0.0 } else { matches as f64 / columns as f64 }
+    }
+}
+
+// \*|([0-9]+[MIDNSHP=X])+
+impl Cigar {
+    pub fn as_cigar_ref(&self) -> CigarRef<'_> {
+        CigarRef(&self.0)
+    }
+
+    pub fn add_softclip(&mut self, n: usize) {
+        self.0.extend(std::iter::repeat(b'S').take(n));
+    }
+
+    pub fn add_matches(&mut self, n: usize) {
+        self.0.extend(std::iter::repeat(b'M').take(n));
+    }
+
+    pub fn count_leading_chars(&self, c: u8) -> usize {
+        self.as_cigar_ref().count_leading_chars(c)
+    }
+
+    pub fn count_trailing_chars(&self, c: u8) -> usize {
+        self.as_cigar_ref().count_trailing_chars(c)
+    }
+
+    /// Soft-clipped bases at both ends.
+    pub fn softclip_length(&self) -> usize {
+        self.count_leading_chars(b'S') + self.count_trailing_chars(b'S')
+    }
+
+    /// See `CigarRef::to_rle_string`.
+    pub fn to_rle_string(&self) -> String {
+        self.as_cigar_ref().to_rle_string()
+    }
+
+    pub fn edit_distance(&self) -> u64 {
+        self.as_cigar_ref().edit_distance()
+    }
+
+    pub fn reference_consumed(&self) -> usize {
+        self.as_cigar_ref().reference_consumed()
+    }
+
+    pub fn query_consumed(&self) -> usize {
+        self.as_cigar_ref().query_consumed()
+    }
+
+    /// See `CigarRef::block_length`.
+    pub fn block_length(&self) -> usize {
+        self.as_cigar_ref().block_length()
+    }
+
+    /// See `CigarRef::runs`.
+    pub fn runs(&self) -> CigarRuns<'_> {
+        self.as_cigar_ref().runs()
+    }
+
+    pub fn matches(&self) -> usize {
+        self.0.iter().filter(|&&c| c == b'M' || c == b'=').count()
+    }
+
+    pub fn mismatches(&self) -> usize {
+        self.0.iter().filter(|&&c| c == b'X').count()
+    }
+
+    pub fn gap_opens(&self) -> usize {
+        self.as_cigar_ref().gap_opens()
+    }
+
+    pub fn identity(&self, model: IdentityModel) -> f64 {
+        self.as_cigar_ref().identity(model)
+    }
+
+    /// Moves every insertion and deletion to its leftmost equivalent position within a repeat, so that
+    /// equal alignments get equal cigars (`--left-align-indels`). `query` and `reference` start where the
+    /// cigar starts, as in `is_alignment_valid`. A gap only moves across matches, which keeps the alignment valid.
+    pub fn normalize_indels(&mut self, query: &[u8], reference: &[u8]) {
+        let ops = &mut self.0;
+        let (mut q, mut r) = (0, 0);
+        let mut i = 0;
+        while i < ops.len() {
+            let op = ops[i];
+            match op {
+                b'D' | b'I' => {
+                    let length = ops[i..].iter().take_while(|&&c| c == op).count();
+                    let (mut start, mut qs, mut rs) = (i, q, r);
+                    while start > 0 && ops[start - 1] == b'M' && qs > 0 && rs > 0 {
+                        let shifts = match op {
+                            b'D' => query[qs - 1] == query[qs + length - 1],
+                            _ => reference[rs - 1] == reference[rs + length - 1],
+                        };
+                        if !shifts { break };
+                        ops[start - 1] = op;
+                        ops[start + length - 1] = b'M';
+                        start -= 1;
+                        qs -= 1;
+                        rs -= 1;
+                    }
+                    if op == b'D' { q += length } else { r += length };
+                    i += length;
+                    continue
+                },
+                b'M' | b'X' | b'=' => { q += 1; r += 1 },
+                b'N' => r += 1,
+                _ => q += 1,
+            }
+            i += 1;
+        }
+    }
+
+    /// Parses a run-length encoded SAM cigar such as `3S10M1I2M` (see `to_rle_string`). `=` is read as `M`,
+    /// `*` gives an empty cigar. Returns None for malformed strings and for H and P, which have no
+    /// representation here.
+    pub fn from_rle_str(rle: &str) -> Option<Self> {
+        let mut cigar = Self::new();
+        if rle == "*" {
+            return Some(cigar)
+        }
+
+        let mut count = 0usize;
+        let mut has_count = false;
+        for c in rle.bytes() {
+            if c.is_ascii_digit() {
+                count = count * 10 + (c - b'0') as usize;
+                has_count = true;
+                continue
+            }
+            let op = match c {
+                b'M' | b'=' => b'M',
+                b'X' => b'X',
+                b'S' => b'S',
+                b'N' => b'N',
+                b'I' => b'D',
+                b'D' => b'I',
+                _ => return None,
+            };
+            if !has_count { return None };
+            cigar.0.extend(std::iter::repeat(op).take(count));
+            count = 0;
+            has_count = false;
+        }
+
+        if has_count { None } else { Some(cigar) }
+    }
+
     pub fn valid(&self) -> bool {
         true
     }
@@ -207,3 +424,225 @@ impl Display for Cigar {
         write!(f, "{}", String::from_utf8_lossy(&self.0))
     }
 }
+
+
+/// A single entry of the BWA-style `XA:Z` alternative hits tag.
+pub struct AlternativeHit<'a> {
+    pub reference_name: &'a str,
+    pub forward: bool,
+    /// 1-based leftmost reference position
+    pub position: usize,
+    pub cigar: String,
+    pub edit_distance: u64,
+}
+
+impl<'a> Display for AlternativeHit<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{}{},{},{};",
+            self.reference_name,
+            if self.forward { '+' } else { '-' },
+            self.position,
+            self.cigar,
+            self.edit_distance)
+    }
+}
+
+/// `XA:Z:rname,±pos,cigar,NM;...` or None if there are no alternatives.
+pub fn xa_tag(hits: &[AlternativeHit]) -> Option<String> {
+    if hits.is_empty() {
+        return None
+    }
+    Some(format!("XA:Z:{}", hits.iter().map(|h| h.to_string()).collect::<String>()))
+}
+
+/// Reference and leftmost position of the other mate of a pair.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MatePosition<'a> {
+    pub reference: u64,
+    pub reference_name: &'a str,
+    /// 1-based leftmost reference position
+    pub position: usize,
+}
+
+impl<'a> MatePosition<'a> {
+    /// `mr:Z` (`=` on the same reference) and the 0-based `mp:i` start of the mate for paired PAF lines.
+    pub fn paf_tags(&self, reference: u64) -> [String; 2] {
+        let name = if self.reference == reference { "=" } else { self.reference_name };
+        [format!("mr:Z:{}", name), format!("mp:i:{}", self.position - 1)]
+    }
+}
+
+/// RNEXT and PNEXT of a record on `reference` whose mate is at `mate`.
+pub fn mate_columns<'a>(reference: Option<u64>, mate: Option<&MatePosition<'a>>) -> (&'a str, usize) {
+    match mate {
+        Some(m) if reference == Some(m.reference) => ("=", m.position),
+        Some(m) => (m.reference_name, m.position),
+        None => ("*", 0),
+    }
+}
+
+/// Read group given by --read-group, --sample, --library and --platform.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadGroup {
+    pub id: String,
+    pub sample: Option<String>,
+    pub library: Option<String>,
+    pub platform: Option<String>,
+}
+
+impl ReadGroup {
+    pub fn from_options(options: &Options) -> Option<Self> {
+        let args = &options.args;
+        let id = match &args.read_group {
+            Some(id) => id,
+            None => {
+                if args.sample.is_some() || args.library.is_some() || args.platform.is_some() {
+                    eprintln!("--sample, --library and --platform require --read-group.");
+                    exit(9);
+                }
+                return None
+            },
+        };
+
+        let fields = [Some(id), args.sample.as_ref(), args.library.as_ref(), args.platform.as_ref()];
+        if id.is_empty() || fields.iter().flatten().any(|f| f.contains(char::is_whitespace)) {
+            eprintln!("Read group fields must be non-empty and must not contain whitespace.");
+            exit(9);
+        }
+
+        Some(Self {
+            id: id.clone(),
+            sample: args.sample.clone(),
+            library: args.library.clone(),
+            platform: args.platform.clone(),
+        })
+    }
+
+    pub fn header_line(&self) -> String {
+        let mut line = format!("@RG\tID:{}", self.id);
+        for (key, value) in [("SM", &self.sample), ("LB", &self.library), ("PL", &self.platform)] {
+            if let Some(value) = value {
+                line.push_str(&format!("\t{}:{}", key, value));
+            }
+        }
+        line.push('\n');
+        line
+    }
+
+    /// RG tag attached to every record of this read group
+    pub fn tag(&self) -> String {
+        format!("RG:Z:{}", self.id)
+    }
+}
+
+pub fn sam_header<D: FlexalignDatabase>(db: &D, read_group: Option<&ReadGroup>) -> String {
+    let mut header = String::from("@HD\tVN:1.6\tSO:unsorted\n");
+    for id in 0..db.reference_count() {
+        header.push_str(&format!("@SQ\tSN:{}\tLN:{}\n", db.get_rname(id).unwrap(), db.get_reference_length(id).unwrap()));
+    }
+    if let Some(read_group) = read_group {
+        header.push_str(&read_group.header_line());
+    }
+    header.push_str(&format!("@PG\tID:flexalign\tPN:flexalign\tVN:{}\n", env!("CARGO_PKG_VERSION")));
+    header
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_length_full_match() {
+        let mut cigar = Cigar::new();
+        cigar.add_matches(100);
+        assert_eq!(cigar.block_length(), 100);
+    }
+
+    #[test]
+    fn block_length_ignores_softclips_and_counts_gaps() {
+        let mut cigar = Cigar::new();
+        cigar.add_softclip(5);
+        cigar.add_matches(10);
+        cigar.0.extend_from_slice(b"XIID");
+        cigar.add_matches(10);
+        cigar.add_softclip(3);
+        assert_eq!(cigar.block_length(), 24);
+    }
+
+    #[test]
+    fn read_group_header_and_tag() {
+        let read_group = ReadGroup {
+            id: String::from("lane1"),
+            sample: Some(String::from("NA12878")),
+            library: None,
+            platform: Some(String::from("ILLUMINA")),
+        };
+        let line = read_group.header_line();
+        assert_eq!(line, "@RG\tID:lane1\tSM:NA12878\tPL:ILLUMINA\n");
+        assert_eq!(line.matches("@RG").count(), 1);
+        assert_eq!(read_group.tag(), "RG:Z:lane1");
+    }
+
+    #[test]
+    fn skipped_reference_rle_round_trip() {
+        let cigar = Cigar::from_rle_str("2S10M200N5M1I3M").unwrap();
+        assert_eq!(cigar.to_rle_string(), "2S10M200N5M1I3M");
+        assert_eq!(cigar.reference_consumed(), 218);
+        assert_eq!(cigar.edit_distance(), 1);
+    }
+
+    #[test]
+    fn deletion_in_homopolymer_shifts_left() {
+        use crate::align::common::is_alignment_valid;
+
+        let reference = b"ACGTAAAAGCT";
+        let query = b"ACGTAAAGCT";
+        let mut cigar = Cigar::from_rle_str("7M1D3M").unwrap();
+        assert!(is_alignment_valid(query, reference, &cigar.0));
+
+        cigar.normalize_indels(query, reference);
+        assert_eq!(cigar.to_rle_string(), "4M1D6M");
+        assert!(is_alignment_valid(query, reference, &cigar.0));
+    }
+
+    #[test]
+    fn identity_models_with_indel_and_mismatch() {
+        // 12 matches, 1 mismatch and a 2 base deletion.
+        let cigar = Cigar::from_rle_str("5M1X3M2D4M").unwrap();
+        assert_eq!(cigar.identity(IdentityModel::Blast), 12.0 / 15.0);
+        assert_eq!(cigar.identity(IdentityModel::GapCompressed), 12.0 / 14.0);
+        assert_eq!(Cigar::new().identity(IdentityModel::GapCompressed), 0.0);
+    }
+
+    #[test]
+    fn proper_pair_mate_columns() {
+        let fwd = MatePosition { reference: 3, reference_name: "chr1", position: 101 };
+        let rev = MatePosition { reference: 3, reference_name: "chr1", position: 251 };
+        assert_eq!(mate_columns(Some(fwd.reference), Some(&rev)), ("=", 251));
+        assert_eq!(mate_columns(Some(rev.reference), Some(&fwd)), ("=", 101));
+        assert_eq!(mate_columns(Some(4), Some(&rev)), ("chr1", 251));
+        assert_eq!(mate_columns(Some(3), None), ("*", 0));
+        assert_eq!(rev.paf_tags(3), ["mr:Z:=".to_string(), "mp:i:250".to_string()]);
+    }
+
+    #[test]
+    fn runs_of_clipped_and_gapped_cigars() {
+        let cigar = Cigar::from_rle_str("3S10M1X2M2I4M1D5S").unwrap();
+        // I and D are swapped in the internal convention.
+        assert_eq!(cigar.runs().collect::<Vec<_>>(),
+            vec![(b'S', 3), (b'M', 10), (b'X', 1), (b'M', 2), (b'D', 2), (b'M', 4), (b'I', 1), (b'S', 5)]);
+        assert_eq!(cigar.to_rle_string(), "3S10M1X2M2I4M1D5S");
+        assert_eq!(cigar.query_consumed(), 27);
+        assert_eq!(cigar.reference_consumed(), 18);
+        assert_eq!(cigar.block_length(), 20);
+        assert_eq!(cigar.gap_opens(), 2);
+
+        // Adjacent runs of different ops stay separate, a single op is one run.
+        let cigar = Cigar(b"MMIID".to_vec());
+        assert_eq!(cigar.runs().collect::<Vec<_>>(), vec![(b'M', 2), (b'I', 2), (b'D', 1)]);
+        assert_eq!(Cigar(b"S".to_vec()).runs().collect::<Vec<_>>(), vec![(b'S', 1)]);
+        assert_eq!(Cigar::new().runs().count(), 0);
+        assert_eq!(Cigar::new().to_rle_string(), "*");
+    }
+}
@@ -0,0 +1,65 @@
+use std::process::exit;
+
+use super::{common::{is_alignment_valid, roundtrip_mismatches, Align, Status}, process::alignment::{LIBWFA2Alignment, GAP_EXTENSION_PENALTY, GAP_OPENING_PENALTY, MISMATCH_PENALTY}, sam::Cigar};
+
+/// Query, reference and expected score (negative penalty sum) of the alignment checks.
+fn alignment_cases() -> Vec<(&'static str, &'static [u8], &'static [u8], i32)> {
+    vec![
+        ("identical", b"ACGTTGCAAGCTTACGGATC", b"ACGTTGCAAGCTTACGGATC", 0),
+        ("one mismatch", b"ACGTTGCAAGATTACGGATC", b"ACGTTGCAAGCTTACGGATC", -MISMATCH_PENALTY),
+        ("two mismatches", b"ACCTTGCAAGCTTACGTATC", b"ACGTTGCAAGCTTACGGATC", -2 * MISMATCH_PENALTY),
+        ("one inserted base", b"ACGTTGCAAGGCTTACGGATC", b"ACGTTGCAAGCTTACGGATC", -(GAP_OPENING_PENALTY + GAP_EXTENSION_PENALTY)),
+        ("two deleted bases", b"ACGTTGCAAGTACGGATC", b"ACGTTGCAAGCTTACGGATC", -(GAP_OPENING_PENALTY + 2 * GAP_EXTENSION_PENALTY)),
+    ]
+}
+
+/// Run-length encoded cigars that have to survive parsing and encoding unchanged.
+const RLE_CASES: [&str; 4] = ["20M", "3S10M1X2M", "5M1I4M2D6M", "2S5M3I1X4M1D2M4S"];
+
+/// Checks the WFA aligner behind LIBWFA2Alignment and the cigar encoding without any input files (`--selftest`).
+/// Prints one line per check and a summary. Exits with 9 if any check fails.
+pub fn selftest() {
+    let mut checks = 0;
+    let mut failed = 0;
+    let mut report = |name: String, error: Option<String>| {
+        checks += 1;
+        match error {
+            None => eprintln!("ok      {}", name),
+            Some(error) => {
+                failed += 1;
+                eprintln!("FAILED  {}: {}", name, error);
+            },
+        }
+    };
+
+    let mut align = LIBWFA2Alignment::default();
+    for (name, query, reference, expected) in alignment_cases() {
+        let (score, cigar, status) = align.align(query, reference);
+        let error = if !matches!(status, Status::OK) {
+            Some(format!("status {:?}", status))
+        } else if score != expected {
+            Some(format!("score {} instead of {}", score, expected))
+        } else if !is_alignment_valid(query, reference, &cigar.0) || roundtrip_mismatches(query, reference, &cigar.0) > 0 {
+            Some(format!("cigar {} does not explain the query", cigar.to_rle_string()))
+        } else if Cigar::from_rle_str(&cigar.to_rle_string()).as_ref() != Some(cigar) {
+            Some(format!("cigar {} changes in the RLE round-trip", cigar.to_rle_string()))
+        } else {
+            None
+        };
+        report(format!("align {}", name), error);
+    }
+
+    for rle in RLE_CASES {
+        let error = match Cigar::from_rle_str(rle) {
+            Some(cigar) if cigar.to_rle_string() == rle => None,
+            Some(cigar) => Some(format!("encoded as {}", cigar.to_rle_string())),
+            None => Some("cannot be parsed".to_string()),
+        };
+        report(format!("cigar round-trip {}", rle), error);
+    }
+
+    eprintln!("Selftest: {}/{} checks passed", checks - failed, checks);
+    if failed > 0 {
+        exit(9);
+    }
+}
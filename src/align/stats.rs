@@ -5,12 +5,39 @@ use textplots::{Chart, ColorPlot, Plot, Shape};
 
 use crate::GOLDSTD_EVAL;
 
-use super::eval::MapqEvaluation; 
+use super::{common::Status, eval::MapqEvaluation}; 
+
+/// Lower bounds of the soft-clip length bins in the stats output.
+pub const SOFTCLIP_BINS: [usize; 6] = [0, 1, 5, 10, 20, 50];
+
+/// One bin per whole percent of alignment identity, 0 to 100.
+pub const IDENTITY_BINS: usize = 101;
+
+/// Identity thresholds listed below the identity histogram (--identity-histogram).
+pub const IDENTITY_THRESHOLDS: [usize; 7] = [70, 80, 85, 90, 95, 97, 99];
+
 #[derive(Clone, Debug)]
 pub struct Stats {
     pub reads_processed: usize,
     pub kmers_processed: usize,
+    pub kmers_ambiguous: usize,
+    /// Reference k-mers not indexed because of ambiguous bases, see `FlexalignDatabase::ambiguous_kmers`
+    pub reference_kmers_ambiguous: usize,
     pub minimizer: usize,
+    pub minimizer_low_complexity: usize,
+    /// Minimizers skipped because of `--kmer-blacklist`
+    pub minimizer_blacklisted: usize,
+    /// Reads or mates dominated by a single k-mer (`--max-repeat-kmer-fraction`), not seeded
+    pub repeat_reads: usize,
+    pub mates_qc_failed: usize,
+    /// Reads longer than `MAX_READ_LENGTH`, skipped
+    pub reads_oversized: usize,
+    /// Reads without bases, skipped
+    pub reads_empty: usize,
+    /// Mates trimmed and mapped again after their 3' soft-clip matched `--adapter`
+    pub adapter_trimmed: usize,
+    /// Seeds added by aligning mates against `--priority-ref` references
+    pub seeds_rescued: usize,
     pub ranges: usize,
     pub seeds: usize,
     pub anchors: usize,
@@ -18,6 +45,34 @@ pub struct Stats {
     pub alignments_successful: usize,
     pub alignments_partial: usize,
     pub alignments_dropped: usize,
+    pub anchors_unresolved: usize,
+    pub pairs_ambiguous_strand: usize,
+    pub reads_timed_out: usize,
+    pub roundtrip_failures: usize,
+    pub duplicates: usize,
+    pub reads_tied: usize,
+    pub mates_softclipped: usize,
+    pub mates_softclip_filtered: usize,
+    /// Candidate mate placements without a partner on the same reference, dropped by `--strict-pairing`
+    pub mates_unpaired_dropped: usize,
+    /// Soft-clip lengths of reported mates, binned by `SOFTCLIP_BINS`
+    pub softclip_histogram: [usize; SOFTCLIP_BINS.len()],
+    /// Identities of reported aligned mates in whole percent
+    pub identity_histogram: [usize; IDENTITY_BINS],
+    /// Sum of the exact identities of `identity_histogram`, for the mean
+    pub identity_sum: f64,
+    pub pairs_processed: usize,
+    /// Reported mates (or single-end reads) that are not unmapped
+    pub mates_mapped: usize,
+    /// Sum of the MAPQ of `mates_mapped`
+    pub mapq_sum: usize,
+    /// Unambiguous reference bases under reported mates
+    pub mapped_reference_bases: usize,
+    /// G and C among `mapped_reference_bases`
+    pub mapped_reference_gc: usize,
+    pub pairs_proper: usize,
+    /// Reads or pairs with a secondary placement reported (--tie-policy report-both, --report-top-n)
+    pub reads_secondary: usize,
 
     pub time_get_kmers: Duration,
     pub time_get_minimizer: Duration,
@@ -35,6 +90,7 @@ pub struct Stats {
     pub time_alignment: Duration,
 
     pub threads: usize,
+    pub wall_time: Option<Duration>,
 
     pub gold_std_evaluation: Option<MapqEvaluation>,
 }
@@ -79,11 +135,122 @@ impl Stats {
     }
 }
 
+impl Stats {
+    /// Records an attempted alignment by its outcome.
+    pub fn add_alignment(&mut self, status: Status) {
+        self.alignments += 1;
+        match status {
+            Status::OK => self.alignments_successful += 1,
+            Status::Dropped => self.alignments_dropped += 1,
+            Status::Partial => self.alignments_partial += 1,
+        }
+    }
+
+    /// Records the mapped mates of a reported read (pair) with their shared MAPQ.
+    pub fn add_mapped(&mut self, mates: usize, mapq: usize, proper_pair: bool, secondary: bool) {
+        self.mates_mapped += mates;
+        self.mapq_sum += mates * mapq;
+        self.pairs_proper += proper_pair as usize;
+        self.reads_secondary += secondary as usize;
+    }
+
+    /// Records the total soft-clip length of a reported mate. Mates clipped by more than `threshold` are counted separately.
+    pub fn add_softclip(&mut self, length: usize, threshold: usize) {
+        let bin = SOFTCLIP_BINS.iter().rposition(|&lower| length >= lower).unwrap();
+        self.softclip_histogram[bin] += 1;
+        self.mates_softclipped += (length > threshold) as usize;
+    }
+
+    /// Records the reference bases under a reported mate, for the GC content of the mapped regions.
+    pub fn add_reference_gc(&mut self, span: &[u8]) {
+        for base in span.iter().map(u8::to_ascii_uppercase) {
+            self.mapped_reference_bases += matches!(base, b'A' | b'C' | b'G' | b'T') as usize;
+            self.mapped_reference_gc += matches!(base, b'C' | b'G') as usize;
+        }
+    }
+
+    /// GC content in percent of the reference bases under reported mates.
+    pub fn mapped_gc_percent(&self) -> f64 {
+        100.0 * self.mapped_reference_gc as f64 / max(self.mapped_reference_bases, 1) as f64
+    }
+
+    /// Records the identity (0 to 1) of a reported aligned mate.
+    pub fn add_identity(&mut self, identity: f64) {
+        let bin = (identity.clamp(0.0, 1.0) * 100.0).floor() as usize;
+        self.identity_histogram[bin] += 1;
+        self.identity_sum += identity.clamp(0.0, 1.0);
+    }
+
+    /// Short overview of the run in the spirit of `samtools flagstat` (--summary).
+    pub fn summary(&self) -> String {
+        let percent = |n: usize, total: usize| 100.0 * n as f64 / max(total, 1) as f64;
+        let aligned: usize = self.identity_histogram.iter().sum();
+        let mut summary = format!("{:.<44}{}\n", "Reads", self.reads_processed);
+        summary += &format!("{:.<44}{} ({:.2}%)\n", "Mapped", self.mates_mapped, percent(self.mates_mapped, self.reads_processed));
+        if self.pairs_processed > 0 {
+            summary += &format!("{:.<44}{} ({:.2}%)\n", "Properly paired (pairs)", self.pairs_proper, percent(self.pairs_proper, self.pairs_processed));
+        }
+        summary += &format!("{:.<44}{} ({:.2}%)\n", "With secondary placement", self.reads_secondary,
+            percent(self.reads_secondary, if self.pairs_processed > 0 { self.pairs_processed } else { self.reads_processed }));
+        summary += &format!("{:.<44}{:.2}%\n", "Mean identity (aligned mates)", 100.0 * self.identity_sum / max(aligned, 1) as f64);
+        summary += &format!("{:.<44}{:.1}\n", "Mean MAPQ (mapped)", self.mapq_sum as f64 / max(self.mates_mapped, 1) as f64);
+        summary += &format!("{:.<44}{:.2}%\n", "GC content (mapped reference)", self.mapped_gc_percent());
+        summary += &format!("{:.<44}{}\n", "Ambiguous k-mers skipped (reads)", self.kmers_ambiguous);
+        summary += &format!("{:.<44}{}\n", "Ambiguous k-mers skipped (reference)", self.reference_kmers_ambiguous);
+        summary
+    }
+
+    /// Share of mates at or above each of `IDENTITY_THRESHOLDS`, to pick --min-ani without a gold standard.
+    pub fn identity_summary(&self) -> String {
+        let total: usize = self.identity_histogram.iter().sum();
+        let mut summary = format!("Identity of reported aligned mates ({} mates)\n", total);
+        for threshold in IDENTITY_THRESHOLDS {
+            let above: usize = self.identity_histogram[threshold..].iter().sum();
+            summary += &format!("{:.<44}{:.2}%\n", format!(">= {}%", threshold), 100.0 * above as f64 / max(total, 1) as f64);
+        }
+        summary
+    }
+
+    /// Plots the identity histogram, mates per percent of identity, from the lowest filled bin.
+    pub fn plot_identity(&self) {
+        let lowest = match self.identity_histogram.iter().position(|&count| count > 0) {
+            Some(lowest) => lowest,
+            None => return,
+        };
+        let points: Vec<(f32, f32)> = (lowest..IDENTITY_BINS)
+            .map(|bin| (bin as f32, self.identity_histogram[bin] as f32))
+            .collect();
+        Chart::new(200, 60, lowest as f32, IDENTITY_BINS as f32)
+            .lineplot(&Shape::Bars(&points))
+            .edisplay();
+    }
+}
+
 impl Merge for Stats {
     fn merge_from(&mut self, other: &mut Self) {
+        self.add(other);
+        // Every state accounts for at least the thread that produced it.
+        self.threads = max(self.threads, 1) + max(other.threads, 1);
+    }
+}
+
+impl Stats {
+    /// Adds the counts of `other`, e.g. of one pass over a pair (`--adapter`). The thread count is kept.
+    pub fn add(&mut self, other: &mut Self) {
         self.reads_processed += other.reads_processed;
         self.kmers_processed += other.kmers_processed;
+        // A property of the index, the same for every input.
+        self.reference_kmers_ambiguous = max(self.reference_kmers_ambiguous, other.reference_kmers_ambiguous);
         self.minimizer += other.minimizer;
+        self.minimizer_low_complexity += other.minimizer_low_complexity;
+        self.minimizer_blacklisted += other.minimizer_blacklisted;
+        self.repeat_reads += other.repeat_reads;
+        self.mates_qc_failed += other.mates_qc_failed;
+        self.reads_oversized += other.reads_oversized;
+        self.reads_empty += other.reads_empty;
+        self.adapter_trimmed += other.adapter_trimmed;
+        self.seeds_rescued += other.seeds_rescued;
+        self.kmers_ambiguous += other.kmers_ambiguous;
 
         self.time_reverse_complement += other.time_reverse_complement;
         self.time_extend_anchors += other.time_extend_anchors;
@@ -109,7 +276,29 @@ impl Merge for Stats {
         self.alignments_successful += other.alignments_successful;
         self.alignments_partial += other.alignments_partial;
         self.alignments_dropped += other.alignments_dropped;
-        self.threads += 1;
+        self.anchors_unresolved += other.anchors_unresolved;
+        self.pairs_ambiguous_strand += other.pairs_ambiguous_strand;
+        self.reads_timed_out += other.reads_timed_out;
+        self.roundtrip_failures += other.roundtrip_failures;
+        self.duplicates += other.duplicates;
+        self.reads_tied += other.reads_tied;
+        self.mates_softclipped += other.mates_softclipped;
+        self.mates_softclip_filtered += other.mates_softclip_filtered;
+        self.mates_unpaired_dropped += other.mates_unpaired_dropped;
+        for (bin, count) in self.softclip_histogram.iter_mut().zip(other.softclip_histogram) {
+            *bin += count;
+        }
+        for (bin, count) in self.identity_histogram.iter_mut().zip(other.identity_histogram) {
+            *bin += count;
+        }
+        self.identity_sum += other.identity_sum;
+        self.pairs_processed += other.pairs_processed;
+        self.mates_mapped += other.mates_mapped;
+        self.mapq_sum += other.mapq_sum;
+        self.mapped_reference_bases += other.mapped_reference_bases;
+        self.mapped_reference_gc += other.mapped_reference_gc;
+        self.pairs_proper += other.pairs_proper;
+        self.reads_secondary += other.reads_secondary;
 
         if self.gold_std_evaluation.is_some() && other.gold_std_evaluation.is_some() {
             self.gold_std_evaluation.as_mut().unwrap().merge_from(&mut other.gold_std_evaluation.as_mut().unwrap());
@@ -117,58 +306,152 @@ impl Merge for Stats {
     }
 }
 
+impl Stats {
+    /// Average of a summed per-thread duration bucket.
+    pub fn per_thread(&self, duration: Duration) -> Duration {
+        duration / max(self.threads, 1) as u32
+    }
+
+    /// Sum of all top-level timing buckets across all threads (nested buckets prefixed with '....' are excluded).
+    pub fn cpu_time(&self) -> Duration {
+        self.time_reverse_complement +
+        self.time_get_kmers +
+        self.time_get_ranges +
+        self.time_range_sorting +
+        self.time_range_header +
+        self.time_seed_sorting +
+        self.time_get_anchors +
+        self.time_anchor_sorting +
+        self.time_extend_anchors +
+        self.time_offset +
+        self.time_checking_anchors +
+        self.time_alignment
+    }
+}
+
+impl Stats {
+    /// One tab-separated `key=value` line for --benchmark: wall-clock seconds and throughput per wall-clock second.
+    pub fn benchmark_line(&self, wall_time: Duration) -> String {
+        let seconds = wall_time.as_secs_f64().max(f64::EPSILON);
+        format!("benchmark\twall_s={:.3}\treads={}\treads_per_s={:.1}\tminimizers_per_s={:.1}\tseeds_per_s={:.1}\tanchors_per_s={:.1}\talignments_per_s={:.1}",
+            seconds,
+            self.reads_processed,
+            self.reads_processed as f64 / seconds,
+            self.minimizer as f64 / seconds,
+            self.seeds as f64 / seconds,
+            self.anchors as f64 / seconds,
+            self.alignments as f64 / seconds)
+    }
+}
+
 impl Display for Stats {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let timings = [
+            ("Time for getting reverse complement.........", self.time_reverse_complement),
+            ("Time for getting kmers......................", self.time_get_kmers),
+            ("....Time for getting minimizers.............", self.time_get_minimizer),
+            ("Time for getting ranges.....................", self.time_get_ranges),
+            ("....Time for getting vranges................", self.time_get_vranges),
+            ("Time for sorting ranges.....................", self.time_range_sorting),
+            ("Time for getting range headers..............", self.time_range_header),
+            ("Time for sorting seeds......................", self.time_seed_sorting),
+            ("Time for getting anchors....................", self.time_get_anchors),
+            ("Time for sorting anchors....................", self.time_anchor_sorting),
+            ("Time for extending anchors..................", self.time_extend_anchors),
+            ("Time for calculating offsets................", self.time_offset),
+            ("Time for checking anchors...................", self.time_checking_anchors),
+            ("Time for alignment..........................", self.time_alignment),
+        ];
+
+        // Every bucket is summed over all threads. Both the total CPU time and the per-thread average are shown.
+        let mut timing_str = format!("Timings (CPU time summed over {} threads / average per thread)\n", self.threads);
+        for (label, duration) in timings {
+            timing_str += &format!("{}{:?} / {:?}\n", label, duration, self.per_thread(duration));
+        }
+
+        let cpu_time = self.cpu_time();
+        timing_str += &format!("Total CPU time..............................{:?} / {:?}\n", cpu_time, self.per_thread(cpu_time));
+        if let Some(wall_time) = self.wall_time {
+            timing_str += &format!("Wall-clock time.............................{:?}\n", wall_time);
+            timing_str += &format!("Parallel efficiency.........................{:.2}%\n",
+                100.0 * cpu_time.as_secs_f64() / (wall_time.as_secs_f64() * max(self.threads, 1) as f64));
+        }
+
+        let mut softclip_str = "Soft-clip length distribution of reported mates\n".to_string();
+        for (i, lower) in SOFTCLIP_BINS.iter().enumerate() {
+            let range = match SOFTCLIP_BINS.get(i + 1) {
+                Some(next) if next - lower > 1 => format!("{}-{}", lower, next - 1),
+                Some(_) => lower.to_string(),
+                None => format!("{}+", lower),
+            };
+            softclip_str += &format!("{:.<44}{}\n", range, self.softclip_histogram[i]);
+        }
+
         write!(f, 
             "\
-            Time for getting reverse complement.........{:?}\n\
-            Time for getting kmers......................{:?}\n\
-            ....Time for getting minimizers.............{:?}\n\
-            Time for getting ranges.....................{:?}\n\
-            ....Time for getting vranges................{:?}\n\
-            Time for sorting ranges.....................{:?}\n\
-            Time for getting range headers..............{:?}\n\
-            Time for sorting seeds......................{:?}\n\
-            Time for getting anchors....................{:?}\n\
-            Time for sorting anchors....................{:?}\n\
-            Time for extending anchors..................{:?}\n\
-            Time for calculating offsets................{:?}\n\
-            Time for checking anchors...................{:?}\n\
-            Time for alignment..........................{:?}\n\n\
+            {}\n\
             Total Reads.................................{:?}\n\
             Total Alignments............................{:?}\n\
             Total Alignments successful.................{:?}\n\
             Total Alignments partial....................{:?}\n\
             Total Alignments dropped....................{:?}\n\
+            Total Anchors unresolved strand.............{:?}\n\
+            Total Pairs with ambiguous strand...........{:?}\n\
+            Total Read pairs timed out..................{:?}\n\
+            Total Roundtrip failures....................{:?}\n\
+            Total Read pairs marked duplicate...........{:?}\n\
+            Total Reads with tied best anchors..........{:?}\n\
+            Total Mates above soft-clip threshold.......{:?}\n\
+            Total Mates filtered by --max-softclip......{:?}\n\
+            Total Unpaired mate placements dropped......{:?}\n\
             Total Minimizers per read...................{:.2}x\n\
+            Total Low-complexity minimizers skipped.....{:?}\n\
+            Total Blacklisted minimizers skipped........{:?}\n\
+            Total Repeat reads or mates not seeded......{:?}\n\
+            Total Mates failing QC......................{:?}\n\
+            Total Reads too long (skipped)..............{:?}\n\
+            Total Empty reads (skipped).................{:?}\n\
+            Total Mates adapter trimmed.................{:?}\n\
+            Total Mates rescued on priority references..{:?}\n\
+            Total Ambiguous k-mers skipped..............{:?}\n\
+            Total Ambiguous reference k-mers............{:?}\n\
+            Total GC content of mapped reference........{:.2}%\n\
             Total Ranges per read.......................{:.2}x\n\
             Total Seeds per read........................{:.2}x\n\
             Total Anchors per read......................{:.2}x\n\
             Total Alignments per read...................{:.2}x\n\
             Total Alignments success per read...........{:.2}x\n\
             Total Alignments partial per read...........{:.2}x\n\
-            Total Alignments dropped per read...........{:.2}x\
+            Total Alignments dropped per read...........{:.2}x\n\n\
+            {}\
             {}",
-            self.time_reverse_complement / self.threads as u32,
-            self.time_get_kmers / self.threads as u32,
-            self.time_get_minimizer / self.threads as u32,
-            self.time_get_ranges / self.threads as u32,
-            self.time_get_vranges / self.threads as u32,
-            self.time_range_sorting / self.threads as u32,
-            self.time_range_header / self.threads as u32,
-            self.time_seed_sorting / self.threads as u32,
-            self.time_get_anchors / self.threads as u32,
-            self.time_anchor_sorting / self.threads as u32,
-            self.time_extend_anchors / self.threads as u32,
-            self.time_offset / self.threads as u32,
-            self.time_checking_anchors / self.threads as u32,
-            self.time_alignment / self.threads as u32,
+            timing_str,
             self.reads_processed,
             self.alignments,
             self.alignments_successful,
             self.alignments_partial,
             self.alignments_dropped,
+            self.anchors_unresolved,
+            self.pairs_ambiguous_strand,
+            self.reads_timed_out,
+            self.roundtrip_failures,
+            self.duplicates,
+            self.reads_tied,
+            self.mates_softclipped,
+            self.mates_softclip_filtered,
+            self.mates_unpaired_dropped,
             self.minimizer as f64 / self.reads_processed as f64,
+            self.minimizer_low_complexity,
+            self.minimizer_blacklisted,
+            self.repeat_reads,
+            self.mates_qc_failed,
+            self.reads_oversized,
+            self.reads_empty,
+            self.adapter_trimmed,
+            self.seeds_rescued,
+            self.kmers_ambiguous,
+            self.reference_kmers_ambiguous,
+            self.mapped_gc_percent(),
             self.ranges as f64 / self.reads_processed as f64,
             self.seeds as f64 / self.reads_processed as f64,
             self.anchors as f64 / self.reads_processed as f64,
@@ -176,6 +459,7 @@ impl Display for Stats {
             self.alignments_successful as f64 / self.reads_processed as f64,
             self.alignments_partial as f64 / self.reads_processed as f64,
             self.alignments_dropped as f64 / self.reads_processed as f64,
+            softclip_str,
             if self.gold_std_evaluation.is_some() {
                 "\n\n".to_string() + &self.gold_std_evaluation.as_ref().unwrap().to_string()
             } else { 
@@ -189,7 +473,17 @@ impl Default for Stats {
         Self {
             reads_processed: 0,
             kmers_processed: 0,
+            kmers_ambiguous: 0,
+            reference_kmers_ambiguous: 0,
             minimizer: 0,
+            minimizer_low_complexity: 0,
+            minimizer_blacklisted: 0,
+            repeat_reads: 0,
+            mates_qc_failed: 0,
+            reads_oversized: 0,
+            reads_empty: 0,
+            adapter_trimmed: 0,
+            seeds_rescued: 0,
             ranges: 0,
             seeds: 0,
             anchors: 0,
@@ -197,6 +491,25 @@ impl Default for Stats {
             alignments_successful: 0,
             alignments_partial: 0,
             alignments_dropped: 0,
+            anchors_unresolved: 0,
+            pairs_ambiguous_strand: 0,
+            reads_timed_out: 0,
+            roundtrip_failures: 0,
+            duplicates: 0,
+            reads_tied: 0,
+            mates_softclipped: 0,
+            mates_softclip_filtered: 0,
+            mates_unpaired_dropped: 0,
+            softclip_histogram: [0; SOFTCLIP_BINS.len()],
+            identity_histogram: [0; IDENTITY_BINS],
+            identity_sum: 0.0,
+            pairs_processed: 0,
+            mates_mapped: 0,
+            mapq_sum: 0,
+            mapped_reference_bases: 0,
+            mapped_reference_gc: 0,
+            pairs_proper: 0,
+            reads_secondary: 0,
 
             time_reverse_complement: Duration::default(),
             time_extend_anchors: Duration::default(),
@@ -213,9 +526,77 @@ impl Default for Stats {
             time_get_anchors: Duration::default(),
             time_alignment: Duration::default(),
             
-            threads: 0,
+            threads: 1,
+            wall_time: None,
 
             gold_std_evaluation: if GOLDSTD_EVAL { Some(MapqEvaluation::default()) } else { None },
         }
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_counts_threads() {
+        for n in 1..5 {
+            let mut base = Stats::default();
+            for _ in 1..n {
+                base.merge_from(&mut Stats::default());
+            }
+            assert_eq!(base.threads, n);
+        }
+    }
+
+    #[test]
+    fn alignments_add_up() {
+        let mut stats = Stats::default();
+        for status in [Status::OK, Status::OK, Status::Partial, Status::Dropped] {
+            stats.add_alignment(status);
+        }
+        let mut other = Stats::default();
+        other.add_alignment(Status::Dropped);
+        stats.merge_from(&mut other);
+
+        assert_eq!(stats.alignments, 5);
+        assert_eq!(stats.alignments, stats.alignments_successful + stats.alignments_partial + stats.alignments_dropped);
+    }
+
+    #[test]
+    fn gc_content_of_mapped_reference_adds_up() {
+        let mut stats = Stats::default();
+        stats.add_reference_gc(b"ACGTNNgc");
+        let mut other = Stats::default();
+        other.add_reference_gc(b"AATT");
+        stats.merge_from(&mut other);
+
+        assert_eq!((stats.mapped_reference_bases, stats.mapped_reference_gc), (10, 4));
+        assert!((stats.mapped_gc_percent() - 40.0).abs() < 1e-9);
+        assert!(stats.summary().contains("GC content (mapped reference)...............40.00%"));
+        assert_eq!(Stats::default().mapped_gc_percent(), 0.0);
+    }
+
+    #[test]
+    fn ambiguous_kmers_are_summarized() {
+        let mut stats = Stats { kmers_ambiguous: 3, reference_kmers_ambiguous: 40, ..Stats::default() };
+        let mut other = Stats { kmers_ambiguous: 2, reference_kmers_ambiguous: 40, ..Stats::default() };
+        stats.merge_from(&mut other);
+
+        assert_eq!((stats.kmers_ambiguous, stats.reference_kmers_ambiguous), (5, 40));
+        let summary = stats.summary();
+        assert!(summary.contains("Ambiguous k-mers skipped (reads)............5\n"));
+        assert!(summary.contains("Ambiguous k-mers skipped (reference)........40\n"));
+    }
+
+    #[test]
+    fn benchmark_line_reports_rates_per_second() {
+        let stats = Stats { reads_processed: 1000, minimizer: 5000, seeds: 3000, anchors: 2000, alignments: 1500, ..Stats::default() };
+        assert_eq!(stats.benchmark_line(Duration::from_millis(500)),
+            "benchmark\twall_s=0.500\treads=1000\treads_per_s=2000.0\tminimizers_per_s=10000.0\tseeds_per_s=6000.0\tanchors_per_s=4000.0\talignments_per_s=3000.0");
+
+        // A zero wall time does not divide by zero.
+        assert!(Stats::default().benchmark_line(Duration::ZERO).starts_with("benchmark\twall_s=0.000\treads=0\treads_per_s=0.0\t"));
+    }
+}
@@ -1,7 +1,7 @@
 
 use std::{cmp::{max, min}, io::stdin, mem::swap};
 
-use super::{super::GOLDSTD_EVAL, common::{KmerExtractor}, stats::Stats};
+use super::{super::GOLDSTD_EVAL, common::{sort_by_key, sort_unstable_by_key, AnchorScore, KmerExtractor, StdAnchorScore}, process::{anchor_extractor::ColinearChainer, kmer_extractor::complexity, seed_extractor::RECOVERY_MAX_BEST_FLEX}, stats::Stats};
 use bioreader::sequence::fastq_record::{print_color_qualities, OwnedFastqRecord, RefFastqRecord};
 use flexmap::{values::{VData, VRange}, VD};
 use kmerrs::{consecutive::kmer::{Kmer, KmerIter}, minimizer::context_free::Minimizer};
@@ -26,7 +26,7 @@ pub struct Standard<
 > {
     pub options: &'a Options,
 
-    pub ranges: Vec<(usize, Kmer<F>, VRange<'a>, usize)>,
+    pub ranges: Vec<(usize, Kmer<F>, VRange<'a>, usize, bool)>,
     pub seeds: Vec<Seed>,
     pub anchors: Vec<Anchor>,
     pub indices: Vec<usize>,
@@ -42,6 +42,8 @@ pub struct Standard<
     pub rev_rec: OwnedFastqRecord,
 
     pub count_difficult_anchors: usize,
+    /// Scratch of `--chaining`
+    pub chainer: ColinearChainer,
 
     // pub range_iteration_order: Vec<(usize, usize)>,
     // pub seeds: Vec<(usize, VCell)>,
@@ -81,6 +83,7 @@ impl<
             rev_rec: OwnedFastqRecord::new(),
 
             count_difficult_anchors: 0,
+            chainer: ColinearChainer::default(),
         }
     }
 
@@ -110,10 +113,14 @@ impl<
         });
         stats.time_get_anchors += duration;
 
+        if self.options.args.chaining {
+            let chainer = &mut self.chainer;
+            self.anchors.iter_mut().for_each(|a| a.score = chainer.score(&a.seeds));
+        }
+
+        let scorer = StdAnchorScore::from_options(self.options);
         let (duration, _) = time(|| {
-            self.anchors.sort_unstable_by_key(|a| {
-                - ((a.core_matches() - a.mismatches as usize - a.indels()) as i64)
-            });
+            sort_unstable_by_key(&mut self.anchors, |a| - scorer.score(a), self.options.args.sort_impl);
         });
         stats.time_anchor_sorting += duration;
 
@@ -152,6 +159,17 @@ impl<
         // eprintln!("{}", self.count_difficult_anchors as f64 / stats.anchors as f64);
 
 
+        // Never report an anchor with an arbitrary strand.
+        let best = self.anchors.first_mut().unwrap();
+        let resolved = match self.db.get_reference(best.reference as usize) {
+            Some(reference) => best.resolve_orientation(rec, &self.rev_rec, reference),
+            None => best.orientation_set,
+        };
+        if !resolved {
+            stats.anchors_unresolved += 1;
+            return
+        }
+
         let best = self.anchors.first().unwrap();
         let ref_string = &self.db.get_rname(best.reference as usize).unwrap();
         let reference = &self.db.get_reference(best.reference as usize).unwrap();
@@ -254,9 +272,9 @@ impl<
         
         let reference = &self.db.get_reference(anchor.reference as usize).unwrap();
 
-        if anchor.seeds.first().unwrap().rpos as usize > reference.len() {
-            eprintln!("Seed and anchor are invalid\n{}", anchor.to_string());
-            panic!("Not good");
+        if let Err(why) = anchor.check_seed_bounds(rec_fwd.seq().len(), reference.len()) {
+            eprintln!("Seed and anchor are invalid: {}\n{}", why, anchor.to_string());
+            return false
         }
 
         let mut _sane = true;
@@ -471,7 +489,7 @@ impl<
 
         let mut matches = 0;
         let mut discarded_max_flex_count = 0;
-        for (qpos, flex, range, _range_size) in &self.ranges {
+        for (qpos, flex, range, _range_size, palindromic) in &self.ranges {
             match range.header {
                 Some(headers) => {
                     let mut min_dist = u32::MAX;
@@ -501,7 +519,7 @@ impl<
                         if dist == min_dist {
                             // self.seeds.push((*pos, range.positions[index].clone()))
                             let (value, rpos) = VD::get(range.positions[index].0);
-                            self.seeds.push(Seed::from_flexmer::<K,C,F>(*qpos, rpos, value, dist));
+                            self.seeds.push(Seed::from_flexmer::<K,C,F>(*qpos, rpos, value, dist).with_palindromic(*palindromic));
                             // eprintln!("H Push: {} {} {}", self.seeds.last().unwrap().to_string(), *pos, rpos);
                         }
                     }
@@ -510,7 +528,7 @@ impl<
                     for cell in range.positions {
                         // self.seeds.push((*pos, cell.clone()));
                         let (value, rpos) = VD::get(cell.0);
-                        self.seeds.push(Seed::from_coremer::<K,C,F>(*qpos, rpos, value));
+                        self.seeds.push(Seed::from_coremer::<K,C,F>(*qpos, rpos, value).with_palindromic(*palindromic));
                         // eprintln!("N Push: {} {} {}", self.seeds.last().unwrap().to_string(), *pos, rpos);
                     }
                 },
@@ -537,7 +555,7 @@ impl<
                 // eprintln!("----------------- Recover Ranges....");
                 let old_ranges = ranges;
                 let (ranges, discarded_max_flex_count) = self.retrieve_seeds(
-                    128,
+                    RECOVERY_MAX_BEST_FLEX,
                     self.options.args.ranges as usize,
                     stats
                 );
@@ -550,9 +568,9 @@ impl<
         stats.seeds += self.seeds.len();
 
         let (duration, _) = time(|| {
-            glidesort::sort_by_key(&mut self.seeds, |seed: &Seed| {
+            sort_by_key(&mut self.seeds, |seed: &Seed| {
                 (seed.rval, seed.rpos)//, seed.length) // seed.offset
-            });
+            }, self.options.args.sort_impl);
 
             // self.seeds.sort_unstable_by_key(|seed| {
             //     (seed.rval, seed.rpos)//, seed.length) // seed.offset
@@ -587,6 +605,11 @@ impl<
 
             stats.minimizer += 1;
 
+            if self.options.args.min_complexity > 0.0 && complexity::<C>(cmer.0) < self.options.args.min_complexity {
+                stats.minimizer_low_complexity += 1;
+                continue;
+            }
+
             let fmer = kmer.flanks::<F>();
 
             let (duration, result) = time(|| self.db.get_vrange(cmer.0));
@@ -597,10 +620,9 @@ impl<
                 None => continue,
             };
             let range_len = (&range).positions.len();
-            self.ranges.push((pos, fmer, range, range_len));
-
-            // eprintln!("{}: {} / {} -> {} .. Is own rc? {}", 
-            //     pos, cmer.to_string().unwrap(), cmer.rc().to_string().unwrap(), range_len, cmer.is_own_rc());
+            if range_len > self.options.args.query_max_range_size.unwrap_or(usize::MAX) { continue };
+            // cmer_fwd == cmer_rev: the seeds of this range cannot tell the strand. Only possible for even C.
+            self.ranges.push((pos, fmer, range, range_len, C % 2 == 0 && cmer.is_own_rc()));
         }
 
 
@@ -644,7 +666,7 @@ impl<
 
     pub fn sort_ranges(&mut self, _stats: &mut Stats) {
         // Faster than glidesort in this example
-        self.ranges.sort_unstable_by_key(|r| r.2.positions.len());
+        sort_unstable_by_key(&mut self.ranges, |r| r.2.positions.len(), self.options.args.sort_impl);
     }
 
     pub fn get_anchors(&mut self, _stats: &mut Stats) {
@@ -1,11 +1,25 @@
-use std::{collections::HashMap, io::Read, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+use std::{collections::{HashMap, HashSet}, fs::File, io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write}, ops::Range, path::{Path, PathBuf}, process::exit, sync::{Arc, Mutex}};
 use bioreader::{fasta_byte_reader::FastaByteReader, fasta_reader::FastaReader, sequence::fasta_record::OwnedFastaRecord};
 use flexmap::values::VRange;
-use crate::options::Options;
+use crate::{options::{Options, ReferenceOrder}, utils::read_lines_from_file};
 
 const INDEX_EXTENSION: &str = ".flex.index";
 const ID2REF_MAP_EXTENSION: &str = ".flex.id2ref";
 const REF2ID_MAP_EXTENSION: &str = ".flex.ref2id";
+const REFERENCE_INFO_EXTENSION: &str = ".flex.refinfo";
+const LAYOUT_EXTENSION: &str = ".flex.layout";
+const BUILD_PARAMS_EXTENSION: &str = ".flex.build";
+
+/// (cells per body, header threshold) combinations flexalign is compiled for (`--cells-per-body`, `--header-threshold`).
+pub const SUPPORTED_INDEX_LAYOUTS: [(u64, usize); 4] = [(16, 2), (8, 2), (32, 2), (16, 4)];
+
+/// Layout of indexes written before the layout was recorded.
+pub const DEFAULT_INDEX_LAYOUT: (u64, usize) = (16, 2);
+
+/// Index cells pack the reference id into 20 and the position within the reference into 40 bits
+/// (`VData<20, 40>`), so an index holds at most 2^20 references of at most 2^40 bases each.
+pub const MAX_REFERENCES: usize = 1 << 20;
+pub const MAX_REFERENCE_LENGTH: u64 = 1 << 40;
 
 
 pub struct DBPaths {
@@ -13,6 +27,43 @@ pub struct DBPaths {
     pub index_path: PathBuf,
     pub reference2id_path: PathBuf,
     pub id2reference_path: PathBuf,
+    /// Optional per-reference (length, GC content) table, see `--reference-gc`
+    pub reference_info_path: PathBuf,
+    /// Cells per body and header threshold the index was built with
+    pub layout_path: PathBuf,
+    /// Options that select the indexed references, see `BuildParams`
+    pub build_params_path: PathBuf,
+}
+
+/// Options that change which references an index holds and their ids. The index files are named after the
+/// reference alone, so they are stored next to them to tell a matching index from one built with other options.
+#[derive(Clone, Debug, PartialEq, Eq, Savefile)]
+pub struct BuildParams {
+    /// Sorted reference names of `--include-ref-file`, empty for the whole reference
+    pub included_references: Vec<String>,
+    /// Name of the `--reference-order`, which assigns the reference ids
+    pub reference_order: String,
+    /// `--drop-short-refs`
+    pub drop_short_refs: bool,
+}
+
+impl Default for BuildParams {
+    fn default() -> Self {
+        Self { included_references: Vec::new(), reference_order: format!("{:?}", ReferenceOrder::Fasta), drop_short_refs: false }
+    }
+}
+
+impl BuildParams {
+    pub fn from_options(options: &Options) -> Self {
+        let mut included_references: Vec<String> = options.args.include_ref_file.as_ref()
+            .map_or_else(Vec::new, |path| include_ref_names(path).into_iter().collect());
+        included_references.sort_unstable();
+        Self {
+            included_references,
+            reference_order: format!("{:?}", options.args.reference_order),
+            drop_short_refs: options.args.drop_short_refs,
+        }
+    }
 }
 
 impl DBPaths {
@@ -25,12 +76,18 @@ impl DBPaths {
         let index_path = PathBuf::from(reference_path.as_ref().display().to_string() + INDEX_EXTENSION);
         let id2reference_path = PathBuf::from(reference_path.as_ref().display().to_string() + ID2REF_MAP_EXTENSION);
         let reference2id_path = PathBuf::from(reference_path.as_ref().display().to_string() + REF2ID_MAP_EXTENSION);
+        let reference_info_path = PathBuf::from(reference_path.as_ref().display().to_string() + REFERENCE_INFO_EXTENSION);
+        let layout_path = PathBuf::from(reference_path.as_ref().display().to_string() + LAYOUT_EXTENSION);
+        let build_params_path = PathBuf::from(reference_path.as_ref().display().to_string() + BUILD_PARAMS_EXTENSION);
         
         DBPaths {
             reference_path: reference_path.as_ref().to_path_buf(),
             index_path,
             reference2id_path,
             id2reference_path,
+            reference_info_path,
+            layout_path,
+            build_params_path,
         }
     }
 
@@ -40,18 +97,317 @@ impl DBPaths {
         Path::exists(&self.reference2id_path) &
         Path::exists(&self.id2reference_path)
     }
+
+    /// Whether the reference was modified after the index was written. Unknown modification times count as fresh.
+    pub fn is_stale(&self) -> bool {
+        let modified = |path: &PathBuf| path.metadata().and_then(|m| m.modified()).ok();
+        match (modified(&self.reference_path), modified(&self.index_path)) {
+            (Some(reference), Some(index)) => reference > index,
+            _ => false,
+        }
+    }
+
+    pub fn save_layout(&self, version: u32, layout: (u64, usize)) {
+        let mut file = match File::create(&self.layout_path) {
+            Err(why) => panic!("couldn't open {}: {}", self.layout_path.display(), why),
+            Ok(file) => file,
+        };
+        let _ = savefile::save(&mut file, version, &(layout.0, layout.1 as u64));
+    }
+
+    /// Exits with 9 if the index was built with another layout than `layout`. Indexes without a layout file
+    /// have `DEFAULT_INDEX_LAYOUT`.
+    pub fn check_layout(&self, version: u32, layout: (u64, usize)) {
+        let stored = match File::open(&self.layout_path) {
+            Ok(mut file) => {
+                let (cells, header): (u64, u64) = savefile::load(&mut file, version).expect("Valid layout file");
+                (cells, header as usize)
+            },
+            Err(_) => DEFAULT_INDEX_LAYOUT,
+        };
+        if stored != layout {
+            eprintln!("Index {} was built with --cells-per-body {} --header-threshold {}, but {} {} were given. Pass the build values or --force-build.",
+                self.index_path.display(), stored.0, stored.1, layout.0, layout.1);
+            exit(9);
+        }
+    }
+
+    pub fn save_build_params(&self, version: u32, params: &BuildParams) {
+        let mut file = match File::create(&self.build_params_path) {
+            Err(why) => panic!("couldn't open {}: {}", self.build_params_path.display(), why),
+            Ok(file) => file,
+        };
+        let _ = savefile::save(&mut file, version, params);
+    }
+
+    /// Whether the index was built with `params`. Indexes without a parameter file were built with the default
+    /// parameters, an unreadable file matches nothing.
+    pub fn build_params_match(&self, version: u32, params: &BuildParams) -> bool {
+        match File::open(&self.build_params_path) {
+            Ok(mut file) => savefile::load::<BuildParams>(&mut file, version).is_ok_and(|stored| stored == *params),
+            Err(_) => *params == BuildParams::default(),
+        }
+    }
 }
 
 pub trait FlexalignDatabase {
     fn get_rid(&self, reference: &str) -> Option<&usize>;
     fn get_rname(&self, id: usize) -> Option<&str>;
+    /// None if the reference does not exist or its bases were not loaded (`--no-reference-seqs`).
     fn get_reference(&self, id: usize) -> Option<&[u8]>;
+    fn get_reference_length(&self, id: usize) -> Option<usize>;
+    fn reference_count(&self) -> usize;
+    /// Reference k-mers that are not indexed because of ambiguous bases, 0 without loaded sequences.
+    fn ambiguous_kmers(&self) -> usize;
     fn get_vrange(&self, canonical_kmer: u64) -> Option<VRange>;
     fn build(options: &Options) -> Self;
     fn save(&self, paths: &DBPaths, version: u32) -> Result<(), std::io::Error>;
-    fn load(paths: &DBPaths, version: u32) -> Self;
+    /// Without `sequences` only names and lengths of the references are kept.
+    fn load(paths: &DBPaths, version: u32, sequences: bool) -> Self;
+}
+
+
+/// Whether the sequence contains a base other than A, C, G or T (in either case).
+pub fn is_ambiguous(seq: &[u8]) -> bool {
+    seq.iter().any(|b| !matches!(b, b'A' | b'C' | b'G' | b'T' | b'a' | b'c' | b'g' | b't'))
+}
+
+/// Number of k-mer windows of the sequence that contain at least one ambiguous base. These positions are not
+/// indexed, and the k-mer extractor skips the same windows on the query side.
+pub fn ambiguous_kmer_count<const K: usize>(seq: &[u8]) -> usize {
+    if seq.len() < K { return 0 };
+
+    // Position after the most recent ambiguous base
+    let mut clean_from = 0;
+    let mut count = 0;
+    for (i, base) in seq.iter().enumerate() {
+        if is_ambiguous(std::slice::from_ref(base)) {
+            clean_from = i + 1;
+        }
+        if i + 1 >= K && clean_from > i + 1 - K {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Checks that the references fit the cell packing before indexing. Larger inputs would silently
+/// truncate ids or positions, so they are rejected.
+pub fn check_addressable(reference: &Path) {
+    let input = match File::open(reference) {
+        Err(why) => panic!("couldn't open {}: {}", reference.display(), why),
+        Ok(file) => BufReader::new(file),
+    };
+
+    if let Some(error) = addressing_error(input) {
+        eprintln!("{}: {}", reference.display(), error);
+        exit(9);
+    }
+}
+
+/// Why the FASTA `input` does not fit the cell packing, if it does not.
+fn addressing_error(input: impl BufRead) -> Option<String> {
+    let (mut count, mut length, mut longest) = (0usize, 0u64, (0u64, String::new()));
+    let mut name = String::new();
+    for line in input.lines() {
+        let line = line.expect("Valid reference file");
+        if let Some(header) = line.strip_prefix('>') {
+            if length > longest.0 { longest = (length, name.clone()) };
+            name = header.split(' ').next().unwrap().to_string();
+            count += 1;
+            length = 0;
+        } else {
+            length += line.trim_end().len() as u64;
+        }
+    }
+    if length > longest.0 { longest = (length, name) };
+
+    if count > MAX_REFERENCES {
+        return Some(format!("{} references, an index holds at most {}. Split the references into several indexes.",
+            count, MAX_REFERENCES))
+    }
+    if longest.0 > MAX_REFERENCE_LENGTH {
+        return Some(format!("Reference {} has {} bases, an index addresses at most {} per reference.",
+            longest.1, longest.0, MAX_REFERENCE_LENGTH))
+    }
+    None
+}
+
+/// FASTA the index is built from: the reference, restricted to `--include-ref-file`, without references shorter
+/// than `k` with `--drop-short-refs` and ordered by `--reference-order`. A temporary file unless it is the
+/// reference itself, remove it after the build.
+pub fn build_source(options: &Options, paths: &DBPaths, k: usize) -> PathBuf {
+    let mut source = match &options.args.include_ref_file {
+        Some(include_ref_file) => subset_reference(&paths.reference_path, include_ref_file),
+        None => paths.reference_path.clone(),
+    };
+    if options.args.drop_short_refs || options.args.reference_order != ReferenceOrder::Fasta {
+        let rewritten = rewrite_reference(&source, options.args.drop_short_refs.then_some(k), options.args.reference_order);
+        if source != paths.reference_path {
+            let _ = std::fs::remove_file(&source);
+        }
+        source = rewritten;
+    }
+    source
+}
+
+/// A record of a FASTA file: name, number of bases and byte range from its header to the next record.
+struct FastaEntry {
+    name: String,
+    length: usize,
+    range: Range<u64>,
+    /// The last line of the record ends with a newline
+    terminated: bool,
+}
+
+/// Indexes the records of a FASTA file in one streaming pass. Sequences are not kept.
+fn index_fasta(reference: &Path) -> Vec<FastaEntry> {
+    let mut input = match File::open(reference) {
+        Err(why) => panic!("couldn't open {}: {}", reference.display(), why),
+        Ok(file) => BufReader::new(file),
+    };
+
+    let mut entries: Vec<FastaEntry> = Vec::new();
+    let (mut line, mut offset) = (Vec::new(), 0u64);
+    loop {
+        line.clear();
+        let read = input.read_until(b'\n', &mut line).expect("Valid reference file");
+        if read == 0 { break };
+
+        let header = line[0] == b'>';
+        if header {
+            let name = String::from_utf8_lossy(&line[1..]).trim_end().split(' ').next().unwrap().to_string();
+            entries.push(FastaEntry { name, length: 0, range: offset..offset, terminated: true });
+        }
+        offset += read as u64;
+        // Lines before the first header belong to no record.
+        if let Some(entry) = entries.last_mut() {
+            if !header {
+                entry.length += line.iter().rposition(|c| !c.is_ascii_whitespace()).map_or(0, |end| end + 1);
+            }
+            entry.range.end = offset;
+            entry.terminated = line.ends_with(b"\n");
+        }
+    }
+    entries
+}
+
+/// Writes the records of the reference FASTA to a temporary FASTA and returns its path: without records shorter
+/// than `min_length` if given, sorted by `order`. Ids are assigned in file order during the build. Records are
+/// indexed in one pass and copied by byte range, so only their names and lengths are held in memory.
+pub fn rewrite_reference(reference: &Path, min_length: Option<usize>, order: ReferenceOrder) -> PathBuf {
+    let mut entries = index_fasta(reference);
+
+    if let Some(k) = min_length {
+        let (kept, dropped): (Vec<_>, Vec<_>) = entries.into_iter().partition(|entry| entry.length >= k);
+        report_short_references(dropped.iter().map(|entry| entry.name.as_str()), k, true);
+        if kept.is_empty() {
+            eprintln!("All references are shorter than k = {}, nothing to index.", k);
+            exit(9);
+        }
+        entries = kept;
+    }
+
+    match order {
+        ReferenceOrder::Fasta => (),
+        ReferenceOrder::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+        ReferenceOrder::Length => entries.sort_by(|a, b| b.length.cmp(&a.length)),
+    }
+
+    let mut input = match File::open(reference) {
+        Err(why) => panic!("couldn't open {}: {}", reference.display(), why),
+        Ok(file) => file,
+    };
+    let rewritten_path = std::env::temp_dir().join(format!("flexalign-reference-{}.fa", std::process::id()));
+    let mut output = match File::create(&rewritten_path) {
+        Err(why) => panic!("couldn't open {}: {}", rewritten_path.display(), why),
+        Ok(file) => BufWriter::new(file),
+    };
+    for entry in &entries {
+        input.seek(SeekFrom::Start(entry.range.start)).expect("Valid reference file");
+        io::copy(&mut (&mut input).take(entry.range.end - entry.range.start), &mut output).expect("Cannot write reference");
+        if !entry.terminated {
+            output.write_all(b"\n").expect("Cannot write reference");
+        }
+    }
+    output.flush().expect("Cannot write reference");
+    rewritten_path
+}
+
+/// Number of short references named in the build report, the rest is only counted.
+const SHORT_REFERENCES_LISTED: usize = 20;
+
+/// Reports references shorter than `k`. They contribute no k-mers, so no read is ever seeded on them.
+pub fn report_short_references<'a>(names: impl Iterator<Item = &'a str>, k: usize, dropped: bool) {
+    let names: Vec<&str> = names.collect();
+    if names.is_empty() { return };
+
+    let action = if dropped { "dropped from the index" } else { "never seeded, see --drop-short-refs" };
+    eprintln!("Warning: {} references shorter than k = {} are {}:", names.len(), k, action);
+    for name in names.iter().take(SHORT_REFERENCES_LISTED) {
+        eprintln!("  {}", name);
+    }
+    if names.len() > SHORT_REFERENCES_LISTED {
+        eprintln!("  ... and {} more", names.len() - SHORT_REFERENCES_LISTED);
+    }
+}
+
+/// Reference names listed in `include_ref_file`, one per line and optionally with a leading '>'.
+pub fn include_ref_names(include_ref_file: &str) -> HashSet<String> {
+    match read_lines_from_file(include_ref_file) {
+        Ok(lines) => lines.iter()
+            .map(|l| l.trim().trim_start_matches('>').split_whitespace().next().unwrap_or("").to_string())
+            .filter(|l| !l.is_empty())
+            .collect(),
+        Err(why) => {
+            eprintln!("Cannot read --include-ref-file {}: {}", include_ref_file, why);
+            exit(9);
+        },
+    }
 }
 
+/// Writes the records of the reference FASTA whose name is listed in `include_ref_file` (one per line) to a
+/// temporary FASTA and returns its path. The index is built from this file, so excluded references get no
+/// ids and cannot be alignment targets.
+pub fn subset_reference(reference: &Path, include_ref_file: &str) -> PathBuf {
+    let names = include_ref_names(include_ref_file);
+
+    let input = match File::open(reference) {
+        Err(why) => panic!("couldn't open {}: {}", reference.display(), why),
+        Ok(file) => BufReader::new(file),
+    };
+    let subset_path = std::env::temp_dir().join(format!("flexalign-subset-{}.fa", std::process::id()));
+    let mut output = match File::create(&subset_path) {
+        Err(why) => panic!("couldn't open {}: {}", subset_path.display(), why),
+        Ok(file) => BufWriter::new(file),
+    };
+
+    let mut found = HashSet::new();
+    let mut keep = false;
+    for line in input.lines() {
+        let line = line.expect("Valid reference file");
+        if let Some(header) = line.strip_prefix('>') {
+            let name = header.split(' ').next().unwrap();
+            keep = names.contains(name);
+            if keep { found.insert(name.to_string()); }
+        }
+        if keep {
+            writeln!(output, "{}", line).expect("Cannot write reference subset");
+        }
+    }
+    output.flush().expect("Cannot write reference subset");
+
+    for name in names.difference(&found) {
+        eprintln!("Reference {} from --include-ref-file not found in {}", name, reference.display());
+    }
+    if found.is_empty() {
+        eprintln!("No reference of --include-ref-file found, nothing to index.");
+        exit(9);
+    }
+    eprintln!("Indexing {} of the references", found.len());
+
+    subset_path
+}
 
 pub fn load_references<R>(references_file: R, reference2id: &HashMap<String, usize>, id2reference: &Vec<String>) -> Result<Vec<OwnedFastaRecord>, std::io::Error> where R: Read {
     let buffer_size: usize = usize::pow(2, 24);
@@ -75,9 +431,195 @@ pub fn load_references<R>(references_file: R, reference2id: &HashMap<String, usi
 
             // let header = String::from_utf8_lossy(&record.head()[1..]).into_owned();
             let header = String::from_utf8_lossy(&record.head()[1..]).split(' ').next().unwrap().to_string();
-            let reference_id = reference2id[&header];
+            // References excluded from the index (--include-ref-file) are skipped.
+            let reference_id = match reference2id.get(&header) {
+                Some(&reference_id) => reference_id,
+                None => continue,
+            };
             data[reference_id] = record.clone();
         }
     }
     Ok(data)
-}
\ No newline at end of file
+}
+
+/// Lengths of the references in the order of their ids, without keeping their bases.
+pub fn load_reference_lengths<R>(references_file: R, reference2id: &HashMap<String, usize>, id2reference: &Vec<String>) -> Result<Vec<usize>, std::io::Error> where R: Read {
+    let mut lengths = vec![0; id2reference.len()];
+    let mut current = None;
+    for line in BufReader::new(references_file).lines() {
+        let line = line?;
+        match line.strip_prefix('>') {
+            Some(header) => current = reference2id.get(header.split(' ').next().unwrap()).copied(),
+            None => if let Some(id) = current {
+                lengths[id] += line.trim_end().len();
+            },
+        }
+    }
+    Ok(lengths)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference with an N run at positions 7..10.
+    const REFERENCE: &[u8] = b"ACGTACGNNNACGTACGT";
+
+    #[test]
+    fn ambiguous_kmers_over_n_run() {
+        // Windows starting at 3..=9 overlap the N run.
+        assert_eq!(ambiguous_kmer_count::<5>(REFERENCE), 7);
+        assert_eq!(ambiguous_kmer_count::<5>(b"ACGTACGTACGT"), 0);
+        assert_eq!(ambiguous_kmer_count::<5>(b"ACGn"), 0);
+        assert_eq!(ambiguous_kmer_count::<3>(b"acgnacg"), 3);
+    }
+
+    #[test]
+    fn build_params_are_compared_with_the_stored_ones() {
+        use clap::Parser;
+        use crate::{options::Args, test_utils::temp_path};
+
+        let reference = temp_path("build-params", "fa");
+        std::fs::write(&reference, b">a\nACGT\n>b\nACGT\n").unwrap();
+        let include = temp_path("build-params", "txt");
+        std::fs::write(&include, b"b\n>a extra\n\n").unwrap();
+        let options = Options::from_args(Args::parse_from(["flexalign", "-r", reference.to_str().unwrap(), "--include-ref-file", include.to_str().unwrap()]));
+        let included = BuildParams::from_options(&options);
+        assert_eq!(included.included_references, ["a", "b"]);
+        let by_name = BuildParams { reference_order: format!("{:?}", ReferenceOrder::Name), ..BuildParams::default() };
+
+        // Without a parameter file the index holds every reference.
+        let paths = DBPaths::new(&reference);
+        assert!(paths.build_params_match(1, &BuildParams::default()));
+        assert!(!paths.build_params_match(1, &included));
+        assert!(!paths.build_params_match(1, &by_name));
+
+        paths.save_build_params(1, &by_name);
+        assert!(paths.build_params_match(1, &by_name));
+        assert!(!paths.build_params_match(1, &BuildParams::default()));
+
+        paths.save_build_params(1, &included);
+        assert!(paths.build_params_match(1, &included));
+        assert!(!paths.build_params_match(1, &BuildParams::default()));
+
+        std::fs::write(&paths.build_params_path, b"unreadable").unwrap();
+        assert!(!paths.build_params_match(1, &BuildParams::default()));
+
+        for path in [&reference, &include, &paths.build_params_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn references_are_addressable_up_to_the_limit() {
+        let fasta = |count: usize| io::Cursor::new(">r\nACGT\n".repeat(count));
+        assert!(addressing_error(fasta(MAX_REFERENCES)).is_none());
+        assert!(addressing_error(fasta(MAX_REFERENCES + 1)).unwrap().contains(&format!("{} references", MAX_REFERENCES + 1)));
+    }
+
+    #[test]
+    fn reference_lengths_span_lines_and_skip_unknown_names() {
+        let fasta = io::Cursor::new(">b desc\nACGTACGT\nACG\n>unindexed\nACGT\n>a\nAC\n");
+        let reference2id = HashMap::from([("a".to_string(), 0), ("b".to_string(), 1)]);
+        let id2reference = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(load_reference_lengths(fasta, &reference2id, &id2reference).unwrap(), [2, 11]);
+    }
+
+    #[test]
+    fn index_loads_lengths_without_sequences() {
+        use clap::Parser;
+        use crate::{options::Args, test_utils::{pseudo_random_bases, temp_path, TestDB}};
+
+        let reference = temp_path("lengths-only", "fa");
+        let bases = pseudo_random_bases(300, 11);
+        std::fs::write(&reference, [b">ref\n".as_slice(), &bases, b"\n"].concat()).unwrap();
+        let options = Options::from_args(Args::parse_from(["flexalign", "-r", reference.to_str().unwrap()]));
+        let paths = DBPaths::new(&reference);
+        TestDB::build(&options).save(&paths, 1).unwrap();
+
+        let db = TestDB::load(&paths, 1, false);
+        assert_eq!(db.get_reference(0), None);
+        assert_eq!(db.get_reference_length(0), Some(300));
+
+        let db = TestDB::load(&paths, 1, true);
+        assert_eq!(db.get_reference(0), Some(bases.as_slice()));
+        assert_eq!(db.get_reference_length(0), Some(300));
+
+        for path in [&reference, &paths.index_path, &paths.reference2id_path, &paths.id2reference_path, &paths.layout_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn index_older_than_the_reference_is_stale() {
+        use std::time::{Duration, SystemTime};
+        use crate::test_utils::temp_path;
+
+        let reference = temp_path("stale", "fa");
+        std::fs::write(&reference, b">a\nACGT\n").unwrap();
+        let paths = DBPaths::new(&reference);
+        // Without an index the modification time is unknown.
+        assert!(!paths.is_stale());
+
+        std::fs::write(&paths.index_path, b"index").unwrap();
+        let set_modified = |path: &PathBuf, time: SystemTime| File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+        let now = SystemTime::now();
+        set_modified(&paths.reference_path, now - Duration::from_secs(60));
+        set_modified(&paths.index_path, now);
+        assert!(!paths.is_stale());
+
+        set_modified(&paths.reference_path, now + Duration::from_secs(60));
+        assert!(paths.is_stale());
+
+        for path in [&reference, &paths.index_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn rewrite_reference_drops_and_sorts_by_byte_range() {
+        let path = std::env::temp_dir().join(format!("flexalign-rewrite-{}.fa", std::process::id()));
+        std::fs::write(&path, b">b desc\nACGTACGT\nACGT\n>short\nACG\n>a\nACGTAC\nACGTAC").unwrap();
+
+        let rewritten = rewrite_reference(&path, Some(5), ReferenceOrder::Name);
+        assert_eq!(std::fs::read(&rewritten).unwrap(), b">a\nACGTAC\nACGTAC\n>b desc\nACGTACGT\nACGT\n");
+        std::fs::remove_file(&rewritten).unwrap();
+
+        let rewritten = rewrite_reference(&path, None, ReferenceOrder::Length);
+        assert_eq!(std::fs::read(&rewritten).unwrap(), b">b desc\nACGTACGT\nACGT\n>a\nACGTAC\nACGTAC\n>short\nACG\n");
+        std::fs::remove_file(&rewritten).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn n_runs_are_neither_indexed_nor_seeded() {
+        use bioreader::sequence::fastq_record::RefFastqRecord;
+        use kmerrs::syncmer::closed_syncmer::ClosedSyncmer;
+        use crate::{align::{common::KmerExtractor, process::kmer_extractor::StdKmerExtractor, stats::Stats}, test_utils::{build_db, pseudo_random_bases, seeds, C, F, K, L, S}};
+
+        let mut reference = pseudo_random_bases(600, 7);
+        reference[200..210].fill(b'N');
+        reference[400..403].fill(b'N');
+        let (_, db) = build_db(&reference, &[]);
+
+        // Every window overlapping a run: run length + K - 1 each.
+        assert_eq!(ambiguous_kmer_count::<K>(db.get_reference(0).unwrap()), (10 + K - 1) + (3 + K - 1));
+        assert_eq!(db.ambiguous_kmers(), (10 + K - 1) + (3 + K - 1));
+
+        // The reference itself as a read seeds everywhere but over the N runs.
+        let quality = vec![b'I'; reference.len()];
+        let mut kmer_extractor = StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(0.0);
+        let kmers = kmer_extractor.generate(&RefFastqRecord::new(b"read", &reference, b"+", &quality), &mut Stats::default());
+        assert!(kmers.iter().all(|(pos, _)| !is_ambiguous(&reference[*pos..*pos + K])));
+        let seeds = seeds(&db, &reference);
+        assert!(!seeds.is_empty());
+
+        // Exact hits start at the k-mer, core-mer hits F/2 bases into it.
+        let window = |rpos: u64, length: u8| {
+            let start = if length as usize == K { rpos as usize } else { rpos as usize - F / 2 };
+            &reference[start..start + K]
+        };
+        assert!(seeds.iter().all(|seed| !is_ambiguous(window(seed.rpos, seed.length))));
+    }
+}
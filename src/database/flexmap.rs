@@ -7,7 +7,7 @@ use ser_raw::{storage, CompleteSerializer, PureCopySerializer, Serialize, Serial
 
 use crate::flexalign::time;
 
-use super::common::{DBPaths, load_references, FlexalignDatabase};
+use super::common::{ambiguous_kmer_count, build_source, check_addressable, report_short_references, DBPaths, load_reference_lengths, load_references, FlexalignDatabase};
 
 
 #[repr(C)]
@@ -24,7 +24,9 @@ pub struct DB<
     flexmap: Flexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>,
     rid_to_rname: Vec<String>,
     rname_to_rid: HashMap<String, usize>,
+    /// Empty if loaded without sequences
     references: Vec<OwnedFastaRecord>,
+    reference_lengths: Vec<usize>,
 }
 
 impl<
@@ -54,9 +56,11 @@ impl<
 
     fn build(options: &crate::options::Options) -> Self {
         let db_paths = DBPaths::new(&options.reference);
+        let source = build_source(options, &db_paths, K);
+        check_addressable(&source);
 
         let result = flexmap::build::default_build::<K, C, F, S, L, CELLS_PER_BODY, HEADER_THRESHOLD>(
-            &options.reference, options.args.max_range_size
+            &source, options.args.max_range_size
         );
 
         let (flexmap, rname_to_rid, rid_to_rname) = match result {
@@ -66,22 +70,34 @@ impl<
             Err(_) => todo!(),
         };
 
-        let references_file = &mut File::open(&db_paths.reference_path).expect("Working references file");
+        let references_file = &mut File::open(&source).expect("Working references file");
         let references = load_references(references_file, &rname_to_rid, &rid_to_rname);
+        if source != db_paths.reference_path {
+            let _ = std::fs::remove_file(&source);
+        }
 
         let references = match references {
             Ok(references) => references,
             Err(why) => panic!("Could not load references {}", why),
         };
+
+        report_short_references(references.iter().enumerate()
+            .filter(|(_, r)| r.seq().len() < K)
+            .map(|(id, _)| rid_to_rname[id].as_str()), K, false);
+        let reference_lengths = references.iter().map(|r| r.seq().len()).collect();
+
         Self {
             flexmap,
             rid_to_rname,
             rname_to_rid,
             references,
+            reference_lengths,
         }
     }
 
-    fn load(paths: &super::common::DBPaths, version: u32) -> Self {
+    fn load(paths: &super::common::DBPaths, version: u32, sequences: bool) -> Self {
+        paths.check_layout(version, (CELLS_PER_BODY, HEADER_THRESHOLD));
+
         let map_file = &mut File::open(&paths.index_path).expect("Working flexmap file");
         let mut map_reader = BufReader::new(map_file);
 
@@ -98,21 +114,17 @@ impl<
         let rid_to_rname: Vec<String> = load(rid2rname_file, version).expect("Valid reference database");
         let rname_to_rid: HashMap<String, usize> = load(rname2rid_file, version).expect("Valid reference database");
 
-        let (duration, references) = time(|| {
-            load_references(references_file, &rname_to_rid, &rid_to_rname)
+        let (duration, (references, reference_lengths)) = time(|| {
+            load_sequences_or_lengths(references_file, &rname_to_rid, &rid_to_rname, sequences)
         });
         eprintln!("Loading references took {:?}", duration);
 
-        let references = match references {
-            Ok(references) => references,
-            Err(why) => panic!("Could not load references {}", why),
-        };
-
         Self {
             flexmap,
             rid_to_rname,
             rname_to_rid,
             references: references,
+            reference_lengths: reference_lengths,
         }
     }
     
@@ -144,6 +156,8 @@ impl<
         };
         let _ = save(&mut file, version, &self.rname_to_rid);
 
+        paths.save_layout(version, (CELLS_PER_BODY, HEADER_THRESHOLD));
+
         // let mut ser = PureCopySerializer::<16, 8, 16, 1024, _>::new();
         // let storage = ser.serialize(&self.flexmap);
 
@@ -151,7 +165,19 @@ impl<
     }
     
     fn get_reference(&self, id: usize) -> Option<&[u8]> {
-        Some(self.references[id].seq())
+        self.references.get(id).map(|r| r.seq())
+    }
+
+    fn get_reference_length(&self, id: usize) -> Option<usize> {
+        self.reference_lengths.get(id).copied()
+    }
+
+    fn reference_count(&self) -> usize {
+        self.rid_to_rname.len()
+    }
+
+    fn ambiguous_kmers(&self) -> usize {
+        self.references.iter().map(|r| ambiguous_kmer_count::<K>(r.seq())).sum()
     }
 }
 
@@ -170,7 +196,9 @@ pub struct DBHash<
     flexmap: FlexmapHash<C, F, HEADER_THRESHOLD>,
     rid_to_rname: Vec<String>,
     rname_to_rid: HashMap<String, usize>,
+    /// Empty if loaded without sequences
     references: Vec<OwnedFastaRecord>,
+    reference_lengths: Vec<usize>,
 }
 
 impl<
@@ -199,9 +227,11 @@ impl<
 
     fn build(options: &crate::options::Options) -> Self {
         let db_paths = DBPaths::new(&options.reference);
+        let source = build_source(options, &db_paths, K);
+        check_addressable(&source);
 
         let result = flexmap::build::hash_build::<K, C, F, S, L, HEADER_THRESHOLD>(
-            &options.reference, options.args.max_range_size
+            &source, options.args.max_range_size
         );
 
         let (flexmap, rname_to_rid, rid_to_rname) = match result {
@@ -211,22 +241,32 @@ impl<
             Err(_) => todo!(),
         };
 
-        let references_file = &mut File::open(&db_paths.reference_path).expect("Working references file");
+        let references_file = &mut File::open(&source).expect("Working references file");
         let references = load_references(references_file, &rname_to_rid, &rid_to_rname);
+        if source != db_paths.reference_path {
+            let _ = std::fs::remove_file(&source);
+        }
 
         let references = match references {
             Ok(references) => references,
             Err(why) => panic!("Could not load references {}", why),
         };
+
+        report_short_references(references.iter().enumerate()
+            .filter(|(_, r)| r.seq().len() < K)
+            .map(|(id, _)| rid_to_rname[id].as_str()), K, false);
+        let reference_lengths = references.iter().map(|r| r.seq().len()).collect();
+
         Self {
             flexmap,
             rid_to_rname,
             rname_to_rid,
             references,
+            reference_lengths,
         }
     }
 
-    fn load(paths: &super::common::DBPaths, version: u32) -> Self {
+    fn load(paths: &super::common::DBPaths, version: u32, sequences: bool) -> Self {
         let map_file = &mut File::open(&paths.index_path).expect("Working flexmap file");
         let mut map_reader = BufReader::new(map_file);
 
@@ -243,18 +283,14 @@ impl<
         let rid_to_rname: Vec<String> = load(rid2rname_file, version).expect("Valid reference database");
         let rname_to_rid: HashMap<String, usize> = load(rname2rid_file, version).expect("Valid reference database");
 
-        let references = load_references(references_file, &rname_to_rid, &rid_to_rname);
-
-        let references = match references {
-            Ok(references) => references,
-            Err(why) => panic!("Could not load references {}", why),
-        };
+        let (references, reference_lengths) = load_sequences_or_lengths(references_file, &rname_to_rid, &rid_to_rname, sequences);
 
         Self {
             flexmap,
             rid_to_rname,
             rname_to_rid,
             references: references,
+            reference_lengths: reference_lengths,
         }
     }
     
@@ -290,6 +326,36 @@ impl<
     }
     
     fn get_reference(&self, id: usize) -> Option<&[u8]> {
-        Some(self.references[id].seq())
+        self.references.get(id).map(|r| r.seq())
+    }
+
+    fn get_reference_length(&self, id: usize) -> Option<usize> {
+        self.reference_lengths.get(id).copied()
+    }
+
+    fn reference_count(&self) -> usize {
+        self.rid_to_rname.len()
     }
-}
\ No newline at end of file
+
+    fn ambiguous_kmers(&self) -> usize {
+        self.references.iter().map(|r| ambiguous_kmer_count::<K>(r.seq())).sum()
+    }
+}
+
+
+/// Loaded references and their lengths. Without `sequences` the first is empty.
+fn load_sequences_or_lengths(references_file: &mut File, rname_to_rid: &HashMap<String, usize>, rid_to_rname: &Vec<String>, sequences: bool) -> (Vec<OwnedFastaRecord>, Vec<usize>) {
+    if !sequences {
+        return match load_reference_lengths(references_file, rname_to_rid, rid_to_rname) {
+            Ok(lengths) => (Vec::new(), lengths),
+            Err(why) => panic!("Could not load reference lengths {}", why),
+        }
+    }
+    match load_references(references_file, rname_to_rid, rid_to_rname) {
+        Ok(references) => {
+            let lengths = references.iter().map(|r| r.seq().len()).collect();
+            (references, lengths)
+        },
+        Err(why) => panic!("Could not load references {}", why),
+    }
+}
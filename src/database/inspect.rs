@@ -0,0 +1,228 @@
+use std::{cmp::{max, min}, fs::File, io::{stdout, BufWriter, Write}, process::exit, time::UNIX_EPOCH};
+
+use flexmap::VD;
+use kmerrs::{consecutive::kmer::KmerIter, minimizer::context_free::Minimizer, syncmer::closed_syncmer::ClosedSyncmer};
+use savefile::{load, save};
+
+use super::common::{DBPaths, FlexalignDatabase};
+
+
+/// Looks up a single k-mer in the index the same way `get_ranges`/`retrieve_seeds` do and prints
+/// the raw range contents. Used to debug why a read does (not) seed.
+pub fn query_kmer<
+    const K: usize,
+    const C: usize,
+    const F: usize,
+    const S: usize,
+    const L: usize,
+    D: FlexalignDatabase,
+>(db: &D, kmer_str: &str) {
+    let mut iter = KmerIter::<K, true>::new(kmer_str.as_bytes());
+
+    let (_pos, kmer_fwd, kmer_rev) = match iter.next() {
+        Some(kmer) => kmer,
+        None => {
+            eprintln!("--query-kmer requires a valid sequence of length {} (got {})", K, kmer_str.len());
+            exit(9);
+        },
+    };
+
+    if kmer_str.len() != K {
+        eprintln!("--query-kmer has length {}, only the first {} bases are used", kmer_str.len(), K);
+    }
+
+    let cmer_fwd = kmer_fwd.middle::<C>();
+    let cmer_rev = kmer_rev.middle::<C>();
+    let kmer = if cmer_fwd < cmer_rev { kmer_fwd } else { kmer_rev };
+    let cmer = min(cmer_fwd, cmer_rev);
+    let fmer = kmer.flanks::<F>();
+
+    let minimizer = ClosedSyncmer::<C, S, L>::new();
+
+    println!("k-mer:      {}", kmer.to_string().expect("Correct k-mer"));
+    println!("core-mer:   {} ({})", cmer.to_string().expect("Correct k-mer"), cmer.0);
+    println!("flank-mer:  {} ({})", fmer.to_string().expect("Correct k-mer"), fmer.0);
+    println!("canonical:  {}", if cmer_fwd < cmer_rev { "forward" } else { "reverse" });
+    println!("minimizer:  {}", minimizer.is_minimizer(cmer.0));
+
+    let range = match db.get_vrange(cmer.0) {
+        Some(range) => range,
+        None => {
+            println!("Core-mer is not in the index.");
+            return
+        },
+    };
+
+    println!("positions:  {}", range.positions.len());
+    println!("header:     {}", range.header.is_some());
+    println!("reference\trid\tposition\tflank_dist");
+
+    for (index, cell) in range.positions.iter().enumerate() {
+        let (value, rpos) = VD::get(cell.0);
+        let dist = match range.header {
+            Some(headers) => headers[index].dist(fmer.0 as u32).to_string(),
+            None => "-".to_string(),
+        };
+        println!("{}\t{}\t{}\t{}",
+            db.get_rname(value as usize).unwrap_or("?"),
+            value,
+            rpos,
+            dist);
+    }
+}
+
+
+/// Fraction of G and C among the unambiguous bases of a sequence.
+pub fn gc_content(seq: &[u8]) -> f64 {
+    let (gc, acgt) = seq.iter().fold((0usize, 0usize), |(gc, acgt), c| {
+        match c.to_ascii_uppercase() {
+            b'G' | b'C' => (gc + 1, acgt + 1),
+            b'A' | b'T' => (gc, acgt + 1),
+            _ => (gc, acgt),
+        }
+    });
+    if acgt == 0 { 0.0 } else { gc as f64 / acgt as f64 }
+}
+
+/// Size and modification time (ns since the epoch) of the reference file, stored with the reference table
+/// so that it is recomputed when the sequences change.
+fn reference_stamp(paths: &DBPaths) -> (u64, u64) {
+    let metadata = paths.reference_path.metadata().ok();
+    let size = metadata.as_ref().map_or(0, |m| m.len());
+    let modified = metadata.and_then(|m| m.modified().ok())
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as u64);
+    (size, modified)
+}
+
+/// Computes (length, GC content) for every reference and stores it next to the index.
+pub fn save_reference_info<D: FlexalignDatabase>(db: &D, paths: &DBPaths, version: u32) -> Vec<(u64, f64)> {
+    let info = (0..db.reference_count())
+        .map(|id| {
+            let seq = db.get_reference(id).unwrap();
+            (seq.len() as u64, gc_content(seq))
+        })
+        .collect::<Vec<_>>();
+
+    let mut file = match File::create(&paths.reference_info_path) {
+        Err(why) => panic!("couldn't open {}: {}", paths.reference_info_path.display(), why),
+        Ok(file) => file,
+    };
+    let (size, modified) = reference_stamp(paths);
+    let _ = save(&mut file, version, &(size, modified, info.clone()));
+    info
+}
+
+/// Prints id, name, length and GC content of all references. The table is read from the index
+/// directory if it was computed from the current reference file, otherwise it is computed once and cached.
+pub fn list_references<D: FlexalignDatabase>(db: &D, paths: &DBPaths, version: u32) {
+    let stamp = reference_stamp(paths);
+    let cached: Option<Vec<(u64, f64)>> = File::open(&paths.reference_info_path).ok()
+        .and_then(|mut file| load(&mut file, version).ok())
+        .filter(|(size, modified, info): &(u64, u64, Vec<(u64, f64)>)| (*size, *modified) == stamp && info.len() == db.reference_count())
+        .map(|(_, _, info)| info);
+
+    let info = match cached {
+        Some(info) => info,
+        None => save_reference_info(db, paths, version),
+    };
+
+    println!("rid\treference\tlength\tgc");
+    for (id, (length, gc)) in info.iter().enumerate() {
+        println!("{}\t{}\t{}\t{:.4}", id, db.get_rname(id).unwrap_or("?"), length, gc);
+    }
+}
+
+/// Writes a single reference from the index to stdout as FASTA, wrapped at `line_width` bases (0 for no wrapping).
+/// The header is the name stored in the index.
+pub fn extract_reference<D: FlexalignDatabase>(db: &D, name: &str, line_width: usize) {
+    let sequence = match db.get_rid(name).and_then(|&id| db.get_reference(id)) {
+        Some(sequence) => sequence,
+        None => {
+            eprintln!("--extract-ref: reference {} is not in the index", name);
+            exit(9);
+        },
+    };
+
+    let mut out = BufWriter::new(stdout().lock());
+    let line_width = if line_width == 0 { sequence.len().max(1) } else { line_width };
+    let _ = writeln!(out, ">{}", name);
+    for line in sequence.chunks(line_width) {
+        let _ = out.write_all(line);
+        let _ = out.write_all(b"\n");
+    }
+    let _ = out.flush();
+}
+
+/// Approximate number of reference k-mers looked up by --validate-index.
+pub const VALIDATE_INDEX_SAMPLE: usize = 100_000;
+
+/// Looks up an evenly spaced sample of reference minimizers with `get_vrange`, like the seed extraction, and
+/// checks that every returned position holds the same canonical core-mer in the stored reference. Keys can be
+/// missing legitimately (e.g. dropped by --max-range-size) and are reported separately. Exits with 9 on mismatches.
+pub fn validate_index<
+    const K: usize,
+    const C: usize,
+    const S: usize,
+    const L: usize,
+    D: FlexalignDatabase,
+>(db: &D) {
+    let total: usize = (0..db.reference_count()).map(|id| db.get_reference(id).unwrap().len()).sum();
+    let stride = max(1, total / VALIDATE_INDEX_SAMPLE);
+    let minimizer = ClosedSyncmer::<C, S, L>::new();
+
+    let canonical_cmer = |seq: &[u8]| {
+        KmerIter::<K, true>::new(seq).next().map(|(_, fwd, rev)| min(fwd.middle::<C>(), rev.middle::<C>()).0)
+    };
+
+    let (mut sampled, mut missing, mut valid, mut positions, mut mismatches) = (0usize, 0usize, 0usize, 0usize, 0usize);
+    let mut seen = 0usize;
+    for id in 0..db.reference_count() {
+        let reference = db.get_reference(id).unwrap();
+        for (_pos, kmer_fwd, kmer_rev) in KmerIter::<K, true>::new(reference) {
+            let cmer = min(kmer_fwd.middle::<C>(), kmer_rev.middle::<C>());
+            if !minimizer.is_minimizer(cmer.0) { continue };
+            seen += 1;
+            if seen % stride != 0 { continue };
+            sampled += 1;
+
+            let range = match db.get_vrange(cmer.0) {
+                Some(range) => range,
+                None => {
+                    missing += 1;
+                    continue
+                },
+            };
+
+            let mut key_valid = true;
+            for cell in range.positions.iter() {
+                let (value, rpos) = VD::get(cell.0);
+                positions += 1;
+                let stored = db.get_reference(value as usize)
+                    .and_then(|r| r.get(rpos as usize..rpos as usize + K))
+                    .and_then(|seq| canonical_cmer(seq));
+                if stored != Some(cmer.0) {
+                    if mismatches < 10 {
+                        eprintln!("Mismatch: core-mer {} of {} resolves to {}:{}, which holds {:?}",
+                            cmer.0, db.get_rname(id).unwrap_or("?"), db.get_rname(value as usize).unwrap_or("?"), rpos, stored);
+                    }
+                    mismatches += 1;
+                    key_valid = false;
+                }
+            }
+            valid += key_valid as usize;
+        }
+    }
+
+    let present = sampled - missing;
+    println!("Sampled keys........................{}", sampled);
+    println!("Keys not in the index...............{}", missing);
+    println!("Validated keys......................{} ({:.4}% of keys in the index)", valid, 100.0 * valid as f64 / max(present, 1) as f64);
+    println!("Positions checked...................{}", positions);
+    println!("Mismatching positions...............{}", mismatches);
+
+    if mismatches > 0 {
+        eprintln!("Index validation failed");
+        exit(9);
+    }
+}
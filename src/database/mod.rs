@@ -6,4 +6,5 @@ use crate::options::Options;
 pub mod common;
 pub mod build;
 pub mod flexmap;
+pub mod inspect;
 
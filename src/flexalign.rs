@@ -1,15 +1,40 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 use std::process::exit;
 use log::info;
 
-use crate::align::process_fastq::{process_fastq_wrapper, process_fastq_wrapper_modular};
+use crate::align::process_fastq::{process_fastq_wrapper, process_fastq_wrapper_modular, process_single_sequence};
 use crate::database::flexmap::DB;
-use crate::database::common::{DBPaths, FlexalignDatabase};
-use crate::options::{Args, Options};
+use crate::database::common::{BuildParams, DBPaths, FlexalignDatabase, SUPPORTED_INDEX_LAYOUTS};
+use crate::database::inspect::{extract_reference, list_references, query_kmer, save_reference_info, validate_index};
+use crate::align::paf::sam_from_paf;
+use crate::align::selftest::selftest;
+use crate::options::{Args, Command, Options, OutputFormat};
 use crate::utils::infer_output_prefix;
 use crate::GLOBAL_VERSION;
 
 
+/// Set on the first SIGINT. The input ends early and workers skip the reads already read, so all output buffers
+/// are dropped and flushed normally.
+pub static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::Relaxed)
+}
+
+fn install_shutdown_handler() {
+    let result = ctrlc::set_handler(|| {
+        if SHUTDOWN.swap(true, Ordering::SeqCst) {
+            // Second Ctrl-C, give up on a clean shutdown.
+            exit(130);
+        }
+        eprintln!("Interrupted, finishing the current batch and flushing output. Press Ctrl-C again to abort.");
+    });
+    if let Err(why) = result {
+        eprintln!("Cannot install SIGINT handler: {}", why);
+    }
+}
+
 pub fn time<F, T>(f: F) -> (Duration, T)
     where F: FnOnce() -> T {
 
@@ -25,30 +50,106 @@ pub fn time<F, T>(f: F) -> (Duration, T)
 pub fn run(args: Args) {
     let options = Options::from_args(args);
 
+    if options.args.selftest {
+        selftest();
+        return
+    }
 
     if !options.reference.exists() {
         eprintln!("Reference does not exist {:?}", options.reference);
         exit(9);
     }
 
+    if options.args.no_reference_seqs {
+        check_no_reference_seqs(&options);
+    }
+
     let db_paths = DBPaths::new(&options.reference);
-    
-    let build = !db_paths.valid_paths() || options.args.force_build;
-    
+    let build_params = BuildParams::from_options(&options);
+    let build = needs_build(&options, &db_paths, &build_params);
+
+    // Every supported layout is a separate monomorphization, see SUPPORTED_INDEX_LAYOUTS.
+    match (options.args.cells_per_body, options.args.header_threshold) {
+        (16, 2) => run_with_layout::<16, 2>(&options, &db_paths, &build_params, build),
+        (8, 2) => run_with_layout::<8, 2>(&options, &db_paths, &build_params, build),
+        (32, 2) => run_with_layout::<32, 2>(&options, &db_paths, &build_params, build),
+        (16, 4) => run_with_layout::<16, 4>(&options, &db_paths, &build_params, build),
+        (cells, header) => {
+            eprintln!("Unsupported index layout --cells-per-body {} --header-threshold {}. Supported (cells per body, header threshold): {:?}",
+                cells, header, SUPPORTED_INDEX_LAYOUTS);
+            exit(9);
+        },
+    }
+}
+
+/// Whether the index has to be built: it is missing, --force-build is given, the reference is newer than it (unless
+/// --no-rebuild) or it was built with other `BuildParams`. The latter exits with 9 under --no-rebuild.
+fn needs_build(options: &Options, db_paths: &DBPaths, build_params: &BuildParams) -> bool {
+    if !db_paths.valid_paths() || options.args.force_build {
+        return true
+    }
+
+    let stale = db_paths.is_stale();
+    if stale && options.args.no_rebuild {
+        eprintln!("Warning: reference {} is newer than its index, using the existing index (--no-rebuild)", options.reference.display());
+    } else if stale {
+        eprintln!("Reference {} is newer than its index, rebuilding. Pass --no-rebuild to keep the existing index.", options.reference.display());
+    }
+
+    // The index files are named after the reference alone, whatever subset of it they hold.
+    let mismatched = !db_paths.build_params_match(GLOBAL_VERSION, build_params);
+    if mismatched && options.args.no_rebuild {
+        eprintln!("Index {} was built with another --include-ref-file, --reference-order or --drop-short-refs. Remove --no-rebuild to rebuild it.",
+            db_paths.index_path.display());
+        exit(9);
+    } else if mismatched {
+        eprintln!("Index {} was built with another --include-ref-file, --reference-order or --drop-short-refs, rebuilding.", db_paths.index_path.display());
+    }
+
+    (stale && !options.args.no_rebuild) || mismatched
+}
+
+/// Rejects everything that compares read and reference bases when they are not loaded (`--no-reference-seqs`).
+fn check_no_reference_seqs(options: &Options) {
+    let args = &options.args;
+    let conflicts = [
+        (options.rev.iter().any(Option::is_some), "Paired-end input, whose mates are aligned,"),
+        (!matches!(args.format, OutputFormat::Paf | OutputFormat::Gaf), "--format sam and m8"),
+        (args.paf_long, "--paf-long"),
+        (args.compat_minimap_paf, "--compat-minimap-paf"),
+        (args.query_seq.is_some(), "--query-seq"),
+        (args.extract_ref.is_some(), "--extract-ref"),
+        (args.validate_index, "--validate-index"),
+        (args.list_references || args.reference_gc, "The reference table"),
+    ];
+
+    let mut conflicting = false;
+    for (_, what) in conflicts.iter().filter(|(set, _)| *set) {
+        eprintln!("{} needs the reference sequences, which --no-reference-seqs does not load.", what);
+        conflicting = true;
+    }
+    if conflicting {
+        exit(9);
+    }
+}
+
+fn run_with_layout<const CELLS_PER_BODY: u64, const HEADER_THRESHOLD: usize>(options: &Options, db_paths: &DBPaths, build_params: &BuildParams, build: bool) {
     const K: usize = 31;
     const C: usize = 15;
     const F: usize = 16; 
     const S: usize = 7; // 7 0.34 //8 0.37 //6 0.31 //5  0.29 //4 0.289 //3 0.413  //2  0.413
     const L: usize = C - S + 1; //1
-    const CELLS_PER_BODY: u64 = 16;
-    const HEADER_THRESHOLD: usize = 2;
     
     let db: DB<K, C, F, S, L, CELLS_PER_BODY, HEADER_THRESHOLD> = match build {
         true => {
             
             let (_duration, result) = 
-                time(|| DB::build(&options));
-            let _ = result.save(&db_paths, GLOBAL_VERSION);
+                time(|| DB::build(options));
+            let _ = result.save(db_paths, GLOBAL_VERSION);
+            db_paths.save_build_params(GLOBAL_VERSION, build_params);
+            if options.args.reference_gc {
+                save_reference_info(&result, db_paths, GLOBAL_VERSION);
+            }
 
             result
 
@@ -56,12 +157,42 @@ pub fn run(args: Args) {
         false => {
             eprintln!("Load index.");
             let (duration, result) = 
-                time(|| DB::load(&db_paths, GLOBAL_VERSION));
+                time(|| DB::load(db_paths, GLOBAL_VERSION, !options.args.no_reference_seqs));
             eprintln!("Loading index took: {:?}", duration);
             result
         },
     };
 
+    if options.args.list_references {
+        list_references(&db, db_paths, GLOBAL_VERSION);
+        return
+    }
+
+    if options.args.validate_index {
+        validate_index::<K, C, S, L, _>(&db);
+        return
+    }
+
+    if let Some(name) = &options.args.extract_ref {
+        extract_reference(&db, name, options.args.line_width);
+        return
+    }
+
+    if let Some(Command::SamFromPaf { paf }) = &options.args.command {
+        sam_from_paf(&db, paf);
+        return
+    }
+
+    if let Some(kmer) = &options.args.query_kmer {
+        query_kmer::<K, C, F, S, L, _>(&db, kmer);
+        return
+    }
+
+    if let Some(seq) = &options.args.query_seq {
+        process_single_sequence::<K, C, F, S, L, HEADER_THRESHOLD, _>(options, &db, &options.args.query_name, seq);
+        return
+    }
+
     // Check if all files exist
     for file in &options.fwd {
         if !file.exists() {
@@ -77,12 +208,53 @@ pub fn run(args: Args) {
         }
     }
 
-    let (duration, _result) = time(|| process_fastq_wrapper_modular::<K, C, F, S, L, HEADER_THRESHOLD,DB<K, C, F, S, L, CELLS_PER_BODY, HEADER_THRESHOLD>>(&options, &db));
+    install_shutdown_handler();
+
+    let (duration, stats) = time(|| process_fastq_wrapper_modular::<K, C, F, S, L, HEADER_THRESHOLD,DB<K, C, F, S, L, CELLS_PER_BODY, HEADER_THRESHOLD>>(options, &db));
     eprintln!("Modular: Process reads: {:?}", duration);
 
-    // let (duration, _result) = time(|| process_fastq_wrapper::<K, C, F, S, L, HEADER_THRESHOLD,DB<K, C, F, S, L, CELLS_PER_BODY, HEADER_THRESHOLD>>(&options, &db));
+    if options.args.benchmark {
+        eprintln!("{}", stats.benchmark_line(duration));
+    }
+
+    if shutdown_requested() {
+        eprintln!("Output is incomplete due to interruption.");
+        exit(130);
+    }
+
+    // let (duration, _result) = time(|| process_fastq_wrapper::<K, C, F, S, L, HEADER_THRESHOLD,DB<K, C, F, S, L, CELLS_PER_BODY, HEADER_THRESHOLD>>(options, &db));
     // eprintln!("Naive: Process reads: {:?}", duration);
 
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use crate::test_utils::temp_path;
+
+    #[test]
+    fn index_without_dropped_short_refs_is_rebuilt() {
+        let reference = temp_path("cached-index", "fa");
+        std::fs::write(&reference, b">a\nACGT\n").unwrap();
+        let options = |args: &[&str]| Options::from_args(Args::parse_from(["flexalign", "-r", reference.to_str().unwrap()].into_iter().chain(args.iter().copied())));
+
+        // A cached index, built from the whole reference without --drop-short-refs.
+        let db_paths = DBPaths::new(&reference);
+        for path in [&db_paths.index_path, &db_paths.reference2id_path, &db_paths.id2reference_path] {
+            std::fs::write(path, b"").unwrap();
+        }
+        db_paths.save_build_params(GLOBAL_VERSION, &BuildParams::default());
+
+        let plain = options(&[]);
+        assert!(!needs_build(&plain, &db_paths, &BuildParams::from_options(&plain)));
+        let dropping = options(&["--drop-short-refs"]);
+        assert!(needs_build(&dropping, &db_paths, &BuildParams::from_options(&dropping)));
+
+        for path in [&reference, &db_paths.index_path, &db_paths.reference2id_path, &db_paths.id2reference_path, &db_paths.build_params_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fs::File, io::{self, Write}, sync::{Arc, Mutex}};
+use std::{collections::{HashMap, HashSet, VecDeque}, fs::{create_dir_all, File, OpenOptions}, io::{self, Write}, path::PathBuf, sync::{Arc, Mutex}};
 
 
 
@@ -24,6 +24,13 @@ impl Write for OutputTarget {
     }
 }
 
+// The target is shared by all buffers of a run, so this runs once after the last buffer has written its rest.
+impl Drop for OutputTarget {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
 #[derive(Clone)]
 pub struct OutputBuffer {
     writer: Arc<Mutex<OutputTarget>>,
@@ -54,6 +61,10 @@ impl OutputBuffer {
         if self.buffer.len() > self.threshold {
             let mut wr = self.writer.lock().expect("Cannot lock writer");
             let _ = wr.write_all(&self.buffer);
+            // A threshold of 0 asks for unbuffered output (`--flush-per-read`).
+            if self.threshold == 0 {
+                let _ = wr.flush();
+            }
             self.buffer.clear();
         }
     }
@@ -66,3 +77,159 @@ impl Drop for OutputBuffer {
     }
 }
 
+
+/// Upper bound on the files kept open by `--split-by-reference`.
+pub const MAX_OPEN_SPLIT_FILES: usize = 64;
+
+/// One output file per reference name in a directory (`--split-by-reference`). Every record goes to the file of
+/// its own reference, records without a reference (`*`) go to `unmapped`. The least recently used file is closed when more than `max_open` files are open and is
+/// reopened in append mode if needed again.
+pub struct SplitTarget {
+    dir: PathBuf,
+    extension: &'static str,
+    header: Option<String>,
+    max_open: usize,
+    open: HashMap<String, File>,
+    lru: VecDeque<String>,
+    created: HashSet<String>,
+}
+
+impl SplitTarget {
+    pub fn new(dir: impl Into<PathBuf>, extension: &'static str, header: Option<String>, max_open: usize) -> Self {
+        let dir = dir.into();
+        create_dir_all(&dir).expect(&format!("Cannot create output directory {:?}", dir));
+        Self {
+            dir,
+            extension,
+            header,
+            max_open: max_open.max(1),
+            open: HashMap::new(),
+            lru: VecDeque::new(),
+            created: HashSet::new(),
+        }
+    }
+
+    /// Distinct reference names never share a file: bytes outside `[A-Za-z0-9_.-]` and a leading `.` are
+    /// percent-encoded, and so is the `u` of a reference called `unmapped`.
+    fn file_name(reference_name: &str) -> String {
+        if matches!(reference_name, "*" | "") { return "unmapped".to_string() };
+
+        let mut name = String::with_capacity(reference_name.len());
+        for (i, b) in reference_name.bytes().enumerate() {
+            let keep = b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || (b == b'.' && i > 0);
+            match keep && !(i == 0 && reference_name == "unmapped") {
+                true => name.push(b as char),
+                false => name.push_str(&format!("%{:02X}", b)),
+            }
+        }
+        name
+    }
+
+    pub fn write_all(&mut self, reference_name: &str, data: &[u8]) -> io::Result<()> {
+        let name = Self::file_name(reference_name);
+
+        if self.open.contains_key(&name) {
+            let position = self.lru.iter().position(|n| *n == name).unwrap();
+            self.lru.remove(position);
+        } else {
+            if self.open.len() >= self.max_open {
+                let oldest = self.lru.pop_front().unwrap();
+                self.open.remove(&oldest);
+            }
+            let path = self.dir.join(format!("{}.{}", name, self.extension));
+            let mut file = match self.created.insert(name.clone()) {
+                true => File::create(&path)?,
+                false => OpenOptions::new().append(true).open(&path)?,
+            };
+            if let (true, Some(header)) = (file.metadata()?.len() == 0, &self.header) {
+                file.write_all(header.as_bytes())?;
+            }
+            self.open.insert(name.clone(), file);
+        }
+
+        self.lru.push_back(name.clone());
+        self.open.get_mut(&name).unwrap().write_all(data)
+    }
+}
+
+/// Per-worker buffers of a shared `SplitTarget`, one per reference name.
+#[derive(Clone)]
+pub struct SplitOutputBuffer {
+    target: Arc<Mutex<SplitTarget>>,
+    buffers: HashMap<String, Vec<u8>>,
+    size: usize,
+    pub threshold: usize,
+}
+
+impl SplitOutputBuffer {
+    pub fn new(target: Arc<Mutex<SplitTarget>>, threshold: usize) -> Self {
+        Self {
+            target,
+            buffers: HashMap::new(),
+            size: 0,
+            threshold,
+        }
+    }
+
+    pub fn write(&mut self, reference_name: &str, str: String) {
+        self.size += str.len();
+        self.buffers.entry(reference_name.to_string()).or_default().extend_from_slice(str.as_bytes());
+
+        if self.size > self.threshold {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        let mut target = self.target.lock().expect("Cannot lock writer");
+        for (reference_name, buffer) in self.buffers.iter_mut().filter(|(_, b)| !b.is_empty()) {
+            if let Err(why) = target.write_all(reference_name, buffer) {
+                eprintln!("Cannot write output for reference {}: {}", reference_name, why);
+            }
+            buffer.clear();
+        }
+        self.size = 0;
+    }
+}
+
+impl Drop for SplitOutputBuffer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_threshold_writes_every_record_through() {
+        let path = crate::test_utils::temp_path("output-buffer", "txt");
+        let mut buffer = OutputBuffer::new(Arc::new(Mutex::new(OutputTarget::File(File::create(&path).unwrap()))), 0);
+
+        buffer.write("first\n".to_string());
+        assert!(buffer.buffer.is_empty());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\n");
+        buffer.write("second\n".to_string());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+
+        drop(buffer);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn split_file_names_do_not_collide() {
+        let names = ["a/b", "a_b", "a%2Fb", "a b", ".hidden", "unmapped", "*", "chr1"];
+        let files: Vec<String> = names.iter().map(|n| SplitTarget::file_name(n)).collect();
+        for i in 0..files.len() {
+            for j in i + 1..files.len() {
+                assert_ne!(files[i], files[j], "{} and {} share a file", names[i], names[j]);
+            }
+        }
+        assert_eq!(SplitTarget::file_name("chr1"), "chr1");
+        assert_eq!(SplitTarget::file_name("a/b"), "a%2Fb");
+        assert_eq!(SplitTarget::file_name("*"), "unmapped");
+        assert!(!files.iter().any(|f| f.starts_with('.') || f.contains('/')));
+    }
+}
@@ -14,6 +14,8 @@ pub mod options;
 pub mod io;
 pub mod misc;
 pub mod utils;
+#[cfg(test)]
+pub(crate) mod test_utils;
 
 const GLOBAL_VERSION: u32 = 1;
 
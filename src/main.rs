@@ -5,7 +5,7 @@
 // #![feature(effects)]
 
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufReader, BufWriter, IsTerminal};
 
 use clap::Parser;
 #[allow(unused_parens)]
@@ -50,14 +50,22 @@ fn logo2() -> String {
 }
 
 fn main() {
+    let args: Args = Args::parse();
+
     // CAUTION: do not colorize anything that goes into stdout
     // otherwise the resulting sam files will be broken.
-    SHOULD_COLORIZE.set_override(true);
+    // The colored crate decides based on stdout, which is usually redirected, so decide for stderr here.
+    let colorize = !args.quiet && std::env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal();
+    SHOULD_COLORIZE.set_override(colorize);
 
-    eprintln!("{}", logo());
+    if !args.quiet && !args.no_logo {
+        eprintln!("{}", logo());
+    }
 
-    let args: Args = Args::parse();
+    let quiet = args.quiet;
     let (duration, _) = time(|| run(args));
 
-    eprintln!("Flexalign took {:?}", duration);
+    if !quiet {
+        eprintln!("Flexalign took {:?}", duration);
+    }
 }
\ No newline at end of file
@@ -1,10 +1,16 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{path::PathBuf, process::exit, str::FromStr};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_derive::Args;
 
 use crate::utils::infer_output_prefix;
 
+/// Smallest accepted --batch-size.
+pub const MIN_BATCH_SIZE: usize = 1 << 20;
+
+/// Default --max-insert-size. The insert size spans both mates, so two 150 base mates may lie up to 1000 bases apart.
+pub const MAX_INSERT_SIZE: i64 = 1300;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 #[command(styles=get_styles())]
@@ -24,7 +30,7 @@ pub struct Args {
     pub output: Option<String>,
 
     /// Database reference
-    #[arg(short = 'r', long = "reference", default_value_t = String::default())]
+    #[arg(short = 'r', long = "reference", global = true, default_value_t = String::default())]
     pub reference: String,
 
     /// Input map file 
@@ -35,28 +41,382 @@ pub struct Args {
     #[arg(short, long, default_value_t = 1)]
     pub threads: u32,
 
+    /// Threads for decompressing block gzip (BGZF) input. Plain gzip is always decompressed by a single thread.
+    #[arg(long = "threads-io", default_value_t = 1)]
+    pub threads_io: usize,
+
+    /// Bytes of FASTQ input handed to a worker thread at once. At least 1 MiB, so that a batch holds the longest
+    /// read.
+    #[arg(long = "batch-size", default_value_t = 1 << 24)]
+    pub batch_size: usize,
+
+    /// Write and flush alignments as soon as a read is processed instead of in 16 MiB blocks, for consumers
+    /// reading the output live. Every record takes the output lock and a write call, which costs throughput
+    /// with many threads. Reads still arrive in batches of --batch-size bytes.
+    #[arg(long = "flush-per-read")]
+    pub flush_per_read: bool,
+
     /// How many minimizers should be looked at
     #[arg(short = 'a', long = "ranges", default_value_t = 15)]
     pub ranges: u32,
 
-    /// For a single minimizer, how many occurances may there be at max.
+    /// For a single minimizer, how many occurances may there be at max. This is applied when building the index
+    /// and cannot be raised afterwards without a rebuild (see --query-max-range-size).
     #[arg(short = 'b', long = "max-range-size", default_value_t = 256)]
     pub max_range_size: usize,
 
+    /// Query-time cap on occurrences per minimizer. Minimizers with larger ranges in the (possibly pre-built) index
+    /// are skipped during seeding. Unlike --max-range-size this does not require rebuilding the index.
+    #[arg(long = "query-max-range-size")]
+    pub query_max_range_size: Option<usize>,
+
+    /// Skip minimizers whose core-mer has a normalized dinucleotide entropy below this value (0-1).
+    /// Homopolymers score 0, dinucleotide repeats around 0.26. 0 disables the filter.
+    #[arg(long = "min-complexity", default_value_t = 0.0)]
+    pub min_complexity: f64,
+
+    /// File of repetitive k-mers that never seed, one C-mer or K-mer per line. Matching is by canonical core-mer,
+    /// so the index does not need to be rebuilt.
+    #[arg(long = "kmer-blacklist")]
+    pub kmer_blacklist: Option<PathBuf>,
+
+    /// Reads in which a single minimizer makes up more than this fraction of all minimizers (e.g. poly-A) are
+    /// not seeded and stay unmapped, e.g. 0.9. 1 disables the check.
+    #[arg(long = "max-repeat-kmer-fraction", default_value_t = 1.0)]
+    pub max_repeat_kmer_fraction: f64,
+
+    /// Test only every N-th read position for a minimizer, which reports fewer k-mers per read. A stride above 1
+    /// can miss all seeds between closely spaced SNPs.
+    #[arg(long = "kmer-stride", default_value_t = 1)]
+    pub kmer_stride: usize,
+
     /// For all occurrences of a key, flexalign only takes the seeds with the highest matching flanking region.
-    /// This limits the number of values to be retrieved in this scenario. 
+    /// This limits the number of values to be retrieved in this scenario. Only applies to ranges large enough to
+    /// carry flex headers, and values above --max-range-size never filter anything. Reads with fewer than
+    /// --min-ranges seeded ranges are looked up again with a limit of 128.
     #[arg(short = 'f', long = "max-best-flex", default_value_t = 16)]
     pub max_best_flex: usize,
 
+    /// --max-best-flex for read 2 of paired-end input, e.g. to seed lower quality R2 reads more permissively.
+    /// Read 1 always uses --max-best-flex.
+    #[arg(long = "rev-max-best-flex")]
+    pub rev_max_best_flex: Option<usize>,
+
+    /// --max-range-size for the seeding of read 2 of paired-end input, defaults to --max-range-size. Read 1
+    /// always uses --max-range-size. Larger ranges than the index was built with do not exist.
+    #[arg(long = "rev-max-range-size")]
+    pub rev_max_range_size: Option<usize>,
+
+    /// --min-ranges for read 2 of paired-end input. Read 1 always uses --min-ranges.
+    #[arg(long = "rev-min-ranges")]
+    pub rev_min_ranges: Option<usize>,
+
     /// After the seeds are grouped into anchors, the top x will be extended with the use of hamming distance.
     /// This affects speed negatively but sensitivity and precision positively
     #[arg(short = 'x', long = "extend-top-x", default_value_t = 4)]
     pub extend_top_x: usize,
 
+    /// Keep only the best n anchor pairs of a read pair, before extension and before the full anchor sort. Bounds the
+    /// time spent on reads hitting large repeat families, but a true location ranked below n by seeds alone is lost
+    /// and MAPQ sees fewer competitors.
+    #[arg(long = "max-anchors-per-read")]
+    pub max_anchors_per_read: Option<usize>,
+
+    /// Only report pairs with both mates on the same reference within the maximum insert size. Orphaned mates
+    /// and pairs split across references are dropped and counted in the stats.
+    #[arg(long = "strict-pairing", action)]
+    pub strict_pairing: bool,
+
+    /// Largest insert size, from the outer end of one mate to the outer end of the other, of a properly paired or
+    /// co-anchored read pair.
+    #[arg(long = "max-insert-size", default_value_t = MAX_INSERT_SIZE)]
+    pub max_insert_size: i64,
+
     /// align the top y anchors. This happens after anchor extension 
     #[arg(short = 'y', long = "align-top-y", default_value_t = 4)]
     pub align_top_y: usize,
 
+    /// Soft-clip read ends rather than aligning them when a mismatch or gap falls within this many columns of
+    /// the end, e.g. for reads overhanging contig ends. 0 always extends the alignment to the read end.
+    #[arg(long = "end-clip-preference", default_value_t = 0)]
+    pub end_clip_preference: usize,
+
+    /// Free end columns of the flank alignment for anchors flagged for an indel: their seeds lie on different
+    /// diagonals, or another anchor of the read lies within 10 diagonals. Other anchors are placed ungapped on
+    /// their diagonal if they have at most 4 mismatches, and use 10 otherwise.
+    #[arg(long = "indel-free-ends", default_value_t = 20)]
+    pub indel_free_ends: usize,
+
+    /// How gaps between the seeds of an anchor are aligned. `hamming` compares them base by base, which
+    /// misaligns an indel between seeds, and keeps anchors on one diagonal. `align` aligns such gaps with WFA
+    /// and lets paired-end anchors span seeds up to 10 diagonals apart.
+    #[arg(long = "middle-gap", value_enum, default_value_t = MiddleGap::Hamming)]
+    pub middle_gap: MiddleGap,
+
+    /// Stop aligning further candidates of a read pair after this many milliseconds. The pair is reported with
+    /// the best result so far, falling back to the seed-based extension of unaligned anchors.
+    #[arg(long = "read-timeout-ms")]
+    pub read_timeout_ms: Option<u64>,
+
+    /// Additionally report up to N distinct references per read (PAF only). Extra lines carry `tp:A:S`,
+    /// their rank as `rk:i` and the seed score of the pair as `s1:i`. Limited by --extend-top-x.
+    #[arg(long = "report-top-n", default_value_t = 0)]
+    pub report_top_n: usize,
+
+    /// Report a read against at most N distinct references, counting the primary. Caps --report-top-n and
+    /// drops a --tie-policy report-both runner-up on another reference once reached.
+    #[arg(long = "max-references-per-read")]
+    pub max_references_per_read: Option<usize>,
+
+    /// Append minimap2's optional PAF tags (tp, cm, s1, s2, NM, AS, de, cg) to primary PAF lines. NM is the edit
+    /// distance of the reported cigar and AS the alignment score in aligner penalties, as with --score-tag.
+    #[arg(long = "paf-long", action)]
+    pub paf_long: bool,
+
+    /// Append the mate's reference as `mr:Z` (`=` on the same reference) and its 0-based start as `mp:i` to
+    /// paired PAF lines of mapped mates.
+    #[arg(long = "paf-mate-tags", action)]
+    pub paf_mate_tags: bool,
+
+    /// Minimum identity of paired-end alignments. Alignments whose score falls below it are dropped and the
+    /// next candidate reference is tried.
+    #[arg(long = "min-ani", default_value_t = 0.5)]
+    pub min_ani: f64,
+
+    /// Per-reference overrides of --min-ani, one `<reference name> <identity>` per line
+    #[arg(long = "min-ani-file")]
+    pub min_ani_file: Option<PathBuf>,
+
+    /// Treat all references as circular. Paired-end reads overhanging a reference end are aligned across the
+    /// origin and reported on the side of it with more aligned bases, the rest of the read soft-clipped.
+    #[arg(long = "circular", action)]
+    pub circular: bool,
+
+    /// Write a pileup of the primary paired-end alignments to this file after the run: base counts (A, C, G, T,
+    /// deletions, insertions) per covered reference position. Each hit reference keeps 24 bytes per base in
+    /// memory until the end, once per thread, which adds up for large references.
+    #[arg(long = "pileup")]
+    pub pileup: Option<PathBuf>,
+
+    /// Align paired-end reads against this reference only (name as in the index), to inspect reads that
+    /// should map there. Seeds on other references are ignored; a mate without seeds on it gets one from an
+    /// ungapped scan of the reference within the maximum insert size of its mate's seeds.
+    #[arg(long = "force-reference")]
+    pub force_reference: Option<String>,
+
+    /// With --force-reference, scan the whole reference for pairs where neither mate has seeds on it.
+    /// Slow: each such mate is compared at every position of the reference.
+    #[arg(long = "force-reference-scan", requires = "force_reference")]
+    pub force_reference_scan: bool,
+
+    /// Reference to rescue a paired-end mate on when it gets no seed at all but its mate has one there (repeatable).
+    /// The mate is aligned against windows within --max-insert-size of that seed; alignments below --min-ani are
+    /// abandoned.
+    #[arg(long = "priority-ref")]
+    pub priority_ref: Vec<String>,
+
+    /// Adapter sequence left on paired-end reads. A mate whose 3' soft-clip matches its start is trimmed there and
+    /// the pair is mapped again, once, so it is reported without the adapter. --dump-anchors lists the
+    /// anchors of the untrimmed pair.
+    #[arg(long = "adapter")]
+    pub adapter: Option<String>,
+
+    /// Also write the mapping summary printed at the end of the run (mapped, properly paired, mean identity
+    /// and MAPQ) to this file.
+    #[arg(long = "summary")]
+    pub summary: Option<PathBuf>,
+
+    /// Write every candidate anchor of every read to this TSV file: reference, strand, score and seeds
+    /// as `qpos:rpos:length`. Meant for offline analysis of mapping decisions, unlike the interactive `--debug`.
+    #[arg(long = "dump-anchors")]
+    pub dump_anchors: Option<PathBuf>,
+
+    /// Write only the score and identity of the reported alignment of every read to this TSV file, `*` for
+    /// unmapped reads, instead of the regular output. Meant for comparing runs. The score is in aligner
+    /// penalties as `AS:i` of --paf-long, for single- and paired-end reads alike. Nothing else is written,
+    /// so --output is rejected.
+    #[arg(long = "score-only", conflicts_with_all = ["output", "no_reference_seqs"])]
+    pub score_only: Option<PathBuf>,
+
+    /// Write the aligner calls, WFA score steps, aligned bases and wall time of every read pair to this TSV
+    /// file, to find the pairs that dominate the alignment time. Paired-end only.
+    #[arg(long = "dump-alignment-work")]
+    pub dump_alignment_work: Option<PathBuf>,
+
+    /// Pairs of unoriented single-seed mates are resolved from the combined seed evidence of both mates. If
+    /// both strands remain possible, the pair is reported with the tag st:A:? or dropped.
+    #[arg(long = "ambiguous-strand", value_enum, default_value_t = AmbiguousStrand::Report)]
+    pub ambiguous_strand: AmbiguousStrand,
+
+    /// Paired-end mates shorter than this fail QC and are not seeded. 0 disables the check.
+    #[arg(long = "qc-min-length", default_value_t = 0)]
+    pub qc_min_length: usize,
+
+    /// Paired-end mates with a lower mean phred quality fail QC and are not seeded. 0 disables the check.
+    #[arg(long = "qc-min-mean-quality", default_value_t = 0.0)]
+    pub qc_min_mean_quality: f64,
+
+    /// Write paired-end mates failing QC (see --qc-min-length, --qc-min-mean-quality, --max-repeat-kmer-fraction)
+    /// to SAM with flag 0x200, including pairs that are otherwise left out as unmapped, whichever step drops them.
+    #[arg(long = "qc-fail-flag", action)]
+    pub qc_fail_flag: bool,
+
+    /// Write the PAF coordinate and count columns like minimap2: query coordinates on the read as given (also
+    /// for '-'), ranges of the aligned region without soft-clips, residue matches as identical bases only and
+    /// block length as alignment columns. By default the seed range, on the reverse complement for '-', and
+    /// the read length minus mismatches are reported.
+    #[arg(long = "compat-minimap-paf", action)]
+    pub compat_minimap_paf: bool,
+
+    /// Add the alignment status of reported paired-end mates as `fl:A:O` (OK), `fl:A:P` (partial) or `fl:A:D`
+    /// (dropped by the score limit). Mates that were not aligned get no tag.
+    #[arg(long = "status-tag", action)]
+    pub status_tag: bool,
+
+    /// Add the raw WFA score of aligned paired-end mates as `AS:i` (0 for a perfect match, negative otherwise,
+    /// see the aligner penalties). Mates that were not aligned or were dropped get no tag.
+    #[arg(long = "score-tag", action)]
+    pub score_tag: bool,
+
+    /// Add `ZC:i` with the number of distinct references the read maps to equally well. Together with MAPQ 0 it
+    /// tells a repeat within one reference from ambiguity across references.
+    #[arg(long = "zc-tag", action)]
+    pub zc_tag: bool,
+
+    /// Write the output into this directory with one file per reference (`<reference>.paf`/`.sam`) and
+    /// `unmapped.*` for unmapped reads, instead of stdout or --output. Every record goes to the file of its own
+    /// reference, so secondary and supplementary records may land apart from their primary. Characters other
+    /// than `[A-Za-z0-9_.-]` in reference names are percent-encoded in the file names.
+    #[arg(long = "split-by-reference")]
+    pub split_by_reference: Option<String>,
+
+    /// Write reads that stay unmapped (no anchor, or every alignment dropped below --min-ani) as FASTQ, for
+    /// example to pass host-depleted reads on. Single-end reads go to `<prefix>.fq`, pairs with any unmapped
+    /// mate to `<prefix>_1.fq` and `<prefix>_2.fq`.
+    #[arg(long = "unmapped-fastq")]
+    pub unmapped_fastq: Option<String>,
+
+    /// Charge mismatches at low-quality bases (phred < 20) only part of the mismatch penalty, proportional to their quality.
+    #[arg(long = "qual-aware", action)]
+    pub qual_aware: bool,
+
+    /// Mate orientation of paired-end libraries. Determines the strand inferred for a mate from the other one
+    /// and which pairs are flagged as properly paired.
+    #[arg(long = "library-type", value_enum, default_value_t = LibraryType::Fr)]
+    pub library_type: LibraryType,
+
+    /// How seeds are grouped into anchors for single-end reads. `exact` groups seeds with identical offsets,
+    /// `chaining` links colinear seeds and tolerates small indels between them.
+    #[arg(long = "anchor-strategy", value_enum, default_value_t = AnchorStrategy::Exact)]
+    pub anchor_strategy: AnchorStrategy,
+
+    /// Weight of core (seed) matches when ranking anchors
+    #[arg(long = "match-weight", default_value_t = 1)]
+    pub match_weight: i32,
+
+    /// Weight of seed mismatches when ranking anchors
+    #[arg(long = "mismatch-weight", default_value_t = 1)]
+    pub mismatch_weight: i32,
+
+    /// Weight of indels between seeds when ranking anchors. Raise it to prefer ungapped candidates.
+    #[arg(long = "indel-weight", default_value_t = 1)]
+    pub indel_weight: i32,
+
+    /// Rank single-end anchors by a colinear chaining score over their seeds instead of the weighted score
+    #[arg(long = "chaining", action)]
+    pub chaining: bool,
+
+    /// Mates whose total soft-clip length exceeds this are counted in the stats output
+    #[arg(long = "softclip-threshold", default_value_t = 20)]
+    pub softclip_threshold: usize,
+
+    /// Drop aligned paired-end mates with more soft-clipped bases than this
+    #[arg(long = "max-softclip")]
+    pub max_softclip: Option<usize>,
+
+    /// QC mode: rebuild every successfully aligned read from the reference and its cigar and report reads that
+    /// are not fully explained by it. Failures are counted in the stats.
+    #[arg(long = "roundtrip", action)]
+    pub roundtrip: bool,
+
+    /// Shift insertions and deletions of reported paired-end alignments to their leftmost position within
+    /// repeats, so equivalent alignments get the same cigar. Single-end alignments are ungapped.
+    #[arg(long = "left-align-indels", action)]
+    pub left_align_indels: bool,
+
+    /// Look up the reference of the reported pair once per read and reuse it for the output of both mates,
+    /// instead of looking it up again for each mate. The output is the same.
+    #[arg(long = "reference-buffer-reuse", action)]
+    pub reference_buffer_reuse: bool,
+
+    /// Merge neighbouring seeds of a paired-end anchor that are separated by at most this many mismatches
+    /// (and no indel) during seed extension. 0 merges only exact matches.
+    #[arg(long = "extend-max-mismatch", default_value_t = 0)]
+    pub extend_max_mismatch: usize,
+
+    /// Drop seeds shorter than this before grouping them into anchors. Exact flexmer hits have length K (31),
+    /// core-mer and inexact flexmer hits length C (15), so values in between keep exact hits only.
+    #[arg(long = "min-seed-length", default_value_t = 0)]
+    pub min_seed_length: usize,
+
+    /// Set the SAM duplicate flag (0x400) on read pairs whose mates align to the same 5' positions and strands
+    /// as an earlier pair. All distinct pair coordinates are kept in memory for the whole run.
+    #[arg(long = "mark-duplicates", action)]
+    pub mark_duplicates: bool,
+
+    /// Handling of reads whose best and second best candidates have the same score
+    #[arg(long = "tie-policy", value_enum, default_value_t = TiePolicy::Best)]
+    pub tie_policy: TiePolicy,
+
+    /// Treatment of lowercase bases in reads
+    #[arg(long = "query-case", value_enum, default_value_t = QueryCase::Upper)]
+    pub query_case: QueryCase,
+
+    /// How identity is computed from the cigar for the M8 %identity, the `de:f` divergence of --paf-long and
+    /// the identity statistics
+    #[arg(long = "identity-model", value_enum, default_value_t = IdentityModel::Blast)]
+    pub identity_model: IdentityModel,
+
+    /// Single-end anchors: also split the seeds of a reference into separate groups where consecutive seeds
+    /// are more than this many bases apart. Should exceed the read length. Requires seeds sorted by position
+    /// (--seed-sort-key rpos).
+    #[arg(long = "group-rpos-window")]
+    pub group_rpos_window: Option<u64>,
+
+    /// Seed of a seed group that the other seeds are compared against when forming anchors. Seeds on another
+    /// diagonal form separate anchors, so a spurious `first` seed can split a true location into several.
+    /// Not used by --anchor-strategy chaining.
+    #[arg(long = "group-seed", value_enum, default_value_t = GroupSeed::First)]
+    pub group_seed: GroupSeed,
+
+    /// Order of the seeds of a reference before they are grouped into anchors
+    #[arg(long = "seed-sort-key", value_enum, default_value_t = SeedSortKey::Rpos)]
+    pub seed_sort_key: SeedSortKey,
+
+    /// Print the identity distribution of the reported paired-end alignments after each input, as a plot and
+    /// as the share of mates above common --min-ani values
+    #[arg(long = "identity-histogram", action)]
+    pub identity_histogram: bool,
+
+    /// MAPQ model for paired-end reads
+    #[arg(long = "mapq-model", value_enum, default_value_t = MapqModel::Simple)]
+    pub mapq_model: MapqModel,
+
+    /// Score margin below the best pair within which a candidate counts as near-best (--mapq-model bwa)
+    #[arg(long = "mapq-margin", default_value_t = 5)]
+    pub mapq_margin: i32,
+
+    /// Upper bound on the capacity (in elements) kept by each per-thread scratch buffer (k-mers, ranges, seeds,
+    /// anchors) between reads. Buffers that grew larger for an outlier read are shrunk before the next one.
+    /// Output is not affected. Unbounded by default.
+    #[arg(long = "max-scratch-capacity")]
+    pub max_scratch_capacity: Option<usize>,
+
+    /// Sort implementation for seeds, ranges and anchors. Without it each sort keeps its default: glidesort
+    /// for seeds and seed groups, the standard unstable sort for ranges and single-end anchor ranking.
+    #[arg(long = "sort-impl", value_enum)]
+    pub sort_impl: Option<SortImpl>,
+
     /// Minimum number of ranges for lookup. With max-best-flex defines, none of the ranges might actually yield any seeds.
     #[arg(long = "min-ranges", default_value_t = 4)]
     pub min_ranges: usize,
@@ -65,9 +425,262 @@ pub struct Args {
     #[arg(long = "force-build", action)]
     pub force_build: bool,
 
+    /// Use an existing index even if the reference is newer than it
+    #[arg(long = "no-rebuild", action)]
+    pub no_rebuild: bool,
+
+    /// Cells per body of the index. Fewer cells give a smaller index. The index remembers the value it was
+    /// built with, loading it with another value fails. Supported with --header-threshold: 8/2, 16/2, 32/2, 16/4.
+    #[arg(long = "cells-per-body", default_value_t = 16)]
+    pub cells_per_body: u64,
+
+    /// Header threshold of the flexmer headers in the index, which --max-best-flex works on. Recorded in the
+    /// index like --cells-per-body.
+    #[arg(long = "header-threshold", default_value_t = 2)]
+    pub header_threshold: usize,
+
+    /// Build the index only for the references listed in this file (one name per line). Other references of
+    /// the FASTA are not indexed and never reported. An existing index of another selection is rebuilt.
+    #[arg(long = "include-ref-file")]
+    pub include_ref_file: Option<String>,
+
+    /// Leave references shorter than the k-mer length out of the index. They can never be seeded, and are
+    /// otherwise kept with an id and listed in a warning during the build. An existing index built without this
+    /// flag, or with it when it is not given, is rebuilt.
+    #[arg(long = "drop-short-refs", action)]
+    pub drop_short_refs: bool,
+
+    /// Order in which references get their ids when building the index. Ties between equally good references
+    /// resolve to the lower id, so indexes built with different orders can report different references. An existing
+    /// index of another order is rebuilt.
+    #[arg(long = "reference-order", value_enum, default_value_t = ReferenceOrder::Fasta)]
+    pub reference_order: ReferenceOrder,
+
     /// force_build
     #[arg(long = "debug", action)]
     pub debug: bool,
+
+    /// Do not print the logo to stderr
+    #[arg(long = "no-logo", action)]
+    pub no_logo: bool,
+
+    /// No logo, no colors and no timing summary on stderr. Colors are also off if NO_COLOR is set or stderr is
+    /// not a terminal.
+    #[arg(short = 'q', long = "quiet", action)]
+    pub quiet: bool,
+
+    /// Output format
+    #[arg(long = "format", value_enum, default_value_t = OutputFormat::Paf)]
+    pub format: OutputFormat,
+
+    /// Load only names and lengths of the references from an existing index, not their bases. Cuts memory for
+    /// seed-based PAF output of single-end reads; anchors whose strand the seeds leave open stay unmapped.
+    #[arg(long = "no-reference-seqs", action)]
+    pub no_reference_seqs: bool,
+
+    /// Maximum number of alternative hits listed in the SAM XA tag. 0 disables the tag.
+    #[arg(long = "max-xa", default_value_t = 5)]
+    pub max_xa: usize,
+
+    /// Align a single literal sequence instead of reading FASTQ files and print details of the best anchor to stderr.
+    #[arg(long = "query-seq")]
+    pub query_seq: Option<String>,
+
+    /// Read group ID for SAM output. Adds an @RG header line and an RG:Z tag to every record.
+    #[arg(long = "read-group")]
+    pub read_group: Option<String>,
+
+    /// Sample name (SM) of the read group
+    #[arg(long = "sample")]
+    pub sample: Option<String>,
+
+    /// Library (LB) of the read group
+    #[arg(long = "library")]
+    pub library: Option<String>,
+
+    /// Sequencing platform (PL) of the read group, e.g. ILLUMINA
+    #[arg(long = "platform")]
+    pub platform: Option<String>,
+
+    /// Read name used with --query-seq
+    #[arg(long = "query-name", default_value_t = String::from("query"))]
+    pub query_name: String,
+
+    /// Print id, name, length and GC content of every reference in the index and exit.
+    #[arg(long = "list-references", action)]
+    pub list_references: bool,
+
+    /// Compute and store per-reference GC content while building the index. Otherwise it is computed
+    /// on the first --list-references.
+    #[arg(long = "reference-gc", action)]
+    pub reference_gc: bool,
+
+    /// Print the raw index contents (references, positions, flank distances) for a single k-mer and exit.
+    #[arg(long = "query-kmer")]
+    pub query_kmer: Option<String>,
+
+    /// Check that a sample of reference minimizers resolves to positions holding the same core-mer, report
+    /// the fraction of validated keys and exit. Exits with 9 if the index is inconsistent.
+    #[arg(long = "validate-index", action)]
+    pub validate_index: bool,
+
+    /// Align a few built-in sequence pairs with the WFA aligner and round-trip cigars through the RLE encoding,
+    /// print the results and exit. Needs no reference or reads. Exits with 9 if a check fails.
+    #[arg(long = "selftest", action)]
+    pub selftest: bool,
+
+    /// After processing all inputs, print a tab-separated `benchmark` line with wall-clock time and reads,
+    /// minimizers, seeds, anchors and alignments per second to stderr.
+    #[arg(long = "benchmark", action)]
+    pub benchmark: bool,
+
+    /// Write the reference with this name from the index to stdout as FASTA and exit.
+    #[arg(long = "extract-ref")]
+    pub extract_ref: Option<String>,
+
+    /// Bases per line for FASTA written by --extract-ref. 0 writes each sequence on a single line.
+    #[arg(long = "line-width", default_value_t = 60)]
+    pub line_width: usize,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Convert PAF (with cg:Z cigar tags) to SAM on stdout, using the reference lengths of the index (-r)
+    SamFromPaf {
+        /// Input PAF file
+        #[arg(long = "paf")]
+        paf: String,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Paf,
+    Sam,
+    /// BLAST tabular (outfmt 6)
+    M8,
+    /// GAF with every alignment on a single-segment path `>reference` or `<reference`
+    Gaf,
+}
+
+/// Relative orientation of the mates in a paired-end library
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LibraryType {
+    /// Mates on opposite strands, pointing towards each other (standard paired-end)
+    #[default]
+    Fr,
+    /// Mates on opposite strands, pointing away from each other (mate-pair)
+    Rf,
+    /// Both mates on the same strand, read 1 first
+    Ff,
+}
+
+/// Secondary sort key of seeds within a reference
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeedSortKey {
+    /// Reference position
+    Rpos,
+    /// Diagonal (reference minus query position), which puts colinear forward seeds next to each other
+    Offset,
+}
+
+/// Reference id assignment at build time
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReferenceOrder {
+    /// Order of the FASTA
+    Fasta,
+    /// Lexicographic by name, independent of the FASTA order
+    Name,
+    /// Longest first
+    Length,
+}
+
+/// Seed of a seed group the others are compared against (`--group-seed`)
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GroupSeed {
+    /// First seed in seed order
+    #[default]
+    First,
+    /// Longest seed
+    Longest,
+    /// Seed closest to the middle of the read
+    Central,
+}
+
+/// Alignment of the gaps between the seeds of an anchor
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MiddleGap {
+    /// Base by base comparison, assuming the seeds lie on one diagonal
+    #[default]
+    Hamming,
+    /// Aligns gaps with an indel between their seeds with WFA
+    Align,
+}
+
+/// Definition of the reported alignment identity
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdentityModel {
+    /// Matches over all alignment columns, gaps included
+    Blast,
+    /// Matches over matches, mismatches and gaps, each gap counted once as in minimap2
+    GapCompressed,
+}
+
+/// Treatment of lowercase (soft-masked) read bases
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryCase {
+    /// Same as uppercase
+    Upper,
+    /// Replaced by N: not seeded and aligned as mismatches
+    Mask,
+}
+
+/// What to report when the best and second best candidates have the same score
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TiePolicy {
+    /// Report the first candidate of the sorted list
+    Best,
+    /// Report both candidates with the same MAPQ, the second as a secondary alignment (SAM flag 0x100)
+    ReportBoth,
+    /// Report the first candidate with MAPQ 0 and the tag tp:A:?
+    Unmapped,
+}
+
+/// What to do with a pair whose strand the seeds of both mates cannot determine
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AmbiguousStrand {
+    /// Resolve each mate on its own and tag the pair with st:A:?
+    Report,
+    /// Leave the pair unmapped
+    Drop,
+}
+
+/// How paired-end MAPQ is derived from the sorted candidate list
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapqModel {
+    /// Score difference between the best and second best pair
+    Simple,
+    /// Best/second-best ratio penalized by the number of near-best pairs, following the BWA-MEM formula.
+    /// Not calibrated against simulated reads.
+    Bwa,
+}
+
+/// Sort used for seeds, ranges and anchors. Both sorts are stable and give the same order.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortImpl {
+    Glidesort,
+    Std,
+    /// Standard library sort for short lists, glidesort otherwise
+    Auto,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnchorStrategy {
+    Exact,
+    Chaining,
 }
 
 #[derive(Debug)]
@@ -131,6 +744,64 @@ impl Options {
         }
 
         self.reference.push(self.args.reference.clone());
+
+        if self.args.batch_size < MIN_BATCH_SIZE {
+            eprintln!("--batch-size {} is below the minimum of {} bytes", self.args.batch_size, MIN_BATCH_SIZE);
+            exit(9);
+        }
+
+        if self.args.max_anchors_per_read == Some(0) {
+            eprintln!("--max-anchors-per-read must be at least 1");
+            exit(9);
+        }
+        if self.args.max_references_per_read == Some(0) {
+            eprintln!("--max-references-per-read must be at least 1");
+            exit(9);
+        }
+        if !(0.0..=1.0).contains(&self.args.min_ani) {
+            eprintln!("--min-ani must be an identity in [0, 1], got {}", self.args.min_ani);
+            exit(9);
+        }
+        if self.args.max_insert_size < 1 {
+            eprintln!("--max-insert-size must be at least 1");
+            exit(9);
+        }
+
+        // Single-end reads take another workflow that does not implement these options.
+        if self.args.rev.iter().all(String::is_empty) {
+            let paired_only = [
+                (self.args.max_softclip.is_some(), "--max-softclip"),
+                (self.args.qc_min_length > 0, "--qc-min-length"),
+                (self.args.qc_min_mean_quality > 0.0, "--qc-min-mean-quality"),
+                (self.args.qc_fail_flag, "--qc-fail-flag"),
+                (self.args.force_reference.is_some(), "--force-reference"),
+                (self.args.left_align_indels, "--left-align-indels"),
+                (self.args.adapter.is_some(), "--adapter"),
+                (self.args.dump_alignment_work.is_some(), "--dump-alignment-work"),
+            ];
+            let mut conflicting = false;
+            for (_, option) in paired_only.iter().filter(|(set, _)| *set) {
+                eprintln!("{} only applies to paired-end input (-2)", option);
+                conflicting = true;
+            }
+            if conflicting {
+                exit(9);
+            }
+        }
+
+        // The window compares consecutive seeds, which are only ordered by position with the rpos key.
+        if self.args.group_rpos_window.is_some() && self.args.seed_sort_key == SeedSortKey::Offset {
+            eprintln!("--group-rpos-window cannot be combined with --seed-sort-key offset");
+            exit(9);
+        }
+
+        let max_range_size = self.args.query_max_range_size.map_or(self.args.max_range_size, |q| q.min(self.args.max_range_size));
+        if self.args.max_best_flex == 0 {
+            eprintln!("Warning: --max-best-flex 0 discards every range with flex headers, seeds only come from the recovery lookup");
+        } else if self.args.max_best_flex >= max_range_size {
+            eprintln!("Warning: --max-best-flex {} is not below the maximum range size {} and filters nothing",
+                self.args.max_best_flex, max_range_size);
+        }
     }
 }
 
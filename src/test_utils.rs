@@ -0,0 +1,88 @@
+//! Fixtures shared by the unit tests: a small index over pseudo-random bases, the seeds of reads against it and
+//! an allocation counter.
+
+use std::{alloc::{GlobalAlloc, Layout, System}, cell::Cell, path::PathBuf, sync::atomic::{AtomicUsize, Ordering}};
+
+use bioreader::sequence::fastq_record::RefFastqRecord;
+use clap::Parser;
+use kmerrs::syncmer::closed_syncmer::ClosedSyncmer;
+
+use crate::{align::{common::{KmerExtractor, RangeExtractor, SeedExtractor}, data_structures::Seed, process::{kmer_extractor::StdKmerExtractor, range_extractor::StdRangeExtractor, seed_extractor::StdSeedExtractor}, stats::Stats}, database::{common::FlexalignDatabase, flexmap::DB}, options::{Args, Options}};
+
+pub const K: usize = 31;
+pub const C: usize = 15;
+pub const F: usize = 16;
+pub const S: usize = 7;
+pub const L: usize = C - S + 1;
+
+pub type TestDB = DB<K, C, F, S, L, 16, 2>;
+
+/// Bases from a fixed linear congruential generator.
+pub fn pseudo_random_bases(len: usize, mut state: u64) -> Vec<u8> {
+    (0..len).map(|_| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        b"ACGT"[(state >> 62) as usize]
+    }).collect()
+}
+
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|b| match b { b'A' => b'T', b'C' => b'G', b'G' => b'C', _ => b'A' }).collect()
+}
+
+/// Path in the temporary directory that no other test of this process uses.
+pub fn temp_path(name: &str, extension: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!("flexalign-{}-{}-{}.{}", name, std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed), extension))
+}
+
+/// Options of `flexalign -r <reference> <args>` and the index built from `reference` as the single record `ref`.
+pub fn build_db(reference: &[u8], args: &[&str]) -> (Options, TestDB) {
+    let path = temp_path("reference", "fa");
+    std::fs::write(&path, [b">ref\n".as_slice(), reference, b"\n"].concat()).unwrap();
+    let options = Options::from_args(Args::parse_from(["flexalign", "-r", path.to_str().unwrap()].into_iter().chain(args.iter().copied())));
+    let db = TestDB::build(&options);
+    std::fs::remove_file(&path).unwrap();
+    (options, db)
+}
+
+/// Seeds of `seq` against `db` with the default seeding parameters and no complexity filter.
+pub fn seeds(db: &TestDB, seq: &[u8]) -> Vec<Seed> {
+    let quality = vec![b'I'; seq.len()];
+    let read = RefFastqRecord::new(b"read", seq, b"+", &quality);
+    let mut stats = Stats::default();
+    let mut kmer_extractor = StdKmerExtractor::<K, C, ClosedSyncmer<C, S, L>>::new(0.0);
+    let mut range_extractor = StdRangeExtractor::<K, C, F, _>::new(db, usize::MAX);
+    let mut seed_extractor = StdSeedExtractor::<K, C, F>::new(16, usize::MAX, 4, 0);
+
+    let kmers = kmer_extractor.generate(&read, &mut stats);
+    let ranges = range_extractor.generate(kmers, &mut stats);
+    seed_extractor.generate(ranges, &mut stats).to_vec()
+}
+
+/// Allocator of the test binary, counting the allocations of each thread.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Result of `f` and the number of allocations of the current thread while running it.
+pub fn count_allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (result, ALLOCATIONS.with(Cell::get) - before)
+}
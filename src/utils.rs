@@ -1,8 +1,8 @@
-use std::{cmp::max, collections::HashSet, fs::File, io::{self, BufRead}, path::Path};
+use std::{cmp::max, collections::HashSet, fs::File, io::{self, BufRead, Read}, path::Path};
 
 
 
-fn read_lines_from_file(filename: &str) -> io::Result<Vec<String>> {
+pub fn read_lines_from_file(filename: &str) -> io::Result<Vec<String>> {
     let path = Path::new(filename);
     let file = File::open(&path)?;
     let reader = io::BufReader::new(file);
@@ -15,6 +15,17 @@ fn read_lines_from_file(filename: &str) -> io::Result<Vec<String>> {
 }
 
 
+/// Whether the file is block gzip (BGZF): a gzip header with the FEXTRA flag and a `BC` extra subfield.
+pub fn is_bgzf(path: impl AsRef<Path>) -> io::Result<bool> {
+    let mut header = [0u8; 14];
+    let mut file = File::open(path)?;
+    if file.read(&mut header)? < header.len() {
+        return Ok(false)
+    }
+    Ok(header[0] == 0x1f && header[1] == 0x8b && header[2] == 8 && header[3] & 0x04 != 0 && &header[12..14] == b"BC")
+}
+
+
 pub fn infer_output_prefix(input: &[String]) -> Vec::<String> {
     let tokens: Vec<Vec<_>> = input.iter()
         .map(|s| { 